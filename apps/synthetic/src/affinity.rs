@@ -0,0 +1,179 @@
+use std::io;
+
+use libc;
+
+/// Cores the client's worker threads are pinned to, one for each worker in
+/// turn, so that they can be placed beside the NIC queues their flows land
+/// on.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CpuList {
+    cpus: &'static [usize],
+}
+
+impl CpuList {
+    pub fn new(cpus: Vec<usize>) -> CpuList {
+        CpuList {
+            cpus: Box::leak(cpus.into_boxed_slice()),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.cpus.is_empty()
+    }
+
+    /// The core worker `n` is pinned to, None if the list is unset.
+    pub fn cpu(&self, n: usize) -> Option<usize> {
+        match self.cpus.len() {
+            0 => None,
+            len => Some(self.cpus[n % len]),
+        }
+    }
+
+    /// Checks that every core listed is one the client may run on.
+    pub fn check(&self) -> io::Result<()> {
+        let allowed = affinity()?;
+        match self.cpus.iter().find(|cpu| !allowed.contains(cpu)) {
+            Some(cpu) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CPU {} is not one the client may run on", cpu),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Pins the calling thread, as worker `n`, to its core. The threads it
+    /// spawns after inherit the affinity, so a worker's receive threads run
+    /// on its core too. A thread that cannot be pinned is left to run where
+    /// it may, with a warning.
+    pub fn pin(&self, n: usize) {
+        if let Some(cpu) = self.cpu(n) {
+            if let Err(e) = set_affinity(&[cpu]) {
+                println!("Warning: could not pin worker {} to CPU {}: {}", n, cpu, e);
+            }
+        }
+    }
+
+    /// The cores listed, for the metadata.
+    pub fn to_string(self) -> Option<String> {
+        if self.cpus.is_empty() {
+            return None;
+        }
+        Some(
+            self.cpus
+                .iter()
+                .map(|cpu| cpu.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+}
+
+/// Parses a comma-separated list of cores and inclusive core ranges, as
+/// `0-3,8,10-11`, keeping the order they are listed in.
+pub fn parse_cpu_list(spec: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in spec.split(',') {
+        let bad = || format!("bad CPU list entry '{}', must be CPU or FIRST-LAST", part);
+        let mut bounds = part.splitn(2, '-');
+        let first = bounds.next().unwrap().trim();
+        let last = bounds.next().unwrap_or(first).trim();
+        let first: usize = first.parse().map_err(|_| bad())?;
+        let last: usize = last.parse().map_err(|_| bad())?;
+        if first > last || last >= libc::CPU_SETSIZE as usize {
+            return Err(bad());
+        }
+        for cpu in first..=last {
+            if cpus.contains(&cpu) {
+                return Err(format!("CPU {} is listed twice", cpu));
+            }
+            cpus.push(cpu);
+        }
+    }
+    Ok(cpus)
+}
+
+/// Restricts the calling thread to `cpus`.
+#[cfg(target_os = "linux")]
+pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_setaffinity(0, size, &set) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The cores the calling thread may run on, from the lowest up.
+#[cfg(target_os = "linux")]
+pub fn affinity() -> io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_getaffinity(0, size, &mut set) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn affinity() -> io::Result<Vec<usize>> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::other("thread affinity is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn cpu_list_formats() {
+        assert_eq!(parse_cpu_list("3"), Ok(vec![3]));
+        assert_eq!(parse_cpu_list("0-2, 8,5-5"), Ok(vec![0, 1, 2, 8, 5]));
+        for bad in &["", "a", "3-1", "1-", "0-3,2", "-1", "100000"] {
+            assert!(parse_cpu_list(bad).is_err(), "{}", bad);
+        }
+        let list = CpuList::new(vec![4, 6]);
+        assert_eq!(
+            (0..3).map(|n| list.cpu(n)).collect::<Vec<_>>(),
+            vec![Some(4), Some(6), Some(4)]
+        );
+        assert_eq!(list.to_string(), Some("4,6".to_string()));
+        assert_eq!(CpuList::default().cpu(0), None);
+        assert!(CpuList::new(vec![libc::CPU_SETSIZE as usize - 1])
+            .check()
+            .is_err());
+    }
+
+    #[test]
+    fn workers_pinned_to_their_cpu() {
+        // The last core the tests may run on, which every worker can take.
+        let allowed = affinity().unwrap();
+        let cpu = *allowed.last().unwrap();
+        let list = CpuList::new(vec![cpu]);
+        assert!(list.check().is_ok());
+        let worker = thread::spawn(move || {
+            list.pin(1);
+            let pinned = affinity().unwrap();
+            // Threads a worker spawns share its core.
+            let spawned = thread::spawn(|| affinity().unwrap()).join().unwrap();
+            (pinned, spawned)
+        });
+        assert_eq!(worker.join().unwrap(), (vec![cpu], vec![cpu]));
+        // Only the worker was pinned.
+        assert_eq!(affinity().unwrap(), allowed);
+    }
+}
@@ -2,10 +2,18 @@ use shenango::tcp::TcpConnection;
 use shenango::udp::UdpConnection;
 
 use std::any::Any;
+use std::cell::{Cell, RefCell};
 use std::io;
 use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
-use std::os::unix::io::AsRawFd;
+use std::mem;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket,
+};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
@@ -13,6 +21,416 @@ use net2::unix::UnixUdpBuilderExt;
 use net2::TcpBuilder;
 use net2::UdpBuilder;
 
+use tls::TlsStream;
+use uring::UringSocket;
+
+/// The wildcard address of `addr`'s family, at `port`.
+pub fn any_addr(addr: SocketAddr, port: u16) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+    }
+}
+
+/// The largest UDP payload a packet to `addr` carries: the largest IP
+/// packet, less the header of its family, which is 40 bytes for IPv6 rather
+/// than 20, and the UDP header.
+pub fn max_udp_payload(addr: SocketAddr) -> usize {
+    let ip_header = match addr {
+        SocketAddr::V4(_) => 20,
+        SocketAddr::V6(_) => 40,
+    };
+    65535 - ip_header - 8
+}
+
+/// Room for the largest datagram a UDP read can return.
+pub const MAX_DATAGRAM: usize = 65536;
+
+/// Parts a datagram may be sent from with one vectored send.
+const MAX_PARTS: usize = 4;
+
+/// Room for the control message of a segment size, in aligned words.
+const CONTROL_WORDS: usize = 4;
+
+/// The first `len` bytes of `scratch`, growing it first if it is shorter, so
+/// one buffer can be reused for reads of any size.
+pub fn scratch_space(scratch: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    if scratch.len() < len {
+        scratch.resize(len, 0);
+    }
+    &mut scratch[..len]
+}
+
+/// Size buckets of received datagrams: bucket 0 holds empty ones, and bucket
+/// `i` those of 2^(i-1) up to 2^i - 1 bytes, the last of which holds
+/// MAX_DATAGRAM.
+const SIZE_BUCKETS: usize = 18;
+
+/// Response datagrams received in each size bucket by the runs so far, added
+/// as each ends.
+static DATAGRAM_SIZES: [AtomicUsize; SIZE_BUCKETS] = [const { AtomicUsize::new(0) }; SIZE_BUCKETS];
+
+/// Records the size of a response datagram received, before it is parsed.
+pub fn record_datagram_size(len: usize) {
+    let bucket = (0usize.leading_zeros() - len.leading_zeros()) as usize;
+    counted(|t| add(&t.datagrams[usize::min(bucket, SIZE_BUCKETS - 1)], 1));
+}
+
+/// The size buckets of the response datagrams of every run so far, as
+/// `TrafficCounts::datagram_sizes` gives them.
+pub fn datagram_sizes() -> Vec<(usize, usize, usize)> {
+    let mut counts = TrafficCounts::default();
+    for (count, total) in counts.datagrams.iter_mut().zip(&DATAGRAM_SIZES) {
+        *count = total.load(Ordering::SeqCst);
+    }
+    counts.datagram_sizes()
+}
+
+/// Adds the response datagrams of a run that has ended to those of every
+/// run.
+pub fn add_datagram_sizes(counts: &TrafficCounts) {
+    for (total, &count) in DATAGRAM_SIZES.iter().zip(&counts.datagrams) {
+        total.fetch_add(count, Ordering::SeqCst);
+    }
+}
+
+/// The batched sends and receives of the runs so far, and the datagrams they
+/// carried, added as each ends.
+static BATCHES: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+/// Sends or receives of datagrams in batches, as sendmmsg, recvmmsg and the
+/// offloads make them, and the datagrams they carried in all.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Batches {
+    pub calls: u64,
+    pub datagrams: u64,
+}
+
+/// The batched sends and receives of every run so far.
+pub fn udp_batches() -> (Batches, Batches) {
+    let total = |i: usize| BATCHES[i].load(Ordering::SeqCst);
+    (
+        Batches {
+            calls: total(0),
+            datagrams: total(1),
+        },
+        Batches {
+            calls: total(2),
+            datagrams: total(3),
+        },
+    )
+}
+
+/// Adds the batched sends and receives of a run that has ended to those of
+/// every run.
+pub fn add_udp_batches(counts: &TrafficCounts) {
+    let (out, received) = counts.batches;
+    let counts = [out.calls, out.datagrams, received.calls, received.datagrams];
+    for (total, &count) in BATCHES.iter().zip(&counts) {
+        total.fetch_add(count, Ordering::SeqCst);
+    }
+}
+
+/// What one thread of a run has written and read: the bytes as they went
+/// over its sockets, so a TLS connection counts its records rather than the
+/// requests and responses in them, and the estimated bytes of the IP and
+/// transport headers that carried them, the response datagrams it received
+/// in each size bucket, and the batches it sent and received datagrams in.
+/// Only its own thread writes to them.
+#[derive(Default)]
+struct ThreadTraffic {
+    bytes_out: AtomicU64,
+    bytes_in: AtomicU64,
+    headers_out: AtomicU64,
+    headers_in: AtomicU64,
+    datagrams: [AtomicU64; SIZE_BUCKETS],
+    batches_out: AtomicU64,
+    batched_out: AtomicU64,
+    batches_in: AtomicU64,
+    batched_in: AtomicU64,
+}
+
+/// Adds to a counter only one thread writes to, without a locked add, as no
+/// other can race it. Others read it as they like.
+fn add(counter: &AtomicU64, n: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+}
+
+/// The bytes written and read in one run. Each of its threads counts its own
+/// in a `ThreadTraffic`, which the run adds up when it asks. A thread counts
+/// only while it counts for a run, and the threads it spawns then count for
+/// the same run.
+#[derive(Clone, Default)]
+pub struct Traffic {
+    threads: Arc<Mutex<Vec<Arc<ThreadTraffic>>>>,
+}
+
+thread_local! {
+    /// The run this thread counts for, and its own counts in it.
+    static COUNTING: RefCell<Option<(Traffic, Arc<ThreadTraffic>)>> = const { RefCell::new(None) };
+}
+
+impl Traffic {
+    /// Counts what this thread writes and reads for this run, until the
+    /// returned guard is dropped.
+    pub fn count_here(&self) -> Counting {
+        let counts = Arc::new(ThreadTraffic::default());
+        self.threads.lock().unwrap().push(counts.clone());
+        Counting {
+            previous: COUNTING.with(|c| c.replace(Some((self.clone(), counts)))),
+        }
+    }
+
+    /// The run this thread counts for, if any.
+    fn current() -> Option<Traffic> {
+        COUNTING.with(|c| c.borrow().as_ref().map(|(traffic, _)| traffic.clone()))
+    }
+
+    /// The bytes written and read so far, each with the headers estimated to
+    /// have carried them.
+    pub fn counts(&self) -> TrafficCounts {
+        let mut counts = TrafficCounts::default();
+        for t in self.threads.lock().unwrap().iter() {
+            counts.bytes.0 += t.bytes_out.load(Ordering::Relaxed);
+            counts.bytes.1 += t.bytes_in.load(Ordering::Relaxed);
+            counts.headers.0 += t.headers_out.load(Ordering::Relaxed);
+            counts.headers.1 += t.headers_in.load(Ordering::Relaxed);
+            for (count, n) in counts.datagrams.iter_mut().zip(&t.datagrams) {
+                *count += n.load(Ordering::Relaxed) as usize;
+            }
+            counts.batches.0.calls += t.batches_out.load(Ordering::Relaxed);
+            counts.batches.0.datagrams += t.batched_out.load(Ordering::Relaxed);
+            counts.batches.1.calls += t.batches_in.load(Ordering::Relaxed);
+            counts.batches.1.datagrams += t.batched_in.load(Ordering::Relaxed);
+        }
+        counts
+    }
+}
+
+/// What a run has written and read, out then in, the response datagrams it
+/// received in each size bucket, and the batches it sent and received
+/// datagrams in.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TrafficCounts {
+    pub bytes: (u64, u64),
+    pub headers: (u64, u64),
+    datagrams: [usize; SIZE_BUCKETS],
+    pub batches: (Batches, Batches),
+}
+
+impl TrafficCounts {
+    /// The smallest and largest size of each bucket that response datagrams
+    /// were received in, and how many were.
+    pub fn datagram_sizes(&self) -> Vec<(usize, usize, usize)> {
+        self.datagrams
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| match i {
+                0 => (0, 0, count),
+                _ => (1 << (i - 1), (1 << i) - 1, count),
+            })
+            .filter(|&(_, _, count)| count > 0)
+            .collect()
+    }
+}
+
+/// Keeps a thread counting for a run until dropped, when it goes back to
+/// counting for whatever it counted for before.
+pub struct Counting {
+    previous: Option<(Traffic, Arc<ThreadTraffic>)>,
+}
+
+impl Drop for Counting {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        COUNTING.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Runs `f` on this thread's counts, if it counts for a run.
+fn counted(f: impl FnOnce(&ThreadTraffic)) {
+    COUNTING.with(|c| {
+        if let Some((_, ref counts)) = *c.borrow() {
+            f(counts);
+        }
+    });
+}
+
+/// IP headers without options.
+const IPV4_HEADER: u64 = 20;
+const IPV6_HEADER: u64 = 40;
+const UDP_HEADER: u64 = 8;
+/// A TCP header with timestamps.
+const TCP_HEADER: u64 = 32;
+/// The largest packet, headers included, that a segment is sized to.
+const MTU: u64 = 1500;
+
+thread_local! {
+    /// Bytes read on this thread since the last response ended.
+    static READ_HERE: Cell<u64> = const { Cell::new(0) };
+    /// The size of the last response read on this thread.
+    static LAST_RESPONSE: Cell<u64> = const { Cell::new(0) };
+}
+
+/// How a connection's bytes are carried, to estimate the headers they took
+/// on the wire.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Framing {
+    Tcp { ipv6: bool },
+    Udp { ipv6: bool },
+    Unix,
+}
+
+impl Framing {
+    fn of(udp: bool, addr: io::Result<SocketAddr>) -> Framing {
+        // A socket whose address cannot be had is counted as IPv4, as the
+        // runtime's always are.
+        let ipv6 = addr.is_ok_and(|a| a.is_ipv6());
+        if udp {
+            Framing::Udp { ipv6 }
+        } else {
+            Framing::Tcp { ipv6 }
+        }
+    }
+
+    /// The headers that carried `len` bytes written in one call or read as one
+    /// response: the datagram's, or those of each full sized segment of a
+    /// stream, counting none for acknowledgements. A unix socket has none.
+    fn headers(self, len: usize) -> u64 {
+        let ip = |ipv6| if ipv6 { IPV6_HEADER } else { IPV4_HEADER };
+        match self {
+            Framing::Udp { ipv6 } => ip(ipv6) + UDP_HEADER,
+            Framing::Tcp { ipv6 } => {
+                let headers = ip(ipv6) + TCP_HEADER;
+                let mss = MTU - headers;
+                (len as u64).div_ceil(mss) * headers
+            }
+            Framing::Unix => 0,
+        }
+    }
+
+    /// The headers of each datagram over this framing.
+    fn datagram_headers(self) -> u64 {
+        self.headers(0)
+    }
+
+    /// The headers counted for a read of `len` bytes. A stream's reads split
+    /// and join its segments, so its headers are counted for each response
+    /// instead, by `response_headers`.
+    fn read_headers(self, len: usize) -> u64 {
+        match self {
+            Framing::Tcp { .. } => 0,
+            _ => self.headers(len),
+        }
+    }
+
+    /// The headers of a response of `len` bytes not counted for its reads.
+    fn response_headers(self, len: usize) -> u64 {
+        match self {
+            Framing::Tcp { .. } => self.headers(len),
+            _ => 0,
+        }
+    }
+}
+
+/// Counts the bytes of a write if it succeeded.
+fn counted_write(framing: Framing, result: io::Result<usize>) -> io::Result<usize> {
+    if let Ok(len) = result {
+        count_written(len, framing.headers(len));
+    }
+    result
+}
+
+/// Counts the bytes of a read if it succeeded.
+fn counted_read(framing: Framing, result: io::Result<usize>) -> io::Result<usize> {
+    if let Ok(len) = result {
+        count_read(len, framing.read_headers(len));
+    }
+    result
+}
+
+fn count_written(len: usize, headers: u64) {
+    counted(|t| {
+        add(&t.bytes_out, len as u64);
+        add(&t.headers_out, headers);
+    });
+}
+
+fn count_read(len: usize, headers: u64) {
+    counted(|t| {
+        add(&t.bytes_in, len as u64);
+        add(&t.headers_in, headers);
+    });
+    READ_HERE.with(|n| n.set(n.get() + len as u64));
+}
+
+/// Counts `len` bytes read from a connection's socket by its descriptor,
+/// as a poller reads them, rather than through the connection.
+pub fn count_polled(framing: Framing, len: usize) {
+    count_read(len, framing.read_headers(len));
+}
+
+/// Counts the headers of a response of `len` bytes, as a poller that parses
+/// the responses out of what it read finds them.
+pub fn count_response(framing: Framing, len: usize) {
+    counted(|t| add(&t.headers_in, framing.response_headers(len)));
+}
+
+/// Ends the response being read on this thread: the bytes read since the last
+/// one ended are its size, which is returned and which `take_bytes_read`
+/// then gives.
+pub fn response_read(framing: Framing) -> usize {
+    let len = READ_HERE.with(|n| n.replace(0));
+    count_response(framing, len as usize);
+    LAST_RESPONSE.with(|n| n.set(len));
+    len as usize
+}
+
+/// Counts a send of `datagrams` in one batch.
+fn batch_sent(datagrams: usize) {
+    counted(|t| {
+        add(&t.batches_out, 1);
+        add(&t.batched_out, datagrams as u64);
+    });
+}
+
+/// Counts a receive of `datagrams` in one batch.
+fn batch_received(datagrams: usize) {
+    counted(|t| {
+        add(&t.batches_in, 1);
+        add(&t.batched_in, datagrams as u64);
+    });
+}
+
+/// Counts the bytes of the first `sent` of `datagrams` as written, in one
+/// batch.
+fn datagrams_sent(
+    framing: Framing,
+    datagrams: &[&[u8]],
+    sent: io::Result<usize>,
+) -> io::Result<usize> {
+    if let Ok(n) = sent {
+        let len: usize = datagrams[..n].iter().map(|d| d.len()).sum();
+        count_written(len, n as u64 * framing.datagram_headers());
+        batch_sent(n);
+    }
+    sent
+}
+
+/// The size of the response this thread last read, which a receiver that
+/// reads one response at a time records.
+pub fn take_bytes_read() -> usize {
+    LAST_RESPONSE.with(|n| n.replace(0)) as usize
+}
+
+/// `addr` for the runtime, whose sockets are IPv4 only.
+fn runtime_addr(addr: SocketAddr) -> io::Result<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Ok(addr),
+        SocketAddr::V6(addr) => Err(Error::other(format!("{}: the runtime has no IPv6", addr))),
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Backend {
     Linux,
@@ -21,51 +439,95 @@ pub enum Backend {
 impl Backend {
     pub fn create_udp_connection(
         &self,
-        local_addr: SocketAddrV4,
-        remote_addr: Option<SocketAddrV4>,
+        local_addr: SocketAddr,
+        remote_addr: Option<SocketAddr>,
     ) -> io::Result<Connection> {
         Ok(match (self, remote_addr) {
-            (&Backend::Linux, None) => Connection::LinuxUdp(
-                UdpBuilder::new_v4()?
-                    .reuse_address(true)?
-                    .reuse_port(true)?
-                    .bind(local_addr)?,
-            ),
-            (&Backend::Runtime, None) => Connection::RuntimeUdp(UdpConnection::listen(local_addr)?),
+            (&Backend::Linux, None) => {
+                let builder = match local_addr {
+                    SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+                    SocketAddr::V6(_) => UdpBuilder::new_v6()?,
+                };
+                Connection::LinuxUdp(
+                    builder
+                        .reuse_address(true)?
+                        .reuse_port(true)?
+                        .bind(local_addr)?,
+                )
+            }
+            (&Backend::Runtime, None) => {
+                Connection::RuntimeUdp(UdpConnection::listen(runtime_addr(local_addr)?)?)
+            }
             (&Backend::Linux, Some(remote_addr)) => {
-                let socket = UdpSocket::bind(local_addr)?;
+                let socket = UdpSocket::bind(local_addr).map_err(|e| bind_error(local_addr, e))?;
                 socket.connect(remote_addr)?;
                 Connection::LinuxUdp(socket)
             }
-            (&Backend::Runtime, Some(remote_addr)) => {
-                Connection::RuntimeUdp(UdpConnection::dial(local_addr, remote_addr)?)
-            }
+            (&Backend::Runtime, Some(remote_addr)) => Connection::RuntimeUdp(UdpConnection::dial(
+                runtime_addr(local_addr)?,
+                runtime_addr(remote_addr)?,
+            )?),
         })
     }
 
     pub fn create_tcp_connection(
         &self,
-        local_addr: Option<SocketAddrV4>,
-        remote_addr: SocketAddrV4,
+        local_addr: Option<SocketAddr>,
+        remote_addr: SocketAddr,
     ) -> io::Result<Connection> {
         let laddr = match local_addr {
             Some(x) => x,
-            _ => "0.0.0.0:0".parse().unwrap(),
+            _ => any_addr(remote_addr, 0),
         };
-        Ok(match *self {
-            Backend::Linux => Connection::LinuxTcp(TcpStream::connect(remote_addr)?),
-            Backend::Runtime => Connection::RuntimeTcp(TcpConnection::dial(laddr, remote_addr)?),
+        Ok(match (*self, local_addr) {
+            (Backend::Linux, None) => Connection::LinuxTcp(TcpStream::connect(remote_addr)?),
+            (Backend::Linux, Some(local_addr)) => {
+                let builder = match local_addr {
+                    SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+                    SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+                };
+                // A port left in TIME_WAIT by an earlier session can be bound
+                // again.
+                builder
+                    .reuse_address(true)?
+                    .bind(local_addr)
+                    .map_err(|e| bind_error(local_addr, e))?;
+                Connection::LinuxTcp(builder.connect(remote_addr)?)
+            }
+            (Backend::Runtime, _) => Connection::RuntimeTcp(TcpConnection::dial(
+                runtime_addr(laddr)?,
+                runtime_addr(remote_addr)?,
+            )?),
         })
     }
 
-    pub fn create_tcp_listener(&self, local_addr: SocketAddrV4) -> io::Result<ConnectionListener> {
+    /// A stream to the server listening on the unix socket at `path`. Its
+    /// errors name the path.
+    pub fn create_unix_connection(&self, path: &str) -> io::Result<Connection> {
+        match *self {
+            Backend::Linux => UnixStream::connect(path)
+                .map(Connection::LinuxUnix)
+                .map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e))),
+            Backend::Runtime => Err(Error::other(format!(
+                "{}: the runtime has no unix sockets",
+                path
+            ))),
+        }
+    }
+
+    pub fn create_tcp_listener(&self, local_addr: SocketAddr) -> io::Result<ConnectionListener> {
         Ok(match *self {
             Backend::Linux => {
-                ConnectionListener::LinuxTcp(TcpBuilder::new_v4()?.bind(local_addr)?.listen(1024)?)
-            }
-            Backend::Runtime => {
-                ConnectionListener::RuntimeTcp(shenango::tcp::TcpQueue::listen(local_addr, 1024)?)
+                let builder = match local_addr {
+                    SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+                    SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+                };
+                ConnectionListener::LinuxTcp(builder.bind(local_addr)?.listen(1024)?)
             }
+            Backend::Runtime => ConnectionListener::RuntimeTcp(shenango::tcp::TcpQueue::listen(
+                runtime_addr(local_addr)?,
+                1024,
+            )?),
         })
     }
 
@@ -75,6 +537,12 @@ impl Backend {
         F: FnOnce() -> T,
         F: Send + 'static,
     {
+        // A thread spawned for a run counts for it too.
+        let traffic = Traffic::current();
+        let f = move || {
+            let _counting = traffic.as_ref().map(Traffic::count_here);
+            f()
+        };
         match *self {
             Backend::Linux => JoinHandle::Linux(thread::spawn(f)),
             Backend::Runtime => JoinHandle::Runtime(shenango::thread::spawn(f)),
@@ -135,45 +603,476 @@ impl ConnectionListener {
     }
 }
 
+/// Socket options set on every client connection as it is opened.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm on TCP connections.
+    pub nodelay: bool,
+    /// SO_SNDBUF and SO_RCVBUF in bytes, or None for the system's defaults.
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// IP type of service byte, DSCP and ECN.
+    pub tos: Option<u8>,
+}
+
+/// Says what to fix when a connection cannot be bound to `addr`.
+fn bind_error(addr: SocketAddr, e: Error) -> Error {
+    let why = match e.kind() {
+        ErrorKind::AddrInUse => "the port is in use",
+        ErrorKind::AddrNotAvailable => "the address is not one of this host's",
+        _ => "",
+    };
+    let message = match why {
+        "" => format!("could not bind {}: {}", addr, e),
+        why => format!("could not bind {}, {}: {}", addr, why, e),
+    };
+    Error::new(e.kind(), message)
+}
+
+fn unsupported() -> Error {
+    Error::other("unsupported by the runtime's sockets")
+}
+
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: usize) -> io::Result<()> {
+    if value > libc::c_int::MAX as usize {
+        return Err(Error::from_raw_os_error(libc::EINVAL));
+    }
+    let value = value as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
 pub enum Connection {
     LinuxTcp(TcpStream),
     LinuxUdp(UdpSocket),
+    LinuxUnix(UnixStream),
     RuntimeUdp(shenango::udp::UdpConnection),
     RuntimeTcp(shenango::tcp::TcpConnection),
+    /// TLS over any of the stream connections.
+    Tls(Box<TlsStream>),
+    /// Any of the Linux connections, doing its I/O through io_uring.
+    Uring(Box<UringSocket>),
 }
 
 impl Connection {
-    pub fn send_to(&self, buf: &[u8], addr: SocketAddrV4) -> io::Result<usize> {
+    pub fn framing(&self) -> Framing {
         match *self {
-            Connection::LinuxUdp(ref s) => s.send_to(buf, addr),
-            Connection::RuntimeUdp(ref s) => s.write_to(buf, addr),
-            _ => Err(Error::new(ErrorKind::Other, "unimplemented")),
+            Connection::LinuxUdp(ref s) => Framing::of(true, s.local_addr()),
+            Connection::LinuxTcp(ref s) => Framing::of(false, s.local_addr()),
+            Connection::RuntimeUdp(_) => Framing::Udp { ipv6: false },
+            Connection::RuntimeTcp(_) => Framing::Tcp { ipv6: false },
+            Connection::LinuxUnix(_) => Framing::Unix,
+            Connection::Tls(ref s) => s.inner().framing(),
+            Connection::Uring(ref s) => s.inner().framing(),
         }
     }
-    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
         match *self {
-            Connection::LinuxUdp(ref s) => s.recv_from(buf).map(|(len, addr)| match addr {
-                SocketAddr::V4(addr) => (len, addr),
-                _ => unreachable!(),
-            }),
-            Connection::RuntimeUdp(ref s) => s.read_from(buf),
-            _ => Err(Error::new(ErrorKind::Other, "unimplemented")),
+            Connection::LinuxUdp(ref s) => {
+                counted_write(Framing::of(true, Ok(addr)), s.send_to(buf, addr))
+            }
+            Connection::RuntimeUdp(ref s) => counted_write(
+                Framing::Udp { ipv6: false },
+                s.write_to(buf, runtime_addr(addr)?),
+            ),
+            Connection::Uring(ref s) => s.inner().send_to(buf, addr),
+            _ => Err(Error::other("sending to an address needs a UDP socket")),
+        }
+    }
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let received = match *self {
+            Connection::LinuxUdp(ref s) => s.recv_from(buf),
+            Connection::RuntimeUdp(ref s) => s
+                .read_from(buf)
+                .map(|(len, addr)| (len, SocketAddr::V4(addr))),
+            Connection::Uring(ref s) => return s.inner().recv_from(buf),
+            _ => Err(Error::other("receiving from an address needs a UDP socket")),
+        };
+        if let Ok((len, from)) = received {
+            count_read(len, Framing::of(true, Ok(from)).datagram_headers());
         }
+        received
     }
 
-    pub fn local_addr(&self) -> SocketAddrV4 {
-        match *self {
-            Connection::LinuxUdp(ref s) => match s.local_addr() {
-                Ok(SocketAddr::V4(addr)) => addr,
-                _ => unreachable!(),
+    /// Sends each of `datagrams` as a message of its own, all with a single
+    /// sendmmsg over a Linux UDP socket, or linked SENDs through io_uring,
+    /// and one at a time otherwise. Returns how many were sent, which is
+    /// fewer than all only if the next one could not be.
+    pub fn send_datagrams(&self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        let socket = match *self {
+            Connection::LinuxUdp(ref s) => s,
+            Connection::Uring(ref s) => {
+                return datagrams_sent(self.framing(), datagrams, s.send_datagrams(datagrams))
+            }
+            _ => {
+                for (n, datagram) in datagrams.iter().enumerate() {
+                    if let Err(e) = (&*self).write_all(datagram) {
+                        return if n > 0 { Ok(n) } else { Err(e) };
+                    }
+                    batch_sent(1);
+                }
+                return Ok(datagrams.len());
+            }
+        };
+        let mut iovecs: Vec<libc::iovec> = datagrams
+            .iter()
+            .map(|d| libc::iovec {
+                iov_base: d.as_ptr() as *mut libc::c_void,
+                iov_len: d.len(),
+            })
+            .collect();
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iov| {
+                let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+                message.msg_hdr.msg_iov = iov;
+                message.msg_hdr.msg_iovlen = 1;
+                message
+            })
+            .collect();
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+            )
+        };
+        datagrams_sent(
+            self.framing(),
+            datagrams,
+            match sent {
+                -1 => Err(Error::last_os_error()),
+                n => Ok(n as usize),
             },
-            Connection::LinuxTcp(ref s) => match s.local_addr() {
-                Ok(SocketAddr::V4(addr)) => addr,
-                _ => unreachable!(),
+        )
+    }
+
+    /// Sends `parts` as one datagram with a single sendmsg over a Linux UDP
+    /// socket, so that they need not be copied together first. Returns the
+    /// bytes sent.
+    pub fn send_vectored(&self, parts: &[&[u8]]) -> io::Result<usize> {
+        let socket = match *self {
+            Connection::LinuxUdp(ref s) => s,
+            _ => return Err(Error::other("a vectored send needs a Linux UDP socket")),
+        };
+        let mut iovecs = [libc::iovec {
+            iov_base: ptr::null_mut(),
+            iov_len: 0,
+        }; MAX_PARTS];
+        assert!(parts.len() <= MAX_PARTS, "{} parts", parts.len());
+        for (iov, part) in iovecs.iter_mut().zip(parts) {
+            iov.iov_base = part.as_ptr() as *mut libc::c_void;
+            iov.iov_len = part.len();
+        }
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = iovecs.as_mut_ptr();
+        message.msg_iovlen = parts.len() as _;
+        counted_write(
+            self.framing(),
+            match unsafe { libc::sendmsg(socket.as_raw_fd(), &message, 0) } {
+                -1 => Err(Error::last_os_error()),
+                n => Ok(n as usize),
             },
-            Connection::RuntimeUdp(ref s) => s.local_addr(),
-            Connection::RuntimeTcp(ref s) => s.local_addr(),
+        )
+    }
+
+    /// Checks that the kernel segments UDP sends, which needs UDP_SEGMENT
+    /// from Linux 4.18, on a Linux UDP socket.
+    pub fn enable_gso(&self) -> io::Result<()> {
+        match *self {
+            // A segment size of 0 leaves sends unsegmented unless they ask.
+            Connection::LinuxUdp(ref s) => {
+                setsockopt(s.as_raw_fd(), libc::SOL_UDP, libc::UDP_SEGMENT, 0)
+            }
+            _ => Err(Error::other("UDP GSO needs a Linux UDP socket")),
+        }
+    }
+
+    /// Asks the kernel to coalesce datagrams received together on a Linux
+    /// UDP socket, which needs UDP_GRO from Linux 5.0.
+    pub fn enable_gro(&self) -> io::Result<()> {
+        match *self {
+            Connection::LinuxUdp(ref s) => {
+                setsockopt(s.as_raw_fd(), libc::SOL_UDP, libc::UDP_GRO, 1)
+            }
+            _ => Err(Error::other("UDP GRO needs a Linux UDP socket")),
+        }
+    }
+
+    /// Sends `buf` as datagrams of `segment` bytes each, the last of which
+    /// may be shorter, with one sendmsg the kernel segments. Returns the
+    /// bytes sent.
+    pub fn send_segments(&self, buf: &[u8], segment: usize) -> io::Result<usize> {
+        let socket = match *self {
+            Connection::LinuxUdp(ref s) => s,
+            _ => return Err(Error::other("a segmented send needs a Linux UDP socket")),
+        };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; CONTROL_WORDS];
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        unsafe {
+            message.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&message);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment as u16);
+        }
+        match unsafe { libc::sendmsg(socket.as_raw_fd(), &message, 0) } {
+            -1 => Err(Error::last_os_error()),
+            n => {
+                let datagrams = (n as usize).div_ceil(segment);
+                count_written(
+                    n as usize,
+                    datagrams as u64 * self.framing().datagram_headers(),
+                );
+                batch_sent(datagrams);
+                Ok(n as usize)
+            }
+        }
+    }
+
+    /// Receives as `recv_datagrams` does on a socket with GRO enabled, and
+    /// sets `segments` to the size of the datagrams each buffer holds, which
+    /// is its length unless the kernel coalesced several into it.
+    pub fn recv_segmented(
+        &self,
+        bufs: &mut [Vec<u8>],
+        lens: &mut [usize],
+        segments: &mut [usize],
+    ) -> io::Result<usize> {
+        let socket = match *self {
+            Connection::LinuxUdp(ref s) => s,
+            _ => {
+                let received = self.recv_datagrams(bufs, lens)?;
+                segments[..received].copy_from_slice(&lens[..received]);
+                return Ok(received);
+            }
+        };
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect();
+        let mut controls = vec![[0u64; CONTROL_WORDS]; bufs.len()];
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(controls.iter_mut())
+            .map(|(iov, control)| {
+                let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+                message.msg_hdr.msg_iov = iov;
+                message.msg_hdr.msg_iovlen = 1;
+                message.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                message.msg_hdr.msg_controllen = mem::size_of_val(control) as _;
+                message
+            })
+            .collect();
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if received == -1 {
+            return Err(Error::last_os_error());
+        }
+        let headers = self.framing().datagram_headers();
+        let mut coalesced = 0;
+        for (i, message) in messages[..received as usize].iter().enumerate() {
+            lens[i] = message.msg_len as usize;
+            segments[i] = lens[i];
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&message.msg_hdr);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                        let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                        if size > 0 {
+                            segments[i] = size as usize;
+                        }
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&message.msg_hdr, cmsg);
+                }
+            }
+            let datagrams = match segments[i] {
+                0 => 1,
+                segment => lens[i].div_ceil(segment),
+            };
+            count_read(lens[i], usize::max(datagrams, 1) as u64 * headers);
+            coalesced += usize::max(datagrams, 1);
         }
+        batch_received(coalesced);
+        Ok(received as usize)
+    }
+
+    /// Receives up to one datagram into each of `bufs`, all with a single
+    /// recvmmsg over a Linux UDP socket that waits only for the first, or as
+    /// many as io_uring has received, and just the one otherwise. Sets the
+    /// length of each in `lens` and returns how many were received.
+    pub fn recv_datagrams(&self, bufs: &mut [Vec<u8>], lens: &mut [usize]) -> io::Result<usize> {
+        let socket = match *self {
+            Connection::LinuxUdp(ref s) => s,
+            Connection::Uring(ref s) => {
+                let received = s.recv_datagrams(bufs, lens)?;
+                let len: usize = lens[..received].iter().sum();
+                count_read(len, received as u64 * self.framing().datagram_headers());
+                batch_received(received);
+                return Ok(received);
+            }
+            _ => {
+                lens[0] = (&*self).read(&mut bufs[0])?;
+                batch_received(1);
+                return Ok(1);
+            }
+        };
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect();
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iov| {
+                let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+                message.msg_hdr.msg_iov = iov;
+                message.msg_hdr.msg_iovlen = 1;
+                message
+            })
+            .collect();
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if received == -1 {
+            return Err(Error::last_os_error());
+        }
+        let headers = self.framing().datagram_headers();
+        for (len, message) in lens.iter_mut().zip(&messages[..received as usize]) {
+            *len = message.msg_len as usize;
+            count_read(*len, headers);
+        }
+        batch_received(received as usize);
+        Ok(received as usize)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        match *self {
+            Connection::LinuxUdp(ref s) => s.local_addr().unwrap(),
+            Connection::LinuxTcp(ref s) => s.local_addr().unwrap(),
+            // A unix socket has no address to report.
+            Connection::LinuxUnix(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            Connection::RuntimeUdp(ref s) => SocketAddr::V4(s.local_addr()),
+            Connection::RuntimeTcp(ref s) => SocketAddr::V4(s.local_addr()),
+            Connection::Tls(ref s) => s.inner().local_addr(),
+            Connection::Uring(ref s) => s.inner().local_addr(),
+        }
+    }
+
+    /// Bounds how long a read blocks, after which it fails with `WouldBlock`.
+    /// The runtime's sockets cannot time out reads.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Connection::LinuxUdp(ref s) => s.set_read_timeout(timeout),
+            Connection::LinuxTcp(ref s) => s.set_read_timeout(timeout),
+            Connection::LinuxUnix(ref s) => s.set_read_timeout(timeout),
+            Connection::Tls(ref s) => s.inner().set_read_timeout(timeout),
+            Connection::Uring(ref s) => s.set_read_timeout(timeout),
+            _ => Err(Error::other("unimplemented")),
+        }
+    }
+
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        match *self {
+            Connection::LinuxUdp(ref s) => Some(s.as_raw_fd()),
+            Connection::LinuxTcp(ref s) => Some(s.as_raw_fd()),
+            Connection::LinuxUnix(ref s) => Some(s.as_raw_fd()),
+            Connection::Tls(ref s) => s.inner().raw_fd(),
+            Connection::Uring(ref s) => s.inner().raw_fd(),
+            _ => None,
+        }
+    }
+
+    /// Sets `opts` on the socket, returning the name and error of each option
+    /// that could not be set. TCP_NODELAY only applies to TCP connections, and
+    /// IPv6 sockets take the ToS as their traffic class.
+    /// The runtime's TCP never delays a segment to coalesce it, so asking it
+    /// not to does nothing, but it has no other option to set.
+    pub fn set_options(&self, opts: SocketOptions) -> Vec<(&'static str, io::Error)> {
+        let mut failed = Vec::new();
+        let fd = self.raw_fd();
+        let v6 = self.local_addr().is_ipv6();
+        let mut set = |name, level, option, value| {
+            let result = match fd {
+                Some(fd) => setsockopt(fd, level, option, value),
+                None => Err(unsupported()),
+            };
+            if let Err(e) = result {
+                failed.push((name, e));
+            }
+        };
+        if let Some(size) = opts.send_buffer {
+            set("SO_SNDBUF", libc::SOL_SOCKET, libc::SO_SNDBUF, size);
+        }
+        if let Some(size) = opts.recv_buffer {
+            set("SO_RCVBUF", libc::SOL_SOCKET, libc::SO_RCVBUF, size);
+        }
+        match opts.tos {
+            Some(tos) if v6 => set(
+                "IPV6_TCLASS",
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                tos as usize,
+            ),
+            Some(tos) => set("IP_TOS", libc::IPPROTO_IP, libc::IP_TOS, tos as usize),
+            None => {}
+        }
+        let mut tcp = self;
+        loop {
+            tcp = match *tcp {
+                Connection::Tls(ref s) => s.inner(),
+                Connection::Uring(ref s) => s.inner(),
+                _ => break,
+            };
+        }
+        let nodelay = match *tcp {
+            Connection::LinuxTcp(ref s) => s.set_nodelay(opts.nodelay),
+            Connection::RuntimeTcp(_) if !opts.nodelay => Err(unsupported()),
+            _ => Ok(()),
+        };
+        if let Err(e) = nodelay {
+            failed.push(("TCP_NODELAY", e));
+        }
+        failed
     }
 
     #[allow(unused)]
@@ -185,24 +1084,35 @@ impl Connection {
             Connection::LinuxTcp(ref s) => unsafe {
                 let _ = libc::shutdown(s.as_raw_fd(), libc::SHUT_RDWR);
             },
+            Connection::LinuxUnix(ref s) => {
+                let _ = s.shutdown(std::net::Shutdown::Both);
+            }
             Connection::RuntimeUdp(ref s) => s.shutdown(),
             Connection::RuntimeTcp(ref s) => {
                 if s.shutdown(libc::SHUT_RDWR).is_err() {
                     s.abort()
                 }
             }
+            Connection::Tls(ref s) => s.inner().shutdown(),
+            Connection::Uring(ref s) => s.shutdown(),
         }
     }
 }
 
 impl Read for Connection {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match *self {
-            Connection::LinuxUdp(ref s) => s.recv(buf),
-            Connection::LinuxTcp(ref mut s) => s.read(buf),
-            Connection::RuntimeUdp(ref mut s) => s.read(buf),
-            Connection::RuntimeTcp(ref mut s) => s.read(buf),
-        }
+        counted_read(
+            self.framing(),
+            match *self {
+                Connection::LinuxUdp(ref s) => s.recv(buf),
+                Connection::LinuxTcp(ref mut s) => s.read(buf),
+                Connection::LinuxUnix(ref mut s) => s.read(buf),
+                Connection::RuntimeUdp(ref mut s) => s.read(buf),
+                Connection::RuntimeTcp(ref mut s) => s.read(buf),
+                Connection::Tls(ref s) => return (&**s).read(buf),
+                Connection::Uring(ref s) => s.read(buf),
+            },
+        )
     }
 }
 
@@ -216,51 +1126,75 @@ impl Drop for Connection {
 
 impl<'a> Read for &'a Connection {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match *self {
-            Connection::LinuxUdp(ref s) => s.recv(buf),
-            Connection::LinuxTcp(ref s) => (&*s).read(buf),
-            Connection::RuntimeUdp(ref s) => (&*s).read(buf),
-            Connection::RuntimeTcp(ref s) => (&*s).read(buf),
-        }
+        counted_read(
+            self.framing(),
+            match *self {
+                Connection::LinuxUdp(ref s) => s.recv(buf),
+                Connection::LinuxTcp(ref s) => (&*s).read(buf),
+                Connection::LinuxUnix(ref s) => (&*s).read(buf),
+                Connection::RuntimeUdp(ref s) => (&*s).read(buf),
+                Connection::RuntimeTcp(ref s) => (&*s).read(buf),
+                Connection::Tls(ref s) => return (&**s).read(buf),
+                Connection::Uring(ref s) => s.read(buf),
+            },
+        )
     }
 }
 
 impl<'a> Write for &'a Connection {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match *self {
-            Connection::LinuxUdp(ref s) => s.send(buf),
-            Connection::LinuxTcp(ref s) => (&*s).write(buf),
-            Connection::RuntimeUdp(ref s) => (&*s).write(buf),
-            Connection::RuntimeTcp(ref s) => (&*s).write(buf),
-        }
+        counted_write(
+            self.framing(),
+            match *self {
+                Connection::LinuxUdp(ref s) => s.send(buf),
+                Connection::LinuxTcp(ref s) => (&*s).write(buf),
+                Connection::LinuxUnix(ref s) => (&*s).write(buf),
+                Connection::RuntimeUdp(ref s) => (&*s).write(buf),
+                Connection::RuntimeTcp(ref s) => (&*s).write(buf),
+                Connection::Tls(ref s) => return (&**s).write(buf),
+                Connection::Uring(ref s) => s.write(buf),
+            },
+        )
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Connection::LinuxUdp(_) => Ok(()),
             Connection::LinuxTcp(ref s) => (&*s).flush(),
+            Connection::LinuxUnix(ref s) => (&*s).flush(),
             Connection::RuntimeUdp(ref s) => (&*s).flush(),
             Connection::RuntimeTcp(ref s) => (&*s).flush(),
+            Connection::Tls(ref s) => (&**s).flush(),
+            Connection::Uring(_) => Ok(()),
         }
     }
 }
 
 impl Write for Connection {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match *self {
-            Connection::LinuxUdp(ref s) => s.send(buf),
-            Connection::LinuxTcp(ref mut s) => s.write(buf),
-            Connection::RuntimeUdp(ref mut s) => s.write(buf),
-            Connection::RuntimeTcp(ref mut s) => s.write(buf),
-        }
+        counted_write(
+            self.framing(),
+            match *self {
+                Connection::LinuxUdp(ref s) => s.send(buf),
+                Connection::LinuxTcp(ref mut s) => s.write(buf),
+                Connection::LinuxUnix(ref mut s) => s.write(buf),
+                Connection::RuntimeUdp(ref mut s) => s.write(buf),
+                Connection::RuntimeTcp(ref mut s) => s.write(buf),
+                Connection::Tls(ref s) => return (&**s).write(buf),
+                Connection::Uring(ref s) => s.write(buf),
+            },
+        )
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Connection::LinuxUdp(_) => Ok(()),
             Connection::LinuxTcp(ref mut s) => s.flush(),
+            Connection::LinuxUnix(ref mut s) => s.flush(),
             Connection::RuntimeUdp(ref mut s) => s.flush(),
             Connection::RuntimeTcp(ref mut s) => s.flush(),
+            Connection::Tls(ref s) => (&**s).flush(),
+            Connection::Uring(_) => Ok(()),
         }
     }
 }
@@ -277,3 +1211,25 @@ impl<T: Send + 'static> JoinHandle<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_follow_address_family() {
+        let v4 = Connection::LinuxUdp(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let v6 = Connection::LinuxUdp(UdpSocket::bind("[::1]:0").unwrap());
+        assert_eq!(v4.framing(), Framing::Udp { ipv6: false });
+        assert_eq!(v6.framing(), Framing::Udp { ipv6: true });
+        assert_eq!(v4.framing().headers(100), 28);
+        assert_eq!(v6.framing().headers(100), 48);
+
+        // IPv6 segments carry 20 bytes less, so 1448 bytes take two.
+        let tcp = |ipv6| Framing::Tcp { ipv6 };
+        assert_eq!(tcp(false).headers(1448), 52);
+        assert_eq!(tcp(true).headers(1428), 72);
+        assert_eq!(tcp(true).headers(1448), 2 * 72);
+        assert_eq!(Framing::Unix.headers(1448), 0);
+    }
+}
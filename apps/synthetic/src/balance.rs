@@ -0,0 +1,152 @@
+/// Spreads requests over servers in proportion to their weights, one pick at
+/// a time. This is smooth weighted round robin: every pick credits each
+/// server its weight and charges the one picked the total, so a server never
+/// falls behind its share by more than one request. Equal weights pick the
+/// servers in turn.
+#[derive(Clone, Debug)]
+pub struct Balancer {
+    weights: Vec<i64>,
+    credit: Vec<i64>,
+    total: i64,
+}
+
+impl Balancer {
+    /// Servers of weight zero are never picked, and at least one must be
+    /// nonzero.
+    pub fn new(weights: &[u32]) -> Balancer {
+        let weights: Vec<i64> = weights.iter().map(|&w| w as i64).collect();
+        let total = weights.iter().sum();
+        assert!(total > 0, "no server to balance over");
+        Balancer {
+            credit: vec![0; weights.len()],
+            weights,
+            total,
+        }
+    }
+
+    /// The server the next request goes to.
+    pub fn next(&mut self) -> usize {
+        let mut best = 0;
+        for i in 0..self.weights.len() {
+            self.credit[i] += self.weights[i];
+            if self.credit[i] > self.credit[best] {
+                best = i;
+            }
+        }
+        self.credit[best] -= self.total;
+        best
+    }
+}
+
+/// Jump consistent hash (Lamping and Veach): which of `buckets` buckets `key`
+/// falls in. Adding a bucket moves only its share of the keys, all onto it.
+pub fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let (mut b, mut j) = (-1i64, 0i64);
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+/// Scatters the small sequential keys memcached runs use before they are
+/// hashed, as a client hashing the key strings would.
+fn mix(key: u64) -> u64 {
+    let mut x = key.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// The server of `n` that a memcached key belongs to.
+pub fn home_server(key: u64, n: usize) -> usize {
+    jump_hash(mix(key), n)
+}
+
+/// The server a memcached key is sent to. The keys of a server that is not
+/// `reachable` are spread over the others, the same way for every request, so
+/// a SET and the GETs after it still meet.
+pub fn key_server(key: u64, reachable: &[bool]) -> usize {
+    let home = home_server(key, reachable.len());
+    if reachable[home] {
+        return home;
+    }
+    let up: Vec<usize> = (0..reachable.len()).filter(|&i| reachable[i]).collect();
+    up[jump_hash(mix(key).rotate_left(32), up.len())]
+}
+
+/// Parses a comma-separated list of server weights, which must be positive.
+pub fn parse_weights(spec: &str) -> Result<Vec<u32>, String> {
+    spec.split(',')
+        .map(|s| match s.trim().parse::<u32>() {
+            Ok(w) if w > 0 => Ok(w),
+            _ => Err(format!("bad weight '{}', must be a positive integer", s)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_weights_take_turns() {
+        let mut b = Balancer::new(&[1, 1, 1]);
+        let picks: Vec<usize> = (0..7).map(|_| b.next()).collect();
+        assert_eq!(picks, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn picks_follow_weights() {
+        let mut b = Balancer::new(&[5, 1, 0, 2]);
+        let mut counts = [0; 4];
+        let mut picks = Vec::new();
+        for _ in 0..800 {
+            let i = b.next();
+            counts[i] += 1;
+            picks.push(i);
+        }
+        assert_eq!(counts, [500, 100, 0, 200]);
+        // No stretch of picks strays from the weights by more than one.
+        for window in picks.windows(8) {
+            assert!(window.iter().filter(|&&i| i == 0).count() >= 4);
+            assert!(window.iter().filter(|&&i| i == 3).count() >= 1);
+        }
+
+        assert!(parse_weights("3, 1").is_ok());
+        assert!(parse_weights("3,0").is_err());
+        assert!(parse_weights("3,x").is_err());
+    }
+
+    #[test]
+    fn keys_stay_put() {
+        let n = 100_000;
+        let mut counts = [0; 5];
+        for key in 0..n {
+            let home = home_server(key, 5);
+            counts[home] += 1;
+            // A sixth server only takes keys from the others.
+            let grown = home_server(key, 6);
+            assert!(grown == home || grown == 5);
+        }
+        for &c in &counts {
+            assert!(
+                (c as f64 / (n / 5) as f64 - 1.0).abs() < 0.05,
+                "{:?}",
+                counts
+            );
+        }
+
+        // The keys of a server that is down move, always to the same place.
+        let reachable = [true, false, true, true, true];
+        for key in 0..1000 {
+            let server = key_server(key, &reachable);
+            assert!(reachable[server]);
+            assert_eq!(server, key_server(key, &reachable));
+            if home_server(key, 5) != 1 {
+                assert_eq!(server, home_server(key, 5));
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use Connection;
+use MAX_DATAGRAM;
+
+/// Datagrams the kernel segments one send into, at most.
+const MAX_SEGMENTS: usize = 64;
+
+/// The most one segmented send may carry, the largest UDP payload of either
+/// family.
+const MAX_SEGMENTED: usize = 65535 - 40 - 8;
+
+/// Connections that asked the kernel to segment their sends and how many it
+/// did so for, with the sends it segmented, the datagrams they carried, and
+/// the longest a request batched into one waited for it.
+static GSO_WANTED: AtomicUsize = AtomicUsize::new(0);
+static GSO_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static GSO_SENDS: AtomicUsize = AtomicUsize::new(0);
+static GSO_DATAGRAMS: AtomicUsize = AtomicUsize::new(0);
+static GSO_DELAY_NS: AtomicUsize = AtomicUsize::new(0);
+
+/// Connections that asked the kernel to coalesce their receives and how many
+/// it agreed for, with the receives it coalesced and the datagrams they
+/// held.
+static GRO_WANTED: AtomicUsize = AtomicUsize::new(0);
+static GRO_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static GRO_RECEIVES: AtomicUsize = AtomicUsize::new(0);
+static GRO_DATAGRAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// What UDP segmentation or coalescing did over the whole run: how many
+/// connections asked for it, how many the kernel did it for, and how many
+/// sends or receives it covered, of how many datagrams in all.
+pub struct Offload {
+    pub wanted: usize,
+    pub active: usize,
+    pub batches: usize,
+    pub datagrams: usize,
+}
+
+fn offload(
+    wanted: &AtomicUsize,
+    active: &AtomicUsize,
+    batches: &AtomicUsize,
+    datagrams: &AtomicUsize,
+) -> Option<Offload> {
+    match wanted.load(Ordering::SeqCst) {
+        0 => None,
+        n => Some(Offload {
+            wanted: n,
+            active: active.load(Ordering::SeqCst),
+            batches: batches.load(Ordering::SeqCst),
+            datagrams: datagrams.load(Ordering::SeqCst),
+        }),
+    }
+}
+
+/// How UDP sends were segmented over the run, or None if none asked to be.
+pub fn gso_stats() -> Option<Offload> {
+    offload(&GSO_WANTED, &GSO_ACTIVE, &GSO_SENDS, &GSO_DATAGRAMS)
+}
+
+/// How UDP receives were coalesced over the run, or None if none asked to
+/// be.
+pub fn gro_stats() -> Option<Offload> {
+    offload(&GRO_WANTED, &GRO_ACTIVE, &GRO_RECEIVES, &GRO_DATAGRAMS)
+}
+
+/// The longest a request waited between being batched, when it is stamped,
+/// and the segmented send it went out with.
+pub fn gso_delay() -> Duration {
+    Duration::from_nanos(GSO_DELAY_NS.load(Ordering::SeqCst) as u64)
+}
+
+/// Requests held back to go out together in one sendmmsg, or in as few
+/// segmented sends as will take them, with when each was batched.
+pub struct SendBatch {
+    size: usize,
+    payloads: Vec<Vec<u8>>,
+    requests: Vec<(usize, Duration)>,
+    gso: bool,
+    /// The payloads of a segmented send, one after another.
+    joined: Vec<u8>,
+}
+
+impl SendBatch {
+    pub fn new(size: usize) -> SendBatch {
+        SendBatch {
+            size,
+            payloads: Vec::with_capacity(size),
+            requests: Vec::with_capacity(size),
+            gso: false,
+            joined: Vec::new(),
+        }
+    }
+
+    /// Sends runs of equal payloads on `sock` as one send the kernel
+    /// segments, if it can, rather than with sendmmsg.
+    pub fn enable_gso(&mut self, sock: &Connection) {
+        GSO_WANTED.fetch_add(1, Ordering::SeqCst);
+        if sock.enable_gso().is_ok() {
+            self.gso = true;
+            GSO_ACTIVE.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.requests.len() == self.size
+    }
+
+    /// Adds request `i`, batched at `t`, reusing the buffer of an earlier
+    /// batch for its payload.
+    pub fn push(&mut self, i: usize, t: Duration, payload: &[u8]) {
+        let n = self.requests.len();
+        if n == self.payloads.len() {
+            self.payloads.push(Vec::with_capacity(payload.len()));
+        }
+        self.payloads[n].clear();
+        self.payloads[n].extend_from_slice(payload);
+        self.requests.push((i, t));
+    }
+
+    /// Each request batched, as its index and the time it was batched.
+    pub fn requests(&self) -> &[(usize, Duration)] {
+        &self.requests
+    }
+
+    /// The payloads of the requests from the `from`th on.
+    pub fn datagrams(&self, from: usize) -> Vec<&[u8]> {
+        self.payloads[from..self.requests.len()]
+            .iter()
+            .map(|p| &p[..])
+            .collect()
+    }
+
+    /// How many requests from the `from`th on one segmented send can take:
+    /// a run of payloads of one size, the last of which may be shorter.
+    fn run(&self, from: usize) -> usize {
+        let end = self.requests.len();
+        let size = self.payloads[from].len();
+        let mut total = size;
+        let mut n = 1;
+        while from + n < end && n < MAX_SEGMENTS {
+            let len = self.payloads[from + n].len();
+            if len == 0 || len > size || total + len > MAX_SEGMENTED {
+                break;
+            }
+            total += len;
+            n += 1;
+            if len < size {
+                break;
+            }
+        }
+        n
+    }
+
+    /// Sends requests from the `from`th on over `sock`, with one segmented
+    /// send if it segments them and they make a run, or else with sendmmsg,
+    /// and returns how many went out. A connection whose kernel turns the
+    /// send down goes back to sendmmsg for good.
+    pub fn send(&mut self, sock: &Connection, from: usize, start: Instant) -> io::Result<usize> {
+        let run = match self.gso {
+            true => self.run(from),
+            false => 1,
+        };
+        if run > 1 {
+            self.joined.clear();
+            for p in &self.payloads[from..from + run] {
+                self.joined.extend_from_slice(p);
+            }
+            match sock.send_segments(&self.joined, self.payloads[from].len()) {
+                Ok(_) => {
+                    let waited = start.elapsed() - self.requests[from].1;
+                    GSO_SENDS.fetch_add(1, Ordering::SeqCst);
+                    GSO_DATAGRAMS.fetch_add(run, Ordering::SeqCst);
+                    GSO_DELAY_NS.fetch_max(waited.as_nanos() as usize, Ordering::SeqCst);
+                    return Ok(run);
+                }
+                Err(ref e)
+                    if e.raw_os_error() == Some(libc::EIO)
+                        || e.raw_os_error() == Some(libc::EINVAL) =>
+                {
+                    self.gso = false;
+                    GSO_ACTIVE.fetch_sub(1, Ordering::SeqCst);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        sock.send_datagrams(&self.datagrams(from))
+    }
+
+    pub fn clear(&mut self) {
+        self.requests.clear();
+    }
+}
+
+/// Datagrams received together by one recvmmsg, handed out one at a time.
+/// With coalescing, a buffer may hold several datagrams of one size, the
+/// last of which may be shorter.
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    segments: Vec<usize>,
+    next: usize,
+    /// Where the next datagram starts in its buffer.
+    offset: usize,
+    count: usize,
+    received: Duration,
+    gro: bool,
+}
+
+impl RecvBatch {
+    pub fn new(size: usize) -> RecvBatch {
+        RecvBatch {
+            bufs: vec![vec![0; MAX_DATAGRAM]; size],
+            lens: vec![0; size],
+            segments: vec![0; size],
+            next: 0,
+            offset: 0,
+            count: 0,
+            received: Duration::default(),
+            gro: false,
+        }
+    }
+
+    /// Has the kernel coalesce datagrams `sock` receives together, if it
+    /// can.
+    pub fn enable_gro(&mut self, sock: &Connection) {
+        GRO_WANTED.fetch_add(1, Ordering::SeqCst);
+        if sock.enable_gro().is_ok() {
+            self.gro = true;
+            GRO_ACTIVE.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The next datagram on `sock`, receiving another batch once this one
+    /// is used up, and when its batch was received since `start`.
+    pub fn next(&mut self, sock: &Connection, start: Instant) -> io::Result<(&[u8], Duration)> {
+        if self.next == self.count {
+            self.next = 0;
+            self.offset = 0;
+            self.count = 0;
+            self.count = match self.gro {
+                true => sock.recv_segmented(&mut self.bufs, &mut self.lens, &mut self.segments)?,
+                false => sock.recv_datagrams(&mut self.bufs, &mut self.lens)?,
+            };
+            self.received = start.elapsed();
+            if self.gro {
+                for i in 0..self.count {
+                    if self.segments[i] < self.lens[i] {
+                        let datagrams = self.lens[i].div_ceil(self.segments[i]);
+                        GRO_RECEIVES.fetch_add(1, Ordering::SeqCst);
+                        GRO_DATAGRAMS.fetch_add(datagrams, Ordering::SeqCst);
+                    }
+                }
+            } else {
+                self.segments[..self.count].copy_from_slice(&self.lens[..self.count]);
+            }
+        }
+        let i = self.next;
+        let at = self.offset;
+        let end = usize::min(at + self.segments[i], self.lens[i]);
+        if end == self.lens[i] {
+            self.next += 1;
+            self.offset = 0;
+        } else {
+            self.offset = end;
+        }
+        Ok((&self.bufs[i][at..end], self.received))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn batches_round_trip() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        server.connect(client.local_addr().unwrap()).unwrap();
+        let client = Connection::LinuxUdp(client);
+        let server = Connection::LinuxUdp(server);
+
+        let mut batch = SendBatch::new(3);
+        for i in 0..3 {
+            batch.push(i, Duration::from_millis(i as u64), &vec![i as u8; i + 1]);
+        }
+        assert!(batch.is_full());
+        assert_eq!(client.send_datagrams(&batch.datagrams(0)).unwrap(), 3);
+        batch.clear();
+        // Buffers are reused without leaking an earlier payload.
+        batch.push(7, Duration::default(), b"x");
+        assert_eq!(batch.datagrams(0), vec![&b"x"[..]]);
+        assert_eq!(client.send_datagrams(&batch.datagrams(0)).unwrap(), 1);
+
+        // All four are in the socket's queue, so one receive takes them.
+        let mut recv = RecvBatch::new(4);
+        let start = Instant::now();
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let (datagram, t) = recv.next(&server, start).unwrap();
+            seen.push((datagram.to_vec(), t));
+        }
+        assert_eq!(
+            seen.iter().map(|s| &s.0[..]).collect::<Vec<_>>(),
+            vec![&[0u8][..], &[1, 1][..], &[2, 2, 2][..], &b"x"[..]]
+        );
+        assert!(seen.iter().all(|s| s.1 == seen[0].1));
+    }
+
+    #[test]
+    fn segmented_sends_arrive_in_order() {
+        let lens = [10, 10, 10, 10, 10, 4, 12, 12];
+        for &gro in &[false, true] {
+            let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.connect(server.local_addr().unwrap()).unwrap();
+            let client = Connection::LinuxUdp(client);
+            let server = Connection::LinuxUdp(server);
+
+            let mut batch = SendBatch::new(lens.len());
+            batch.enable_gso(&client);
+            let mut recv = RecvBatch::new(lens.len());
+            if gro {
+                recv.enable_gro(&server);
+            }
+            for (i, &len) in lens.iter().enumerate() {
+                batch.push(i, Duration::default(), &vec![i as u8; len]);
+            }
+            // A run ends after a shorter payload, or before a longer one.
+            assert_eq!(batch.run(0), 6);
+            assert_eq!(batch.run(6), 2);
+            let start = Instant::now();
+            let mut sent = 0;
+            while sent < lens.len() {
+                sent += batch.send(&client, sent, start).unwrap();
+            }
+
+            // Coalesced or not, each datagram is handed out on its own.
+            for (i, &len) in lens.iter().enumerate() {
+                let (datagram, _) = recv.next(&server, start).unwrap();
+                assert_eq!(datagram, &vec![i as u8; len][..], "gro {}", gro);
+            }
+        }
+        assert!(gso_stats().unwrap().wanted >= 2);
+    }
+}
@@ -0,0 +1,230 @@
+use std::sync::Mutex;
+
+/// Bytes a request buffer is allocated with, enough for any request but a
+/// large store, which grows the buffer it is built into once.
+pub const REQUEST_SIZE: usize = 4096;
+
+/// Preallocated buffers that requests are copied or built into, each handed
+/// out cleared and given back once its request is done with, so that in a
+/// steady state no request allocates.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new(count: usize) -> BufferPool {
+        BufferPool {
+            free: Mutex::new(
+                (0..count)
+                    .map(|_| Vec::with_capacity(REQUEST_SIZE))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// An empty buffer, allocating one only if every buffer is taken.
+    pub fn take(&self) -> Vec<u8> {
+        self.free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(REQUEST_SIZE))
+    }
+
+    /// Gives `buf` back, keeping whatever it grew to.
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        buf.clear();
+        self.free.lock().unwrap().push(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::time::Duration;
+    use test::Bencher;
+
+    use memcached::MemcachedProtocol;
+    use memcached_meta::MetaProtocol;
+    use Connection;
+    use InFlight;
+    use Packet;
+    use Payload;
+    use Protocol;
+    use Transport;
+
+    /// Counts the allocations of each thread, so that tests running
+    /// alongside do not count towards another's.
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|n| n.get())
+    }
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(1);
+        let mut buf = pool.take();
+        buf.extend_from_slice(&[7; 2 * REQUEST_SIZE]);
+        let grown = buf.as_ptr();
+        pool.put(buf);
+        // The grown buffer comes back empty, and a second is allocated.
+        let buf = pool.take();
+        assert!(buf.is_empty() && buf.capacity() >= 2 * REQUEST_SIZE);
+        assert_eq!(buf.as_ptr(), grown);
+        assert_eq!(pool.take().capacity(), REQUEST_SIZE);
+    }
+
+    /// Builds requests of every protocol into a reused buffer, keeping each
+    /// for retransmission until it is answered, and checks that none
+    /// allocates once the buffers are warm.
+    #[bench]
+    fn requests_build_without_allocating(b: &mut Bencher) {
+        let protocols = [
+            (Protocol::Synthetic, Transport::Tcp),
+            (
+                Protocol::Memcached(MemcachedProtocol::default()),
+                Transport::Tcp,
+            ),
+            (
+                Protocol::Memcached(MemcachedProtocol::default()),
+                Transport::Udp,
+            ),
+            (
+                Protocol::MemcachedMeta(MetaProtocol::default()),
+                Transport::Tcp,
+            ),
+            (Protocol::Dns, Transport::Udp),
+        ];
+        let packets: Vec<Packet> = (0..1000u64)
+            .map(|i| Packet {
+                work_iterations: i,
+                randomness: i.wrapping_mul(0x9e3779b97f4a7c15),
+                ..Default::default()
+            })
+            .collect();
+        let in_flight = InFlight::new(packets.len(), 1);
+        let mut payload = Vec::with_capacity(REQUEST_SIZE);
+        let mut build = |pass: u64| {
+            for &(ref protocol, tport) in &protocols {
+                for (i, p) in packets.iter().enumerate() {
+                    payload.clear();
+                    protocol.gen_request(i, p, &mut payload, tport);
+                    in_flight.send(i, Duration::from_nanos(pass), &[&payload]);
+                    in_flight.answer(i);
+                }
+            }
+        };
+        // The first pass warms the buffers up.
+        build(0);
+        let mut passes = 0;
+        let mut allocated = 0;
+        b.iter(|| {
+            passes += 1;
+            let before = allocations();
+            build(passes);
+            allocated += allocations() - before;
+        });
+        let requests = passes as usize * protocols.len() * packets.len();
+        assert_eq!(
+            allocated, 0,
+            "{} allocations in {} requests",
+            allocated, requests
+        );
+    }
+
+    /// Reads responses of stream and datagram protocols into one
+    /// connection's receive buffer, and checks that none allocates once it
+    /// has held the largest.
+    #[test]
+    fn responses_read_without_allocating() {
+        // Few enough that every datagram fits in the socket's receive buffer.
+        const RESPONSES: usize = 100;
+        let synthetic = |i: usize, out: &mut Vec<u8>| {
+            Payload {
+                work_iterations: 0,
+                index: i as u64,
+            }
+            .serialize_into(out)
+            .unwrap()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sockets = Vec::new();
+        for &(name, protocol) in &[
+            ("synthetic", Protocol::Synthetic),
+            ("meta", Protocol::MemcachedMeta(MetaProtocol::default())),
+        ] {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut server, _) = listener.accept().unwrap();
+            let mut responses = Vec::new();
+            for i in 0..RESPONSES {
+                match protocol {
+                    Protocol::Synthetic => synthetic(i, &mut responses),
+                    _ => write!(responses, "VA 2 f0 t-1 O{}\r\nxx\r\n", i).unwrap(),
+                }
+            }
+            server.write_all(&responses).unwrap();
+            sockets.push((name, protocol, Transport::Tcp, Connection::LinuxTcp(client)));
+        }
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        for i in 0..RESPONSES {
+            let mut datagram = Vec::new();
+            synthetic(i, &mut datagram);
+            server
+                .send_to(&datagram, client.local_addr().unwrap())
+                .unwrap();
+        }
+        sockets.push((
+            "udp",
+            Protocol::Synthetic,
+            Transport::Udp,
+            Connection::LinuxUdp(client),
+        ));
+
+        for (name, protocol, tport, sock) in sockets {
+            let mut recv_buf = vec![0; 4096];
+
+            // A datagram read makes room for the largest the first time.
+            let (first, _) = protocol.read_response(&sock, tport, &mut recv_buf).unwrap();
+            assert_eq!(first, 0);
+            let before = allocations();
+            for i in 1..RESPONSES {
+                let (idx, _) = protocol.read_response(&sock, tport, &mut recv_buf).unwrap();
+                assert_eq!(idx, i);
+            }
+            assert_eq!(allocations() - before, 0, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,758 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use rand::distributions::{Exp, IndependentSample, LogNormal};
+use rand::Rng;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Distribution {
+    Zero,
+    Constant(u64),
+    Exponential(f64),
+    Bimodal1(f64),
+    Bimodal2(f64),
+    RocksDB,
+    GEV(f64, f64, f64),
+    GPerato(f64, f64, f64),
+    Lognormal(f64, f64),
+    Weibull(f64, f64),
+    BoundedPareto(f64, f64, f64),
+    Clamped(&'static Distribution, u64, u64),
+    Bimodal(f64, f64, f64),
+    /// Integers drawn uniformly from [min, max], inclusive.
+    Uniform(u64, u64),
+    /// Piecewise-linear CDF as (value, cumulative probability) points. A table
+    /// ending below probability 1.0 is sampled conditionally on its range.
+    Empirical(&'static [(f64, f64)]),
+    /// Picks a component with probability proportional to its weight.
+    Mixture(&'static [(f64, Distribution)]),
+    /// Ranks 1 to n with probability proportional to 1 / rank^s.
+    Zipf(&'static Zipf),
+}
+impl Distribution {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Distribution::Zero => "zero",
+            Distribution::Constant(_) => "constant",
+            Distribution::Exponential(_) => "exponential",
+            Distribution::Bimodal1(_) => "bimodal1",
+            Distribution::Bimodal2(_) => "bimodal2",
+            Distribution::RocksDB => "rocksdb",
+            Distribution::GEV(..) => "GEV",
+            Distribution::GPerato(..) => "GPerato",
+            Distribution::Lognormal(..) => "lognormal",
+            Distribution::Weibull(..) => "weibull",
+            Distribution::BoundedPareto(..) => "bpareto",
+            Distribution::Clamped(d, ..) => d.name(),
+            Distribution::Bimodal(..) => "bimodal",
+            Distribution::Uniform(..) => "uniform",
+            Distribution::Empirical(_) => "empirical",
+            Distribution::Zipf(_) => "zipf",
+            Distribution::Mixture(_) => "mixture",
+        }
+    }
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        match *self {
+            Distribution::Zero => 0,
+            Distribution::Constant(m) => m,
+            Distribution::Exponential(m) => Exp::new(1.0 / m).ind_sample(rng) as u64,
+            Distribution::Bimodal1(m) => {
+                if rng.gen_weighted_bool(2) {
+                    (m * 100.0) as u64
+                } else {
+                    (m * 1.0) as u64
+                }
+            }
+            Distribution::Bimodal2(m) => {
+                if rng.gen_weighted_bool(200) {
+                    (m * 500.0) as u64
+                } else {
+                    (m * 0.5) as u64
+                }
+            }
+            Distribution::RocksDB => {
+                if rng.gen_weighted_bool(2) {
+                    (591 * 1000) as u64
+                } else {
+                    (950) as u64
+                }
+            }
+            Distribution::GEV(loc, scale, shape) => {
+                (loc + scale * (rng.gen::<f64>().powf(-shape) - 1.0) / shape) as u64
+            }
+            Distribution::GPerato(loc, scale, shape) => {
+                (loc + scale * (Exp::new(1.0).ind_sample(rng).powf(-shape) - 1.0) / shape) as u64
+            }
+            Distribution::Lognormal(mu, sigma) => LogNormal::new(mu, sigma).ind_sample(rng) as u64,
+            Distribution::Weibull(shape, scale) => {
+                // Inversion on 1 - U so that ln() never sees zero. For shape < 1 the
+                // tail can exceed u64::MAX, in which case the cast saturates.
+                let u = 1.0 - rng.gen::<f64>();
+                (scale * (-u.ln()).powf(1.0 / shape)) as u64
+            }
+            Distribution::BoundedPareto(alpha, min, max) => {
+                // Inversion of the Pareto CDF truncated to [min, max].
+                let u = rng.gen::<f64>();
+                let tail = 1.0 - (min / max).powf(alpha);
+                f64::min(min * (1.0 - u * tail).powf(-1.0 / alpha), max) as u64
+            }
+            Distribution::Clamped(d, min, max) => u64::max(u64::min(d.sample(rng), max), min),
+            Distribution::Bimodal(p, v1, v2) => {
+                if rng.gen::<f64>() < p {
+                    v1 as u64
+                } else {
+                    v2 as u64
+                }
+            }
+            Distribution::Uniform(min, max) => min + rng.gen_range(0, max - min + 1),
+            Distribution::Empirical(table) => {
+                let max = table[table.len() - 1].1;
+                quantile(table, rng.gen::<f64>() * max) as u64
+            }
+            Distribution::Mixture(components) => {
+                let total: f64 = components.iter().map(|c| c.0).sum();
+                let mut u = rng.gen::<f64>() * total;
+                for &(weight, ref d) in components {
+                    if u < weight {
+                        return d.sample(rng);
+                    }
+                    u -= weight;
+                }
+                components[components.len() - 1].1.sample(rng)
+            }
+            Distribution::Zipf(z) => z.sample(rng),
+        }
+    }
+}
+
+/// Zipf sampler with its CDF precomputed, normalized by the generalized
+/// harmonic number H(n, s), so that each draw is a binary search.
+pub struct Zipf {
+    n: u64,
+    s: f64,
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    /// Builds the table for ranks 1 to `n`. It is kept for the rest of the
+    /// run, like the tables of `Distribution::Empirical`.
+    pub fn new(n: u64, s: f64) -> &'static Zipf {
+        let mut cdf = Vec::with_capacity(n as usize);
+        let mut sum = 0.0;
+        for rank in 1..=n {
+            sum += (rank as f64).powf(-s);
+            cdf.push(sum);
+        }
+        for p in cdf.iter_mut() {
+            *p /= sum;
+        }
+        Box::leak(Box::new(Zipf { n, s, cdf }))
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u = rng.gen::<f64>();
+        u64::min(self.cdf.partition_point(|&p| p <= u) as u64 + 1, self.n)
+    }
+}
+
+/// Writes the distribution's name and parameters as `key=value` pairs, in the
+/// units it samples, e.g. `exponential mean=1000` or
+/// `clamp min=20 max=250 gev loc=30.8 scale=8.2 shape=0.08`.
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Distribution::Clamped(d, min, max) = *self {
+            return write!(f, "clamp min={} max={} {}", min, max, d);
+        }
+        write!(f, "{}", self.name())?;
+        match *self {
+            Distribution::Zero | Distribution::RocksDB | Distribution::Clamped(..) => Ok(()),
+            Distribution::Constant(v) => write!(f, " value={}", v),
+            Distribution::Exponential(m)
+            | Distribution::Bimodal1(m)
+            | Distribution::Bimodal2(m) => {
+                write!(f, " mean={}", m)
+            }
+            Distribution::GEV(loc, scale, shape) | Distribution::GPerato(loc, scale, shape) => {
+                write!(f, " loc={} scale={} shape={}", loc, scale, shape)
+            }
+            Distribution::Lognormal(mu, sigma) => write!(f, " mu={} sigma={}", mu, sigma),
+            Distribution::Weibull(shape, scale) => write!(f, " shape={} scale={}", shape, scale),
+            Distribution::BoundedPareto(alpha, min, max) => {
+                write!(f, " alpha={} min={} max={}", alpha, min, max)
+            }
+            Distribution::Bimodal(p, v1, v2) => write!(f, " p={} v1={} v2={}", p, v1, v2),
+            Distribution::Uniform(min, max) => write!(f, " min={} max={}", min, max),
+            Distribution::Empirical(table) => write!(f, " points={}", table.len()),
+            Distribution::Mixture(components) => write!(f, " components={}", components.len()),
+            Distribution::Zipf(z) => write!(f, " n={} s={}", z.n, z.s),
+        }
+    }
+}
+
+impl fmt::Debug for Zipf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Zipf({}, {})", self.n, self.s)
+    }
+}
+
+/// Inverts a piecewise-linear CDF by binary search for the segment containing
+/// `u`. Repeating a value with two probabilities makes a step, so discrete
+/// distributions can be tabulated too. Draws beyond the last point return its
+/// value.
+pub fn quantile(table: &[(f64, f64)], u: f64) -> f64 {
+    let i = table.partition_point(|&(_, p)| p <= u);
+    if i == 0 {
+        return table[0].0;
+    }
+    if i == table.len() {
+        return table[i - 1].0;
+    }
+    let (v0, p0) = table[i - 1];
+    let (v1, p1) = table[i];
+    v0 + (v1 - v0) * (u - p0) / (p1 - p0)
+}
+
+/// Parses a CDF with one `value probability` point per line, separated by
+/// whitespace or a comma. Blank lines and lines starting with '#' are skipped.
+/// Both columns must be nondecreasing and the last probability must be 1.0.
+fn parse_cdf(text: &str) -> Result<Vec<(f64, f64)>, String> {
+    let mut table: Vec<(f64, f64)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let columns: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|c| !c.is_empty())
+            .collect();
+        if columns.len() != 2 {
+            return Err(format!(
+                "line {}: expected 2 columns, found {}",
+                n + 1,
+                columns.len()
+            ));
+        }
+        let parse = |c: &str| {
+            c.parse::<f64>()
+                .map_err(|_| format!("line {}: bad number '{}'", n + 1, c))
+        };
+        let (value, p) = (parse(columns[0])?, parse(columns[1])?);
+        if !(0.0..=1.0).contains(&p) {
+            return Err(format!("line {}: probability {} not in [0, 1]", n + 1, p));
+        }
+        if let Some(&(last_value, last_p)) = table.last() {
+            if value < last_value || p < last_p {
+                return Err(format!("line {}: CDF is not monotone", n + 1));
+            }
+        }
+        table.push((value, p));
+    }
+    match table.last() {
+        None => Err("no points".to_string()),
+        Some(&(_, p)) if (p - 1.0).abs() > 1e-9 => {
+            Err(format!("last probability is {}, not 1.0", p))
+        }
+        Some(_) => Ok(table),
+    }
+}
+
+/// Reads a CDF file into a table that lives for the rest of the run, so that
+/// `Distribution` stays `Copy` and sampling never goes back to the file.
+fn load_cdf(path: &str) -> Result<&'static [(f64, f64)], String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let table = parse_cdf(&text).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Box::leak(table.into_boxed_slice()))
+}
+
+/// Parses a mixture with one `weight distribution` component per line, where
+/// the distribution is any spec accepted by `nested`, including another
+/// mixture file. Blank lines and lines starting with '#' are skipped.
+fn parse_mixture<F>(text: &str, nested: &F) -> Result<Vec<(f64, Distribution)>, String>
+where
+    F: Fn(&str) -> Result<Distribution, String>,
+{
+    let mut components = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split_whitespace();
+        let (weight, spec) = match (columns.next(), columns.next(), columns.next()) {
+            (Some(weight), Some(spec), None) => (weight, spec),
+            _ => {
+                return Err(format!(
+                    "line {}: expected a weight and a distribution",
+                    n + 1
+                ))
+            }
+        };
+        let weight = match weight.parse::<f64>() {
+            Ok(w) if w > 0.0 => w,
+            _ => return Err(format!("line {}: bad weight '{}'", n + 1, weight)),
+        };
+        let d = nested(spec).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        components.push((weight, d));
+    }
+    if components.is_empty() {
+        return Err("no components".to_string());
+    }
+    Ok(components)
+}
+
+/// Reads a mixture file. Like CDF tables, the components live for the rest of
+/// the run.
+fn load_mixture<F>(path: &str, nested: &F) -> Result<&'static [(f64, Distribution)], String>
+where
+    F: Fn(&str) -> Result<Distribution, String>,
+{
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let components = parse_mixture(&text, nested).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Box::leak(components.into_boxed_slice()))
+}
+
+/// Lanczos approximation of the gamma function, accurate to ~15 digits for x > 0.
+fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.5203681218851,
+        -1259.1392167224028,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507343278686905,
+        -0.13857109526572012,
+        9.984_369_578_019_572e-6,
+        1.5056327351493116e-7,
+    ];
+    if x < 0.5 {
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let a = COEFFS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFS[0], |a, (i, c)| a + c / (x + i as f64 + 1.0));
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * a
+}
+
+impl Distribution {
+    /// Parses a distribution spec as accepted by `FromStr`, except that the
+    /// bare names constant, exponential, bimodal1, bimodal2, lognormal and
+    /// weibull are parameterized by `mean` and the shape arguments.
+    pub fn parse(spec: &str, mean: f64, sigma: f64, shape: f64) -> Result<Distribution, String> {
+        Ok(match spec {
+            "constant" => Distribution::Constant(mean as u64),
+            "exponential" => Distribution::Exponential(mean),
+            "bimodal1" => Distribution::Bimodal1(mean),
+            "bimodal2" => Distribution::Bimodal2(mean),
+            // Pick mu so that the distribution has the requested mean.
+            "lognormal" => Distribution::Lognormal(mean.ln() - sigma * sigma / 2.0, sigma),
+            // Likewise pick the scale from the mean, scale * gamma(1 + 1/shape).
+            "weibull" => Distribution::Weibull(shape, mean / gamma(1.0 + 1.0 / shape)),
+            _ => parse_spec(spec, &|s| Distribution::parse(s, mean, sigma, shape))?,
+        })
+    }
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    /// Parses `name:param,...`, e.g. `exp:10`, `lognormal:5.2,1.3` or
+    /// `gev:30.8,8.2,0.08`. A bare number is a constant.
+    fn from_str(spec: &str) -> Result<Distribution, String> {
+        parse_spec(spec, &|s| s.parse())
+    }
+}
+
+/// Splits `params` into exactly one number per entry of `names`, which are
+/// used to point at the offending parameter in errors.
+fn parse_params(name: &str, params: Option<&str>, names: &[&str]) -> Result<Vec<f64>, String> {
+    let params: Vec<&str> = match params {
+        Some(params) => params.split(',').collect(),
+        None => Vec::new(),
+    };
+    if params.len() != names.len() {
+        return Err(if names.is_empty() {
+            format!("{} takes no parameters", name)
+        } else {
+            format!("{} takes {}", name, names.join(","))
+        });
+    }
+    params
+        .iter()
+        .zip(names)
+        .map(|(p, n)| {
+            p.trim()
+                .parse::<f64>()
+                .map_err(|_| format!("bad {} '{}' for {}", n, p, name))
+        })
+        .collect()
+}
+
+/// Parses an explicit spec. `nested` parses the specs embedded in clamp and
+/// mixture files, so that they are read the same way as the outer spec.
+fn parse_spec<F>(spec: &str, nested: &F) -> Result<Distribution, String>
+where
+    F: Fn(&str) -> Result<Distribution, String>,
+{
+    if let Ok(c) = spec.parse::<u64>() {
+        return Ok(Distribution::Constant(c));
+    }
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap();
+    let params = parts.next();
+    let p = |names: &[&str]| parse_params(name, params, names);
+    Ok(match name {
+        "zero" => {
+            p(&[])?;
+            Distribution::Zero
+        }
+        "rocksdb" => {
+            p(&[])?;
+            Distribution::RocksDB
+        }
+        "constant" | "const" => Distribution::Constant(p(&["VALUE"])?[0] as u64),
+        "exponential" | "exp" => {
+            let mean = p(&["MEAN"])?[0];
+            if mean <= 0.0 {
+                return Err(format!("exponential MEAN {} must be positive", mean));
+            }
+            Distribution::Exponential(mean)
+        }
+        "bimodal1" => Distribution::Bimodal1(p(&["MEAN"])?[0]),
+        "bimodal2" => Distribution::Bimodal2(p(&["MEAN"])?[0]),
+        "gev" => {
+            let v = p(&["LOC", "SCALE", "SHAPE"])?;
+            Distribution::GEV(v[0], v[1], v[2])
+        }
+        "gpareto" => {
+            let v = p(&["LOC", "SCALE", "SHAPE"])?;
+            Distribution::GPerato(v[0], v[1], v[2])
+        }
+        "lognormal" => {
+            let v = p(&["MU", "SIGMA"])?;
+            if v[1] < 0.0 {
+                return Err(format!("lognormal SIGMA {} is negative", v[1]));
+            }
+            Distribution::Lognormal(v[0], v[1])
+        }
+        "weibull" => {
+            let v = p(&["SHAPE", "SCALE"])?;
+            if v[0] <= 0.0 || v[1] <= 0.0 {
+                return Err(format!(
+                    "weibull SHAPE and SCALE must be positive in '{}'",
+                    spec
+                ));
+            }
+            Distribution::Weibull(v[0], v[1])
+        }
+        "bpareto" => {
+            let v = p(&["ALPHA", "MIN", "MAX"])?;
+            if v[0] <= 0.0 || v[1] <= 0.0 || v[1] > v[2] {
+                return Err(format!("bad bpareto parameters in '{}'", spec));
+            }
+            Distribution::BoundedPareto(v[0], v[1], v[2])
+        }
+        "bimodal" => {
+            let v = p(&["P", "V1", "V2"])?;
+            if v[0] < 0.0 || v[0] > 1.0 {
+                return Err(format!("bimodal probability {} not in [0, 1]", v[0]));
+            }
+            // Samples are whole, so a fractional mode could only be truncated.
+            if v[1..].iter().any(|&x| x < 0.0 || x.fract() != 0.0) {
+                return Err(format!(
+                    "bimodal V1 and V2 must be non-negative integers in '{}'",
+                    spec
+                ));
+            }
+            Distribution::Bimodal(v[0], v[1], v[2])
+        }
+        "uniform" => {
+            let v = p(&["MIN", "MAX"])?;
+            if v[0] < 0.0 || v[0] > v[1] {
+                return Err(format!("bad uniform range [{}, {}]", v[0], v[1]));
+            }
+            Distribution::Uniform(v[0] as u64, v[1] as u64)
+        }
+        "zipf" => {
+            let v = p(&["N", "S"])?;
+            if v[0] < 1.0 || v[0].fract() != 0.0 || v[1] < 0.0 {
+                return Err(format!("bad zipf parameters in '{}'", spec));
+            }
+            Distribution::Zipf(Zipf::new(v[0] as u64, v[1]))
+        }
+        "clamp" => {
+            let mut clamp = params.unwrap_or("").splitn(3, ',');
+            let (min, max, inner) = match (clamp.next(), clamp.next(), clamp.next()) {
+                (Some(min), Some(max), Some(inner)) => (min, max, inner),
+                _ => return Err("clamp takes MIN,MAX,SPEC".to_string()),
+            };
+            let min = min
+                .parse::<u64>()
+                .map_err(|_| format!("bad MIN '{}' for clamp", min))?;
+            let max = max
+                .parse::<u64>()
+                .map_err(|_| format!("bad MAX '{}' for clamp", max))?;
+            if min > max {
+                return Err(format!("bad clamp range [{}, {}]", min, max));
+            }
+            Distribution::Clamped(Box::leak(Box::new(nested(inner)?)), min, max)
+        }
+        "empirical" => match params {
+            Some(path) => Distribution::Empirical(load_cdf(path)?),
+            None => return Err("empirical takes CDF_FILE".to_string()),
+        },
+        "mixture" => match params {
+            Some(path) => Distribution::Mixture(load_mixture(path, nested)?),
+            None => return Err("mixture takes FILE".to_string()),
+        },
+        _ => return Err(format!("unknown distribution '{}'", name)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, XorShiftRng};
+
+    fn moments(d: Distribution, n: usize) -> (f64, f64) {
+        let mut rng = rand::thread_rng();
+        let samples: Vec<f64> = (0..n).map(|_| d.sample(&mut rng) as f64).collect();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let var = samples.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / n as f64;
+        (mean, var)
+    }
+
+    #[test]
+    fn lognormal_moments() {
+        let (mu, sigma) = (7.0f64, 0.5f64);
+        let (mean, var) = moments(Distribution::Lognormal(mu, sigma), 1_000_000);
+
+        let expected_mean = (mu + sigma * sigma / 2.0).exp();
+        let expected_var = ((sigma * sigma).exp() - 1.0) * (2.0 * mu + sigma * sigma).exp();
+        assert!((mean - expected_mean).abs() / expected_mean < 0.01);
+        assert!((var - expected_var).abs() / expected_var < 0.03);
+    }
+
+    #[test]
+    fn weibull_quantiles() {
+        let mut rng = rand::thread_rng();
+        for &(shape, scale) in &[(2.0f64, 100_000.0f64), (0.5, 100_000.0)] {
+            let mut samples: Vec<u64> = (0..200_000)
+                .map(|_| Distribution::Weibull(shape, scale).sample(&mut rng))
+                .collect();
+            samples.sort();
+            for &p in &[0.1f64, 0.5, 0.9, 0.99] {
+                let expected = scale * (-(1.0 - p).ln()).powf(1.0 / shape);
+                let actual = samples[(p * samples.len() as f64) as usize] as f64;
+                assert!((actual - expected).abs() / expected < 0.05);
+            }
+        }
+    }
+
+    #[test]
+    fn bounded_pareto_shape() {
+        let (alpha, min, max) = (1.2f64, 10.0f64, 10_000.0f64);
+        let d = Distribution::BoundedPareto(alpha, min, max);
+        let mut rng = rand::thread_rng();
+        let samples: Vec<u64> = (0..200_000).map(|_| d.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|&s| s >= min as u64 && s <= max as u64));
+
+        let cdf = |x: f64| (1.0 - (min / x).powf(alpha)) / (1.0 - (min / max).powf(alpha));
+        for &x in &[20u64, 50, 100, 1000] {
+            let empirical =
+                samples.iter().filter(|&&s| s <= x).count() as f64 / samples.len() as f64;
+            assert!((empirical - cdf(x as f64 + 1.0)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn bimodal_mixing() {
+        let d = Distribution::parse("bimodal:0.995,5,500", 0.0, 0.0, 0.0).unwrap();
+        let mut rng = rand::thread_rng();
+        let n = 1_000_000;
+        let low = (0..n).filter(|_| d.sample(&mut rng) == 5).count();
+        assert!((low as f64 / n as f64 - 0.995).abs() < 0.001);
+
+        assert!(Distribution::parse("bimodal:1.5,5,500", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("bimodal:0.5,5", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("bimodal:0.5,x,5", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("bimodal:0.995,0.5,500", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("bimodal:0.995,5,-1", 0.0, 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn gamma_values() {
+        assert!((gamma(5.0) - 24.0).abs() < 1e-9);
+        assert!((gamma(0.5) - std::f64::consts::PI.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empirical_interpolates() {
+        let table = [(0.0, 0.0), (100.0, 0.5), (200.0, 0.5), (300.0, 1.0)];
+        assert_eq!(quantile(&table, 0.0), 0.0);
+        assert_eq!(quantile(&table, 0.25), 50.0);
+        assert_eq!(quantile(&table, 0.5), 200.0);
+        assert_eq!(quantile(&table, 0.75), 250.0);
+
+        // A step CDF only ever yields its tabulated values.
+        static STEPS: [(f64, f64); 4] = [(1.0, 0.0), (1.0, 0.3), (5.0, 0.3), (5.0, 1.0)];
+        let d = Distribution::Empirical(&STEPS);
+        let mut rng = rand::thread_rng();
+        let n = 100_000;
+        let ones = (0..n)
+            .map(|_| d.sample(&mut rng))
+            .inspect(|&s| assert!(s == 1 || s == 5))
+            .filter(|&s| s == 1)
+            .count();
+        assert!((ones as f64 / n as f64 - 0.3).abs() < 0.01);
+    }
+
+    #[test]
+    fn cdf_file_validation() {
+        let path = std::env::temp_dir().join(format!("cdf-{}.txt", std::process::id()));
+        fs::write(&path, "# value, probability\n10, 0.2\n20 0.6\n\n40\t1.0\n").unwrap();
+        let spec = format!("empirical:{}", path.display());
+        match Distribution::parse(&spec, 0.0, 0.0, 0.0).unwrap() {
+            Distribution::Empirical(table) => {
+                assert_eq!(table, &[(10.0, 0.2), (20.0, 0.6), (40.0, 1.0)][..])
+            }
+            _ => unreachable!(),
+        }
+        fs::remove_file(&path).unwrap();
+
+        let err = |text| parse_cdf(text).unwrap_err();
+        assert!(err("1 0.5\n2 0.4\n3 1.0\n").starts_with("line 2:"));
+        assert!(err("1 0.5\n0 0.7\n3 1.0\n").starts_with("line 2:"));
+        assert!(err("1 0.5\n2 0.9\n").contains("not 1.0"));
+        assert!(err("1 0.5 7\n").starts_with("line 1:"));
+        assert!(err("1 x\n").starts_with("line 1:"));
+        assert!(err("# nothing\n").contains("no points"));
+        assert!(Distribution::parse("empirical:/nonexistent", 0.0, 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn uniform_range() {
+        let d = Distribution::parse("uniform:64,1024", 0.0, 0.0, 0.0).unwrap();
+        // The mean is held within a few standard errors, so the draws are
+        // seeded.
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let samples: Vec<u64> = (0..1_000_000).map(|_| d.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|&s| (64..=1024).contains(&s)));
+        assert!(samples.contains(&64));
+        assert!(samples.contains(&1024));
+        let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+        assert!((mean - 544.0).abs() < 1.0);
+
+        assert_eq!(Distribution::Uniform(7, 7).sample(&mut rng), 7);
+        assert!(Distribution::parse("uniform:10,5", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("uniform:-1,5", 0.0, 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn zipf_rank_frequency() {
+        let mut rng = rand::thread_rng();
+        for &s in &[0.8f64, 1.2] {
+            let d = Distribution::parse(&format!("zipf:1000,{}", s), 0.0, 0.0, 0.0).unwrap();
+            let mut counts = vec![0u64; 1001];
+            for _ in 0..1_000_000 {
+                let rank = d.sample(&mut rng);
+                assert!((1..=1000).contains(&rank));
+                counts[rank as usize] += 1;
+            }
+
+            // Least squares slope of log(frequency) against log(rank).
+            let points: Vec<(f64, f64)> = (1..=20)
+                .map(|r| ((r as f64).ln(), (counts[r] as f64).ln()))
+                .collect();
+            let n = points.len() as f64;
+            let mx = points.iter().map(|p| p.0).sum::<f64>() / n;
+            let my = points.iter().map(|p| p.1).sum::<f64>() / n;
+            let slope = points.iter().map(|p| (p.0 - mx) * (p.1 - my)).sum::<f64>()
+                / points.iter().map(|p| (p.0 - mx) * (p.0 - mx)).sum::<f64>();
+            assert!((slope + s).abs() < 0.05, "slope {} for s = {}", slope, s);
+        }
+
+        assert!(Distribution::parse("zipf:0,1", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("zipf:10.5,1", 0.0, 0.0, 0.0).is_err());
+        assert!(Distribution::parse("zipf:10,-1", 0.0, 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn mixture_weights() {
+        let text = "# small and huge\n9 constant\n1 1000000\n";
+        let nested = |s: &str| Distribution::parse(s, 100.0, 0.0, 0.0);
+        let components = parse_mixture(text, &nested).unwrap();
+        let d = Distribution::Mixture(Box::leak(components.into_boxed_slice()));
+        let mut rng = rand::thread_rng();
+        let n = 1_000_000;
+        let small = (0..n).filter(|_| d.sample(&mut rng) == 100).count();
+        assert!((small as f64 / n as f64 - 0.9).abs() < 0.002);
+
+        assert!(parse_mixture("1 zero\n0 zero\n", &nested)
+            .unwrap_err()
+            .starts_with("line 2:"));
+        assert!(parse_mixture("1 nosuch\n", &nested)
+            .unwrap_err()
+            .starts_with("line 1:"));
+        assert!(parse_mixture("1\n", &nested).is_err());
+        assert!(parse_mixture("", &nested).is_err());
+    }
+
+    #[test]
+    fn compact_specs() {
+        let ok = |spec: &str| spec.parse::<Distribution>().unwrap();
+        match ok("exp:10") {
+            Distribution::Exponential(m) => assert_eq!(m, 10.0),
+            d => panic!("{:?}", d),
+        }
+        match ok("lognormal:5.2,1.3") {
+            Distribution::Lognormal(mu, sigma) => assert_eq!((mu, sigma), (5.2, 1.3)),
+            d => panic!("{:?}", d),
+        }
+        match ok("gev:30.8,8.2,0.08") {
+            Distribution::GEV(loc, scale, shape) => {
+                assert_eq!((loc, scale, shape), (30.8, 8.2, 0.08))
+            }
+            d => panic!("{:?}", d),
+        }
+        match ok("clamp:20,250,gev:30.8,8.2,0.08") {
+            Distribution::Clamped(&Distribution::GEV(..), 20, 250) => {}
+            d => panic!("{:?}", d),
+        }
+        match ok("42") {
+            Distribution::Constant(42) => {}
+            d => panic!("{:?}", d),
+        }
+
+        let err = |spec: &str| spec.parse::<Distribution>().unwrap_err();
+        assert_eq!(err("gev:1,2"), "gev takes LOC,SCALE,SHAPE");
+        assert_eq!(err("zero:1"), "zero takes no parameters");
+        assert_eq!(err("exp"), "exp takes MEAN");
+        assert_eq!(err("lognormal:5.2,wide"), "bad SIGMA 'wide' for lognormal");
+        assert_eq!(err("clamp:1,x,zero"), "bad MAX 'x' for clamp");
+        assert_eq!(err("clamp:1,2"), "clamp takes MIN,MAX,SPEC");
+        assert_eq!(err("normal:1,2"), "unknown distribution 'normal'");
+        assert!(err("clamp:1,2,nosuch").contains("nosuch"));
+
+        // Specs print back as their parameters.
+        assert_eq!(ok("exp:1000").to_string(), "exponential mean=1000");
+        assert_eq!(ok("0").to_string(), "constant value=0");
+        assert_eq!(ok("zero").to_string(), "zero");
+        assert_eq!(
+            ok("clamp:20,250,gev:30.8,8.2,0.08").to_string(),
+            "clamp min=20 max=250 GEV loc=30.8 scale=8.2 shape=0.08"
+        );
+
+        // Bare names only make sense with a mean to scale them by.
+        assert!("lognormal".parse::<Distribution>().is_err());
+        match Distribution::parse("lognormal", 100.0, 0.5, 1.0).unwrap() {
+            Distribution::Lognormal(_, sigma) => assert_eq!(sigma, 0.5),
+            d => panic!("{:?}", d),
+        }
+    }
+}
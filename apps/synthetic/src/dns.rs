@@ -1,6 +1,7 @@
 use Connection;
 use Packet;
 use Transport;
+use {record_datagram_size, scratch_space, MAX_DATAGRAM};
 
 use byteorder::{BigEndian, WriteBytesExt};
 use dns_parser::{Header, Opcode, QueryClass, QueryType, ResponseCode};
@@ -59,9 +60,9 @@ impl DnsProtocol {
             additional: 0,
         };
 
-        buf.extend(vec![0; 12]);
-
-        h.write(&mut buf[..12]);
+        let at = buf.len();
+        buf.resize(at + 12, 0);
+        h.write(&mut buf[at..at + 12]);
 
         let size_idx = buf.len();
         buf.push(0);
@@ -83,24 +84,35 @@ impl DnsProtocol {
     pub fn read_response(
         mut sock: &Connection,
         tport: Transport,
-        scratch: &mut [u8],
+        scratch: &mut Vec<u8>,
     ) -> io::Result<usize> {
         match tport {
             Transport::Udp => (),
             _ => assert!(false),
         }
 
-        let len = sock.read(&mut scratch[..])?;
+        let len = sock.read(scratch_space(scratch, MAX_DATAGRAM))?;
         if len == 0 {
             return Err(Error::new(ErrorKind::UnexpectedEof, "eof"));
         }
+        DnsProtocol::read_datagram(&scratch[..len])
+    }
 
-        if Header::parse(scratch).is_err() || scratch[Header::size()] & 0b1100_0000 != 0 {
-            return Err(Error::new(ErrorKind::Other, "bad packet!"));
+    /// Parses a response datagram already received, as one of a batch.
+    pub fn read_datagram(datagram: &[u8]) -> io::Result<usize> {
+        record_datagram_size(datagram.len());
+        let pos = Header::size();
+        if Header::parse(datagram).is_err()
+            || datagram.len() <= pos
+            || datagram[pos] & 0b1100_0000 != 0
+        {
+            return Err(Error::other("bad packet!"));
         }
 
-        let pos = Header::size();
-        let end = pos + scratch[pos] as usize + 1;
-        Ok(pull_usize(&scratch[pos + 1..end]))
+        let end = pos + datagram[pos] as usize + 1;
+        if datagram.len() < end {
+            return Err(Error::other("bad packet!"));
+        }
+        Ok(pull_usize(&datagram[pos + 1..end]))
     }
 }
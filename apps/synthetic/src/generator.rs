@@ -0,0 +1,342 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use Backend;
+use JoinHandle;
+use Packet;
+use Protocol;
+use RequestInfo;
+use Transport;
+use REQUEST_SIZE;
+
+/// Requests each generator may have built ahead of its sender.
+const QUEUE_DEPTH: usize = 64;
+
+/// Requests taken from generators, how many were waited for once due, and
+/// the queue depth and generation lead each was taken with, summed.
+static TAKEN: AtomicUsize = AtomicUsize::new(0);
+static UNDERRUNS: AtomicUsize = AtomicUsize::new(0);
+static DEPTHS: AtomicUsize = AtomicUsize::new(0);
+static LEAD_NS: AtomicUsize = AtomicUsize::new(0);
+
+/// What generator threads did over the whole run: how many requests they
+/// built, how full their queues were on average when the sender took one, how
+/// many times a request was due before it was built, and how long before it
+/// was due a request was built on average.
+pub struct GeneratorStats {
+    pub requests: usize,
+    pub mean_depth: f64,
+    pub underruns: usize,
+    pub mean_lead: Duration,
+}
+
+/// The generator statistics of the run, or None if no generators ran.
+pub fn stats() -> Option<GeneratorStats> {
+    let taken = TAKEN.load(Ordering::SeqCst);
+    if taken == 0 {
+        return None;
+    }
+    Some(GeneratorStats {
+        requests: taken,
+        mean_depth: DEPTHS.load(Ordering::SeqCst) as f64 / taken as f64,
+        underruns: UNDERRUNS.load(Ordering::SeqCst),
+        mean_lead: Duration::from_nanos((LEAD_NS.load(Ordering::SeqCst) / taken) as u64),
+    })
+}
+
+/// A bounded queue with one thread pushing and one popping, which never
+/// block each other.
+struct Ring<T> {
+    slots: Vec<UnsafeCell<Option<T>>>,
+    /// Items ever popped and pushed. Only the consumer moves `head`, and
+    /// only the producer `tail`.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+/// The pushing end of a ring.
+pub struct Producer<T>(Arc<Ring<T>>);
+
+/// The popping end of a ring.
+pub struct Consumer<T>(Arc<Ring<T>>);
+
+/// A ring of `depth` items, as its two ends.
+pub fn ring<T: Send>(depth: usize) -> (Producer<T>, Consumer<T>) {
+    let ring = Arc::new(Ring {
+        slots: (0..depth).map(|_| UnsafeCell::new(None)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Producer(ring.clone()), Consumer(ring))
+}
+
+impl<T> Producer<T> {
+    /// Adds `item`, giving it back if the ring is full.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let ring = &self.0;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if tail - ring.head.load(Ordering::Acquire) == ring.slots.len() {
+            return Err(item);
+        }
+        // The consumer is done with this slot until `tail` moves past it.
+        unsafe {
+            *ring.slots[tail % ring.slots.len()].get() = Some(item);
+        }
+        ring.tail.store(tail + 1, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Takes the oldest item, if there is one.
+    pub fn pop(&self) -> Option<T> {
+        let ring = &self.0;
+        let head = ring.head.load(Ordering::Relaxed);
+        if head == ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = unsafe { (*ring.slots[head % ring.slots.len()].get()).take() };
+        ring.head.store(head + 1, Ordering::Release);
+        item
+    }
+
+    /// How many items are waiting.
+    pub fn len(&self) -> usize {
+        let ring = &self.0;
+        ring.tail.load(Ordering::Acquire) - ring.head.load(Ordering::Relaxed)
+    }
+}
+
+/// A request built by a generator, with when it was built.
+struct Built {
+    payload: Vec<u8>,
+    info: RequestInfo,
+    built: Duration,
+}
+
+/// The request's own fields of `p`, which are all a protocol builds it
+/// from.
+fn request_of(p: &Packet) -> Packet {
+    Packet {
+        work_iterations: p.work_iterations,
+        randomness: p.randomness,
+        target_start: p.target_start,
+        server: p.server,
+        trace: p.trace,
+        ..Default::default()
+    }
+}
+
+/// Generator threads building the requests of one sender ahead of when they
+/// are due, each every `n`th of them in turn, and handing them over on a
+/// ring of their own. Payloads the sender is done with go back to their
+/// generator to be built into again.
+pub struct Generators {
+    built: Vec<Consumer<Built>>,
+    spent: Vec<Producer<Vec<u8>>>,
+    threads: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+    /// The request taken last, until the sender is done with it.
+    current: Option<(usize, Vec<u8>)>,
+}
+
+impl Generators {
+    /// Starts `n` generators for `packets`, each building a request no more
+    /// than `lead` before it is due.
+    pub fn start(
+        backend: Backend,
+        protocol: Protocol,
+        tport: Transport,
+        packets: &[Packet],
+        start: Instant,
+        n: usize,
+        lead: Duration,
+    ) -> Generators {
+        let requests: Arc<Vec<Packet>> = Arc::new(packets.iter().map(request_of).collect());
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut generators = Generators {
+            built: Vec::new(),
+            spent: Vec::new(),
+            threads: Vec::new(),
+            stop: stop.clone(),
+            current: None,
+        };
+        for k in 0..n {
+            let (built, taken) = ring(QUEUE_DEPTH);
+            let (returned, spent) = ring::<Vec<u8>>(QUEUE_DEPTH + 1);
+            let requests = requests.clone();
+            let stop = stop.clone();
+            generators.threads.push(backend.spawn_thread(move || {
+                for i in (k..requests.len()).step_by(n) {
+                    let due = requests[i].target_start;
+                    loop {
+                        let now = start.elapsed();
+                        if stop.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        if now + lead >= due {
+                            break;
+                        }
+                        match due - lead - now {
+                            wait if wait > Duration::from_millis(1) => {
+                                backend.sleep(wait - Duration::from_micros(500))
+                            }
+                            _ => backend.thread_yield(),
+                        }
+                    }
+                    let mut payload = spent
+                        .pop()
+                        .unwrap_or_else(|| Vec::with_capacity(REQUEST_SIZE));
+                    payload.clear();
+                    let info = protocol.gen_request(i, &requests[i], &mut payload, tport);
+                    let mut request = Built {
+                        payload,
+                        info,
+                        built: start.elapsed(),
+                    };
+                    while let Err(r) = built.push(request) {
+                        if stop.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        request = r;
+                        backend.thread_yield();
+                    }
+                }
+            }));
+            generators.built.push(taken);
+            generators.spent.push(returned);
+        }
+        generators
+    }
+
+    /// Takes request `i` of `packet`'s, waiting for it to be built, and
+    /// returns its payload. Taking a request hands the one before back.
+    pub fn take(
+        &mut self,
+        backend: Backend,
+        start: Instant,
+        i: usize,
+        packet: &mut Packet,
+    ) -> &[u8] {
+        if let Some((k, payload)) = self.current.take() {
+            let _ = self.spent[k].push(payload);
+        }
+        let k = i % self.built.len();
+        let depth = self.built[k].len();
+        let request = match self.built[k].pop() {
+            Some(request) => request,
+            None => {
+                if start.elapsed() >= packet.target_start {
+                    UNDERRUNS.fetch_add(1, Ordering::SeqCst);
+                }
+                loop {
+                    backend.thread_yield();
+                    if let Some(request) = self.built[k].pop() {
+                        break request;
+                    }
+                }
+            }
+        };
+        let lead = packet
+            .target_start
+            .checked_sub(request.built)
+            .unwrap_or_default();
+        TAKEN.fetch_add(1, Ordering::SeqCst);
+        DEPTHS.fetch_add(depth, Ordering::SeqCst);
+        LEAD_NS.fetch_add(lead.as_nanos() as usize, Ordering::SeqCst);
+        packet.info = request.info;
+        self.current = Some((k, request.payload));
+        &self.current.as_ref().unwrap().1[..]
+    }
+}
+
+impl Drop for Generators {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn ring_keeps_order_and_bound() {
+        let (producer, consumer) = ring(3);
+        assert_eq!(consumer.pop(), None);
+        for i in 0..3 {
+            assert_eq!(producer.push(i), Ok(()));
+        }
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(consumer.len(), 3);
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(producer.push(3), Ok(()));
+
+        // Across threads, every item arrives once and in order.
+        let pusher = thread::spawn(move || {
+            for i in 4..100_000 {
+                let mut item = i;
+                while let Err(back) = producer.push(item) {
+                    item = back;
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut next = 1;
+        while next < 100_000 {
+            match consumer.pop() {
+                Some(i) => {
+                    assert_eq!(i, next);
+                    next += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        pusher.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn generators_build_requests_in_order() {
+        let packets: Vec<Packet> = (0..50)
+            .map(|i| Packet {
+                work_iterations: i * 3,
+                randomness: i << 40,
+                target_start: Duration::from_micros(i * 20),
+                ..Default::default()
+            })
+            .collect();
+        let start = Instant::now();
+        let mut generators = Generators::start(
+            Backend::Linux,
+            Protocol::Synthetic,
+            Transport::Tcp,
+            &packets,
+            start,
+            3,
+            Duration::from_micros(100),
+        );
+        let mut expected = Vec::new();
+        for (i, p) in packets.iter().enumerate() {
+            let mut p = request_of(p);
+            expected.clear();
+            let info = Protocol::Synthetic.gen_request(i, &p, &mut expected, Transport::Tcp);
+            assert_eq!(
+                generators.take(Backend::Linux, start, i, &mut p),
+                &expected[..]
+            );
+            assert_eq!(p.info, info);
+        }
+        // Generators still waiting to build are stopped.
+        drop(generators);
+        assert!(stats().unwrap().requests >= packets.len());
+    }
+}
@@ -0,0 +1,314 @@
+/// Significant decimal digits values are kept to when not given.
+pub const DEFAULT_DIGITS: u32 = 3;
+
+/// The most significant digits a histogram keeps, which take a few MB for
+/// values up to seconds in nanoseconds.
+pub const MAX_DIGITS: u32 = 4;
+
+/// Counts of values in log-linear buckets, as HdrHistogram keeps them. Values
+/// below `2^bits` are counted exactly, and each power of two above is split
+/// into `2^(bits - 1)` buckets, so that a bucket is never wider than one part
+/// in `10^digits` of the values it counts. Buckets are added as larger values
+/// arrive, so the memory a histogram takes depends on its largest value, and
+/// not on how many it has counted. A histogram given bounds counts values
+/// outside them at the bound they passed, and how many did.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    bits: u32,
+    counts: Vec<u64>,
+    total: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+    low: u64,
+    high: u64,
+    underflows: u64,
+    overflows: u64,
+}
+
+impl Histogram {
+    /// An empty histogram keeping values to `digits` significant digits.
+    pub fn new(digits: u32) -> Histogram {
+        assert!((1..=MAX_DIGITS).contains(&digits));
+        let exact = 2 * 10u64.pow(digits);
+        Histogram {
+            bits: 64 - (exact - 1).leading_zeros(),
+            counts: Vec::new(),
+            total: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+            low: 0,
+            high: u64::MAX,
+            underflows: 0,
+            overflows: 0,
+        }
+    }
+
+    /// The histogram keeping values from `low` to `high`, which also bounds
+    /// the memory it takes.
+    pub fn with_bounds(mut self, low: u64, high: u64) -> Histogram {
+        assert!(low <= high);
+        self.low = low;
+        self.high = high;
+        self
+    }
+
+    /// The bucket `value` is counted in.
+    fn index(&self, value: u64) -> usize {
+        let bits = 64 - value.leading_zeros();
+        if bits <= self.bits {
+            return value as usize;
+        }
+        let shift = bits - self.bits;
+        let half = 1 << (self.bits - 1);
+        (1 << self.bits) + (shift as usize - 1) * half + ((value >> shift) as usize - half)
+    }
+
+    /// The lowest and highest values counted in bucket `index`.
+    fn range(&self, index: usize) -> (u64, u64) {
+        let exact = 1 << self.bits;
+        if index < exact {
+            return (index as u64, index as u64);
+        }
+        let half = exact / 2;
+        let shift = (index - exact) / half + 1;
+        let low = (((index - exact) % half + half) as u64) << shift;
+        (low, low + ((1 << shift) - 1))
+    }
+
+    pub fn record(&mut self, mut value: u64) {
+        if value > self.high {
+            self.overflows += 1;
+            value = self.high;
+        } else if value < self.low {
+            self.underflows += 1;
+            value = self.low;
+        }
+        let i = self.index(value);
+        if i >= self.counts.len() {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] += 1;
+        self.total += 1;
+        self.sum += value as u128;
+        self.min = u64::min(self.min, value);
+        self.max = u64::max(self.max, value);
+    }
+
+    /// Adds the counts of `other`, which keeps as many digits.
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.bits, other.bits);
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, &n) in self.counts.iter_mut().zip(&other.counts) {
+            *count += n;
+        }
+        self.total += other.total;
+        self.sum += other.sum;
+        self.min = u64::min(self.min, other.min);
+        self.max = u64::max(self.max, other.max);
+        self.underflows += other.underflows;
+        self.overflows += other.overflows;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// The lowest value counted, exactly, None if none was.
+    pub fn min(&self) -> Option<u64> {
+        if self.total == 0 {
+            None
+        } else {
+            Some(self.min)
+        }
+    }
+
+    /// The highest value counted, exactly, None if none was.
+    pub fn max(&self) -> Option<u64> {
+        if self.total == 0 {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+
+    /// The mean of the values counted, which is exact rather than taken from
+    /// their buckets. None if none was.
+    pub fn mean(&self) -> Option<f64> {
+        if self.total == 0 {
+            None
+        } else {
+            Some(self.sum as f64 / self.total as f64)
+        }
+    }
+
+    /// Values counted at the low bound for being below it.
+    pub fn underflows(&self) -> u64 {
+        self.underflows
+    }
+
+    /// Values counted at the high bound for being above it.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// The `rank`th lowest value counted, from 0, as the highest value of its
+    /// bucket, which is at most the highest counted. None if fewer were.
+    pub fn value_at(&self, rank: u64) -> Option<u64> {
+        if rank >= self.total {
+            return None;
+        }
+        let mut below = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            below += n;
+            if below > rank {
+                return Some(u64::min(self.range(i).1, self.max));
+            }
+        }
+        unreachable!()
+    }
+
+    /// The value at quantile `q` of those counted, from 0 for the lowest to 1
+    /// for the highest, as `value_at` gives it. None if none was.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        assert!((0.0..=1.0).contains(&q));
+        if self.total == 0 {
+            return None;
+        }
+        let rank = u64::min((self.total as f64 * q) as u64, self.total - 1);
+        self.value_at(rank)
+    }
+
+    /// Each bucket that counted any values, as the lowest and highest
+    /// values it counts and how many it counted, from the lowest up.
+    pub fn buckets<'a>(&'a self) -> impl Iterator<Item = (u64, u64, u64)> + 'a {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(move |(i, &n)| {
+                let (low, high) = self.range(i);
+                (low, high, n)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values spread over nine orders of magnitude, in no order.
+    fn spread(n: u64) -> Vec<u64> {
+        (0..n)
+            .map(|i| i.wrapping_mul(0x9e3779b97f4a7c15) % 10u64.pow(1 + i as u32 % 9))
+            .collect()
+    }
+
+    #[test]
+    fn values_kept_to_their_digits() {
+        let values = spread(20_000);
+        let mut sorted = values.clone();
+        sorted.sort();
+        for digits in 1..=MAX_DIGITS {
+            let mut histogram = Histogram::new(digits);
+            for &v in &values {
+                histogram.record(v);
+            }
+            assert_eq!(histogram.count(), values.len() as u64);
+            // The lowest, highest and mean are exact at any digits.
+            assert_eq!(histogram.min(), sorted.first().cloned());
+            assert_eq!(histogram.max(), sorted.last().cloned());
+            let sum: u64 = values.iter().sum();
+            assert_eq!(histogram.mean(), Some(sum as f64 / values.len() as f64));
+            for (rank, &v) in sorted.iter().enumerate().step_by(97) {
+                let at = histogram.value_at(rank as u64).unwrap();
+                assert!(at >= v && at - v <= v / 10u64.pow(digits), "{} {}", v, at);
+            }
+            assert_eq!(histogram.value_at(values.len() as u64), None);
+            assert_eq!(histogram.quantile(1.0), histogram.max());
+            assert_eq!(histogram.quantile(0.0), histogram.value_at(0));
+            assert_eq!(
+                histogram.quantile(0.5),
+                histogram.value_at(values.len() as u64 / 2)
+            );
+            // The highest value is kept exactly.
+            assert_eq!(
+                histogram.value_at(values.len() as u64 - 1),
+                sorted.last().cloned()
+            );
+            // Buckets cover every value once, without gaps or overlaps.
+            let buckets: Vec<_> = histogram.buckets().collect();
+            assert_eq!(buckets.iter().map(|b| b.2).sum::<u64>(), histogram.count());
+            for i in 0..histogram.counts.len() {
+                let (low, high) = histogram.range(i);
+                assert_eq!((histogram.index(low), histogram.index(high)), (i, i));
+                assert_eq!(histogram.index(high + 1), i + 1);
+            }
+        }
+        // Exact up to the first power of two that needs buckets.
+        let histogram = Histogram::new(3);
+        assert_eq!(histogram.range(2047), (2047, 2047));
+        assert_eq!(histogram.range(2048), (2048, 2049));
+        assert_eq!(histogram.range(histogram.index(u64::MAX)).1, u64::MAX);
+    }
+
+    #[test]
+    fn merged_histograms_count_both() {
+        let values = spread(5000);
+        let mut whole = Histogram::new(3);
+        let mut halves = (Histogram::new(3), Histogram::new(3));
+        for (i, &v) in values.iter().enumerate() {
+            whole.record(v);
+            match i % 2 {
+                0 => halves.0.record(v),
+                _ => halves.1.record(v),
+            }
+        }
+        halves.1.merge(&halves.0);
+        assert_eq!(
+            halves.1.buckets().collect::<Vec<_>>(),
+            whole.buckets().collect::<Vec<_>>()
+        );
+        assert_eq!(halves.1.count(), whole.count());
+        assert_eq!(halves.1.value_at(4999), whole.value_at(4999));
+        assert_eq!(
+            (halves.1.min(), halves.1.max(), halves.1.mean()),
+            (whole.min(), whole.max(), whole.mean())
+        );
+        let empty = Histogram::new(3);
+        assert_eq!((empty.min(), empty.max(), empty.mean()), (None, None, None));
+        assert_eq!(empty.quantile(0.5), None);
+    }
+
+    #[test]
+    fn values_out_of_bounds_counted() {
+        let mut histogram = Histogram::new(3).with_bounds(10, 1000);
+        for &v in &[50, 5, 1_000_000, 1000] {
+            histogram.record(v);
+        }
+        assert_eq!((histogram.underflows(), histogram.overflows()), (1, 1));
+        // Each is counted at the bound it passed.
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.value_at(0), Some(10));
+        assert_eq!(histogram.value_at(3), Some(1000));
+        let mut merged = Histogram::new(3).with_bounds(10, 1000);
+        merged.record(2000);
+        merged.merge(&histogram);
+        assert_eq!((merged.underflows(), merged.overflows()), (1, 2));
+    }
+
+    #[test]
+    fn memory_independent_of_count() {
+        let mut histogram = Histogram::new(3);
+        histogram.record(10_000_000_000);
+        let buckets = histogram.counts.len();
+        for i in 0..1_000_000u64 {
+            histogram.record(i.wrapping_mul(0x9e3779b97f4a7c15) % 10_000_000_000);
+        }
+        assert_eq!(histogram.counts.len(), buckets);
+        assert_eq!(histogram.count(), 1_000_001);
+    }
+}
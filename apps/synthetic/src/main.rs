@@ -9,23 +9,31 @@ extern crate lockstep;
 extern crate mersenne_twister;
 extern crate net2;
 extern crate rand;
+extern crate rustls;
+extern crate rustls_pemfile;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
 extern crate shenango;
 extern crate test;
 
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::collections::VecDeque;
 use std::f32::INFINITY;
+use std::fs::File;
 use std::io;
 use std::io::{ErrorKind, Write};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::ops::Range;
 use std::slice;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use clap::{App, Arg};
-use rand::distributions::{Exp, IndependentSample};
-use rand::Rng;
+use rand::{Rng, SeedableRng, XorShiftRng};
 use shenango::udp::UdpSpawner;
 
 mod backend;
@@ -42,98 +50,310 @@ pub struct Packet {
     actual_start: Option<Duration>,
     completion_time_ns: AtomicU64,
     completion_time: Option<Duration>,
+    /// Bytes of the response that completed the request, as read, or of the
+    /// reads it took for a receiver that reads one response at a time.
+    response_bytes: usize,
+    window_dropped: bool,
+    /// Whether the response said the key was not found.
+    miss: bool,
+    /// Whether the request was still unanswered when the drain timed out, or
+    /// went unanswered for longer than the request timeout.
+    timed_out: bool,
+    /// Whether a response came after the request had timed out, which is not
+    /// counted as completing it.
+    late: bool,
+    /// Times the request was sent again after going unanswered.
+    retransmits: usize,
+    /// Responses after the first, to earlier transmissions that were answered
+    /// after all.
+    duplicates: usize,
+    /// Whether the request was the first on a connection opened for a new
+    /// session.
+    reconnected: bool,
+    /// Whether the request was in flight on a connection that failed, so it
+    /// will never be answered.
+    failed: bool,
+    /// Whether the server answered the request with an error status.
+    rejected: bool,
+    /// Index of the target the request went to.
+    server: usize,
+    /// Index of the connection the request went over, among its thread's.
+    conn: usize,
+    /// The recorded request to make, for trace-driven runs.
+    trace: Option<TraceRecord>,
+    info: RequestInfo,
+}
+
+/// What a response said about its request. Only GETs can miss.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Miss,
+    /// The server answered with an error status.
+    Rejected,
+}
+
+/// The operation a request performed, filled in when it is sent.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RequestInfo {
+    op: &'static str,
+    key: Option<u64>,
+    /// Bytes of value an ETC request sets, or that its key was last set to,
+    /// if known.
+    value_size: Option<usize>,
+    /// Bytes of the key of a memcached request.
+    key_size: Option<usize>,
+    /// Bytes of the request as built, with its protocol's headers and any
+    /// UDP frame.
+    bytes: usize,
+}
+
+impl RequestInfo {
+    fn new(op: &'static str, key: u64) -> RequestInfo {
+        RequestInfo {
+            op,
+            key: Some(key),
+            value_size: None,
+            key_size: None,
+            bytes: 0,
+        }
+    }
+
+    fn with_value_size(self, value_size: Option<usize>) -> RequestInfo {
+        RequestInfo { value_size, ..self }
+    }
+
+    fn with_key_size(self, key_size: usize) -> RequestInfo {
+        RequestInfo {
+            key_size: Some(key_size),
+            ..self
+        }
+    }
 }
 
 mod fakework;
 use fakework::FakeWorker;
 
 mod memcached;
-use memcached::MemcachedProtocol;
+use memcached::{CasMap, HotKey, MemcachedProtocol, ValueSource, Workload};
+
+mod memcached_meta;
+use memcached_meta::{MetaProtocol, UNMATCHED};
+
+mod distribution;
+use distribution::Distribution;
 
 mod dns;
 use dns::DnsProtocol;
 
-#[derive(Copy, Clone, Debug)]
-pub enum Distribution {
-    Zero,
-    Constant(u64),
-    Exponential(f64),
-    Bimodal1(f64),
-    Bimodal2(f64),
-    RocksDB,
-    GEV(f64, f64, f64),
-    GPerato(f64, f64, f64),
-}
-impl Distribution {
-    fn name(&self) -> &'static str {
-        match *self {
-            Distribution::Zero => "zero",
-            Distribution::Constant(_) => "constant",
-            Distribution::Exponential(_) => "exponential",
-            Distribution::Bimodal1(_) => "bimodal1",
-            Distribution::Bimodal2(_) => "bimodal2",
-            Distribution::RocksDB => "rocksdb",
-            Distribution::GEV(..) => "GEV",
-            Distribution::GPerato(..) => "GPerato",
-        }
-    }
-    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+mod shape;
+use shape::LoadShape;
+
+mod slo;
+use slo::{Bisection, SloController, SloSearch};
+
+mod ratelimit;
+use ratelimit::TokenBucket;
+
+mod balance;
+use balance::{parse_weights, Balancer};
+
+mod mix;
+use mix::OpMix;
+
+mod trace;
+use trace::{read_trace, trace_period, TraceRecord};
+
+mod tls;
+use tls::TlsClient;
+
+mod source;
+use source::{parse_port_range, parse_source_ips, Sources};
+
+mod affinity;
+use affinity::{parse_cpu_list, CpuList};
+
+mod batch;
+use batch::{RecvBatch, SendBatch};
+
+mod buffers;
+use buffers::{BufferPool, REQUEST_SIZE};
+
+mod uring;
+
+mod poll;
+use poll::{Head, Poller, StreamState};
+
+mod generator;
+use generator::Generators;
+
+mod histogram;
+use histogram::Histogram;
+
+mod profile;
+use profile::Phase;
+
+mod report;
+use report::{
+    gbps, parse_percentiles, parse_size_classes, reported_percentiles, samples_to_csv, size_class,
+    BinarySampleWriter, ConnectLatencies, HistogramWriter, Interval, IntervalRow, IntervalWriter,
+    OpResult, OutputFormat, Percentiles, Report, RequestLogWriter, RunMetadata, SampleWriter,
+    ScheduleResult, ServerResult, SizeResult, SweepWriter, ThroughputSeries, SIZE_CLASSES,
+};
+
+mod metrics;
+use metrics::{ConnCounts, Metrics};
+
+#[derive(Copy, Clone)]
+pub enum Arrival {
+    Exponential,
+    Constant,
+    Uniform,
+    /// Any distribution spec with the sigma and shape to parse it with. The
+    /// names `Distribution::parse` takes a mean for are scaled to the rate.
+    Spec(&'static str, f64, f64),
+}
+
+impl Arrival {
+    fn distribution(&self, ns_per_packet: u64) -> Distribution {
         match *self {
-            Distribution::Zero => 0,
-            Distribution::Constant(m) => m,
-            Distribution::Exponential(m) => Exp::new(1.0 / m).ind_sample(rng) as u64,
-            Distribution::Bimodal1(m) => {
-                if rng.gen_weighted_bool(2) {
-                    (m * 100.0) as u64
-                } else {
-                    (m * 1.0) as u64
-                }
-            }
-            Distribution::Bimodal2(m) => {
-                if rng.gen_weighted_bool(200) {
-                    (m * 500.0) as u64
-                } else {
-                    (m * 0.5) as u64
-                }
-            }
-            Distribution::RocksDB => {
-                if rng.gen_weighted_bool(2) {
-                    (591 * 1000) as u64
-                } else {
-                    (950) as u64
-                }
-            }
-            Distribution::GEV(loc, scale, shape) => {
-                (loc + scale * (rng.gen::<f64>().powf(-shape) - 1.0) / shape) as u64
-            }
-            Distribution::GPerato(loc, scale, shape) => {
-                (loc + scale * (Exp::new(1.0).ind_sample(rng).powf(-shape) - 1.0) / shape) as u64
+            Arrival::Spec(spec, sigma, shape) => {
+                Distribution::parse(spec, ns_per_packet as f64, sigma, shape).unwrap()
             }
+            Arrival::Exponential => Distribution::Exponential(ns_per_packet as f64),
+            // A zero gap would never advance the schedule.
+            Arrival::Constant => Distribution::Constant(u64::max(ns_per_packet, 1)),
+            // Gaps in [1, 2 * mean - 1], which keeps the mean.
+            Arrival::Uniform => Distribution::Uniform(1, u64::max(2 * ns_per_packet, 2) - 1),
         }
     }
 }
 
-arg_enum! {
+/// How a client reaches its servers. A unix socket carries the same stream
+/// as TCP, to the server listening at its path.
 #[derive(Copy, Clone)]
 pub enum Transport {
     Udp,
     Tcp,
-}}
+    Unix(&'static str),
+}
+
+impl Transport {
+    fn name(&self) -> &'static str {
+        match *self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Unix(_) => "unix",
+        }
+    }
+}
 
-arg_enum! {
 #[derive(Copy, Clone)]
 enum Protocol {
     Synthetic,
-    Memcached,
+    Memcached(MemcachedProtocol),
+    MemcachedMeta(MetaProtocol),
     Dns,
-}}
+}
 
 impl Protocol {
-    fn gen_request(&self, i: usize, p: &Packet, buf: &mut Vec<u8>, tport: Transport) {
+    fn gen_request(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> RequestInfo {
+        let from = buf.len();
+        let info = profile::timed(Phase::Build, || {
+            let op = match *self {
+                Protocol::Memcached(ref m) => return m.gen_request(i, p, buf, tport),
+                Protocol::MemcachedMeta(ref m) => return m.gen_request(i, p, buf, tport),
+                Protocol::Synthetic => {
+                    SyntheticProtocol::gen_request(i, p, buf, tport);
+                    "work"
+                }
+                Protocol::Dns => {
+                    DnsProtocol::gen_request(i, p, buf, tport);
+                    "query"
+                }
+            };
+            RequestInfo {
+                op,
+                key: None,
+                value_size: None,
+                key_size: None,
+                bytes: 0,
+            }
+        });
+        RequestInfo {
+            bytes: buf.len() - from,
+            ..info
+        }
+    }
+
+    /// Builds request `i` as `gen_request` does, but in parts for a vectored
+    /// send: a memcached request over UDP is sent from its frame header,
+    /// returned apart, and then the request as a stream carries it. A binary
+    /// one is split as `MemcachedProtocol::gen_parts` splits it, with its
+    /// value in `value` if not lent, and any other request is all in the
+    /// first part.
+    fn gen_framed<'a>(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &'a mut Vec<u8>,
+        value: &'a mut Vec<u8>,
+        tport: Transport,
+    ) -> (RequestInfo, Option<[u8; 8]>, [&'a [u8]; 3]) {
+        let (info, frame, parts) = match (*self, tport) {
+            (Protocol::Memcached(ref m), Transport::Udp) => {
+                let (info, parts) =
+                    profile::timed(Phase::Build, move || m.gen_parts(i, p, buf, value));
+                (info, Some(memcached::udp_frame(i as u16)), parts)
+            }
+            (Protocol::MemcachedMeta(_), Transport::Udp) => {
+                let info = self.gen_request(i, p, buf, Transport::Tcp);
+                (
+                    info,
+                    Some(memcached::udp_frame(i as u16)),
+                    [&buf[..], &[], &[]],
+                )
+            }
+            _ => {
+                let info = self.gen_request(i, p, buf, tport);
+                (info, None, [&buf[..], &[], &[]])
+            }
+        };
+        let bytes = frame.map_or(0, |f| f.len()) + parts.iter().map(|p| p.len()).sum::<usize>();
+        (RequestInfo { bytes, ..info }, frame, parts)
+    }
+
+    /// The key a memcached request is for, to route it on.
+    fn request_key(&self, p: &Packet) -> u64 {
+        let (hot_key, popularity) = match *self {
+            Protocol::Memcached(ref m) => (m.hot_key, m.key_distribution),
+            Protocol::MemcachedMeta(ref m) => (m.hot_key, None),
+            _ => (None, None),
+        };
+        memcached::request_key(p, hot_key, popularity)
+    }
+
+    /// As `read_response`, with the CAS a memcached response carried, and 0
+    /// for the other protocols, which have none.
+    fn read_cas_response(
+        &self,
+        sock: &Connection,
+        tport: Transport,
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<(usize, Outcome, u64)> {
         match *self {
-            Protocol::Memcached => MemcachedProtocol::gen_request(i, p, buf, tport),
-            Protocol::Synthetic => SyntheticProtocol::gen_request(i, p, buf, tport),
-            Protocol::Dns => DnsProtocol::gen_request(i, p, buf, tport),
+            Protocol::Memcached(ref m) => profile::timed(Phase::Read, || {
+                end_response(sock, m.read_cas_response(sock, tport, scratch))
+            }),
+            _ => self
+                .read_response(sock, tport, scratch)
+                .map(|(idx, outcome)| (idx, outcome, 0)),
         }
     }
 
@@ -141,731 +361,5350 @@ impl Protocol {
         &self,
         sock: &Connection,
         tport: Transport,
-        scratch: &mut [u8],
-    ) -> io::Result<usize> {
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<(usize, Outcome)> {
+        profile::timed(Phase::Read, || {
+            let response = match *self {
+                Protocol::Synthetic => {
+                    SyntheticProtocol::read_response(sock, tport, scratch).map(|i| (i, Outcome::Ok))
+                }
+                Protocol::Memcached(ref m) => m.read_response(sock, tport, scratch),
+                Protocol::MemcachedMeta(ref m) => m.read_response(sock, tport, scratch),
+                Protocol::Dns => {
+                    DnsProtocol::read_response(sock, tport, scratch).map(|i| (i, Outcome::Ok))
+                }
+            };
+            end_response(sock, response)
+        })
+    }
+
+    /// Parses a response datagram received as one of a batch. Returns None
+    /// for one that completes no request.
+    fn read_datagram(&self, datagram: &[u8]) -> io::Result<Option<(usize, Outcome)>> {
+        profile::timed(Phase::Read, || match *self {
+            Protocol::Synthetic => {
+                SyntheticProtocol::read_datagram(datagram).map(|i| Some((i, Outcome::Ok)))
+            }
+            Protocol::Memcached(ref m) => m.read_datagram(datagram),
+            Protocol::MemcachedMeta(ref m) => m.read_datagram(datagram).map(Some),
+            Protocol::Dns => DnsProtocol::read_datagram(datagram).map(|i| Some((i, Outcome::Ok))),
+        })
+    }
+
+    /// Parses a response from the start of what a stream connection has
+    /// received without blocking.
+    fn parse_head(&self, head: &[u8]) -> io::Result<Head> {
+        profile::timed(Phase::Read, || match *self {
+            Protocol::Synthetic => SyntheticProtocol::parse_head(head),
+            Protocol::Memcached(ref m) => m.parse_head(head),
+            Protocol::MemcachedMeta(ref m) => m.parse_head(head),
+            Protocol::Dns => unreachable!("DNS is only sent over udp"),
+        })
+    }
+
+    /// Opens a session on a new connection, for protocols that have a
+    /// handshake.
+    fn handshake(&self, sock: &Connection, tport: Transport) -> io::Result<()> {
         match *self {
-            Protocol::Synthetic => SyntheticProtocol::read_response(sock, tport, scratch),
-            Protocol::Memcached => MemcachedProtocol::read_response(sock, tport, scratch),
-            Protocol::Dns => DnsProtocol::read_response(sock, tport, scratch),
+            Protocol::Memcached(ref m) => {
+                let mut buf = Vec::new();
+                MemcachedProtocol::version_request(&mut buf);
+                (&*sock).write_all(&buf[..])?;
+                m.read_response(sock, tport, &mut Vec::new()).map(|_| ())
+            }
+            Protocol::MemcachedMeta(_) | Protocol::Synthetic | Protocol::Dns => Ok(()),
         }
     }
+
+    /// Whether a stream connection's responses may leave out which request
+    /// they answer, to be matched to requests in the order they were sent.
+    fn matches_by_order(&self, tport: Transport) -> bool {
+        matches!(*self, Protocol::MemcachedMeta(_)) && !matches!(tport, Transport::Udp)
+    }
 }
 
 arg_enum! {
 #[derive(Copy, Clone)]
-enum OutputMode {
-    Silent,
-    Normal,
-    Buckets,
-    Trace
+enum LoadMode {
+    Open,
+    Closed,
 }}
 
-fn duration_to_ns(duration: Duration) -> u64 {
-    duration.as_secs() * 1000_000_000 + duration.subsec_nanos() as u64
+arg_enum! {
+#[derive(Copy, Clone)]
+enum WindowPolicy {
+    Block,
+    Drop,
+}}
+
+arg_enum! {
+/// How a thread with several connections picks the one for each request.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PoolPolicy {
+    RoundRobin,
+    LeastOutstanding,
+}}
+
+/// Per-run client settings that stay fixed across schedules.
+#[derive(Copy, Clone)]
+struct ClientOptions {
+    /// Maximum requests in flight per connection, 0 for no limit.
+    max_outstanding: usize,
+    window_policy: WindowPolicy,
+    timestamp: SendTimestamp,
+    /// Requests kept in flight by a closed-loop TCP connection, 0 to send open
+    /// loop on the schedule instead.
+    pipeline_depth: usize,
+    /// Pause between a closed-loop response and the next request in its slot.
+    think_time: Distribution,
+    run_length: RunLength,
+    /// Stop sending after the first measured schedule in which fewer than
+    /// this fraction of the requests sent complete.
+    stop_on_saturation: Option<f64>,
+    /// Requests sent this soon after a run starts are left out of its results.
+    warmup: Duration,
+    /// How long a connection waits for outstanding responses after its last
+    /// request is due, before counting them as timeouts.
+    drain_timeout: Duration,
+    /// Print the sends and completions of each such interval during the run.
+    interval: Option<Duration>,
+    /// Keep the intervals off the output, leaving them to the JSON and CSV.
+    quiet: bool,
+    /// Where the counts of every connection are served from, if anywhere.
+    metrics: Option<&'static Metrics>,
+    /// Requests per second and burst size that open-loop sends are capped at,
+    /// split evenly over the connections.
+    rate_limit: Option<(f64, usize)>,
+    /// Requests a closed-loop connection makes before it is closed and a new
+    /// one opened in its place, or None to keep connections for the run.
+    session_length: Option<Distribution>,
+    /// Open every connection with the protocol's handshake.
+    handshake: bool,
+    /// Write requests without ever reading a response, to find how fast they
+    /// can be sent at all.
+    no_read: bool,
+    /// Requests to make in order, looping, instead of drawing them from the
+    /// workload.
+    trace: Option<&'static [TraceRecord]>,
+    /// How many times faster than recorded a trace with times is replayed.
+    replay_speed: f64,
+    /// Connections each thread sends its open-loop requests over.
+    conns_per_thread: usize,
+    pool_policy: PoolPolicy,
+    /// Replace a connection that fails mid-run, rather than leaving the rest
+    /// of its requests unsent.
+    reconnect: bool,
+    /// How long a request may go unanswered before it times out, or None to
+    /// wait for it until the drain is over.
+    request_timeout: Option<Duration>,
+    /// Times a UDP request left unanswered for the request timeout is sent
+    /// again, with the same opaque, before it times out.
+    retransmits: usize,
+    /// How long requests may be in flight with no response before the run is
+    /// aborted as hung.
+    watchdog: Option<Duration>,
+    sockets: SocketOptions,
+    /// Do the I/O of Linux client connections through io_uring.
+    io_uring: bool,
+    /// Read the responses of a thread's pool of connections on a single
+    /// thread, waiting on them all with epoll.
+    epoll: bool,
+    /// Requests a pipelined connection coalesces into each write, when that
+    /// many can be sent at once.
+    send_batch: usize,
+    /// Datagrams an open-loop UDP connection sends with each sendmmsg and
+    /// receives with each recvmmsg, 1 to send and receive one at a time.
+    udp_batch: usize,
+    /// Send each run of equal-size datagrams of a UDP batch as one send the
+    /// kernel segments, where it can.
+    udp_gso: bool,
+    /// Have the kernel coalesce the datagrams of a UDP batch received
+    /// together, where it can.
+    udp_gro: bool,
+    /// Threads building each open-loop connection's requests ahead of their
+    /// sender, or 0 for the sender to build its own.
+    generators: usize,
+    /// How long before it is due a generator may build a request.
+    generator_lead: Duration,
+    /// Bytes each connection's receive buffer is preallocated with. It is
+    /// reused for every response, growing only for one read of more, so a
+    /// stream connection holds at most the larger of this and its largest
+    /// response, capped at the 64 KiB a body is discarded in, and a UDP
+    /// connection the largest datagram, 64 KiB, per buffer it receives into.
+    read_buffer: usize,
+    /// Secure stream connections with TLS.
+    tls: Option<&'static TlsClient>,
+    /// Local addresses to bind client connections to.
+    sources: Sources,
+    /// Cores the threads sending each connection group's requests are pinned
+    /// to, in turn.
+    cpus: CpuList,
+    /// Seed of the packet generator, or None to seed it from the OS.
+    seed: Option<u64>,
+    /// Report each request's slowdown, its latency over its service time,
+    /// instead of its latency.
+    slowdown: bool,
 }
 
-fn run_linux_udp_server(backend: Backend, addr: SocketAddrV4, nthreads: usize, worker: FakeWorker) {
-    let join_handles: Vec<_> = (0..nthreads)
-        .map(|_| {
-            let worker = worker.clone();
-            backend.spawn_thread(move || {
-                let socket = backend.create_udp_connection(addr, None).unwrap();
-                println!("Bound to address {}", socket.local_addr());
-                let mut buf = vec![0; 4096];
-                loop {
-                    let (len, remote_addr) = socket.recv_from(&mut buf[..]).unwrap();
-                    let payload = Payload::deserialize(&mut &buf[..len]).unwrap();
-                    worker.work(payload.work_iterations);
-                    socket.send_to(&buf[..len], remote_addr).unwrap();
-                }
-            })
-        })
-        .collect();
+/// What every connection of a client has in common: the backend it is
+/// opened on, its transport, the protocol spoken over it and the run's
+/// options.
+#[derive(Copy, Clone)]
+struct Client {
+    backend: Backend,
+    protocol: Protocol,
+    tport: Transport,
+    opts: ClientOptions,
+}
 
-    for j in join_handles {
-        j.join().unwrap();
+/// A thread's connection to one server, as the functions sending and
+/// receiving over it see it: the client's settings, the server's address,
+/// and the instant the schedule's times count from.
+#[derive(Copy, Clone)]
+struct Link {
+    client: Client,
+    addr: SocketAddr,
+    start: Instant,
+}
+
+/// The servers a client sends to. Connections are spread over them evenly,
+/// and each request goes to one picked in proportion to its weight, or to
+/// the one its memcached key hashes to.
+#[derive(Copy, Clone, Debug)]
+struct Targets {
+    addrs: &'static [SocketAddr],
+    weights: &'static [u32],
+    key_hash: bool,
+}
+
+/// Which of a target's addresses to connect to, if its name resolves to
+/// both IPv4 and IPv6 ones.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum AddressFamily {
+    /// The first the resolver returns.
+    Any,
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    fn of(addr: SocketAddr) -> AddressFamily {
+        match addr {
+            SocketAddr::V4(_) => AddressFamily::V4,
+            SocketAddr::V6(_) => AddressFamily::V6,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AddressFamily::Any => "any",
+            AddressFamily::V4 => "ipv4",
+            AddressFamily::V6 => "ipv6",
+        }
     }
 }
 
-fn socket_worker(socket: &mut Connection, worker: FakeWorker) {
-    let mut v = vec![0; 4096];
-    let mut r = || {
-        v.clear();
-        let payload = Payload::deserialize(socket)?;
-        worker.work(payload.work_iterations);
-        payload.serialize_into(&mut v)?;
-        Ok(socket.write_all(&v[..])?)
-    };
-    loop {
-        if let Err(e) = r() as io::Result<()> {
-            match e.raw_os_error() {
-                Some(-104) | Some(104) => break,
-                _ => {}
-            }
-            if e.kind() != ErrorKind::UnexpectedEof {
-                println!("Receive thread: {}", e);
+impl Targets {
+    /// A comma-separated list of servers, each an address such as
+    /// `10.0.0.1:11211` or `[::1]:11211`, or a host name and port, which is
+    /// resolved to one address of `family`.
+    fn parse(spec: &str, family: AddressFamily) -> Result<Vec<SocketAddr>, String> {
+        spec.split(',')
+            .map(|s| {
+                let s = s.trim();
+                let mut addrs = s
+                    .to_socket_addrs()
+                    .map_err(|e| format!("bad address '{}': {}", s, e))?;
+                addrs
+                    .find(|&a| family == AddressFamily::Any || AddressFamily::of(a) == family)
+                    .ok_or_else(|| format!("'{}' has no {} address", s, family.name()))
+            })
+            .collect()
+    }
+}
+
+/// What bounds each measured sample: the time it runs for, or the number of
+/// requests sent across all connections.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RunLength {
+    Duration(Duration),
+    Count(usize),
+}
+
+impl RunLength {
+    /// Length of the measured schedule at `packets_per_second`. A count run is
+    /// laid out for twice the time the count should take, so a sender that
+    /// skips more than half of its requests stops short of the count.
+    fn runtime(&self, packets_per_second: usize) -> Duration {
+        match *self {
+            RunLength::Duration(d) => d,
+            RunLength::Count(n) => {
+                Duration::from_nanos(2 * n as u64 * 1_000_000_000 / packets_per_second as u64)
             }
-            break;
         }
     }
 }
 
-fn run_tcp_server(backend: Backend, addr: SocketAddrV4, worker: FakeWorker) {
-    let tcpq = backend.create_tcp_listener(addr).unwrap();
-    println!("Bound to address {}", addr);
-    loop {
-        match tcpq.accept() {
-            Ok(mut c) => {
-                let worker = worker.clone();
-                backend.spawn_thread(move || socket_worker(&mut c, worker));
+/// Which side of the socket write a request's send time is taken from. A
+/// write that blocks counts towards the latency with `PreWrite`, but not with
+/// `PostWrite`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum SendTimestamp {
+    PreWrite,
+    PostWrite,
+}
+
+/// Monotonic time since the start of the run.
+trait Clock {
+    fn now(&self) -> Duration;
+}
+
+impl Clock for Instant {
+    fn now(&self) -> Duration {
+        self.elapsed()
+    }
+}
+
+/// Performs `write` and returns the send time to record for it.
+fn timed_write<C, F>(clock: &C, timestamp: SendTimestamp, write: F) -> io::Result<Duration>
+where
+    C: Clock,
+    F: FnOnce() -> io::Result<()>,
+{
+    let before = clock.now();
+    profile::timed(Phase::Write, write)?;
+    Ok(match timestamp {
+        SendTimestamp::PreWrite => before,
+        SendTimestamp::PostWrite => clock.now(),
+    })
+}
+
+/// Bounds the number of in-flight requests on one connection.
+///
+/// When the window is full, `WindowPolicy::Drop` skips the scheduled request and
+/// marks it `window_dropped`. `WindowPolicy::Block` stalls the sender until a
+/// response frees a slot and then sends immediately, however late. Blocking
+/// keeps every request but reintroduces coordinated omission: latency is
+/// measured from the delayed actual send, and only the gap between
+/// `target_start` and `actual_start` shows the stall.
+struct Window {
+    limit: usize,
+    policy: WindowPolicy,
+    outstanding: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl Window {
+    fn new(limit: usize, policy: WindowPolicy) -> Window {
+        Window {
+            limit,
+            policy,
+            outstanding: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims a slot for a request about to be sent, returning false if the
+    /// request should not be sent. Only the send thread may call this.
+    fn acquire(&self, backend: Backend) -> bool {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return false;
             }
-            Err(e) => {
-                println!("Listener: {}", e);
+            if self.limit == 0 || self.outstanding.load(Ordering::SeqCst) < self.limit {
+                self.outstanding.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+            match self.policy {
+                WindowPolicy::Drop => return false,
+                WindowPolicy::Block => backend.thread_yield(),
             }
         }
     }
-}
 
-fn run_spawner_server(addr: SocketAddrV4, worker: FakeWorker) {
-    static mut SPAWNER_WORKER: Option<FakeWorker> = None;
-    unsafe {
-        SPAWNER_WORKER = Some(worker);
+    fn release(&self) {
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
     }
-    extern "C" fn echo(d: *mut shenango::ffi::udp_spawn_data) {
-        unsafe {
-            let buf = slice::from_raw_parts((*d).buf as *mut u8, (*d).len);
-            let payload = Payload::deserialize(&mut &buf[..]).unwrap();
-            let worker = SPAWNER_WORKER.as_ref().unwrap();
-            worker.work(payload.work_iterations);
-            let _ = UdpSpawner::reply(d, buf);
-            UdpSpawner::release_data(d);
-        }
+
+    /// Called once no more responses will arrive, to unblock the sender.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
     }
 
-    let _s = unsafe { UdpSpawner::new(addr, echo).unwrap() };
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
 
-    let wg = shenango::WaitGroup::new();
-    wg.add(1);
-    wg.wait();
+/// When each request on a connection was last sent, by opaque, while it is
+/// in flight, so that a sweeper can retransmit or time out the ones left
+/// unanswered and free their window slots. An entry stays marked once it is
+/// answered or timed out, since opaques are not reused on a connection, so
+/// that a response that comes after is told apart as late or a duplicate.
+struct InFlight {
+    /// 0 for a request not sent, ANSWERED or EXPIRED once it is done, and
+    /// otherwise one more than the time in ns it was last sent.
+    sent_at: Vec<AtomicU64>,
+    /// How many times each request was sent again.
+    retransmits: Vec<AtomicUsize>,
+    /// Responses to each request after the first.
+    duplicates: Vec<AtomicUsize>,
+    /// Each request's payload, kept while it may be retransmitted, in a
+    /// buffer from `buffers` that goes back once the request is done.
+    payloads: Vec<Mutex<Vec<u8>>>,
+    buffers: BufferPool,
+    /// Times a request is sent again before it times out.
+    max_retransmits: usize,
+    /// Requests below this have been sent or skipped.
+    sent: AtomicUsize,
+    /// Requests below this have been answered, skipped or timed out. Only
+    /// the sweeper uses it.
+    swept: AtomicUsize,
 }
 
-fn run_memcached_preload(
-    backend: Backend,
-    tport: Transport,
-    addr: SocketAddrV4,
-    nthreads: usize,
-) -> bool {
-    let perthread = (memcached::NVALUES as usize + nthreads - 1) / nthreads;
-    let join_handles: Vec<JoinHandle<_>> = (0..nthreads)
-        .map(|i| {
-            backend.spawn_thread(move || {
-                let sock1 = Arc::new(match tport {
-                    Transport::Tcp => backend.create_tcp_connection(None, addr).unwrap(),
-                    Transport::Udp => backend
-                        .create_udp_connection("0.0.0.0:0".parse().unwrap(), Some(addr))
-                        .unwrap(),
-                });
-                let socket = sock1.clone();
-                backend.spawn_thread(move || {
-                    backend.sleep(Duration::from_secs(20));
-                    if Arc::strong_count(&socket) > 1 {
-                        println!("Timing out socket");
-                        socket.shutdown();
-                    }
-                });
+/// The requests sent on a stream connection and still unanswered, oldest
+/// first, for protocols whose responses may not say which request they
+/// answer. The server answers them in turn, so such a response answers the
+/// oldest.
+#[derive(Default)]
+struct SendOrder {
+    unanswered: Mutex<VecDeque<usize>>,
+}
 
-                let mut vec_s: Vec<u8> = Vec::with_capacity(4096);
-                let mut vec_r: Vec<u8> = vec![0; 4096];
-                for n in 0..perthread {
-                    vec_s.clear();
-                    MemcachedProtocol::set_request(
-                        (i * perthread + n) as u64,
-                        0,
-                        &mut vec_s,
-                        tport,
-                    );
+impl SendOrder {
+    /// Puts request `i` in line. Requests are sent in order.
+    fn send(&self, i: usize) {
+        self.unanswered.lock().unwrap().push_back(i);
+    }
 
-                    if let Err(e) = (&*sock1).write_all(&vec_s[..]) {
-                        println!("Preload send ({}/{}): {}", n, perthread, e);
-                        return false;
-                    }
+    /// Takes request `i` out of line once it is answered, or its write
+    /// failed, and returns it, or the oldest request for an UNMATCHED
+    /// response. A response with no request left to answer stays UNMATCHED.
+    fn answer(&self, i: usize) -> usize {
+        let mut unanswered = self.unanswered.lock().unwrap();
+        if i == UNMATCHED {
+            return unanswered.pop_front().unwrap_or(UNMATCHED);
+        }
+        if let Some(k) = unanswered.iter().position(|&j| j == i) {
+            unanswered.remove(k);
+        }
+        i
+    }
+}
 
-                    if let Err(e) = MemcachedProtocol::read_response(&sock1, tport, &mut vec_r[..])
-                    {
-                        println!("preload receive ({}/{}): {}", n, perthread, e);
-                        return false;
-                    }
-                }
-                true
-            })
-        })
-        .collect();
+/// Buffers a connection keeps preallocated for payloads that may be
+/// retransmitted. More are allocated while more requests are in flight.
+const KEPT_BUFFERS: usize = 64;
 
-    return join_handles.into_iter().all(|j| j.join().unwrap());
-}
+const ANSWERED: u64 = u64::MAX - 1;
+const EXPIRED: u64 = u64::MAX;
 
-#[derive(Copy, Clone)]
-struct RequestSchedule {
-    arrival: Distribution,
-    service: Distribution,
-    output: OutputMode,
-    runtime: Duration,
-    discard_pct: usize,
+/// How a response related to its request.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Answer {
+    /// The first response, in time.
+    First,
+    /// The first response, after the request timed out.
+    Late,
+    /// A second response, to an earlier transmission of a request sent again
+    /// or copied on the way.
+    Duplicate,
+    /// A response to no request sent on the connection.
+    Spurious,
 }
 
-fn gen_classic_packet_schedule(
-    runtime: Duration,
-    packets_per_second: usize,
-    output: OutputMode,
-    distribution: Distribution,
-    ramp_up_seconds: usize,
-    nthreads: usize,
-) -> Vec<RequestSchedule> {
-    let mut sched: Vec<RequestSchedule> = Vec::new();
-    /* Ramp up in 100ms increments */
-    for t in 1..(10 * ramp_up_seconds) {
-        let rate = t * packets_per_second / (ramp_up_seconds * 10);
-
-        sched.push(RequestSchedule {
-            arrival: Distribution::Exponential((nthreads * 1000_000_000 / rate) as f64),
-            service: distribution,
-            output: OutputMode::Silent,
-            runtime: Duration::from_millis(100),
-            discard_pct: 0,
-        });
+impl InFlight {
+    fn new(requests: usize, max_retransmits: usize) -> InFlight {
+        InFlight {
+            sent_at: (0..requests).map(|_| AtomicU64::new(0)).collect(),
+            retransmits: (0..requests).map(|_| AtomicUsize::new(0)).collect(),
+            duplicates: (0..requests).map(|_| AtomicUsize::new(0)).collect(),
+            payloads: match max_retransmits {
+                0 => Vec::new(),
+                _ => (0..requests).map(|_| Mutex::new(Vec::new())).collect(),
+            },
+            buffers: match max_retransmits {
+                0 => BufferPool::new(0),
+                _ => BufferPool::new(usize::min(requests, KEPT_BUFFERS)),
+            },
+            max_retransmits,
+            sent: AtomicUsize::new(0),
+            swept: AtomicUsize::new(0),
+        }
     }
 
-    let ns_per_packet = nthreads * 1000_000_000 / packets_per_second;
-    sched.push(RequestSchedule {
-        arrival: Distribution::Exponential(ns_per_packet as f64),
-        service: distribution,
-        output: output,
-        runtime: runtime,
-        discard_pct: 10,
-    });
-    // println!("{} {} {}", ramp_up_seconds, sched.len(), ns_per_packet);
-
-    // sched.push(RequestSchedule {
-    //     arrival: Distribution::GPerato(0.0, 16029.2, 0.154971),
-    //     service: distribution,
-    //     output: output,
-    //     runtime: runtime,
-    //     discard_pct: 10,
-    // });
-    sched
-}
-
-fn gen_loadshift_experiment(
-    spec: &str,
-    service: Distribution,
-    nthreads: usize,
-) -> Vec<RequestSchedule> {
-    spec.split(",")
-        .map(|step_spec| {
-            let s: Vec<&str> = step_spec.split(":").collect();
-            assert!(s.len() == 2);
-            let ns_per_packet = nthreads as u64 * 1000_000_000 / s[0].parse::<u64>().unwrap();
-            let micros = s[1].parse().unwrap();
-            RequestSchedule {
-                arrival: Distribution::Exponential(ns_per_packet as f64),
-                service: service,
-                output: OutputMode::Trace,
-                runtime: Duration::from_micros(micros),
-                discard_pct: 0,
+    /// Puts request `i` in flight from `now`, as sent from `parts` in turn.
+    /// Requests are sent in order.
+    fn send(&self, i: usize, now: Duration, parts: &[&[u8]]) {
+        if self.max_retransmits > 0 {
+            let mut kept = self.buffers.take();
+            for part in parts {
+                kept.extend_from_slice(part);
             }
-        })
-        .collect()
-}
+            self.buffers.put(std::mem::replace(
+                &mut *self.payloads[i].lock().unwrap(),
+                kept,
+            ));
+        }
+        self.sent_at[i].store(duration_to_ns(now) + 1, Ordering::SeqCst);
+        self.sent.store(i + 1, Ordering::SeqCst);
+    }
 
-fn process_result(sched: &RequestSchedule, packets: &mut [Packet], wct_start: SystemTime, slowdown: bool) -> bool {
-    let start_unix = wct_start + packets[0].target_start;
+    /// Records that request `i` was skipped instead.
+    fn skip(&self, i: usize) {
+        self.sent.store(i + 1, Ordering::SeqCst);
+    }
 
-    // Discard the first X% of the packets.
-    let plen = packets.len();
-    let packets = &mut packets[plen * sched.discard_pct / 100..];
+    /// Takes request `i` out of flight once it is answered, or its write
+    /// failed.
+    fn answer(&self, i: usize) -> Answer {
+        if i >= self.sent_at.len() {
+            return Answer::Spurious;
+        }
+        loop {
+            let at = self.sent_at[i].load(Ordering::SeqCst);
+            match at {
+                0 => return Answer::Spurious,
+                EXPIRED => return Answer::Late,
+                ANSWERED => {
+                    self.duplicates[i].fetch_add(1, Ordering::SeqCst);
+                    return Answer::Duplicate;
+                }
+                _ => {}
+            }
+            if self.sent_at[i]
+                .compare_exchange(at, ANSWERED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                if self.max_retransmits > 0 {
+                    self.release(i);
+                }
+                return Answer::First;
+            }
+        }
+    }
 
-    let never_sent = packets.iter().filter(|p| p.actual_start.is_none()).count();
-    let dropped = packets
-        .iter()
-        .filter(|p| p.completion_time.is_none())
-        .count()
-        - never_sent;
-    // println!("{} {} {}", plen, packets.len(), never_sent);
-    if packets.len() - dropped - never_sent <= 1 {
-        match sched.output {
-            OutputMode::Silent => {}
-            _ => {
-                let first_send = packets.iter().map(|p| p.target_start).min().unwrap();
-                let last_send = packets.iter().map(|p| p.target_start).max().unwrap();
-                println!(
-                    "{}, {}, 0, {}, {}, {}",
-                    sched.service.name(),
-                    packets.len() as u64 * 1000_000_000 / duration_to_ns(last_send - first_send),
-                    dropped,
-                    never_sent,
-                    start_unix.duration_since(UNIX_EPOCH).unwrap().as_secs()
-                );
+    /// Sends again over `socket` the requests last sent more than `timeout`
+    /// before `now` that may still be retransmitted, and times out the rest,
+    /// releasing their slots in `window`.
+    /// Returns how many requests it timed out.
+    fn sweep(
+        &self,
+        now: Duration,
+        timeout: Duration,
+        window: &Window,
+        socket: &Connection,
+    ) -> usize {
+        let sent = self.sent.load(Ordering::SeqCst);
+        let mut swept = self.swept.load(Ordering::SeqCst);
+        let mut i = swept;
+        let mut expired = 0;
+        while i < sent {
+            let at = self.sent_at[i].load(Ordering::SeqCst);
+            let done = at == 0 || at == ANSWERED || at == EXPIRED;
+            if done && i == swept {
+                swept += 1;
+            }
+            if done || Duration::from_nanos(at - 1) + timeout > now {
+                i += 1;
+                continue;
+            }
+            let retransmit = self.retransmits[i].load(Ordering::SeqCst) < self.max_retransmits;
+            let next = if retransmit {
+                duration_to_ns(now) + 1
+            } else {
+                EXPIRED
+            };
+            if self.sent_at[i]
+                .compare_exchange(at, next, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                // Answered just now.
+                continue;
+            }
+            if retransmit {
+                self.retransmits[i].fetch_add(1, Ordering::SeqCst);
+                let payload = self.payloads[i].lock().unwrap();
+                // A lost retransmission is retransmitted in turn.
+                let _ = (&*socket).write_all(&payload[..]);
+            } else {
+                if self.max_retransmits > 0 {
+                    self.release(i);
+                }
+                window.release();
+                expired += 1;
             }
+            i += 1;
         }
-        return false;
+        self.swept.store(swept, Ordering::SeqCst);
+        expired
     }
 
-    if let OutputMode::Silent = sched.output {
-        return true;
+    /// Gives the buffer kept for request `i` back, once it will not be sent
+    /// again.
+    fn release(&self, i: usize) {
+        let kept = std::mem::take(&mut *self.payloads[i].lock().unwrap());
+        self.buffers.put(kept);
     }
 
-    let first_send = packets.iter().filter_map(|p| p.actual_start).min().unwrap();
-    let last_send = packets.iter().filter_map(|p| p.actual_start).max().unwrap();
+    fn expired(&self, i: usize) -> bool {
+        self.sent_at[i].load(Ordering::SeqCst) == EXPIRED
+    }
+}
 
-    let mut latencies: Vec<_> = packets
-        .iter()
-        .filter_map(|p| match (p.actual_start, p.completion_time) {
-            (Some(ref start), Some(ref end)) => {
-                let ns = duration_to_ns(*end - *start) as f32;
-                if slowdown {
-                    Some(ns / p.work_iterations as f32)
-                } else {
-                    Some(ns / 1000.0)
-                }
-            }
-            _ => None,
-        })
-        .collect();
-    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+/// Significant digits of the latencies of each live report.
+const LIVE_DIGITS: u32 = 2;
+
+/// When each request of a connection, or of a pool, was admitted and what
+/// it was, the latencies and misses of its responses since the live report
+/// last took them, and its counts for the metrics. Each receiving thread
+/// only ever waits on the report, and only while it swaps the window out.
+struct LiveTimes {
+    /// In ns since the start of the run.
+    sent_at: Vec<AtomicU64>,
+    /// Each request's index in `SAMPLE_OPS`.
+    ops: Vec<AtomicU8>,
+    window: Mutex<LiveWindow>,
+    counts: Arc<ConnCounts>,
+}
+
+struct LiveWindow {
+    latencies: Histogram,
+    misses: usize,
+}
 
-    let percentile = |p| {
-        let idx = ((packets.len() - never_sent) as f32 * p / 100.0) as usize;
-        if idx >= latencies.len() {
-            return INFINITY;
+impl LiveWindow {
+    fn new() -> LiveWindow {
+        LiveWindow {
+            latencies: Histogram::new(LIVE_DIGITS),
+            misses: 0,
         }
-        latencies[idx]
-    };
+    }
+}
 
-    println!(
-        "{}, {}, {}, {}, {}, {:.1}, {:.1}, {:.1}, {:.1}, {:.1}, {}",
-        sched.service.name(),
-        (packets.len() - never_sent) as u64 * 1000_000_000 / duration_to_ns(last_send - first_send),
-        latencies.len() as u64 * 1000_000_000 / duration_to_ns(last_send - first_send),
-        dropped,
-        never_sent,
-        percentile(50.0),
-        percentile(90.0),
-        percentile(99.0),
-        percentile(99.9),
-        percentile(99.99),
-        start_unix.duration_since(UNIX_EPOCH).unwrap().as_secs()
-    );
+impl LiveTimes {
+    /// Times `n` requests. A connection that times its own responses needs
+    /// none, and only counts what it sends.
+    fn new(n: usize, counts: Arc<ConnCounts>) -> LiveTimes {
+        LiveTimes {
+            sent_at: (0..n).map(|_| AtomicU64::new(0)).collect(),
+            ops: (0..n).map(|_| AtomicU8::new(0)).collect(),
+            window: Mutex::new(LiveWindow::new()),
+            counts,
+        }
+    }
 
-    if let OutputMode::Trace = sched.output {
-        packets.sort_by_key(|p| p.actual_start.unwrap_or(p.target_start));
-        print!("Trace: ");
-        for p in packets {
-            if let Some(completion_time) = p.completion_time {
-                let actual_start = p.actual_start.unwrap();
-                println!(
-                    "{}:{}:{}",
-                    duration_to_ns(actual_start),
-                    duration_to_ns(actual_start) as i64 - duration_to_ns(p.target_start) as i64,
-                    duration_to_ns(completion_time - actual_start)
-                )
-            } else if p.actual_start.is_some() {
-                let actual_start = p.actual_start.unwrap();
-                println!(
-                    "{}:{}:-1",
-                    duration_to_ns(actual_start),
-                    duration_to_ns(actual_start) as i64 - duration_to_ns(p.target_start) as i64,
-                )
-            } else {
-                println!("{}:-1:-1", duration_to_ns(p.target_start))
-            }
+    fn sent(&self, i: usize, t: Duration, op: &str) {
+        let op = report::sample_op(op);
+        if i < self.sent_at.len() {
+            self.sent_at[i].store(duration_to_ns(t), Ordering::Relaxed);
+            self.ops[i].store(op, Ordering::Relaxed);
         }
-        println!("");
+        self.counts.sent(op);
     }
 
-    if let OutputMode::Buckets = sched.output {
-        unimplemented!()
-        // let mut buckets = BTreeMap::new();
+    /// Counts the response to request `i`, received at `now`.
+    fn answered(&self, i: usize, now: Duration, outcome: Outcome) {
+        let sent = self.sent_at[i].load(Ordering::Relaxed);
+        let op = self.ops[i].load(Ordering::Relaxed);
+        self.record(duration_to_ns(now).saturating_sub(sent), outcome, op);
+    }
 
-        // for l in latencies {
-        //     *buckets.entry(l).or_insert(0) += 1;
-        // }
-        // print!("Latencies: ");
-        // for k in buckets.keys() {
-        //     print!("{}:{} ", k, buckets[k]);
-        // }
-        // println!("");
+    /// Counts a response that came `latency` ns after its request for `op`.
+    fn record(&self, latency: u64, outcome: Outcome, op: u8) {
+        self.counts.response(op, outcome == Outcome::Miss);
+        let mut window = self.window.lock().unwrap();
+        window.latencies.record(latency);
+        if outcome == Outcome::Miss {
+            window.misses += 1;
+        }
     }
-    true
 }
 
-fn run_client(
-    backend: Backend,
-    addr: SocketAddrV4,
-    nthreads: usize,
-    protocol: Protocol,
-    tport: Transport,
-    barrier_group: &mut Option<lockstep::Group>,
-    schedules: &Vec<RequestSchedule>,
-    index: usize,
-    slowdown: bool,
-) -> bool {
-    let mut rng = rand::thread_rng();
+/// Decides when the send loop stops scheduling new requests, after which the
+/// remaining responses are drained. Shared by all connections of a run.
+struct SendLimit {
+    /// Nothing is sent after the end of the last schedule.
+    deadline: Duration,
+    /// For count runs, the number of requests due in the last schedule that
+    /// may be sent. Ramp up requests before it are not counted.
+    count: Option<usize>,
+    counted_from: Duration,
+    sent: AtomicUsize,
+    /// Set to the time a saturation check stopped the run, in ns.
+    stopped_at: AtomicU64,
+    /// Fraction of the requests sent during each measured schedule that must
+    /// complete for the run to go on, if it stops at saturation.
+    saturation: Option<f64>,
+    /// Whether sends and responses are counted for interval reports and the
+    /// watchdog.
+    counting: bool,
+    admitted: AtomicUsize,
+    completed: AtomicUsize,
+    /// Set once every connection is done, to end the interval reports.
+    finished: AtomicBool,
+    /// The send times and responses of every connection, if each interval is
+    /// printed as it ends or metrics are served.
+    live: Option<Mutex<Vec<Arc<LiveTimes>>>>,
+    metrics: Option<&'static Metrics>,
+    /// How long requests may go unanswered before the watchdog aborts the
+    /// run, and whether it has.
+    watchdog: Option<Duration>,
+    hung: AtomicBool,
+}
 
-    let packet_schedules: Vec<(Vec<Packet>, Vec<Option<Duration>>, Connection)> = (0..nthreads)
-        .map(|tidx| {
-            let mut last = 100_000_000;
-            let mut thread_packets: Vec<Packet> = Vec::new();
-            for sched in schedules {
-                let end = last + duration_to_ns(sched.runtime);
-                while last < end {
-                    last += sched.arrival.sample(&mut rng);
-                    thread_packets.push(Packet {
-                        randomness: rng.gen::<u64>(),
-                        target_start: Duration::from_nanos(last),
-                        work_iterations: sched.service.sample(&mut rng),
-                        ..Default::default()
-                    });
-                }
+impl SendLimit {
+    fn new(run_length: RunLength, schedules: &[RequestSchedule]) -> SendLimit {
+        let mut deadline = Duration::from_millis(100);
+        let mut counted_from = deadline;
+        for sched in schedules {
+            counted_from = deadline;
+            deadline += sched.runtime;
+        }
+        SendLimit {
+            deadline,
+            count: match run_length {
+                RunLength::Duration(_) => None,
+                RunLength::Count(n) => Some(n),
+            },
+            counted_from,
+            sent: AtomicUsize::new(0),
+            stopped_at: AtomicU64::new(u64::MAX),
+            saturation: None,
+            counting: false,
+            admitted: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            finished: AtomicBool::new(false),
+            live: None,
+            metrics: None,
+            watchdog: None,
+            hung: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims the send of `packet` at time `now`, returning false once the run
+    /// is over.
+    fn admit(&self, packet: &Packet, now: Duration) -> bool {
+        if now >= self.deadline || duration_to_ns(now) >= self.stopped_at.load(Ordering::SeqCst) {
+            return false;
+        }
+        let admitted = match self.count {
+            Some(n) if packet.target_start >= self.counted_from => {
+                self.sent.fetch_add(1, Ordering::SeqCst) < n
             }
+            _ => true,
+        };
+        if admitted && (self.saturation.is_some() || self.counting) {
+            self.admitted.fetch_add(1, Ordering::SeqCst);
+        }
+        admitted
+    }
 
-            let src_addr = SocketAddrV4::new(
-                Ipv4Addr::new(0, 0, 0, 0),
-                (100 + (index * nthreads) + tidx) as u16,
-            );
-            let socket = match tport {
-                Transport::Tcp => backend.create_tcp_connection(Some(src_addr), addr).unwrap(),
-                Transport::Udp => backend
-                    .create_udp_connection("0.0.0.0:0".parse().unwrap(), Some(addr))
-                    .unwrap(),
-            };
-            let packets_per_thread = thread_packets.len();
-            (thread_packets, vec![None; packets_per_thread], socket)
-        })
-        .collect();
+    /// Counts a response towards the saturation check and interval reports.
+    fn complete(&self) {
+        if self.saturation.is_some() || self.counting {
+            self.completed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
 
-    if let Some(ref mut g) = *barrier_group {
-        g.barrier();
+    /// A table for the live report to time `n` requests of a connection to
+    /// `server` by, or None if intervals are not printed nor metrics served.
+    fn live_times(&self, n: usize, server: SocketAddr) -> Option<Arc<LiveTimes>> {
+        let live = self.live.as_ref()?;
+        let counts = match self.metrics {
+            Some(metrics) => metrics.connection(server),
+            None => Arc::new(ConnCounts::default()),
+        };
+        let times = Arc::new(LiveTimes::new(n, counts));
+        live.lock().unwrap().push(times.clone());
+        Some(times)
     }
-    let start_unix = SystemTime::now();
-    let start = Instant::now();
 
-    let mut send_threads = Vec::new();
-    let mut receive_threads = Vec::new();
-    for (mut packets, mut receive_times, socket) in packet_schedules {
-        let socket = Arc::new(socket);
-        let socket2 = socket.clone();
-
-        receive_threads.push(backend.spawn_thread(move || {
-            let mut recv_buf = vec![0; 4096];
-            for _ in 0..receive_times.len() {
-                match protocol.read_response(&socket, tport, &mut recv_buf[..]) {
-                    Ok(idx) => receive_times[idx] = Some(start.elapsed()),
-                    Err(e) => {
-                        match e.raw_os_error() {
-                            Some(-103) | Some(-104) => break,
-                            _ => (),
-                        }
-                        if e.kind() != ErrorKind::UnexpectedEof {
-                            println!("Receive thread: {}", e);
-                        }
-                        break;
-                    }
-                }
+    /// Error responses and requests timed out so far, over every connection.
+    fn failures(&self) -> (u64, u64) {
+        let mut failures = (0, 0);
+        if let Some(ref live) = self.live {
+            for times in live.lock().unwrap().iter() {
+                let (errors, timeouts) = times.counts.failures();
+                failures = (failures.0 + errors, failures.1 + timeouts);
             }
-            receive_times
-        }));
-        send_threads.push(backend.spawn_thread(move || {
-            // If the send or receive thread is still running 500 ms after it should have finished,
-            // then stop it by triggering a shutdown on the socket.
-            let last = packets[packets.len() - 1].target_start;
-            let socket = socket2.clone();
-            let timer = backend.spawn_thread(move || {
-                backend.sleep(last + Duration::from_millis(500));
-                if Arc::strong_count(&socket) > 1 {
-                    socket.shutdown();
-                }
-            });
-
-            let mut payload = Vec::with_capacity(4096);
-            for (i, packet) in packets.iter_mut().enumerate() {
-                payload.clear();
-                protocol.gen_request(i, packet, &mut payload, tport);
+        }
+        failures
+    }
 
-                let mut t = start.elapsed();
-                // while t + Duration::from_micros(1) < packet.target_start {
-                // //     // println!("  sleep {} {:?}", i, packet.target_start - t);
-                //     backend.sleep(packet.target_start - t);
-                //     t = start.elapsed();
-                // }
+    /// The latencies and misses of every response since the last call, with
+    /// a fresh window left in their place.
+    fn take_window(&self) -> LiveWindow {
+        let mut taken = LiveWindow::new();
+        if let Some(ref live) = self.live {
+            for times in live.lock().unwrap().iter() {
+                let window =
+                    std::mem::replace(&mut *times.window.lock().unwrap(), LiveWindow::new());
+                taken.latencies.merge(&window.latencies);
+                taken.misses += window.misses;
+            }
+        }
+        taken
+    }
 
-                while t < packet.target_start {
-                    backend.thread_yield();
-                    t = start.elapsed();
-                }
-                if t > packet.target_start + Duration::from_micros(5) {
-                    // println!("send timeout {} {:?}", i, t - packet.target_start);
-                    continue;
-                }
+    /// Compares responses to sends over each of the `windows`, and stops the
+    /// run at the end of the first one that falls short of the saturation
+    /// fraction. Responses still in flight when a window ends count towards
+    /// the next one.
+    fn watch(&self, backend: Backend, start: Instant, windows: &[(Duration, Duration)]) {
+        let threshold = match self.saturation {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let wait_until = |t: Duration| {
+            let now = start.elapsed();
+            if t > now {
+                backend.sleep(t - now);
+            }
+        };
+        let counts = || {
+            (
+                self.admitted.load(Ordering::SeqCst),
+                self.completed.load(Ordering::SeqCst),
+            )
+        };
+        for &(from, to) in windows {
+            wait_until(from);
+            let (sent, completed) = counts();
+            wait_until(to);
+            let (sent_by_end, completed_by_end) = counts();
+            if ((completed_by_end - completed) as f64) < threshold * (sent_by_end - sent) as f64 {
+                self.stopped_at
+                    .store(duration_to_ns(start.elapsed()), Ordering::SeqCst);
+                return;
+            }
+        }
+    }
 
-                packet.actual_start = Some(start.elapsed());
-                // println!("send,{},{},{:?},{:?}", i, len, packet.target_start.as_nanos(), packet.actual_start.unwrap().as_nanos());
-                if let Err(e) = (&*socket2).write_all(&payload[..]) {
-                    packet.actual_start = None;
-                    match e.raw_os_error() {
-                        Some(-105) => {
-                            backend.thread_yield();
-                            continue;
-                        }
-                        Some(-32) | Some(-103) | Some(-104) => {}
-                        _ => println!("Send thread ({}/{}): {}", i, packets.len(), e),
-                    }
-                    break;
-                }
+    /// Aborts the run once requests have been in flight for the watchdog's
+    /// threshold without a response, as a hung server would otherwise stall
+    /// it until the end of the schedule. Nothing more is sent, and receivers
+    /// blocked on a read give up at their next read timeout. Watches until
+    /// the sends are over, after which the drain timeout bounds the run.
+    fn guard(&self, backend: Backend, start: Instant) {
+        let threshold = match self.watchdog {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let mut progress = (Duration::from_nanos(0), 0);
+        while !self.finished.load(Ordering::SeqCst) {
+            let now = start.elapsed();
+            if now >= self.deadline || duration_to_ns(now) >= self.stopped_at.load(Ordering::SeqCst)
+            {
+                return;
+            }
+            let admitted = self.admitted.load(Ordering::SeqCst);
+            let completed = self.completed.load(Ordering::SeqCst);
+            if completed != progress.1 || admitted == completed {
+                progress = (now, completed);
+            } else if now - progress.0 >= threshold {
+                println!(
+                    "Watchdog: no response for {} ms with {} requests in flight, aborting the run",
+                    duration_to_ns(now - progress.0) / 1_000_000,
+                    admitted - completed
+                );
+                self.stopped_at.store(duration_to_ns(now), Ordering::SeqCst);
+                self.hung.store(true, Ordering::SeqCst);
+                return;
             }
-            timer.join().unwrap();
+            backend.sleep(watchdog_poll(threshold));
+        }
+    }
 
-            packets
-        }))
+    fn hung(&self) -> bool {
+        self.hung.load(Ordering::SeqCst)
     }
 
-    let mut packets: Vec<_> = send_threads
-        .into_iter()
-        .zip(receive_threads.into_iter())
-        .flat_map(|(s, r)| {
-            s.join()
-                .unwrap()
-                .into_iter()
-                .zip(r.join().unwrap().into_iter())
-        })
-        .map(|(p, r)| Packet {
-            completion_time: r,
-            ..p
-        })
-        .collect();
-    packets.sort_by_key(|p| p.target_start);
+    /// Counts the requests sent and completed, and the bytes written and
+    /// read, over each `interval` until the run is finished, printing each if
+    /// `print` and writing its row to `rows`, if any, and returns them.
+    /// Printed intervals and rows also give the latencies, misses, errors and
+    /// timeouts of that interval alone, and printed ones the requests
+    /// outstanding at its end. The counts are only read, so results are
+    /// unaffected.
+    fn print_intervals(
+        &self,
+        backend: Backend,
+        start: Instant,
+        interval: Duration,
+        print: bool,
+        traffic: &Traffic,
+        rows: &mut Option<IntervalRows>,
+    ) -> Vec<Interval> {
+        let mut intervals = Vec::new();
+        let mut next = interval;
+        let mut last = (0, 0);
+        let mut last_failures = (0, 0);
+        let TrafficCounts {
+            bytes: mut last_bytes,
+            headers: mut last_headers,
+            ..
+        } = traffic.counts();
+        while !self.finished.load(Ordering::SeqCst) {
+            let now = start.elapsed();
+            if now < next {
+                backend.sleep((next - now).min(Duration::from_millis(10)));
+                continue;
+            }
+            let counts = (
+                self.admitted.load(Ordering::SeqCst),
+                self.completed.load(Ordering::SeqCst),
+            );
+            let rps = ((counts.1 - last.1) as f64 / (duration_to_ns(interval) as f64 / 1e9)) as u64;
+            let window = self.take_window();
+            let failures = self.failures();
+            let TrafficCounts { bytes, headers, .. } = traffic.counts();
+            let wire = (
+                gbps(
+                    bytes.0 - last_bytes.0 + headers.0 - last_headers.0,
+                    interval,
+                ),
+                gbps(
+                    bytes.1 - last_bytes.1 + headers.1 - last_headers.1,
+                    interval,
+                ),
+            );
+            let outstanding = counts
+                .0
+                .saturating_sub(counts.1 + (failures.0 + failures.1) as usize);
+            if let Some(metrics) = self.metrics {
+                metrics.set_interval(outstanding, window.latencies.quantile(0.99), rps);
+            }
+            if print {
+                let us = |q| match window.latencies.quantile(q) {
+                    Some(ns) => format!("{:.1}us", ns as f64 / 1000.0),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "Interval: {:.1}s, {} sent, {} completed, {} rps, {:.3}/{:.3} Gbit/s \
+                     out/in, p50 {}, p99 {}, {} outstanding, {:.1}% missed, {} errors, \
+                     {} timeouts",
+                    duration_to_ns(next) as f64 / 1e9,
+                    counts.0 - last.0,
+                    counts.1 - last.1,
+                    rps,
+                    wire.0,
+                    wire.1,
+                    us(0.5),
+                    us(0.99),
+                    outstanding,
+                    match window.latencies.count() {
+                        0 => 0.0,
+                        n => 100.0 * window.misses as f64 / n as f64,
+                    },
+                    failures.0 - last_failures.0,
+                    failures.1 - last_failures.1,
+                );
+            }
+            let closed = Interval {
+                end: next,
+                sent: counts.0 - last.0,
+                completed: counts.1 - last.1,
+                rps,
+                bytes_out: bytes.0 - last_bytes.0,
+                bytes_in: bytes.1 - last_bytes.1,
+                headers_out: headers.0 - last_headers.0,
+                headers_in: headers.1 - last_headers.1,
+                out_gbps: wire.0,
+                in_gbps: wire.1,
+            };
+            let written = rows.as_mut().map_or(Ok(()), |rows| {
+                let row = IntervalRow {
+                    offered: rows.offered(next - interval),
+                    latencies: &window.latencies,
+                    misses: window.misses as u64,
+                    errors: failures.0 - last_failures.0,
+                    timeouts: failures.1 - last_failures.1,
+                };
+                rows.writer.write(rows.wct_start, interval, &closed, &row)
+            });
+            if written.is_err() {
+                println!("Failed to write the interval rows, no more will be written");
+                *rows = None;
+            }
+            last_failures = failures;
+            intervals.push(closed);
+            last = counts;
+            last_bytes = bytes;
+            last_headers = headers;
+            next += interval;
+        }
+        intervals
+    }
+}
 
-    let mut start = Duration::from_nanos(100_000_000);
-    schedules.iter().all(|sched| {
-        let last_index = packets
+/// Where a run writes the row of each interval as it closes, with when the
+/// run started and the steps of its schedules: when each began and ended
+/// since the start, and the load it offered.
+struct IntervalRows {
+    writer: IntervalWriter,
+    wct_start: SystemTime,
+    steps: Vec<(Duration, Duration, u64)>,
+}
+
+impl IntervalRows {
+    /// The load offered by the step running at `t`, if any.
+    fn offered(&self, t: Duration) -> Option<u64> {
+        self.steps
             .iter()
-            .position(|p| p.target_start >= start + sched.runtime)
-            .unwrap_or(packets.len());
-        let rest = packets.split_off(last_index);
-        let res = process_result(&sched, packets.as_mut_slice(), start_unix, slowdown);
-        packets = rest;
-        start += sched.runtime;
-        res
-    })
+            .find(|&&(from, to, _)| t >= from && t < to)
+            .map(|&(_, _, offered)| offered)
+    }
 }
 
-fn run_local(
-    backend: Backend,
-    nthreads: usize,
-    worker: FakeWorker,
-    schedules: &Vec<RequestSchedule>,
-) -> bool {
-    let mut rng = rand::thread_rng();
+arg_enum! {
+#[derive(Copy, Clone)]
+enum OutputMode {
+    Silent,
+    Normal,
+    Buckets,
+    Trace
+}}
 
-    let packet_schedules: Vec<Vec<Packet>> = (0..nthreads)
+/// How often the watchdog checks for responses, and how long a read blocks
+/// while it is on.
+fn watchdog_poll(threshold: Duration) -> Duration {
+    (threshold / 10).min(Duration::from_millis(10))
+}
+
+/// Whether a read failed only because its read timeout passed.
+fn read_timed_out(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// Ends the response read from `sock`. A read that timed out partway through
+/// one would leave the rest to be taken for the start of the next, so the
+/// connection is shut down and the response fails instead.
+fn end_response<T>(sock: &Connection, response: io::Result<T>) -> io::Result<T> {
+    match (response_read(sock.framing()), response) {
+        (len, Err(ref e)) if len > 0 && read_timed_out(e) => {
+            sock.shutdown();
+            Err(io::Error::other(
+                "a read timed out partway through a response, so its connection was closed",
+            ))
+        }
+        (_, response) => response,
+    }
+}
+
+/// Completes, as rejected, the request a response failed with an error
+/// status, which leaves the connection in step to go on being read. Any other
+/// error is returned.
+fn rejected(e: io::Error) -> io::Result<(usize, Outcome)> {
+    match memcached::failed_request(&e) {
+        Some(idx) => Ok((idx, Outcome::Rejected)),
+        None => Err(e),
+    }
+}
+
+fn duration_to_ns(duration: Duration) -> u64 {
+    duration.as_secs() * 1000_000_000 + duration.subsec_nanos() as u64
+}
+
+fn run_linux_udp_server(backend: Backend, addr: SocketAddr, nthreads: usize, worker: FakeWorker) {
+    let join_handles: Vec<_> = (0..nthreads)
         .map(|_| {
-            let mut last = 100_000_000;
-            let mut thread_packets: Vec<Packet> = Vec::new();
-            for sched in schedules {
-                let end = last + duration_to_ns(sched.runtime);
-                while last < end {
-                    last += sched.arrival.sample(&mut rng);
-                    thread_packets.push(Packet {
-                        randomness: rng.gen::<u64>(),
-                        target_start: Duration::from_nanos(last),
-                        work_iterations: sched.service.sample(&mut rng),
-                        ..Default::default()
-                    });
+            let worker = worker.clone();
+            backend.spawn_thread(move || {
+                let socket = backend.create_udp_connection(addr, None).unwrap();
+                println!("Bound to address {}", socket.local_addr());
+                let mut buf = vec![0; 4096];
+                loop {
+                    let (len, remote_addr) = socket.recv_from(&mut buf[..]).unwrap();
+                    let payload = Payload::deserialize(&mut &buf[..len]).unwrap();
+                    worker.work(payload.work_iterations);
+                    socket.send_to(&buf[..len], remote_addr).unwrap();
                 }
-            }
-            thread_packets
+            })
         })
         .collect();
 
-    let start_unix = SystemTime::now();
-    let start = Instant::now();
+    for j in join_handles {
+        j.join().unwrap();
+    }
+}
 
-    struct AtomicU64Pointer(*const AtomicU64);
-    unsafe impl Send for AtomicU64Pointer {}
+fn socket_worker(socket: &mut Connection, worker: FakeWorker) {
+    let mut v = vec![0; 4096];
+    let mut r = || {
+        v.clear();
+        let payload = Payload::deserialize(socket)?;
+        worker.work(payload.work_iterations);
+        payload.serialize_into(&mut v)?;
+        Ok(socket.write_all(&v[..])?)
+    };
+    loop {
+        if let Err(e) = r() as io::Result<()> {
+            match e.raw_os_error() {
+                Some(-104) | Some(104) => break,
+                _ => {}
+            }
+            if e.kind() != ErrorKind::UnexpectedEof {
+                println!("Receive thread: {}", e);
+            }
+            break;
+        }
+    }
+}
 
-    let mut send_threads = Vec::new();
-    for mut packets in packet_schedules {
-        let worker = worker.clone();
-        send_threads.push(backend.spawn_thread(move || {
-            let remaining = Arc::new(AtomicUsize::new(packets.len()));
-            for i in 0..packets.len() {
-                let (work_iterations, completion_time_ns) = {
-                    let packet = &mut packets[i];
+fn run_tcp_server(backend: Backend, addr: SocketAddr, worker: FakeWorker) {
+    let tcpq = backend.create_tcp_listener(addr).unwrap();
+    println!("Bound to address {}", addr);
+    loop {
+        match tcpq.accept() {
+            Ok(mut c) => {
+                let worker = worker.clone();
+                backend.spawn_thread(move || socket_worker(&mut c, worker));
+            }
+            Err(e) => {
+                println!("Listener: {}", e);
+            }
+        }
+    }
+}
 
-                    let mut t = start.elapsed();
-                    while t < packet.target_start {
-                        t = start.elapsed();
-                    }
+fn run_spawner_server(addr: SocketAddrV4, worker: FakeWorker) {
+    static mut SPAWNER_WORKER: Option<FakeWorker> = None;
+    unsafe {
+        SPAWNER_WORKER = Some(worker);
+    }
+    extern "C" fn echo(d: *mut shenango::ffi::udp_spawn_data) {
+        unsafe {
+            let buf = slice::from_raw_parts((*d).buf as *mut u8, (*d).len);
+            let payload = Payload::deserialize(&mut &buf[..]).unwrap();
+            let worker = SPAWNER_WORKER.as_ref().unwrap();
+            worker.work(payload.work_iterations);
+            let _ = UdpSpawner::reply(d, buf);
+            UdpSpawner::release_data(d);
+        }
+    }
 
-                    packet.actual_start = Some(start.elapsed());
-                    (
-                        packet.work_iterations,
-                        AtomicU64Pointer(&packet.completion_time_ns as *const AtomicU64),
-                    )
-                };
+    let _s = unsafe { UdpSpawner::new(addr, echo).unwrap() };
 
-                let remaining = remaining.clone();
-                let worker = worker.clone();
+    let wg = shenango::WaitGroup::new();
+    wg.add(1);
+    wg.wait();
+}
+
+fn run_memcached_preload(
+    proto: MemcachedProtocol,
+    backend: Backend,
+    tport: Transport,
+    tls: Option<&'static TlsClient>,
+    addr: SocketAddr,
+    nthreads: usize,
+    shard: Option<(usize, usize)>,
+) -> bool {
+    let perthread = (memcached::NVALUES as usize + nthreads - 1) / nthreads;
+    let join_handles: Vec<JoinHandle<_>> = (0..nthreads)
+        .map(|i| {
+            backend.spawn_thread(move || {
+                let sock1 = match tport {
+                    Transport::Tcp => backend.create_tcp_connection(None, addr),
+                    Transport::Udp => backend.create_udp_connection(any_addr(addr, 0), Some(addr)),
+                    Transport::Unix(path) => backend.create_unix_connection(path),
+                };
+                let sock1 = match tls {
+                    Some(tls) => sock1.and_then(|sock| tls.connect(sock)),
+                    None => sock1,
+                };
+                let sock1 = match sock1 {
+                    Ok(sock1) => Arc::new(sock1),
+                    Err(e) => {
+                        println!("Preload connect: {}", e);
+                        return false;
+                    }
+                };
+                let socket = sock1.clone();
                 backend.spawn_thread(move || {
-                    worker.work(work_iterations);
-                    unsafe {
-                        (*completion_time_ns.0)
-                            .store(start.elapsed().as_nanos() as u64, Ordering::SeqCst);
+                    backend.sleep(Duration::from_secs(20));
+                    if Arc::strong_count(&socket) > 1 {
+                        println!("Timing out socket");
+                        socket.shutdown();
                     }
-                    remaining.fetch_sub(1, Ordering::SeqCst);
                 });
-            }
 
-            while remaining.load(Ordering::SeqCst) > 0 {
-                // do nothing
-            }
+                let mut vec_s: Vec<u8> = Vec::with_capacity(4096);
+                let mut vec_r = Vec::new();
+                for n in 0..perthread {
+                    let key = (i * perthread + n) as u64;
+                    match shard {
+                        Some((server, n)) if balance::home_server(key, n) != server => continue,
+                        _ => {}
+                    }
+                    vec_s.clear();
+                    proto.set_request(key, 0, &mut vec_s, tport);
 
-            packets
-        }))
-    }
+                    if let Err(e) = (&*sock1).write_all(&vec_s[..]) {
+                        println!("Preload send ({}/{}): {}", n, perthread, e);
+                        return false;
+                    }
 
-    let mut packets: Vec<_> = send_threads
-        .into_iter()
-        .flat_map(|s| s.join().unwrap().into_iter())
-        .map(|mut p| {
-            p.completion_time = Some(Duration::from_nanos(
-                p.completion_time_ns.load(Ordering::SeqCst),
-            ));
-            p
+                    if let Err(e) = proto.read_response(&sock1, tport, &mut vec_r) {
+                        println!("preload receive ({}/{}): {}", n, perthread, e);
+                        return false;
+                    }
+                }
+                true
+            })
         })
         .collect();
-    packets.sort_by_key(|p| p.target_start);
 
-    let mut start = Duration::from_nanos(100_000_000);
-    schedules.iter().all(|sched| {
-        let last_index = packets
-            .iter()
-            .position(|p| p.target_start >= start + sched.runtime)
-            .unwrap_or(packets.len());
-        let rest = packets.split_off(last_index);
-        let res = process_result(&sched, packets.as_mut_slice(), start_unix, false);
-        packets = rest;
-        start += sched.runtime;
-        res
-    })
+    return join_handles.into_iter().all(|j| j.join().unwrap());
 }
-fn main() {
-    let matches = App::new("Synthetic Workload Application")
-        .version("0.1")
-        .arg(
-            Arg::with_name("ADDR")
-                .index(1)
-                .help("Address and port to listen on")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("threads")
-                .short("t")
-                .long("threads")
-                .value_name("T")
-                .default_value("1")
-                .help("Number of client threads"),
-        )
-        .arg(
-            Arg::with_name("mode")
-                .short("m")
-                .long("mode")
-                .value_name("MODE")
-                .possible_values(&[
-                    "linux-server",
-                    "linux-client",
-                    "runtime-client",
-                    "spawner-server",
-                    "local-client",
-                    "work-bench",
-                ])
-                .required(true)
-                .requires_ifs(&[("runtime-client", "config"), ("spawner-server", "config")])
+
+/// Preloads every target, skipping those that cannot be reached so long as
+/// one can. Targets that keys are hashed to are only given their own keys.
+/// Preloads go over TCP, unless the server is only reachable at a unix
+/// socket, and over TLS if the run is.
+fn preload_targets(
+    proto: MemcachedProtocol,
+    backend: Backend,
+    tport: Transport,
+    tls: Option<&'static TlsClient>,
+    targets: Targets,
+    nthreads: usize,
+) {
+    let tport = match tport {
+        Transport::Unix(_) => tport,
+        _ => Transport::Tcp,
+    };
+    let n = targets.addrs.len();
+    let preloaded = targets
+        .addrs
+        .iter()
+        .enumerate()
+        .filter(|&(i, &addr)| {
+            let shard = if targets.key_hash { Some((i, n)) } else { None };
+            let ok = run_memcached_preload(proto, backend, tport, tls, addr, nthreads, shard);
+            if !ok {
+                println!("Could not preload memcached on {}", addr);
+            }
+            ok
+        })
+        .count();
+    if preloaded == 0 {
+        panic!("Could not preload memcached");
+    }
+}
+
+/// How long a preflight check waits for each response.
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks that `addr` answers before any measured request is sent, over one
+/// connection opened as the run's are: a memcached server must store a SET
+/// and return it to a GET, and any other must answer one request. The
+/// runtime's sockets cannot time out reads, so there a server that never
+/// answers is waited on.
+fn preflight(
+    protocol: Protocol,
+    backend: Backend,
+    tport: Transport,
+    addr: SocketAddr,
+    opts: ClientOptions,
+) -> Result<(), String> {
+    let sock = open_connection(backend, protocol, tport, None, addr, opts, &mut Vec::new())
+        .map_err(|e| format!("could not connect: {}", e))?;
+    let _ = sock.set_read_timeout(Some(PREFLIGHT_TIMEOUT));
+    let mut set = Vec::new();
+    let mut get = Vec::new();
+    let requests = match protocol {
+        Protocol::Memcached(m) => {
+            m.set_request(0, 0, &mut set, tport);
+            m.get_request(0, 1, &mut get, tport);
+            vec![("SET", set), ("GET", get)]
+        }
+        Protocol::MemcachedMeta(m) => {
+            m.store_request(mix::Op::Set, 0, None, 0, &mut set, tport);
+            MetaProtocol::get_request(0, 1, &mut get, tport);
+            vec![("SET", set), ("GET", get)]
+        }
+        Protocol::Synthetic | Protocol::Dns => {
+            protocol.gen_request(0, &Packet::default(), &mut set, tport);
+            vec![("request", set)]
+        }
+    };
+    let mut scratch = Vec::new();
+    for (i, (name, request)) in requests.into_iter().enumerate() {
+        (&sock)
+            .write_all(&request)
+            .map_err(|e| format!("could not send a {}: {}", name, e))?;
+        match protocol.read_response(&sock, tport, &mut scratch) {
+            Ok((idx, _)) if idx != i && idx != UNMATCHED => {
+                return Err(format!("the {} was answered as request {}", name, idx))
+            }
+            Ok((_, Outcome::Miss)) => {
+                return Err(format!("the {} of the key just set missed", name))
+            }
+            Ok((_, Outcome::Rejected)) => return Err(format!("the {} was rejected", name)),
+            Ok((_, Outcome::Ok)) => (),
+            Err(ref e) if read_timed_out(e) => {
+                return Err(format!(
+                    "no response to the {} within {:?}",
+                    name, PREFLIGHT_TIMEOUT
+                ))
+            }
+            Err(e) => return Err(format!("the {} failed: {}", name, e)),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone)]
+struct RequestSchedule {
+    arrival: Distribution,
+    service: Distribution,
+    output: OutputMode,
+    runtime: Duration,
+    discard_pct: usize,
+    /// Consecutive schedules with the same shape follow one continuous wave.
+    shape: LoadShape,
+}
+
+fn gen_classic_packet_schedule(
+    runtime: Duration,
+    packets_per_second: usize,
+    arrival: Arrival,
+    output: OutputMode,
+    distribution: Distribution,
+    ramp_up_seconds: usize,
+    nthreads: usize,
+) -> Vec<RequestSchedule> {
+    let mut sched: Vec<RequestSchedule> = Vec::new();
+    /* Ramp up in 100ms increments */
+    for t in 1..(10 * ramp_up_seconds) {
+        let rate = t * packets_per_second / (ramp_up_seconds * 10);
+
+        sched.push(RequestSchedule {
+            arrival: arrival.distribution((nthreads * 1000_000_000 / rate) as u64),
+            service: distribution,
+            output: OutputMode::Silent,
+            runtime: Duration::from_millis(100),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        });
+    }
+
+    let ns_per_packet = nthreads * 1000_000_000 / packets_per_second;
+    sched.push(RequestSchedule {
+        arrival: arrival.distribution(ns_per_packet as u64),
+        service: distribution,
+        output: output,
+        runtime: runtime,
+        discard_pct: 10,
+        shape: LoadShape::Constant,
+    });
+    // println!("{} {} {}", ramp_up_seconds, sched.len(), ns_per_packet);
+
+    // sched.push(RequestSchedule {
+    //     arrival: Distribution::GPerato(0.0, 16029.2, 0.154971),
+    //     service: distribution,
+    //     output: output,
+    //     runtime: runtime,
+    //     discard_pct: 10,
+    // });
+    sched
+}
+
+fn gen_loadshift_experiment(
+    spec: &str,
+    arrival: Arrival,
+    service: Distribution,
+    nthreads: usize,
+) -> Vec<RequestSchedule> {
+    spec.split(",")
+        .map(|step_spec| {
+            let s: Vec<&str> = step_spec.split(":").collect();
+            assert!(s.len() == 2);
+            let ns_per_packet = nthreads as u64 * 1000_000_000 / s[0].parse::<u64>().unwrap();
+            let micros = s[1].parse().unwrap();
+            RequestSchedule {
+                arrival: arrival.distribution(ns_per_packet),
+                service: service,
+                output: OutputMode::Trace,
+                runtime: Duration::from_micros(micros),
+                discard_pct: 0,
+                shape: LoadShape::Constant,
+            }
+        })
+        .collect()
+}
+
+/// Parses comma separated rates, or `START:STOP:STEP`, all in requests per
+/// second.
+fn parse_rates(spec: &str) -> Result<Vec<usize>, String> {
+    let parse = |r: &str| r.parse::<usize>().map_err(|_| format!("bad rate '{}'", r));
+    let rates = if spec.contains(':') {
+        let bounds = spec.split(':').map(parse).collect::<Result<Vec<_>, _>>()?;
+        if bounds.len() != 3 || bounds[2] == 0 || bounds[0] > bounds[1] {
+            return Err(format!("rates '{}' are not START:STOP:STEP", spec));
+        }
+        (bounds[0]..=bounds[1]).step_by(bounds[2]).collect()
+    } else {
+        spec.split(',').map(parse).collect::<Result<Vec<_>, _>>()?
+    };
+    if rates.contains(&0) {
+        return Err("rates must be nonzero".to_string());
+    }
+    Ok(rates)
+}
+
+/// One schedule per ramp step, run back to back over the same connections.
+/// Requests are attributed to the step they were scheduled in, however late
+/// their responses arrive.
+fn gen_ramp_experiment(
+    rates: &[usize],
+    step: Duration,
+    arrival: Arrival,
+    output: OutputMode,
+    service: Distribution,
+    nthreads: usize,
+) -> Vec<RequestSchedule> {
+    rates
+        .iter()
+        .map(|&rate| RequestSchedule {
+            arrival: arrival.distribution((nthreads * 1_000_000_000 / rate) as u64),
+            service,
+            output,
+            runtime: step,
+            discard_pct: 10,
+            shape: LoadShape::Constant,
+        })
+        .collect()
+}
+
+/// The classic schedule of each rate, ramp up and all, back to back so a
+/// sweep runs over the same connections.
+fn gen_sweep_experiment(
+    rates: &[usize],
+    runtime: Duration,
+    arrival: Arrival,
+    output: OutputMode,
+    service: Distribution,
+    ramp_up_seconds: usize,
+    nthreads: usize,
+) -> Vec<RequestSchedule> {
+    rates
+        .iter()
+        .flat_map(|&rate| {
+            gen_classic_packet_schedule(
+                runtime,
+                rate,
+                arrival,
+                output,
+                service,
+                ramp_up_seconds,
+                nthreads,
+            )
+        })
+        .collect()
+}
+
+/// Splits the last, measured schedule into `bucket` long schedules of the
+/// given shape, so results are reported per bucket as the load changes.
+fn shape_schedule(
+    mut schedules: Vec<RequestSchedule>,
+    shape: LoadShape,
+    bucket: Duration,
+) -> Vec<RequestSchedule> {
+    if shape == LoadShape::Constant {
+        return schedules;
+    }
+    let measured = schedules.pop().unwrap();
+    let mut remaining = measured.runtime;
+    while remaining > Duration::from_nanos(0) {
+        let runtime = remaining.min(bucket);
+        schedules.push(RequestSchedule {
+            runtime,
+            discard_pct: 0,
+            shape,
+            ..measured
+        });
+        remaining -= runtime;
+    }
+    schedules
+}
+
+/// The generator a sample's packets are drawn from. With a seed it is seeded
+/// by the seed and the sample's `index`, so that the same seed and the same
+/// configuration make the same stream of requests, and from the OS otherwise.
+fn packet_rng(seed: Option<u64>, index: usize) -> Box<dyn Rng> {
+    match seed {
+        Some(seed) => Box::new(XorShiftRng::from_seed([
+            seed as u32,
+            (seed >> 32) as u32,
+            index as u32,
+            // Keeps the state nonzero, which the generator needs.
+            0x9e37_79b9,
+        ])),
+        None => Box::new(rand::thread_rng()),
+    }
+}
+
+/// Lays out one thread's packets back to back across the schedules, starting
+/// 100ms in. Nothing here assumes interarrival gaps are random, but they must
+/// be nonzero for the schedule to advance.
+fn gen_packets<R: Rng>(schedules: &[RequestSchedule], rng: &mut R) -> Vec<Packet> {
+    let mut last = 100_000_000;
+    let mut packets: Vec<Packet> = Vec::new();
+    let mut shape = LoadShape::Constant;
+    let mut shape_start = last;
+    for sched in schedules {
+        if sched.shape != shape {
+            shape = sched.shape;
+            shape_start = last;
+        }
+        let end = last + duration_to_ns(sched.runtime);
+        while last < end {
+            let gap = sched.arrival.sample(rng);
+            last = shape_start + shape.advance((last - shape_start) as f64, gap as f64) as u64;
+            packets.push(Packet {
+                randomness: rng.gen::<u64>(),
+                target_start: Duration::from_nanos(last),
+                work_iterations: sched.service.sample(rng),
+                ..Default::default()
+            });
+        }
+    }
+    packets
+}
+
+/// The latency at percentile `p` of `sent` requests, given the histogram of
+/// those that completed, in thousandths of the unit reported. Requests that
+/// never completed count as slower than any that did.
+fn latency_percentile(latencies: &Histogram, sent: usize, p: f32) -> f32 {
+    let idx = usize::min((sent as f32 * p / 100.0) as usize, sent - 1);
+    latencies
+        .value_at(idx as u64)
+        .map_or(INFINITY, |l| l as f32 / 1000.0)
+}
+
+/// The value at percentile `p` of a histogram of nanoseconds, at least one
+/// of which is counted, in microseconds.
+fn histogram_percentile(times: &Histogram, p: f32) -> f32 {
+    let rank = ((times.count() - 1) as f32 * p / 100.0) as u64;
+    times.value_at(rank).unwrap() as f32 / 1000.0
+}
+
+/// The completed requests' queueing in the client, from their intended to
+/// their actual sends, their service, from then to their responses, and
+/// their response times, from their intended sends to their responses, in
+/// microseconds at each of `percentiles`, kept to `digits` significant
+/// digits. None if none completed.
+fn latency_breakdown(
+    packets: &[Packet],
+    percentiles: &[f32],
+    digits: u32,
+) -> Option<(Percentiles, Percentiles, Percentiles)> {
+    let mut queueing = Histogram::new(digits);
+    let mut service = Histogram::new(digits);
+    let mut response = Histogram::new(digits);
+    for p in packets {
+        if let (Some(start), Some(end)) = (p.actual_start, p.completion_time) {
+            queueing.record(duration_to_ns(
+                start.checked_sub(p.target_start).unwrap_or_default(),
+            ));
+            service.record(duration_to_ns(end.checked_sub(start).unwrap_or_default()));
+            response.record(duration_to_ns(
+                end.checked_sub(p.target_start).unwrap_or_default(),
+            ));
+        }
+    }
+    if queueing.count() == 0 {
+        return None;
+    }
+    let at = |times: &Histogram| {
+        Percentiles(
+            percentiles
+                .iter()
+                .map(|&p| (p, histogram_percentile(times, p)))
+                .collect(),
+        )
+    };
+    Some((at(&queueing), at(&service), at(&response)))
+}
+
+/// Fraction of the completed CAS SETs that were not rejected, if any
+/// completed.
+fn cas_success_ratio<'a, I: Iterator<Item = &'a Packet>>(packets: I) -> Option<f32> {
+    let writes = packets.filter(|p| p.completion_time.is_some() && p.info.op == "cas");
+    let (n, rejected) = writes.fold((0, 0), |(n, rejected), p| {
+        (n + 1, rejected + p.miss as usize)
+    });
+    if n == 0 {
+        None
+    } else {
+        Some((n - rejected) as f32 / n as f32)
+    }
+}
+
+/// Fraction of the completed GETs that missed, if any completed.
+fn miss_ratio<'a, I: Iterator<Item = &'a Packet>>(packets: I) -> Option<f32> {
+    let gets = packets.filter(|p| p.completion_time.is_some() && p.info.op == "get");
+    let (n, misses) = gets.fold((0, 0), |(n, misses), p| (n + 1, misses + p.miss as usize));
+    if n == 0 {
+        None
+    } else {
+        Some(misses as f32 / n as f32)
+    }
+}
+
+fn process_result(sched: &RequestSchedule, packets: &mut [Packet], wct_start: SystemTime, warmup_until: Duration, slowdown: bool, report: &mut Report) -> bool {
+    // A bucket of a shaped schedule can fall where the rate is zero.
+    if packets.is_empty() {
+        return true;
+    }
+    let start_unix = wct_start + packets[0].target_start;
+    let start_secs = start_unix.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    // Discard the first X% of the packets.
+    let plen = packets.len();
+    let packets = &mut packets[plen * sched.discard_pct / 100..];
+
+    // Requests sent during the warmup are left out, however late they complete.
+    let in_warmup = |p: &Packet| p.actual_start.unwrap_or(p.target_start) < warmup_until;
+    let nwarmup = packets.iter().filter(|p| in_warmup(p)).count();
+    packets.sort_by_key(|p| !in_warmup(p));
+    let warmup = packets[..nwarmup]
+        .iter()
+        .filter(|p| p.actual_start.is_some())
+        .count();
+    let packets = &mut packets[nwarmup..];
+    if packets.is_empty() {
+        return true;
+    }
+
+    let never_sent = packets.iter().filter(|p| p.actual_start.is_none()).count();
+    let window_dropped = packets.iter().filter(|p| p.window_dropped).count();
+    let timed_out = packets.iter().filter(|p| p.timed_out).count();
+    let late = packets.iter().filter(|p| p.late).count();
+    let retransmits = packets.iter().map(|p| p.retransmits).sum();
+    let duplicates = packets.iter().map(|p| p.duplicates).sum();
+    let failed = packets.iter().filter(|p| p.failed).count();
+    let rejected = packets.iter().filter(|p| p.rejected).count();
+    let dropped = packets
+        .iter()
+        .filter(|p| p.completion_time.is_none())
+        .count()
+        - never_sent
+        - timed_out
+        - failed;
+    let unanswered = dropped + timed_out - late;
+    let unanswered_ratio = match unanswered {
+        0 => None,
+        n => Some(n as f64 / (packets.len() - never_sent) as f64),
+    };
+    // println!("{} {} {}", plen, packets.len(), never_sent);
+    if report.metadata().no_read {
+        if let OutputMode::Silent = sched.output {
+            return true;
+        }
+        let sent = packets.len() - never_sent;
+        let first = packets.iter().map(|p| p.target_start).min().unwrap();
+        let last = packets.iter().map(|p| p.target_start).max().unwrap();
+        let span = u64::max(duration_to_ns(last - first), 1);
+        report.record(ScheduleResult {
+            distribution: sched.service.name().to_string(),
+            target: packets.len() as u64 * 1_000_000_000 / span,
+            actual: sent as u64 * 1_000_000_000 / span,
+            dropped: 0,
+            timed_out: 0,
+            late: 0,
+            retransmits: 0,
+            duplicates: 0,
+            unanswered: 0,
+            unanswered_ratio: None,
+            failed: 0,
+            rejected: 0,
+            never_sent,
+            warmup,
+            window_dropped,
+            miss_ratio: None,
+            cas_success_ratio: None,
+            per_connection: None,
+            connect_rate: None,
+            servers: Vec::new(),
+            sizes: Vec::new(),
+            ops: Vec::new(),
+            imbalance: None,
+            sent: Some(sent),
+            latencies: None,
+            samples: 0,
+            latency_min: None,
+            latency_mean: None,
+            latency_max: None,
+            latency_overflows: 0,
+            latency_underflows: 0,
+            send_lag: None,
+            send_lag_max: None,
+            queueing: None,
+            service: None,
+            response: None,
+            start: start_secs,
+        });
+        return sent > 0;
+    }
+    if packets.len() - dropped - timed_out - failed - never_sent <= 1 {
+        match sched.output {
+            OutputMode::Silent => {}
+            _ => {
+                let first_send = packets.iter().map(|p| p.target_start).min().unwrap();
+                let last_send = packets.iter().map(|p| p.target_start).max().unwrap();
+                report.record(ScheduleResult {
+                    distribution: sched.service.name().to_string(),
+                    target: packets.len() as u64 * 1000_000_000
+                        / duration_to_ns(last_send - first_send),
+                    actual: 0,
+                    dropped,
+                    timed_out,
+                    late,
+                    retransmits,
+                    duplicates,
+                    unanswered,
+                    unanswered_ratio,
+                    failed,
+                    rejected,
+                    never_sent,
+                    warmup,
+                    window_dropped,
+                    miss_ratio: miss_ratio(packets.iter()),
+                    cas_success_ratio: cas_success_ratio(packets.iter()),
+                    per_connection: None,
+                    connect_rate: None,
+                    servers: Vec::new(),
+                    sizes: Vec::new(),
+                    ops: Vec::new(),
+                    imbalance: None,
+                    sent: None,
+                    latencies: None,
+                    samples: 0,
+                    latency_min: None,
+                    latency_mean: None,
+                    latency_max: None,
+                    latency_overflows: 0,
+                    latency_underflows: 0,
+                    send_lag: None,
+                    send_lag_max: None,
+                    queueing: None,
+                    service: None,
+                    response: None,
+                    start: start_secs,
+                });
+            }
+        }
+        return false;
+    }
+
+    if let OutputMode::Silent = sched.output {
+        return true;
+    }
+
+    let first_send = packets.iter().filter_map(|p| p.actual_start).min().unwrap();
+    let last_send = packets.iter().filter_map(|p| p.actual_start).max().unwrap();
+
+    // Latencies are counted in thousandths of the unit they are reported in:
+    // in nanoseconds, or in thousandths of a slowdown.
+    let metadata = report.metadata();
+    let digits = metadata
+        .histogram_digits
+        .unwrap_or(histogram::DEFAULT_DIGITS);
+    let low = metadata.histogram_min.map_or(0, |l| (l * 1000.0) as u64);
+    let high = metadata
+        .histogram_max
+        .map_or(u64::MAX, |h| (h * 1000.0) as u64);
+    let latency_histogram = || Histogram::new(digits).with_bounds(low, high);
+    let latency = |p: &Packet| match (p.actual_start, p.completion_time) {
+        (Some(ref start), Some(ref end)) => {
+            // A post-write timestamp can trail a fast response.
+            let ns = duration_to_ns(end.checked_sub(*start).unwrap_or_default());
+            if slowdown {
+                Some(ns * 1000 / u64::max(p.work_iterations, 1))
+            } else {
+                Some(ns)
+            }
+        }
+        _ => None,
+    };
+    // Each target's latencies apart, as they are broken out when there are
+    // several, merged for the whole run.
+    let nservers = packets.iter().map(|p| p.server + 1).max().unwrap();
+    let mut by_server = vec![latency_histogram(); nservers];
+    for p in packets.iter() {
+        if let Some(l) = latency(p) {
+            by_server[p.server].record(l);
+        }
+    }
+    let mut latencies = latency_histogram();
+    for histogram in &by_server {
+        latencies.merge(histogram);
+    }
+
+    // How late each request went out relative to its timeline.
+    let mut lags = Histogram::new(digits);
+    for p in packets.iter() {
+        if let Some(t) = p.actual_start {
+            lags.record(duration_to_ns(
+                t.checked_sub(p.target_start).unwrap_or_default(),
+            ));
+        }
+    }
+    let lag = |p: f32| histogram_percentile(&lags, p);
+    let breakdown = if report.metadata().latency_breakdown {
+        latency_breakdown(packets, report.percentiles(), digits)
+    } else {
+        None
+    };
+    let (queueing, service, response) = match breakdown {
+        Some((queueing, service, response)) => (Some(queueing), Some(service), Some(response)),
+        None => (None, None, None),
+    };
+
+    // Each target's share of the requests, when there are several.
+    let servers = if report.targets().len() > 1 {
+        report
+            .targets()
+            .iter()
+            .enumerate()
+            .map(|(i, target)| {
+                let sent = packets
+                    .iter()
+                    .filter(|p| p.server == i && p.actual_start.is_some() && !p.failed)
+                    .count();
+                let empty = Histogram::new(digits);
+                let server_latencies = by_server.get(i).unwrap_or(&empty);
+                ServerResult {
+                    target: target.clone(),
+                    sent,
+                    actual: server_latencies.count() * 1_000_000_000
+                        / duration_to_ns(last_send - first_send),
+                    miss_ratio: miss_ratio(packets.iter().filter(|p| p.server == i)),
+                    latencies: if sent == 0 {
+                        None
+                    } else {
+                        Some(Percentiles(
+                            report
+                                .percentiles()
+                                .iter()
+                                .map(|&p| (p, latency_percentile(server_latencies, sent, p)))
+                                .collect(),
+                        ))
+                    },
+                }
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Each value size class's share of the requests, when they are broken
+    // out. A request is classed by the size it was sent with.
+    let sizes = match report.metadata().size_classes.clone() {
+        Some(thresholds) => SIZE_CLASSES
+            .iter()
+            .enumerate()
+            .map(|(c, class)| {
+                let in_class = |p: &&Packet| {
+                    p.info
+                        .value_size
+                        .is_some_and(|n| size_class(n, &thresholds) == c)
+                };
+                let sent = packets
+                    .iter()
+                    .filter(&in_class)
+                    .filter(|p| p.actual_start.is_some() && !p.failed)
+                    .count();
+                let mut size_latencies = latency_histogram();
+                for l in packets.iter().filter(&in_class).filter_map(&latency) {
+                    size_latencies.record(l);
+                }
+                SizeResult {
+                    class: class.to_string(),
+                    sent,
+                    completed: size_latencies.count() as usize,
+                    latencies: if sent == 0 {
+                        None
+                    } else {
+                        Some(Percentiles(
+                            report
+                                .percentiles()
+                                .iter()
+                                .map(|&p| (p, latency_percentile(&size_latencies, sent, p)))
+                                .collect(),
+                        ))
+                    },
+                }
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Each operation's share of the requests, by name, for protocols that
+    // name them, with the bytes of its requests and of the responses that
+    // completed them.
+    let mut by_op: BTreeMap<&str, (usize, Histogram, u64, u64)> = BTreeMap::new();
+    for p in packets.iter() {
+        if p.actual_start.is_some() && !p.failed && !p.info.op.is_empty() {
+            let op = by_op
+                .entry(p.info.op)
+                .or_insert_with(|| (0, latency_histogram(), 0, 0));
+            op.0 += 1;
+            op.2 += p.info.bytes as u64;
+            if let Some(l) = latency(p) {
+                op.1.record(l);
+                op.3 += p.response_bytes as u64;
+            }
+        }
+    }
+    let send_time = last_send - first_send;
+    let ops = by_op
+        .iter()
+        .map(
+            |(&op, &(sent, ref op_latencies, bytes_out, bytes_in))| OpResult {
+                op: op.to_string(),
+                sent,
+                completed: op_latencies.count() as usize,
+                latencies: Percentiles(
+                    report
+                        .percentiles()
+                        .iter()
+                        .map(|&p| (p, latency_percentile(op_latencies, sent, p)))
+                        .collect(),
+                ),
+                bytes_out,
+                bytes_in,
+                out_gbps: gbps(bytes_out, send_time),
+                in_gbps: gbps(bytes_in, send_time),
+            },
+        )
+        .collect();
+
+    // Percentiles are taken over the requests sent in the measured window,
+    // between the warmup and the end of the drain.
+    let samples = packets.len() - never_sent - failed;
+    report.record(ScheduleResult {
+        distribution: sched.service.name().to_string(),
+        target: (packets.len() - never_sent) as u64 * 1000_000_000
+            / duration_to_ns(last_send - first_send),
+        actual: latencies.count() * 1000_000_000 / duration_to_ns(last_send - first_send),
+        dropped,
+        timed_out,
+        late,
+        retransmits,
+        duplicates,
+        unanswered,
+        unanswered_ratio,
+        failed,
+        rejected,
+        never_sent,
+        warmup,
+        window_dropped,
+        miss_ratio: miss_ratio(packets.iter()),
+        cas_success_ratio: cas_success_ratio(packets.iter()),
+        per_connection: None,
+        connect_rate: Some(
+            packets.iter().filter(|p| p.reconnected).count() as u64 * 1_000_000_000
+                / duration_to_ns(last_send - first_send),
+        ),
+        servers,
+        sizes,
+        ops,
+        imbalance: None,
+        sent: None,
+        latencies: Some(Percentiles(
+            report
+                .percentiles()
+                .iter()
+                .map(|&p| (p, latency_percentile(&latencies, samples, p)))
+                .collect(),
+        )),
+        samples,
+        latency_min: latencies.min().map(|l| l as f32 / 1000.0),
+        latency_mean: latencies.mean().map(|l| (l / 1000.0) as f32),
+        latency_max: latencies.max().map(|l| l as f32 / 1000.0),
+        latency_overflows: latencies.overflows() as usize,
+        latency_underflows: latencies.underflows() as usize,
+        send_lag: Some(Percentiles(
+            report.percentiles().iter().map(|&p| (p, lag(p))).collect(),
+        )),
+        send_lag_max: lags.max().map(|l| l as f32 / 1000.0),
+        queueing,
+        service,
+        response,
+        start: start_secs,
+    });
+    report.record_histogram(&latencies);
+    report.record_samples(wct_start, packets);
+
+    if !report.is_text() {
+        return true;
+    }
+
+    if let OutputMode::Trace = sched.output {
+        packets.sort_by_key(|p| p.actual_start.unwrap_or(p.target_start));
+        print!("Trace: ");
+        for p in packets {
+            if let Some(completion_time) = p.completion_time {
+                let actual_start = p.actual_start.unwrap();
+                println!(
+                    "{}:{}:{}",
+                    duration_to_ns(actual_start),
+                    duration_to_ns(actual_start) as i64 - duration_to_ns(p.target_start) as i64,
+                    duration_to_ns(
+                        completion_time
+                            .checked_sub(actual_start)
+                            .unwrap_or_default()
+                    )
+                )
+            } else if p.actual_start.is_some() {
+                let actual_start = p.actual_start.unwrap();
+                println!(
+                    "{}:{}:-1",
+                    duration_to_ns(actual_start),
+                    duration_to_ns(actual_start) as i64 - duration_to_ns(p.target_start) as i64,
+                )
+            } else {
+                println!("{}:-1:-1", duration_to_ns(p.target_start))
+            }
+        }
+        println!("");
+    }
+
+    if let OutputMode::Buckets = sched.output {
+        unimplemented!()
+        // let mut buckets = BTreeMap::new();
+
+        // for l in latencies {
+        //     *buckets.entry(l).or_insert(0) += 1;
+        // }
+        // print!("Latencies: ");
+        // for k in buckets.keys() {
+        //     print!("{}:{} ", k, buckets[k]);
+        // }
+        // println!("");
+    }
+    true
+}
+
+/// What the sends of an open-loop connection are accounted against: its
+/// window of outstanding requests, the thread's send limit, and the table of
+/// requests in flight, the live send times and the order of its sends, if
+/// kept.
+#[derive(Copy, Clone)]
+struct Tracking<'a> {
+    window: &'a Window,
+    limit: &'a SendLimit,
+    in_flight: Option<&'a InFlight>,
+    live: Option<&'a LiveTimes>,
+    order: Option<&'a SendOrder>,
+}
+
+/// Waits until `packet` is due and `bucket` has a token for it, if given, and
+/// returns the time. Returns None if the packet is already too late to send.
+fn wait_to_send(
+    backend: Backend,
+    start: Instant,
+    packet: &Packet,
+    bucket: &mut Option<TokenBucket>,
+) -> Option<Duration> {
+    let mut t = start.elapsed();
+    while t < packet.target_start {
+        backend.thread_yield();
+        t = start.elapsed();
+    }
+    if t > packet.target_start + Duration::from_micros(5) {
+        return None;
+    }
+    if let Some(ref mut bucket) = *bucket {
+        // Tokens come at a known time, so sleep until then rather than spin.
+        while let Err(wait) = bucket.take(t) {
+            backend.sleep(wait);
+            t = start.elapsed();
+        }
+    }
+    Some(t)
+}
+
+/// Sends `packets` on their schedule, holding each back until `bucket` has a
+/// token for it if given, and tracking each in the `in_flight` table of
+/// `tracking` if kept. With an `opts.udp_batch` above 1, requests go out that
+/// many at a time, or as many as are due before the next is. With
+/// `generators`, requests are taken ready built from them rather than built
+/// here. With `opts.udp_gso`, the runs of a batch whose payloads are one size
+/// go out as segmented sends where the kernel allows. Otherwise a memcached
+/// request over a Linux UDP socket is sent with one vectored send from its
+/// frame header and the parts `Protocol::gen_framed` builds, rather than
+/// built in one buffer. Returns how many of them belong to the run, which is
+/// fewer than all of them if `limit` stopped it early, or, if the connection
+/// failed, how many were sent or skipped before it did.
+fn send_packets(
+    link: Link,
+    socket: &Connection,
+    packets: &mut [Packet],
+    tracking: Tracking,
+    bucket: &mut Option<TokenBucket>,
+    mut generators: Option<&mut Generators>,
+) -> Result<usize, usize> {
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = link.client;
+    let Tracking {
+        window,
+        limit,
+        in_flight,
+        live,
+        order,
+    } = tracking;
+    let start = link.start;
+    let mut payload = Vec::with_capacity(REQUEST_SIZE);
+    let mut value = Vec::new();
+    let npackets = packets.len();
+    let mut batch = SendBatch::new(opts.udp_batch);
+    if opts.udp_gso {
+        batch.enable_gso(socket);
+    }
+    let vectored = match *socket {
+        Connection::LinuxUdp(_) => opts.udp_batch == 1 && generators.is_none(),
+        _ => false,
+    };
+    for i in 0..npackets {
+        // A batch goes out rather than wait for a request that is not due.
+        if !batch.is_empty() && start.elapsed() < packets[i].target_start {
+            send_batch(link, socket, &mut batch, packets, tracking)?;
+        }
+        let packet = &mut packets[i];
+        let mut frame = None;
+        let mut parts: [&[u8]; 3] = [&[], &[], &[]];
+        let payload: &[u8] = match generators {
+            Some(ref mut g) => g.take(backend, start, i, packet),
+            None if vectored => {
+                payload.clear();
+                let (info, f, p) = protocol.gen_framed(i, packet, &mut payload, &mut value, tport);
+                packet.info = info;
+                frame = f;
+                // An unframed request is sent as any other.
+                match frame {
+                    Some(_) => {
+                        parts = p;
+                        &[]
+                    }
+                    None => p[0],
+                }
+            }
+            None => {
+                payload.clear();
+                packet.info = protocol.gen_request(i, packet, &mut payload, tport);
+                &payload[..]
+            }
+        };
+
+        let t = match wait_to_send(backend, start, packet, bucket) {
+            Some(t) => t,
+            None => {
+                if let Some(f) = in_flight {
+                    f.skip(i);
+                }
+                continue;
+            }
+        };
+
+        // The window closes early only if the connection stopped answering.
+        if window.is_closed() {
+            if !batch.is_empty() {
+                send_batch(link, socket, &mut batch, packets, tracking)?;
+            }
+            return Err(i);
+        }
+        if !window.acquire(backend) {
+            packet.window_dropped = true;
+            if let Some(f) = in_flight {
+                f.skip(i);
+            }
+            continue;
+        }
+        if !limit.admit(packet, t) {
+            window.release();
+            if !batch.is_empty() {
+                send_batch(link, socket, &mut batch, packets, tracking)?;
+            }
+            return Ok(i);
+        }
+        if let Some(live) = live {
+            live.sent(i, t, packet.info.op);
+        }
+
+        // Tracked from before the write, so a fast response finds it.
+        let header: &[u8] = match frame {
+            Some(ref frame) => frame,
+            None => &[],
+        };
+        let [head, key, value] = parts;
+        if let Some(f) = in_flight {
+            f.send(i, t, &[header, payload, head, key, value]);
+        }
+        if let Some(o) = order {
+            o.send(i);
+        }
+        if opts.udp_batch > 1 {
+            batch.push(i, t, payload);
+            if batch.is_full() {
+                send_batch(link, socket, &mut batch, packets, tracking)?;
+            }
+            continue;
+        }
+        let write = || match frame {
+            Some(_) => socket
+                .send_vectored(&[header, head, key, value])
+                .map(|_| ()),
+            None => (&*socket).write_all(payload),
+        };
+        match timed_write(&start, opts.timestamp, write) {
+            Ok(t) => packet.actual_start = Some(t),
+            Err(e) => {
+                if in_flight.map_or(Answer::First, |f| f.answer(i)) == Answer::First {
+                    window.release();
+                }
+                if let Some(o) = order {
+                    o.answer(i);
+                }
+                match e.raw_os_error() {
+                    Some(-105) => {
+                        backend.thread_yield();
+                        continue;
+                    }
+                    Some(-32) | Some(-103) | Some(-104) => {}
+                    _ => println!("Send thread ({}/{}): {}", i, npackets, e),
+                }
+                return Err(i);
+            }
+        }
+    }
+    if !batch.is_empty() {
+        send_batch(link, socket, &mut batch, packets, tracking)?;
+    }
+    Ok(npackets)
+}
+
+/// Sends the requests of `batch` with as few syscalls as will take them,
+/// stamping each with the time it was batched, or with the time of the write
+/// for post-write timestamps. Returns, if the connection failed, the index of
+/// the first request it did not send.
+fn send_batch(
+    link: Link,
+    socket: &Connection,
+    batch: &mut SendBatch,
+    packets: &mut [Packet],
+    tracking: Tracking,
+) -> Result<(), usize> {
+    let (backend, start) = (link.client.backend, link.start);
+    let Tracking {
+        window,
+        in_flight,
+        order,
+        ..
+    } = tracking;
+    let mut sent = 0;
+    let mut failed = None;
+    while sent < batch.requests().len() {
+        match profile::timed(Phase::Write, || batch.send(socket, sent, start)) {
+            Ok(n) => sent += n,
+            Err(e) => {
+                // None of the rest went out.
+                for &(i, _) in &batch.requests()[sent..] {
+                    if in_flight.map_or(Answer::First, |f| f.answer(i)) == Answer::First {
+                        window.release();
+                    }
+                    if let Some(o) = order {
+                        o.answer(i);
+                    }
+                }
+                let first = batch.requests()[sent].0;
+                match e.raw_os_error() {
+                    Some(-105) => backend.thread_yield(),
+                    Some(-32) | Some(-103) | Some(-104) => failed = Some(first),
+                    _ => {
+                        println!("Send thread ({}/{}): {}", first, packets.len(), e);
+                        failed = Some(first);
+                    }
+                }
+                break;
+            }
+        }
+    }
+    let written = start.elapsed();
+    for &(i, batched) in &batch.requests()[..sent] {
+        packets[i].actual_start = Some(match link.client.opts.timestamp {
+            SendTimestamp::PreWrite => batched,
+            SendTimestamp::PostWrite => written,
+        });
+    }
+    batch.clear();
+    match failed {
+        Some(i) => Err(i),
+        None => Ok(()),
+    }
+}
+
+/// The next response on `socket`, taken from `batch` if given, with the time
+/// since `start` that it was received and its bytes, or None for a datagram
+/// that completes no request. A batch is stamped as a whole, when its
+/// recvmmsg returns.
+fn next_response(
+    protocol: Protocol,
+    socket: &Connection,
+    tport: Transport,
+    scratch: &mut Vec<u8>,
+    batch: &mut Option<RecvBatch>,
+    start: Instant,
+) -> io::Result<Option<(usize, Outcome, Duration, usize)>> {
+    match *batch {
+        Some(ref mut batch) => {
+            let (datagram, received) = batch.next(socket, start)?;
+            let len = datagram.len();
+            Ok(protocol
+                .read_datagram(datagram)
+                .or_else(|e| rejected(e).map(Some))?
+                .map(|(idx, outcome)| (idx, outcome, received, len)))
+        }
+        None => {
+            let (idx, outcome) = protocol
+                .read_response(socket, tport, scratch)
+                .or_else(rejected)?;
+            Ok(Some((idx, outcome, start.elapsed(), take_bytes_read())))
+        }
+    }
+}
+
+/// How a thread's connection fared: how long each connection opened in its
+/// place took, how many times it was replaced, and how many responses
+/// answered no request.
+#[derive(Default)]
+struct ConnStats {
+    connect_times: Vec<Duration>,
+    reconnects: usize,
+    spurious: usize,
+}
+
+/// Sends `packets` open loop over `socket`, whose responses a thread of its
+/// own reads, and drains them until `drain_until`. If the connection fails
+/// first, the requests in flight on it fail, and unless `opts.reconnect` is
+/// off the rest are sent over a new connection to the link's address. The
+/// drain is moved forward if `limit` stops the run early. With
+/// `opts.request_timeout`, a sweeper times out the requests left unanswered,
+/// freeing their window slots. Returns how many packets belong to the run, as
+/// `send_packets` does.
+fn run_open_loop(
+    link: Link,
+    socket: Connection,
+    packets: &mut [Packet],
+    limit: &Arc<SendLimit>,
+    mut bucket: Option<TokenBucket>,
+    drain_until: &Arc<AtomicU64>,
+    stats: &mut ConnStats,
+) -> usize {
+    let Link {
+        client,
+        addr,
+        start,
+    } = link;
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = client;
+    let mut socket = Arc::new(socket);
+    let mut offset = 0;
+    loop {
+        let window = Arc::new(Window::new(opts.max_outstanding, opts.window_policy));
+        let receiving = Arc::new(AtomicBool::new(!opts.no_read));
+        // UDP requests are always tracked, to tell spurious responses apart.
+        let in_flight = match (tport, opts.request_timeout) {
+            (Transport::Tcp, None) | (Transport::Unix(_), None) => None,
+            _ => Some(Arc::new(InFlight::new(
+                packets.len() - offset,
+                opts.retransmits,
+            ))),
+        };
+        let live = match opts.no_read {
+            true => None,
+            false => limit.live_times(packets.len() - offset, addr),
+        };
+        let order = match opts.no_read {
+            false if protocol.matches_by_order(tport) => Some(Arc::new(SendOrder::default())),
+            _ => None,
+        };
+        let receiver = if opts.no_read {
+            None
+        } else {
+            let socket = socket.clone();
+            let window = window.clone();
+            let receiving = receiving.clone();
+            let limit = limit.clone();
+            let in_flight = in_flight.clone();
+            let live = live.clone();
+            let order = order.clone();
+            let npackets = packets.len() - offset;
+            let udp_batch = opts.udp_batch;
+            let udp_gro = opts.udp_gro;
+            let read_buffer = opts.read_buffer;
+            Some(backend.spawn_thread(move || {
+                let mut recv_buf = vec![0; read_buffer];
+                let mut batch = match udp_batch {
+                    1 => None,
+                    size => Some(RecvBatch::new(size)),
+                };
+                if let (Some(ref mut batch), true) = (&mut batch, udp_gro) {
+                    batch.enable_gro(&socket);
+                }
+                let mut receive_times = vec![None; npackets];
+                let mut remaining = npackets;
+                let mut spurious = 0;
+                // Without a table, the requests answered more than once.
+                let mut duplicated = Vec::new();
+                while remaining > 0 {
+                    match next_response(protocol, &socket, tport, &mut recv_buf, &mut batch, start)
+                    {
+                        Ok(None) => {}
+                        Ok(Some((idx, outcome, received, len))) => {
+                            let idx = order.as_ref().map_or(idx, |o| o.answer(idx));
+                            let answer = match in_flight {
+                                Some(ref in_flight) => in_flight.answer(idx),
+                                None if idx < npackets && receive_times[idx].is_none() => {
+                                    Answer::First
+                                }
+                                None if idx < npackets => {
+                                    duplicated.push(idx);
+                                    Answer::Duplicate
+                                }
+                                None => Answer::Spurious,
+                            };
+                            // A late response's slot was freed when it timed
+                            // out, and a duplicate's request was answered.
+                            match answer {
+                                Answer::First => {
+                                    limit.complete();
+                                    window.release();
+                                    if let Some(ref live) = live {
+                                        live.answered(idx, received, outcome);
+                                    }
+                                }
+                                Answer::Late => {}
+                                Answer::Duplicate => continue,
+                                Answer::Spurious => {
+                                    spurious += 1;
+                                    continue;
+                                }
+                            }
+                            receive_times[idx] = Some((received, outcome, len));
+                            remaining -= 1;
+                        }
+                        // A malformed datagram is counted by the protocol and
+                        // skipped, as later ones may be fine.
+                        Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                            if let Some(ref live) = live {
+                                live.counts.error();
+                            }
+                        }
+                        Err(ref e) if read_timed_out(e) => {
+                            if limit.hung() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            match e.raw_os_error() {
+                                Some(-103) | Some(-104) => break,
+                                _ => (),
+                            }
+                            if e.kind() != ErrorKind::UnexpectedEof {
+                                println!("Receive thread: {}", e);
+                            }
+                            break;
+                        }
+                    }
+                }
+                receiving.store(false, Ordering::SeqCst);
+                window.close();
+                (receive_times, remaining > 0, spurious, duplicated)
+            }))
+        };
+        let timer = drain_timer(
+            backend,
+            start,
+            socket.clone(),
+            drain_until.clone(),
+            receiving.clone(),
+        );
+        let sweeper = match (&in_flight, opts.request_timeout) {
+            (Some(in_flight), Some(timeout)) => {
+                let in_flight = in_flight.clone();
+                let window = window.clone();
+                let receiving = receiving.clone();
+                let socket = socket.clone();
+                let live = live.clone();
+                let tick = Duration::min(timeout / 4, Duration::from_millis(1));
+                Some(backend.spawn_thread(move || {
+                    while receiving.load(Ordering::SeqCst) {
+                        let expired = in_flight.sweep(start.elapsed(), timeout, &window, &socket);
+                        if let Some(ref live) = live {
+                            live.counts.time_out(expired);
+                        }
+                        backend.sleep(tick);
+                    }
+                }))
+            }
+            _ => None,
+        };
+
+        let mut generators = match opts.generators {
+            0 => None,
+            n => Some(Generators::start(
+                backend,
+                protocol,
+                tport,
+                &packets[offset..],
+                start,
+                n,
+                opts.generator_lead,
+            )),
+        };
+        let tracking = Tracking {
+            window: &window,
+            limit,
+            in_flight: in_flight.as_deref(),
+            live: live.as_deref(),
+            order: order.as_deref(),
+        };
+        let sent = send_packets(
+            link,
+            &socket,
+            &mut packets[offset..],
+            tracking,
+            &mut bucket,
+            generators.as_mut(),
+        );
+        drop(generators);
+        match sent {
+            Ok(sent) if offset + sent < packets.len() => drain_until.store(
+                duration_to_ns(start.elapsed() + opts.drain_timeout),
+                Ordering::SeqCst,
+            ),
+            Ok(_) => {}
+            Err(_) => socket.shutdown(),
+        }
+        let timed_out = timer.join().unwrap() || limit.hung();
+        // A receive thread left waiting was stopped by the drain or the
+        // watchdog, or by a connection that failed.
+        let broken = match receiver {
+            Some(receiver) => {
+                let (receive_times, waiting, n, duplicated) = receiver.join().unwrap();
+                stats.spurious += n;
+                for (p, r) in packets[offset..].iter_mut().zip(receive_times) {
+                    p.completion_time = r.map(|(t, _, _)| t);
+                    p.miss = r.is_some_and(|(_, outcome, _)| outcome == Outcome::Miss);
+                    p.rejected = r.is_some_and(|(_, outcome, _)| outcome == Outcome::Rejected);
+                    p.response_bytes = r.map_or(0, |(_, _, len)| len);
+                }
+                for i in duplicated {
+                    packets[offset + i].duplicates += 1;
+                }
+                waiting && !timed_out
+            }
+            None => false,
+        };
+        if let Some(sweeper) = sweeper {
+            sweeper.join().unwrap();
+        }
+
+        let end = offset + sent.unwrap_or_else(|sent| sent);
+        if timed_out {
+            for p in packets[offset..end].iter_mut() {
+                p.timed_out = p.actual_start.is_some() && p.completion_time.is_none();
+            }
+            if let Some(ref live) = live {
+                live.counts
+                    .time_out(packets[offset..end].iter().filter(|p| p.timed_out).count());
+            }
+        }
+        if let Some(ref in_flight) = in_flight {
+            for (i, p) in packets[offset..end].iter_mut().enumerate() {
+                p.timed_out |= in_flight.expired(i);
+                p.retransmits = in_flight.retransmits[i].load(Ordering::SeqCst);
+                p.duplicates = in_flight.duplicates[i].load(Ordering::SeqCst);
+            }
+        }
+        // Without responses there is no telling what was in flight.
+        if (broken || sent.is_err()) && !opts.no_read {
+            fail_in_flight(&mut packets[offset..end]);
+        }
+        match sent {
+            Ok(_) => return end,
+            Err(_) if !opts.reconnect => return packets.len(),
+            Err(_) => {}
+        }
+        socket = match reconnect(link, limit, &mut stats.connect_times) {
+            Some(socket) => Arc::new(socket),
+            None => return packets.len(),
+        };
+        stats.reconnects += 1;
+        if let Some(ref live) = live {
+            live.counts.reconnected();
+        }
+        offset = end;
+    }
+}
+
+/// Marks the requests sent on a connection that failed and still unanswered.
+fn fail_in_flight(packets: &mut [Packet]) {
+    for p in packets.iter_mut() {
+        if p.actual_start.is_some() && p.completion_time.is_none() && !p.timed_out {
+            p.failed = true;
+        }
+    }
+}
+
+/// Times out the requests sent and not answered within `timeout`, whether or
+/// not a sweeper caught them in flight. Responses that came after are late,
+/// and no longer count as completions.
+fn expire_requests(packets: &mut [Packet], timeout: Duration) {
+    for p in packets.iter_mut() {
+        let sent = match p.actual_start {
+            Some(t) if !p.failed => t,
+            _ => continue,
+        };
+        // A request sent again was timed out as it went, from its last
+        // transmission.
+        match p.completion_time {
+            Some(t) if !p.timed_out && (p.retransmits > 0 || t <= sent + timeout) => {}
+            Some(_) => {
+                p.timed_out = true;
+                p.late = true;
+                p.completion_time = None;
+                p.miss = false;
+            }
+            None => p.timed_out = true,
+        }
+    }
+}
+
+/// Opens a connection to the link's address in place of one that failed,
+/// retrying with an exponential backoff until one opens or `limit` has
+/// nothing more to send.
+fn reconnect(
+    link: Link,
+    limit: &SendLimit,
+    connect_times: &mut Vec<Duration>,
+) -> Option<Connection> {
+    let Link {
+        client,
+        addr,
+        start,
+    } = link;
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = client;
+    let mut backoff = Duration::from_millis(1);
+    loop {
+        backend.sleep(backoff);
+        if start.elapsed() >= limit.deadline {
+            return None;
+        }
+        let local = opts.sources.reopened(addr);
+        match open_connection(backend, protocol, tport, local, addr, opts, connect_times) {
+            Ok(socket) => return Some(socket),
+            Err(e) => {
+                if backoff >= Duration::from_secs(1) {
+                    println!("Could not reconnect to {}: {}", addr, e);
+                } else {
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+/// The responses a pool's receive thread read: the time and outcome of each
+/// request it completed, how many answered no request, and which requests
+/// were answered again.
+type Received = (Vec<Option<(Duration, Outcome, usize)>>, usize, Vec<usize>);
+
+/// Sends one thread's `packets` on their schedule over a pool of connections,
+/// each read by a thread of its own, or all by one if `opts.epoll`, picking
+/// the connection for every request by `opts.pool_policy`. Once sending is
+/// over, responses are drained until every connection has answered all it
+/// was sent or the drain times out, and then the connections are shut down.
+/// Returns the packets that belong to the run, with their responses, how
+/// many requests each connection was sent, and how many responses answered
+/// no request. A failed connection is not replaced: sending stops at the
+/// first write that fails.
+fn run_pool(
+    link: Link,
+    sockets: Vec<Connection>,
+    mut packets: Vec<Packet>,
+    limit: &Arc<SendLimit>,
+    mut bucket: Option<TokenBucket>,
+) -> (Vec<Packet>, Vec<usize>, usize) {
+    let Link {
+        client,
+        addr,
+        start,
+    } = link;
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = client;
+    let sockets: Vec<Arc<Connection>> = sockets.into_iter().map(Arc::new).collect();
+    let nconns = sockets.len();
+    let outstanding: Arc<Vec<AtomicUsize>> =
+        Arc::new((0..nconns).map(|_| AtomicUsize::new(0)).collect());
+    let npackets = packets.len();
+    let live = match opts.no_read {
+        true => None,
+        false => limit.live_times(npackets, addr),
+    };
+    // Each connection's, if its responses are matched by order.
+    let orders: Arc<Vec<SendOrder>> = Arc::new(match opts.no_read {
+        false if protocol.matches_by_order(tport) => {
+            (0..nconns).map(|_| SendOrder::default()).collect()
+        }
+        _ => Vec::new(),
+    });
+    let receivers: Vec<JoinHandle<Received>> = if opts.no_read {
+        Vec::new()
+    } else if opts.epoll {
+        let sockets = sockets.clone();
+        let outstanding = outstanding.clone();
+        let limit = limit.clone();
+        let live = live.clone();
+        let orders = orders.clone();
+        vec![backend.spawn_thread(move || {
+            poll_pool(
+                link,
+                &sockets,
+                npackets,
+                &limit,
+                live.as_deref(),
+                &outstanding,
+                &orders,
+            )
+        })]
+    } else {
+        (0..nconns)
+            .map(|c| {
+                let socket = sockets[c].clone();
+                let outstanding = outstanding.clone();
+                let limit = limit.clone();
+                let live = live.clone();
+                let orders = orders.clone();
+                backend.spawn_thread(move || {
+                    let mut recv_buf = vec![0; opts.read_buffer];
+                    let mut receive_times = vec![None; npackets];
+                    let mut spurious = 0;
+                    let mut duplicated = Vec::new();
+                    loop {
+                        let response = protocol
+                            .read_response(&socket, tport, &mut recv_buf)
+                            .or_else(rejected)
+                            .map(|(idx, outcome)| {
+                                (orders.get(c).map_or(idx, |o| o.answer(idx)), outcome)
+                            });
+                        let len = match response {
+                            Ok(_) => take_bytes_read(),
+                            Err(_) => 0,
+                        };
+                        match response {
+                            Ok((idx, _)) if idx >= npackets => spurious += 1,
+                            Ok((idx, _)) if receive_times[idx].is_some() => duplicated.push(idx),
+                            Ok((idx, outcome)) => {
+                                let now = start.elapsed();
+                                receive_times[idx] = Some((now, outcome, len));
+                                limit.complete();
+                                outstanding[c].fetch_sub(1, Ordering::SeqCst);
+                                if let Some(ref live) = live {
+                                    live.answered(idx, now, outcome);
+                                }
+                            }
+                            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                                if let Some(ref live) = live {
+                                    live.counts.error();
+                                }
+                            }
+                            Err(ref e) if read_timed_out(e) => {
+                                if limit.hung() {
+                                    break;
+                                }
+                            }
+                            Err(e) => {
+                                match e.raw_os_error() {
+                                    Some(-103) | Some(-104) => break,
+                                    _ => (),
+                                }
+                                if e.kind() != ErrorKind::UnexpectedEof {
+                                    println!("Receive thread: {}", e);
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    (receive_times, spurious, duplicated)
+                })
+            })
+            .collect()
+    };
+
+    let mut payload = Vec::with_capacity(REQUEST_SIZE);
+    let mut counts = vec![0; nconns];
+    let mut next = 0;
+    let mut sent = npackets;
+    for (i, packet) in packets.iter_mut().enumerate() {
+        payload.clear();
+        packet.info = protocol.gen_request(i, packet, &mut payload, tport);
+        let t = match wait_to_send(backend, start, packet, &mut bucket) {
+            Some(t) => t,
+            None => continue,
+        };
+        if !limit.admit(packet, t) {
+            sent = i;
+            break;
+        }
+        if let Some(ref live) = live {
+            live.sent(i, t, packet.info.op);
+        }
+
+        // Ties go to the next connection in turn.
+        let c = match opts.pool_policy {
+            PoolPolicy::RoundRobin => next,
+            PoolPolicy::LeastOutstanding => (0..nconns)
+                .map(|k| (next + k) % nconns)
+                .min_by_key(|&c| outstanding[c].load(Ordering::SeqCst))
+                .unwrap(),
+        };
+        next = (c + 1) % nconns;
+        outstanding[c].fetch_add(1, Ordering::SeqCst);
+        if let Some(o) = orders.get(c) {
+            o.send(i);
+        }
+        match timed_write(&start, opts.timestamp, || {
+            (&*sockets[c]).write_all(&payload[..])
+        }) {
+            Ok(t) => {
+                packet.actual_start = Some(t);
+                packet.conn = c;
+                counts[c] += 1;
+            }
+            Err(e) => {
+                outstanding[c].fetch_sub(1, Ordering::SeqCst);
+                if let Some(o) = orders.get(c) {
+                    o.answer(i);
+                }
+                match e.raw_os_error() {
+                    Some(-105) => {
+                        backend.thread_yield();
+                        continue;
+                    }
+                    Some(-32) | Some(-103) | Some(-104) => {}
+                    _ => println!("Send thread ({}/{}): {}", i, npackets, e),
+                }
+                break;
+            }
+        }
+    }
+    packets.truncate(sent);
+
+    let mut timed_out = false;
+    if !receivers.is_empty() {
+        let last = packets
+            .last()
+            .map_or(Duration::from_nanos(0), |p| p.target_start);
+        let drain_until = Duration::max(last, start.elapsed()) + opts.drain_timeout;
+        while outstanding.iter().any(|o| o.load(Ordering::SeqCst) > 0) {
+            let now = start.elapsed();
+            if now >= drain_until || limit.hung() {
+                timed_out = true;
+                break;
+            }
+            backend.sleep((drain_until - now).min(Duration::from_millis(10)));
+        }
+    }
+    for socket in &sockets {
+        socket.shutdown();
+    }
+
+    let mut receive_times = vec![None; npackets];
+    let mut spurious = 0;
+    let mut duplicated = Vec::new();
+    for receiver in receivers {
+        let (times, n, d) = receiver.join().unwrap();
+        spurious += n;
+        duplicated.extend(d);
+        for (time, r) in receive_times.iter_mut().zip(times) {
+            if r.is_some() {
+                *time = r;
+            }
+        }
+    }
+    for (p, r) in packets.iter_mut().zip(receive_times) {
+        p.completion_time = r.map(|(t, _, _)| t);
+        p.miss = r.is_some_and(|(_, outcome, _)| outcome == Outcome::Miss);
+        p.rejected = r.is_some_and(|(_, outcome, _)| outcome == Outcome::Rejected);
+        p.response_bytes = r.map_or(0, |(_, _, len)| len);
+        p.timed_out = timed_out && p.actual_start.is_some() && r.is_none();
+    }
+    if let Some(ref live) = live {
+        live.counts
+            .time_out(packets.iter().filter(|p| p.timed_out).count());
+    }
+    for i in duplicated {
+        if let Some(p) = packets.get_mut(i) {
+            p.duplicates += 1;
+        }
+    }
+    (packets, counts, spurious)
+}
+
+/// Reads the responses of every connection of a pool on one thread, waiting
+/// on them all with epoll and receiving without blocking, so that a thread
+/// can serve hundreds of connections. A stream connection keeps the part of a
+/// response that has arrived until the rest does. Responses are timed as soon
+/// as the read that completes them returns, and matched by `orders`, if each
+/// connection keeps one. Stops once every connection has been closed or shut
+/// down, or the run has hung.
+fn poll_pool(
+    link: Link,
+    sockets: &[Arc<Connection>],
+    npackets: usize,
+    limit: &SendLimit,
+    live: Option<&LiveTimes>,
+    outstanding: &[AtomicUsize],
+    orders: &[SendOrder],
+) -> Received {
+    let Client {
+        protocol,
+        tport,
+        opts,
+        ..
+    } = link.client;
+    let start = link.start;
+    let mut poller = Poller::new().unwrap_or_else(|e| panic!("{}", e));
+    let fds: Vec<_> = sockets
+        .iter()
+        .map(|s| s.raw_fd().expect("--epoll needs Linux sockets"))
+        .collect();
+    for (c, &fd) in fds.iter().enumerate() {
+        poller.add(fd, c as u64).unwrap_or_else(|e| panic!("{}", e));
+    }
+    let mut streams: Vec<StreamState> = match tport {
+        Transport::Udp => Vec::new(),
+        _ => fds
+            .iter()
+            .map(|_| StreamState::new(opts.read_buffer))
+            .collect(),
+    };
+    let framing = sockets
+        .first()
+        .map_or(Framing::Udp { ipv6: false }, |s| s.framing());
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    let mut open = fds.len();
+    let mut ready = Vec::new();
+    let mut completed = Vec::new();
+    let mut receive_times = vec![None; npackets];
+    let mut spurious = 0;
+    let mut duplicated = Vec::new();
+    while open > 0 {
+        poller
+            .wait(&mut ready, Duration::from_millis(100))
+            .unwrap_or_else(|e| panic!("{}", e));
+        if ready.is_empty() && limit.hung() {
+            break;
+        }
+        for &(c, closed) in &ready {
+            let c = c as usize;
+            completed.clear();
+            let received = match tport {
+                Transport::Udp => poll::recv(fds[c], &mut datagram),
+                _ => streams[c].fill(fds[c]),
+            };
+            let now = start.elapsed();
+            if let Ok(len) = received {
+                count_polled(framing, len);
+            }
+            let parsed = match received {
+                Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+                Ok(len) => match tport {
+                    Transport::Udp => match protocol
+                        .read_datagram(&datagram[..len])
+                        .or_else(|e| rejected(e).map(Some))
+                    {
+                        Ok(r) => {
+                            completed.extend(r.map(|(idx, outcome)| (idx, outcome, len)));
+                            Ok(())
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                            if let Some(live) = live {
+                                live.counts.error();
+                            }
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    },
+                    _ => streams[c].parse(
+                        |head| protocol.parse_head(head),
+                        |idx, outcome, len| completed.push((idx, outcome, len)),
+                    ),
+                },
+                // A shut down datagram socket has nothing more to receive,
+                // though it never reads as over.
+                Err(ref e) if read_timed_out(e) && !closed => Ok(()),
+                Err(ref e) if read_timed_out(e) => Err(ErrorKind::UnexpectedEof.into()),
+                Err(e) => Err(e),
+            };
+            for &(idx, outcome, len) in &completed {
+                count_response(framing, len);
+                let idx = orders.get(c).map_or(idx, |o| o.answer(idx));
+                if idx >= npackets {
+                    spurious += 1;
+                } else if receive_times[idx].is_some() {
+                    duplicated.push(idx);
+                } else {
+                    receive_times[idx] = Some((now, outcome, len));
+                    limit.complete();
+                    outstanding[c].fetch_sub(1, Ordering::SeqCst);
+                    if let Some(live) = live {
+                        live.answered(idx, now, outcome);
+                    }
+                }
+            }
+            if let Err(e) = parsed {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    println!("Receive thread: {}", e);
+                }
+                poller.remove(fds[c]);
+                open -= 1;
+            }
+        }
+    }
+    (receive_times, spurious, duplicated)
+}
+
+/// Runs one connection closed loop with `opts.pipeline_depth` requests in
+/// flight. Sends are driven by the receive path: once a response arrives, its
+/// slot sends the next request after a think time drawn from
+/// `opts.think_time`. Responses are matched to requests by opaque. Each
+/// packet's target start is overwritten with the time its slot became free,
+/// so the schedule only sets how many requests may be sent. While other
+/// requests are in flight, a slot whose think time is over waits for the next
+/// response. A response to a request already answered is counted as its
+/// duplicate, and one to no request sent in `spurious`. Returns how many
+/// packets belong to the run, as `send_packets` does, or how many were sent
+/// if the connection failed.
+fn pipeline_packets(
+    link: Link,
+    socket: &Connection,
+    packets: &mut [Packet],
+    limit: &SendLimit,
+    live: Option<&LiveTimes>,
+    spurious: &mut usize,
+) -> Result<usize, usize> {
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = link.client;
+    let start = link.start;
+    let mut rng = rand::thread_rng();
+    let mut payload = Vec::with_capacity(REQUEST_SIZE);
+    let mut recv_buf = vec![0; opts.read_buffer];
+    let mut end = packets.len();
+    let mut next = 0;
+    let mut outstanding = 0;
+    // Requests below this have been answered.
+    let mut answered = 0;
+    // A connection of the CAS workload is a client of its own, with its own
+    // view of each key's CAS.
+    let mut cas = match protocol {
+        Protocol::Memcached(ref m) if m.cas_writes.is_some() => Some(CasMap::default()),
+        _ => None,
+    };
+    let now = start.elapsed();
+    let mut free: BinaryHeap<Reverse<Duration>> =
+        (0..opts.pipeline_depth).map(|_| Reverse(now)).collect();
+    // Requests generated into `payload` but not yet written, the last
+    // `batched` before `next`.
+    let mut batched = 0;
+    loop {
+        while next < end {
+            let at = match free.peek() {
+                Some(&Reverse(at)) => at,
+                None => break,
+            };
+            let mut now = start.elapsed();
+            if at > now {
+                if outstanding > 0 {
+                    break;
+                }
+                backend.sleep(at - now);
+                now = start.elapsed();
+            }
+            free.pop();
+            packets[next].target_start = at;
+            if !limit.admit(&packets[next], now) {
+                end = next;
+                break;
+            }
+            packets[next].info = match (protocol, cas.as_ref()) {
+                (Protocol::Memcached(ref m), Some(cas)) => {
+                    let from = payload.len();
+                    let info = m.gen_cas_request(next, &packets[next], cas, &mut payload, tport);
+                    RequestInfo {
+                        bytes: payload.len() - from,
+                        ..info
+                    }
+                }
+                _ => protocol.gen_request(next, &packets[next], &mut payload, tport),
+            };
+            if let Some(live) = live {
+                live.sent(next, now, packets[next].info.op);
+            }
+            next += 1;
+            outstanding += 1;
+            batched += 1;
+            if batched == opts.send_batch {
+                flush_batch(
+                    socket,
+                    &mut payload,
+                    packets,
+                    next - batched..next,
+                    &start,
+                    opts.timestamp,
+                )?;
+                batched = 0;
+            }
+        }
+        // Nothing more can be sent until a response arrives.
+        if batched > 0 {
+            flush_batch(
+                socket,
+                &mut payload,
+                packets,
+                next - batched..next,
+                &start,
+                opts.timestamp,
+            )?;
+            batched = 0;
+        }
+        if outstanding == 0 {
+            return Ok(end);
+        }
+
+        while answered < next && packets[answered].completion_time.is_some() {
+            answered += 1;
+        }
+        let response = protocol
+            .read_cas_response(socket, tport, &mut recv_buf)
+            .or_else(|e| rejected(e).map(|(idx, outcome)| (idx, outcome, 0)));
+        let response = match response {
+            // Requests are answered in turn, so a response that does not say
+            // which it answers is the oldest's.
+            Ok((UNMATCHED, outcome, cas)) if answered < next => Ok((answered, outcome, cas)),
+            response => response,
+        };
+        let len = match response {
+            Ok(_) => take_bytes_read(),
+            Err(_) => 0,
+        };
+        match response {
+            Ok((idx, _, _)) if idx >= next || packets[idx].actual_start.is_none() => {
+                *spurious += 1;
+            }
+            Ok((idx, _, _)) if packets[idx].completion_time.is_some() => {
+                packets[idx].duplicates += 1;
+            }
+            Ok((idx, outcome, value)) => {
+                let now = start.elapsed();
+                packets[idx].completion_time = Some(now);
+                packets[idx].response_bytes = len;
+                packets[idx].miss = outcome == Outcome::Miss;
+                packets[idx].rejected = outcome == Outcome::Rejected;
+                if let Some(ref mut cas) = cas {
+                    cas.record(packets[idx].info.key.unwrap(), outcome, value);
+                }
+                limit.complete();
+                if let Some(live) = live {
+                    let latency = now.checked_sub(packets[idx].actual_start.unwrap());
+                    let op = report::sample_op(packets[idx].info.op);
+                    live.record(duration_to_ns(latency.unwrap_or_default()), outcome, op);
+                }
+                outstanding -= 1;
+                free.push(Reverse(
+                    now + Duration::from_nanos(opts.think_time.sample(&mut rng)),
+                ));
+            }
+            Err(ref e) if read_timed_out(e) => {
+                if limit.hung() {
+                    return Err(next);
+                }
+            }
+            Err(e) => {
+                match e.raw_os_error() {
+                    Some(-103) | Some(-104) => return Err(next),
+                    _ => (),
+                }
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    println!("Receive thread: {}", e);
+                }
+                return Err(next);
+            }
+        }
+    }
+}
+
+/// Writes the requests of `batch` in `payload` with a single write, and
+/// stamps each with its time. If the write fails none of them were sent, and
+/// the first is returned as the error.
+fn flush_batch(
+    socket: &Connection,
+    payload: &mut Vec<u8>,
+    packets: &mut [Packet],
+    batch: Range<usize>,
+    start: &Instant,
+    timestamp: SendTimestamp,
+) -> Result<(), usize> {
+    let written = timed_write(start, timestamp, || (&*socket).write_all(&payload[..]));
+    payload.clear();
+    match written {
+        Ok(t) => {
+            for packet in &mut packets[batch] {
+                packet.actual_start = Some(t);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            match e.raw_os_error() {
+                Some(-32) | Some(-103) | Some(-104) => {}
+                _ => println!("Send thread ({}/{}): {}", batch.start, packets.len(), e),
+            }
+            Err(batch.start)
+        }
+    }
+}
+
+/// Waits out the drain of a connection, until `drain_until` ns into the run or
+/// until `receiving` is cleared. A connection still receiving by then is shut
+/// down, which stops whichever of its threads is blocked on it, and true is
+/// returned since its outstanding requests have timed out.
+fn drain_timer(
+    backend: Backend,
+    start: Instant,
+    socket: Arc<Connection>,
+    drain_until: Arc<AtomicU64>,
+    receiving: Arc<AtomicBool>,
+) -> JoinHandle<bool> {
+    backend.spawn_thread(move || {
+        loop {
+            if !receiving.load(Ordering::SeqCst) {
+                return false;
+            }
+            let until = Duration::from_nanos(drain_until.load(Ordering::SeqCst));
+            let now = start.elapsed();
+            if now >= until {
+                break;
+            }
+            backend.sleep((until - now).min(Duration::from_millis(10)));
+        }
+        if Arc::strong_count(&socket) > 1 {
+            socket.shutdown();
+        }
+        true
+    })
+}
+
+/// Runs `packets` closed loop as a series of sessions, each making a number
+/// of requests drawn from `opts.session_length` on a connection of its own,
+/// or as a single session if there is no session length. The first runs on
+/// `socket`, whose drain `receiving` tracks, and is cut to a random fraction
+/// of its length so that connections do not all reconnect at once. The first
+/// request of every later session is marked as reconnected, and how long its
+/// connection took to open is added to the connect times of `stats`. A
+/// connection that fails before the drain is over fails its requests in
+/// flight and, unless `opts.reconnect` is off, is replaced for the rest of
+/// its session, which is counted as a reconnect. Spurious responses are
+/// counted in `stats` too. Returns how many packets belong to the run and
+/// whether the last session timed out.
+fn run_sessions(
+    link: Link,
+    socket: &Arc<Connection>,
+    receiving: &Arc<AtomicBool>,
+    packets: &mut [Packet],
+    limit: &SendLimit,
+    drain_until: &Arc<AtomicU64>,
+    stats: &mut ConnStats,
+) -> (usize, bool) {
+    let Link {
+        client,
+        addr,
+        start,
+    } = link;
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = client;
+    let mut rng = rand::thread_rng();
+    let mut socket = socket.clone();
+    let mut receiving = receiving.clone();
+    let mut timer = None;
+    // Responses are timed as they are read, so the live report needs no send
+    // times.
+    let live = limit.live_times(0, addr);
+    let mut offset = 0;
+    let mut end = 0;
+    while offset < packets.len() {
+        if offset == end {
+            end = match opts.session_length {
+                Some(lengths) => {
+                    let mut length = usize::max(lengths.sample(&mut rng) as usize, 1);
+                    if offset == 0 {
+                        length = rng.gen_range(1, length + 1);
+                    }
+                    usize::min(offset + length, packets.len())
+                }
+                None => packets.len(),
+            };
+        }
+        let sent = pipeline_packets(
+            link,
+            &socket,
+            &mut packets[offset..end],
+            limit,
+            live.as_deref(),
+            &mut stats.spurious,
+        );
+        receiving.store(false, Ordering::SeqCst);
+        let timed_out = timer
+            .take()
+            .is_some_and(|t: JoinHandle<bool>| t.join().unwrap())
+            || limit.hung();
+        let drained = timed_out
+            || start.elapsed() >= Duration::from_nanos(drain_until.load(Ordering::SeqCst));
+        let sent = match sent {
+            Ok(sent) => sent,
+            // The drain shut the connection down.
+            Err(_) if drained => end - offset,
+            Err(sent) => {
+                fail_in_flight(&mut packets[offset..offset + sent]);
+                if !opts.reconnect {
+                    return (packets.len(), false);
+                }
+                socket = match reconnect(link, limit, &mut stats.connect_times) {
+                    Some(socket) => Arc::new(socket),
+                    None => return (packets.len(), false),
+                };
+                stats.reconnects += 1;
+                if let Some(ref live) = live {
+                    live.counts.reconnected();
+                }
+                offset += sent;
+                receiving = Arc::new(AtomicBool::new(true));
+                timer = Some(drain_timer(
+                    backend,
+                    start,
+                    socket.clone(),
+                    drain_until.clone(),
+                    receiving.clone(),
+                ));
+                continue;
+            }
+        };
+        if sent < end - offset
+            || end == packets.len()
+            || start.elapsed() >= limit.deadline
+            || limit.hung()
+        {
+            return (usize::min(offset + sent, end), timed_out);
+        }
+
+        offset = end;
+        let local = opts.sources.reopened(addr);
+        socket = Arc::new(
+            open_connection(
+                backend,
+                protocol,
+                tport,
+                local,
+                addr,
+                opts,
+                &mut stats.connect_times,
+            )
+            .unwrap_or_else(|e| panic!("{}", e)),
+        );
+        packets[offset].reconnected = true;
+        receiving = Arc::new(AtomicBool::new(true));
+        timer = Some(drain_timer(
+            backend,
+            start,
+            socket.clone(),
+            drain_until.clone(),
+            receiving.clone(),
+        ));
+    }
+    (packets.len(), false)
+}
+
+/// The largest value a memcached SET over UDP can carry to every one of
+/// `addrs` in a single datagram.
+fn max_udp_value_size(addrs: &[SocketAddr]) -> usize {
+    let payload = addrs.iter().map(|&a| max_udp_payload(a)).min().unwrap();
+    payload - memcached::UDP_SET_OVERHEAD
+}
+
+/// `socket` doing its I/O through io_uring if `opts` ask for it, or as it is
+/// if its rings cannot be set up.
+fn io_path(socket: Connection, addr: SocketAddr, opts: ClientOptions) -> Connection {
+    if !opts.io_uring {
+        return socket;
+    }
+    uring::wrap(socket).unwrap_or_else(|(socket, e)| {
+        println!(
+            "Warning: could not set up io_uring on a connection to {}: {}",
+            addr, e
+        );
+        socket
+    })
+}
+
+/// Opens a client connection to `addr` from `local`, or from an address the
+/// OS picks if None, securing it with `opts.tls` and running the protocol's
+/// handshake on it if `opts.handshake` is set, and adds how long that took
+/// to `connect_times` if it is a stream one. With `opts.watchdog`, its reads
+/// time out so that a receiver can see the watchdog abort the run, and one
+/// that times out partway through a response closes the connection.
+fn open_connection(
+    backend: Backend,
+    protocol: Protocol,
+    tport: Transport,
+    local: Option<SocketAddr>,
+    addr: SocketAddr,
+    opts: ClientOptions,
+    connect_times: &mut Vec<Duration>,
+) -> io::Result<Connection> {
+    let socket = match tport {
+        Transport::Tcp | Transport::Unix(_) => {
+            let start = Instant::now();
+            let socket = match tport {
+                Transport::Unix(path) => backend.create_unix_connection(path)?,
+                _ => backend.create_tcp_connection(local, addr)?,
+            };
+            let socket = io_path(socket, addr, opts);
+            let socket = match opts.tls {
+                Some(tls) => tls.connect(socket)?,
+                None => socket,
+            };
+            if opts.handshake {
+                protocol.handshake(&socket, tport)?;
+            }
+            connect_times.push(start.elapsed());
+            socket
+        }
+        Transport::Udp => {
+            let local = local.unwrap_or_else(|| any_addr(addr, 0));
+            io_path(
+                backend.create_udp_connection(local, Some(addr))?,
+                addr,
+                opts,
+            )
+        }
+    };
+    if let Some(threshold) = opts.watchdog {
+        socket.set_read_timeout(Some(watchdog_poll(threshold)))?;
+    }
+    for (option, e) in socket.set_options(opts.sockets) {
+        println!(
+            "Warning: could not set {} on a connection to {}: {}",
+            option, addr, e
+        );
+    }
+    Ok(socket)
+}
+
+/// Opens a connection per thread, spreading them over the targets in turn.
+/// A target that refuses a connection gets no more of them, and its share
+/// goes to the next one, so the run goes on as long as any target is up.
+/// Returns each connection with the index of its target.
+fn open_connections(
+    client: Client,
+    targets: Targets,
+    nthreads: usize,
+    index: usize,
+    connect_times: &mut Vec<Duration>,
+) -> Vec<(Connection, usize)> {
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = client;
+    let mut reachable = vec![true; targets.addrs.len()];
+    (0..nthreads)
+        .map(|tidx| {
+            let src_port = (100 + (index * nthreads) + tidx) as u16;
+            let mut server = tidx % targets.addrs.len();
+            loop {
+                if !reachable.iter().any(|&r| r) {
+                    panic!("No target is reachable");
+                }
+                if reachable[server] {
+                    let addr = targets.addrs[server];
+                    // Without sources, the runtime's TCP connections are
+                    // numbered from port 100.
+                    let local = match (opts.sources.addr(tidx, addr), backend, tport) {
+                        (None, Backend::Runtime, Transport::Tcp) => Some(any_addr(addr, src_port)),
+                        (local, _, _) => local,
+                    };
+                    match open_connection(
+                        backend,
+                        protocol,
+                        tport,
+                        local,
+                        addr,
+                        opts,
+                        connect_times,
+                    ) {
+                        Ok(socket) => return (socket, server),
+                        Err(e) => {
+                            println!("Could not connect to {}: {}", addr, e);
+                            reachable[server] = false;
+                        }
+                    }
+                }
+                server = (server + 1) % targets.addrs.len();
+            }
+        })
+        .collect()
+}
+
+/// Makes `requests` requests to `addr` one at a time, over a connection that
+/// does its I/O through io_uring if `opts` ask for it. Returns the CPU time
+/// the client spent on each, in ns, and how many it made per second.
+fn io_bench(
+    protocol: Protocol,
+    tport: Transport,
+    addr: SocketAddr,
+    opts: ClientOptions,
+    requests: usize,
+) -> io::Result<(f64, f64)> {
+    let socket = open_connection(
+        Backend::Linux,
+        protocol,
+        tport,
+        None,
+        addr,
+        opts,
+        &mut Vec::new(),
+    )?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut rng = rand::thread_rng();
+    let mut buf = Vec::new();
+    let mut scratch = Vec::new();
+    let start = Instant::now();
+    let cpu_start = process_cpu_time();
+    for i in 0..requests {
+        let p = Packet {
+            randomness: rng.gen(),
+            ..Default::default()
+        };
+        buf.clear();
+        protocol.gen_request(i, &p, &mut buf, tport);
+        (&socket).write_all(&buf[..])?;
+        // Only request i is outstanding, so a response that does not say which it
+        // answers is its.
+        loop {
+            match protocol
+                .read_response(&socket, tport, &mut scratch)
+                .or_else(rejected)?
+                .0
+            {
+                idx if idx == i || idx == UNMATCHED => break,
+                _ => {}
+            }
+        }
+    }
+    let cpu = duration_to_ns(process_cpu_time() - cpu_start);
+    let elapsed = duration_to_ns(start.elapsed());
+    Ok((
+        cpu as f64 / requests as f64,
+        requests as f64 * 1e9 / elapsed as f64,
+    ))
+}
+
+/// User and system CPU time the process has used.
+fn process_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+    }
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Hands each of `packets`, in order of their send times, to a target picked
+/// by weight, or by the hash of its key if `key_hash` is set, and then to that
+/// target's connections in turn. Targets left without a connection get
+/// nothing. Returns the packets of each connection.
+fn balance_packets(
+    mut packets: Vec<Packet>,
+    servers: &[usize],
+    weights: &[u32],
+    key_hash: bool,
+    protocol: Protocol,
+) -> Vec<Vec<Packet>> {
+    let mut connections: Vec<Vec<usize>> = vec![Vec::new(); weights.len()];
+    for (i, &server) in servers.iter().enumerate() {
+        connections[server].push(i);
+    }
+    let weights: Vec<u32> = weights
+        .iter()
+        .zip(&connections)
+        .map(|(&w, c)| if c.is_empty() { 0 } else { w })
+        .collect();
+    let reachable: Vec<bool> = weights.iter().map(|&w| w > 0).collect();
+    let mut balancer = Balancer::new(&weights);
+    let mut turns = vec![0; weights.len()];
+
+    packets.sort_by_key(|p| p.target_start);
+    let mut balanced: Vec<Vec<Packet>> = servers.iter().map(|_| Vec::new()).collect();
+    for mut p in packets {
+        let server = if key_hash {
+            balance::key_server(protocol.request_key(&p), &reachable)
+        } else {
+            balancer.next()
+        };
+        let conns = &connections[server];
+        p.server = server;
+        balanced[conns[turns[server] % conns.len()]].push(p);
+        turns[server] += 1;
+    }
+    balanced
+}
+
+/// Gives the packets of every thread the records of `trace` in order of their
+/// send times, starting over once it runs out.
+fn assign_trace(thread_packets: &mut [Vec<Packet>], trace: &[TraceRecord]) {
+    let mut order: Vec<(Duration, usize, usize)> = thread_packets
+        .iter()
+        .enumerate()
+        .flat_map(|(t, packets)| {
+            packets
+                .iter()
+                .enumerate()
+                .map(move |(i, p)| (p.target_start, t, i))
+        })
+        .collect();
+    order.sort();
+    for (n, &(_, t, i)) in order.iter().enumerate() {
+        thread_packets[t][i].trace = Some(trace[n % trace.len()]);
+    }
+}
+
+/// Lays out the packets of every thread from the records of a timed `trace`
+/// that starts over every `period`, replayed `speed` times faster than it was
+/// recorded, back to back across the schedules from 100ms in as
+/// `gen_packets` lays them out. The threads take the requests in turn, and
+/// the schedule a request falls in gives its service time.
+fn replay_trace<R: Rng>(
+    schedules: &[RequestSchedule],
+    trace: &[TraceRecord],
+    period: Duration,
+    speed: f64,
+    nthreads: usize,
+    rng: &mut R,
+) -> Vec<Vec<Packet>> {
+    let start = 100_000_000;
+    let first = trace[0].time.unwrap();
+    let mut thread_packets: Vec<Vec<Packet>> = (0..nthreads).map(|_| Vec::new()).collect();
+    let mut end = start;
+    let mut n = 0;
+    for sched in schedules {
+        end += duration_to_ns(sched.runtime);
+        loop {
+            let record = trace[n % trace.len()];
+            let recorded = duration_to_ns(record.time.unwrap() - first)
+                + (n / trace.len()) as u64 * duration_to_ns(period);
+            let at = start + (recorded as f64 / speed) as u64;
+            if at >= end {
+                break;
+            }
+            thread_packets[n % nthreads].push(Packet {
+                randomness: rng.gen::<u64>(),
+                target_start: Duration::from_nanos(at),
+                work_iterations: sched.service.sample(rng),
+                trace: Some(record),
+                ..Default::default()
+            });
+            n += 1;
+        }
+    }
+    thread_packets
+}
+
+fn run_client(
+    client: Client,
+    targets: Targets,
+    nthreads: usize,
+    barrier_group: &mut Option<lockstep::Group>,
+    schedules: &Vec<RequestSchedule>,
+    index: usize,
+    report: &mut Report,
+) -> bool {
+    let Client {
+        backend,
+        protocol,
+        tport,
+        opts,
+    } = client;
+    let mut rng = packet_rng(opts.seed, index);
+    let mut connect_times = Vec::new();
+
+    let connections = open_connections(
+        client,
+        targets,
+        nthreads * opts.conns_per_thread,
+        index,
+        &mut connect_times,
+    );
+    opts.sources.opened(connections.len());
+    match tport {
+        Transport::Unix(_) => {}
+        _ => {
+            report.record_address_families(
+                connections
+                    .iter()
+                    .map(|(socket, _)| AddressFamily::of(socket.local_addr()).name().to_string())
+                    .collect(),
+            );
+            if opts.sources.is_set() {
+                report.record_source_addresses(
+                    connections
+                        .iter()
+                        .map(|(socket, _)| socket.local_addr().to_string())
+                        .collect(),
+                );
+            }
+        }
+    }
+    // A trace with times sets when requests are sent, and one without only
+    // what they are.
+    let timed = opts
+        .trace
+        .and_then(|t| trace_period(t).map(|period| (t, period)));
+    let mut thread_packets: Vec<Vec<Packet>> = match timed {
+        Some((trace, period)) => replay_trace(
+            schedules,
+            trace,
+            period,
+            opts.replay_speed,
+            nthreads,
+            &mut rng,
+        ),
+        None => (0..nthreads)
+            .map(|_| gen_packets(schedules, &mut rng))
+            .collect(),
+    };
+    if let (Some(trace), None) = (opts.trace, timed) {
+        assign_trace(&mut thread_packets, trace);
+    }
+    // A run whose samples could overflow the binary dump is refused before
+    // it sends anything.
+    if let Err(e) = report.reserve_samples(thread_packets.iter().map(|p| p.len()).sum()) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+    if targets.addrs.len() > 1 {
+        let servers: Vec<usize> = connections.iter().map(|&(_, server)| server).collect();
+        thread_packets = balance_packets(
+            thread_packets.into_iter().flatten().collect(),
+            &servers,
+            targets.weights,
+            targets.key_hash,
+            protocol,
+        );
+    }
+    // Each thread takes the next `opts.conns_per_thread` connections.
+    let mut connections = connections.into_iter();
+    let packet_schedules: Vec<(Vec<Packet>, Vec<Connection>, usize)> = thread_packets
+        .into_iter()
+        .map(|packets| {
+            let pool: Vec<_> = connections.by_ref().take(opts.conns_per_thread).collect();
+            let server = pool[0].1;
+            let sockets = pool.into_iter().map(|(socket, _)| socket).collect();
+            (packets, sockets, server)
+        })
+        .filter(|(packets, _, _)| !packets.is_empty())
+        .collect();
+    // Each schedule offers the requests it scheduled over its runtime.
+    let rows = match opts.interval {
+        Some(_) => report.take_interval_rows(),
+        None => None,
+    }
+    .map(|writer| {
+        let mut from = Duration::from_millis(100);
+        let steps = schedules
+            .iter()
+            .map(|sched| {
+                let to = from + sched.runtime;
+                let scheduled = packet_schedules
+                    .iter()
+                    .flat_map(|(packets, _, _)| packets)
+                    .filter(|p| p.target_start >= from && p.target_start < to)
+                    .count();
+                let step = (
+                    from,
+                    to,
+                    (scheduled as f64 / sched.runtime.as_secs_f64()) as u64,
+                );
+                from = to;
+                step
+            })
+            .collect();
+        (writer, steps)
+    });
+
+    if let Some(ref mut g) = *barrier_group {
+        g.barrier();
+    }
+    let traffic = Traffic::default();
+    let counting = traffic.count_here();
+    let start_unix = SystemTime::now();
+    let start = Instant::now();
+    let mut limit = SendLimit::new(opts.run_length, schedules);
+    limit.saturation = opts.stop_on_saturation;
+    let interval = opts.interval;
+    let print = report.is_text() && !opts.quiet;
+    limit.watchdog = opts.watchdog;
+    limit.counting = interval.is_some() || opts.watchdog.is_some();
+    limit.metrics = opts.metrics;
+    if (interval.is_some() && (print || rows.is_some())) || opts.metrics.is_some() {
+        limit.live = Some(Mutex::new(Vec::new()));
+    }
+    let limit = Arc::new(limit);
+    let mut rows = rows.map(|(writer, steps)| IntervalRows {
+        writer,
+        wct_start: start_unix,
+        steps,
+    });
+    let printer = interval.map(|interval| {
+        let limit = limit.clone();
+        let traffic = traffic.clone();
+        backend.spawn_thread(move || {
+            let intervals =
+                limit.print_intervals(backend, start, interval, print, &traffic, &mut rows);
+            (intervals, rows)
+        })
+    });
+    let watcher = limit.saturation.map(|_| {
+        let mut windows = Vec::new();
+        let mut from = Duration::from_millis(100);
+        for sched in schedules {
+            match sched.output {
+                OutputMode::Silent => {}
+                _ => windows.push((from, from + sched.runtime)),
+            }
+            from += sched.runtime;
+        }
+        let limit = limit.clone();
+        backend.spawn_thread(move || limit.watch(backend, start, &windows))
+    });
+    let watchdog = opts.watchdog.map(|_| {
+        let limit = limit.clone();
+        backend.spawn_thread(move || limit.guard(backend, start))
+    });
+
+    let mut send_threads = Vec::new();
+    for (worker, (mut packets, mut sockets, server)) in packet_schedules.into_iter().enumerate() {
+        let link = Link {
+            client,
+            addr: targets.addrs[server],
+            start,
+        };
+        let bucket = opts
+            .rate_limit
+            .map(|(rate, burst)| TokenBucket::new(rate / nthreads as f64, burst / nthreads));
+        let limit = limit.clone();
+        if sockets.len() > 1 {
+            send_threads.push(backend.spawn_thread(move || {
+                opts.cpus.pin(worker);
+                let (packets, counts, spurious) = run_pool(link, sockets, packets, &limit, bucket);
+                (packets, Vec::new(), counts, 0, spurious)
+            }));
+            continue;
+        }
+        let socket = sockets.pop().unwrap();
+
+        send_threads.push(backend.spawn_thread(move || {
+            opts.cpus.pin(worker);
+            // Responses are drained until the drain timeout after the last request is due, then
+            // a shutdown on the socket stops whichever thread is still running, and the requests
+            // still unanswered time out. Stopping early brings this forward.
+            let last = packets[packets.len() - 1].target_start;
+            let drain_until = Arc::new(AtomicU64::new(duration_to_ns(last + opts.drain_timeout)));
+
+            let mut stats = ConnStats::default();
+            let sent = if opts.pipeline_depth > 0 {
+                // A pipelined connection reads its own responses.
+                let socket = Arc::new(socket);
+                let receiving = Arc::new(AtomicBool::new(true));
+                let timer = drain_timer(
+                    backend,
+                    start,
+                    socket.clone(),
+                    drain_until.clone(),
+                    receiving.clone(),
+                );
+                let (sent, session_timed_out) = run_sessions(
+                    link,
+                    &socket,
+                    &receiving,
+                    &mut packets,
+                    &limit,
+                    &drain_until,
+                    &mut stats,
+                );
+                if sent < packets.len() {
+                    drain_until.store(
+                        duration_to_ns(start.elapsed() + opts.drain_timeout),
+                        Ordering::SeqCst,
+                    );
+                }
+                if timer.join().unwrap() || session_timed_out {
+                    for p in packets.iter_mut() {
+                        p.timed_out = p.actual_start.is_some() && p.completion_time.is_none();
+                    }
+                }
+                sent
+            } else {
+                run_open_loop(
+                    link,
+                    socket,
+                    &mut packets,
+                    &limit,
+                    bucket,
+                    &drain_until,
+                    &mut stats,
+                )
+            };
+            packets.truncate(sent);
+
+            let counts = vec![packets.iter().filter(|p| p.actual_start.is_some()).count()];
+            (
+                packets,
+                stats.connect_times,
+                counts,
+                stats.reconnects,
+                stats.spurious,
+            )
+        }))
+    }
+
+    let mut connection_requests = Vec::new();
+    let mut reconnects = 0;
+    let mut spurious = 0;
+    let mut packets: Vec<_> = send_threads
+        .into_iter()
+        .enumerate()
+        .flat_map(|(thread, s)| {
+            let (packets, times, counts, n, m) = s.join().unwrap();
+            report.record_requests(thread, connection_requests.len(), start_unix, &packets);
+            report.record_response_gaps(connection_requests.len(), start_unix, &packets);
+            connect_times.extend(times);
+            connection_requests.extend(counts);
+            reconnects += n;
+            spurious += m;
+            packets
+        })
+        .collect();
+    packets.sort_by_key(|p| p.target_start);
+    drop(counting);
+    let counted = traffic.counts();
+    report.record_bytes(counted.bytes, counted.headers, start.elapsed());
+    add_datagram_sizes(&counted);
+    add_udp_batches(&counted);
+    if let Some(metrics) = opts.metrics {
+        metrics.finish_run();
+    }
+    report.record_connects(&connect_times);
+    report.record_reconnects(reconnects);
+    report.record_spurious(spurious);
+    if opts.conns_per_thread > 1 {
+        report.record_connection_requests(&connection_requests);
+    }
+    if let Some(watcher) = watcher {
+        watcher.join().unwrap();
+    }
+    limit.finished.store(true, Ordering::SeqCst);
+    let intervals = printer.map(|printer| {
+        let (intervals, rows) = printer.join().unwrap();
+        if let Some(rows) = rows {
+            report.write_intervals(rows.writer);
+        }
+        intervals
+    });
+    if let Some(ref intervals) = intervals {
+        report.record_intervals(start_unix, intervals);
+    }
+    if let Some(watchdog) = watchdog {
+        watchdog.join().unwrap();
+    }
+    if let Some(timeout) = opts.request_timeout {
+        expire_requests(&mut packets, timeout);
+        report.record_timeouts(start_unix, &packets, timeout);
+    }
+    if !opts.no_read {
+        report.record_unanswered(start_unix, &packets);
+        report.record_duplicates(start_unix, &packets);
+    }
+    report.record_throughput(start_unix, &packets);
+    // Every schedule is reported, even after one fails to measure, and a run
+    // the watchdog aborted fails with what it measured before.
+    let mut start = Duration::from_nanos(100_000_000);
+    schedules.iter().fold(!limit.hung(), |ok, sched| {
+        let last_index = packets
+            .iter()
+            .position(|p| p.target_start >= start + sched.runtime)
+            .unwrap_or(packets.len());
+        let rest = packets.split_off(last_index);
+        let res = process_result(
+            &sched,
+            packets.as_mut_slice(),
+            start_unix,
+            Duration::from_millis(100) + opts.warmup,
+            opts.slowdown,
+            report,
+        );
+        packets = rest;
+        start += sched.runtime;
+        res && ok
+    })
+}
+
+fn run_local(
+    backend: Backend,
+    nthreads: usize,
+    worker: FakeWorker,
+    schedules: &[RequestSchedule],
+    report: &mut Report,
+) -> bool {
+    let mut rng = rand::thread_rng();
+
+    let packet_schedules: Vec<Vec<Packet>> = (0..nthreads)
+        .map(|_| gen_packets(schedules, &mut rng))
+        .collect();
+    if let Err(e) = report.reserve_samples(packet_schedules.iter().map(|p| p.len()).sum()) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+
+    let start_unix = SystemTime::now();
+    let start = Instant::now();
+
+    struct AtomicU64Pointer(*const AtomicU64);
+    unsafe impl Send for AtomicU64Pointer {}
+
+    let mut send_threads = Vec::new();
+    for mut packets in packet_schedules {
+        let worker = worker.clone();
+        send_threads.push(backend.spawn_thread(move || {
+            let remaining = Arc::new(AtomicUsize::new(packets.len()));
+            for i in 0..packets.len() {
+                let (work_iterations, completion_time_ns) = {
+                    let packet = &mut packets[i];
+
+                    let mut t = start.elapsed();
+                    while t < packet.target_start {
+                        t = start.elapsed();
+                    }
+
+                    packet.actual_start = Some(start.elapsed());
+                    (
+                        packet.work_iterations,
+                        AtomicU64Pointer(&packet.completion_time_ns as *const AtomicU64),
+                    )
+                };
+
+                let remaining = remaining.clone();
+                let worker = worker.clone();
+                backend.spawn_thread(move || {
+                    worker.work(work_iterations);
+                    unsafe {
+                        (*completion_time_ns.0)
+                            .store(start.elapsed().as_nanos() as u64, Ordering::SeqCst);
+                    }
+                    remaining.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+
+            while remaining.load(Ordering::SeqCst) > 0 {
+                // do nothing
+            }
+
+            packets
+        }))
+    }
+
+    let mut packets: Vec<_> = send_threads
+        .into_iter()
+        .flat_map(|s| s.join().unwrap().into_iter())
+        .map(|mut p| {
+            p.completion_time = Some(Duration::from_nanos(
+                p.completion_time_ns.load(Ordering::SeqCst),
+            ));
+            p
+        })
+        .collect();
+    packets.sort_by_key(|p| p.target_start);
+    report.record_throughput(start_unix, &packets);
+
+    // Every schedule is reported, even after one fails to measure.
+    let mut start = Duration::from_nanos(100_000_000);
+    schedules.iter().fold(true, |ok, sched| {
+        let last_index = packets
+            .iter()
+            .position(|p| p.target_start >= start + sched.runtime)
+            .unwrap_or(packets.len());
+        let rest = packets.split_off(last_index);
+        let res = process_result(
+            &sched,
+            packets.as_mut_slice(),
+            start_unix,
+            Duration::from_secs(0),
+            false,
+            report,
+        );
+        packets = rest;
+        start += sched.runtime;
+        res && ok
+    })
+}
+/// Prints the mean and a few quantiles of `distribution`, estimated by sampling.
+fn describe_distribution(distribution: Distribution) {
+    let n = 1_000_000;
+    let mut rng = rand::thread_rng();
+    let mut samples: Vec<u64> = (0..n).map(|_| distribution.sample(&mut rng)).collect();
+    samples.sort();
+    let mean = samples.iter().map(|&s| s as f64).sum::<f64>() / n as f64;
+    let quantile = |p: f64| samples[(n as f64 * p / 100.0) as usize];
+    println!("Distribution: {:?}", distribution);
+    println!(
+        "Mean: {:.1}, Median: {}, 90th: {}, 99th: {}, 99.9th: {}",
+        mean,
+        quantile(50.0),
+        quantile(90.0),
+        quantile(99.0),
+        quantile(99.9)
+    );
+}
+
+/// The `--distribution` spec as given, except that a bare name scaled by
+/// `--mean`, `--sigma` and `--shape` is written with the parameters it took
+/// from them, e.g. `exponential:10`, so that the spec stands on its own.
+fn distribution_spec(spec: &str, distribution: Distribution) -> String {
+    match (spec, distribution) {
+        ("constant", Distribution::Constant(v)) => format!("constant:{}", v),
+        ("exponential", Distribution::Exponential(m))
+        | ("bimodal1", Distribution::Bimodal1(m))
+        | ("bimodal2", Distribution::Bimodal2(m)) => format!("{}:{}", spec, m),
+        ("lognormal", Distribution::Lognormal(mu, sigma)) => {
+            format!("lognormal:{},{}", mu, sigma)
+        }
+        ("weibull", Distribution::Weibull(shape, scale)) => {
+            format!("weibull:{},{}", shape, scale)
+        }
+        _ => spec.to_string(),
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Synthetic Workload Application")
+        .version("0.1")
+        .arg(
+            Arg::with_name("ADDR")
+                .index(1)
+                .validator(|s| Targets::parse(&s, AddressFamily::Any).map(|_| ()))
+                .help(
+                    "Address and port to listen on, or a comma-separated list of servers for \
+                     a client to spread its requests over. A server is an address, such as \
+                     10.0.0.1:11211 or [::1]:11211, or a host name and port",
+                )
+                .required_unless_one(&["describe-dist", "samples-to-csv", "unix-socket"]),
+        )
+        .arg(
+            Arg::with_name("key-hash")
+                .long("key-hash")
+                .takes_value(false)
+                .conflicts_with("weights")
+                .help(
+                    "Send each memcached request to the server its key hashes to, by jump \
+                     consistent hash, as a client of a memcached pool would",
+                ),
+        )
+        .arg(
+            Arg::with_name("weights")
+                .long("weights")
+                .value_name("W1,W2,...")
+                .takes_value(true)
+                .validator(|s| parse_weights(&s).map(|_| ()))
+                .help(
+                    "Send each server a share of the requests in proportion to its weight, \
+                     rather than an equal one",
+                ),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("T")
+                .default_value("1")
+                .help("Number of client threads"),
+        )
+        .arg(
+            Arg::with_name("conns-per-thread")
+                .long("conns-per-thread")
+                .value_name("N")
+                .default_value("1")
+                .help("Connections each client thread spreads its open-loop requests over"),
+        )
+        .arg(
+            Arg::with_name("connections")
+                .long("connections")
+                .value_name("N")
+                .conflicts_with("conns-per-thread")
+                .help(
+                    "Total connections to spread the load over: an open-loop run splits them \
+                     evenly over its threads, a closed-loop one runs each from a thread of its \
+                     own, overriding --threads",
+                ),
+        )
+        .arg(
+            Arg::with_name("pool-policy")
+                .long("pool-policy")
+                .takes_value(true)
+                .possible_values(&PoolPolicy::variants())
+                .case_insensitive(true)
+                .default_value("RoundRobin")
+                .help(
+                    "How a thread with several connections picks one for each request: in \
+                     turn, or the one with the fewest requests in flight",
+                ),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .short("m")
+                .long("mode")
+                .value_name("MODE")
+                .possible_values(&[
+                    "linux-server",
+                    "linux-client",
+                    "runtime-client",
+                    "spawner-server",
+                    "local-client",
+                    "work-bench",
+                    "io-bench",
+                ])
+                .required_unless_one(&["describe-dist", "samples-to-csv"])
+                .requires_ifs(&[("runtime-client", "config"), ("spawner-server", "config")])
                 .help("Which mode to run in"),
         )
         .arg(
-            Arg::with_name("slowdown")
-            .short("s")
-            .long("slowdown")
-            .takes_value(false)
-            .help("Use slowdown instead of latency to represent results"),
+            Arg::with_name("slowdown")
+            .short("s")
+            .long("slowdown")
+            .takes_value(false)
+            .help("Use slowdown instead of latency to represent results"),
+        )
+        .arg(
+            Arg::with_name("runtime")
+                .short("r")
+                .long("runtime")
+                .takes_value(true)
+                .default_value("10")
+                .help("How long the application should run for"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .value_name("SECS")
+                .takes_value(true)
+                .conflicts_with_all(&["runtime", "count"])
+                .help("Seconds each sample sends for"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .value_name("N")
+                .takes_value(true)
+                .conflicts_with("runtime")
+                .help("Number of requests each sample sends, across all connections"),
+        )
+        .arg(
+            Arg::with_name("mpps")
+                .long("mpps")
+                .takes_value(true)
+                .default_value("0.02")
+                .help("How many *million* packets should be sent per second"),
+        )
+        .arg(
+            Arg::with_name("arrival")
+                .long("arrival")
+                .takes_value(true)
+                .possible_values(&["exponential", "constant", "uniform"])
+                .default_value("exponential")
+                .help("Distribution of request interarrival times"),
+        )
+        .arg(
+            Arg::with_name("interarrival")
+                .long("interarrival")
+                .value_name("SPEC")
+                .takes_value(true)
+                .validator(|s| match Distribution::parse(&s, 1.0, 1.0, 1.0)? {
+                    Distribution::Zero | Distribution::Constant(0) => {
+                        Err("interarrival gaps must be nonzero".to_string())
+                    }
+                    _ => Ok(()),
+                })
+                .help(
+                    "Distribution of interarrival times in ns, overriding --arrival. Specs that \
+                     --distribution scales to --mean are scaled to the rate instead, with \
+                     --sigma and --shape",
+                ),
+        )
+        .arg(
+            Arg::with_name("slo-latency")
+                .long("slo-latency")
+                // Its name from when the search only held to the 99th.
+                .alias("slo-p99")
+                .value_name("US")
+                .takes_value(true)
+                .help(
+                    "Instead of stepping through rates, search for the highest rate up to \
+                     --mpps whose latency at --slo-percentile stays under this, for at most \
+                     --samples samples",
+                ),
+        )
+        .arg(
+            Arg::with_name("slo-percentile")
+                .long("slo-percentile")
+                .value_name("P")
+                .takes_value(true)
+                .default_value("99")
+                .help("Percentile the SLO search holds to --slo-latency, one of --percentiles"),
+        )
+        .arg(
+            Arg::with_name("slo-search")
+                .long("slo-search")
+                .takes_value(true)
+                .possible_values(&["aimd", "bisect"])
+                .default_value("aimd")
+                .help(
+                    "Grow the rate additively and back off on violations, or binary search \
+                     from --start_mpps to --mpps",
+                ),
+        )
+        .arg(
+            Arg::with_name("slo-step")
+                .long("slo-step")
+                .value_name("MPPS")
+                .takes_value(true)
+                .default_value("0.01")
+                .help("Initial rate increase after a sample that holds the SLO"),
+        )
+        .arg(
+            Arg::with_name("slo-backoff")
+                .long("slo-backoff")
+                .value_name("FACTOR")
+                .takes_value(true)
+                .default_value("0.8")
+                .help("Rate multiplier after a sample that violates the SLO"),
+        )
+        .arg(
+            Arg::with_name("slo-tolerance")
+                .long("slo-tolerance")
+                .value_name("MPPS")
+                .takes_value(true)
+                .default_value("0.001")
+                .help("A bisect search ends once the rates it brackets are this close"),
+        )
+        .arg(
+            Arg::with_name("slo-repeats")
+                .long("slo-repeats")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("2")
+                .help("Samples in a row that must agree before a bisect search moves on"),
+        )
+        .arg(
+            Arg::with_name("slo-confirm")
+                .long("slo-confirm")
+                .value_name("SECS")
+                .takes_value(true)
+                .default_value("10")
+                .help(
+                    "Length of the confirmation sample at the rate a bisect search finds, 0 to \
+                     skip it",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-outstanding")
+                .long("max-outstanding")
+                .takes_value(true)
+                .default_value("0")
+                .help("Maximum in-flight requests per connection (0 = unlimited)"),
+        )
+        .arg(
+            Arg::with_name("window-policy")
+                .long("window-policy")
+                .takes_value(true)
+                .possible_values(&["block", "drop"])
+                .default_value("drop")
+                .help("Whether a full window delays or drops the next request"),
+        )
+        .arg(
+            Arg::with_name("pipeline-depth")
+                .long("pipeline-depth")
+                .takes_value(true)
+                .default_value("0")
+                .help("Run each tcp connection closed loop with this many requests in flight"),
         )
         .arg(
-            Arg::with_name("runtime")
-                .short("r")
-                .long("runtime")
+            Arg::with_name("load-mode")
+                .long("load-mode")
                 .takes_value(true)
-                .default_value("10")
-                .help("How long the application should run for"),
+                .possible_values(&["open", "closed"])
+                .default_value("open")
+                .help(
+                    "Send on the arrival schedule, or closed loop with --pipeline-depth \
+                     (at least 1) requests in flight per connection. A nonzero \
+                     --pipeline-depth also selects closed loop",
+                ),
         )
         .arg(
-            Arg::with_name("mpps")
-                .long("mpps")
+            Arg::with_name("think-time")
+                .long("think-time")
+                .value_name("SPEC")
                 .takes_value(true)
-                .default_value("0.02")
-                .help("How many *million* packets should be sent per second"),
+                .default_value("zero")
+                .validator(|s| Distribution::parse(&s, 1.0, 1.0, 1.0).map(|_| ()))
+                .help("Closed-loop pause in ns between a response and the next request"),
+        )
+        .arg(
+            Arg::with_name("timestamp")
+                .long("timestamp")
+                .takes_value(true)
+                .possible_values(&["pre-write", "post-write"])
+                .default_value("pre-write")
+                .help("Take each request's send time before or after its socket write"),
         )
         .arg(
             Arg::with_name("start_mpps")
                 .long("start_mpps")
                 .takes_value(true)
-                .default_value("0.0")
-                .help("Initial rate to sample at"),
+                .default_value("0.0")
+                .help("Initial rate to sample at"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .short("p")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .possible_values(&["synthetic", "memcached", "memcached-meta", "dns"])
+                .default_value("synthetic")
+                .help("Server protocol"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .takes_value(false)
+                .help("Verify the value returned by every memcached GET"),
+        )
+        .arg(
+            Arg::with_name("workload")
+                .long("workload")
+                .takes_value(true)
+                .possible_values(&["usr", "etc"])
+                .default_value("usr")
+                .help("Memcached key/value workload"),
+        )
+        .arg(
+            Arg::with_name("etc-value-size")
+                .long("etc-value-size")
+                .takes_value(true)
+                .validator(|s| Distribution::parse(&s, 1.0, 1.0, 1.0).map(|_| ()))
+                .help("Value size (or distribution) to use instead of the ETC one"),
+        )
+        .arg(
+            Arg::with_name("etc-key-size")
+                .long("etc-key-size")
+                .takes_value(true)
+                .default_value(memcached::ETC_KEY_SPEC)
+                .validator(|s| Distribution::parse(&s, 1.0, 1.0, 1.0).map(|_| ()))
+                .help("Key size (or distribution) of ETC requests"),
+        )
+        .arg(
+            Arg::with_name("max-value-size")
+                .long("max-value-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("1048576")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(format!("bad size '{}', must be a positive number of bytes", s)),
+                })
+                .help(
+                    "Clamp sampled ETC value sizes to at most this many bytes, counting how \
+                     many were clamped",
+                ),
+        )
+        .arg(
+            Arg::with_name("size-classes")
+                .long("size-classes")
+                .value_name("MEDIUM,LARGE")
+                .takes_value(true)
+                .validator(|s| parse_size_classes(&s).map(|_| ()))
+                .help(
+                    "Break ETC latencies out by value size into small, medium (from MEDIUM \
+                     bytes) and large (from LARGE bytes) requests. A GET is classed by the size \
+                     its key was last set to, and left out if that is unknown",
+                ),
+        )
+        .arg(
+            Arg::with_name("fanout")
+                .long("fanout")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of keys fetched by each memcached GET"),
+        )
+        .arg(
+            Arg::with_name("write-batch")
+                .long("write-batch")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("1")
+                .help(
+                    "Number of keys written by each USR memcached SET or DELETE, as quiet \
+                     SETQs or DELETEQs ended by a regular one. The server only answers quiet \
+                     writes that fail, so only the whole batch is timed",
+                ),
+        )
+        .arg(
+            Arg::with_name("cas-writes")
+                .long("cas-writes")
+                .value_name("F")
+                .conflicts_with("mix")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+                    _ => Err(format!("bad fraction '{}', must be between 0 and 1", s)),
+                })
+                .help(
+                    "Run a CAS workload, in which each connection keeps the CAS it last read \
+                     of each key: F of the requests are SETs carrying it, which the server \
+                     rejects if another connection wrote the key since, and the rest GETs \
+                     that read it. Reports the fraction of SETs that went through",
+                ),
+        )
+        .arg(
+            Arg::with_name("vbuckets")
+                .long("vbuckets")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("0")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n <= 32768 => Ok(()),
+                    _ => Err(format!("bad vbucket count '{}', must be at most 32768", s)),
+                })
+                .help(
+                    "Set the vbucket of every memcached request to its key's, of N, as a \
+                     Couchbase-style server that shards keys by vbucket expects. 0 leaves \
+                     them all at 0",
+                ),
+        )
+        .arg(
+            Arg::with_name("mix")
+                .long("mix")
+                .value_name("SPEC")
+                .takes_value(true)
+                .validator(|s| s.parse::<OpMix>().map(|_| ()))
+                .help(
+                    "Operation mix of memcached requests, as op:weight pairs such as \
+                     get:70,set:20,delete:10. The ops are get, set, add, replace, delete and \
+                     gat, a GET that sets the key's expiration to --ttl, and the weights are \
+                     relative. Defaults to the workload's own mix",
+                ),
+        )
+        .arg(
+            Arg::with_name("hot-key-fraction")
+                .long("hot-key-fraction")
+                .value_name("F")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+                    _ => Err(format!("bad fraction '{}', must be between 0 and 1", s)),
+                })
+                .help("Fraction of the memcached requests sent to a single hot key"),
+        )
+        .arg(
+            Arg::with_name("hot-key")
+                .long("hot-key")
+                .value_name("KEY")
+                .default_value("0")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(key) if key < memcached::NVALUES => Ok(()),
+                    _ => Err(format!(
+                        "bad key '{}', must be below {}",
+                        s,
+                        memcached::NVALUES
+                    )),
+                })
+                .help("Index of the key that --hot-key-fraction sends requests to"),
+        )
+        .arg(
+            Arg::with_name("key-distribution")
+                .long("key-distribution")
+                .value_name("SPEC")
+                .takes_value(true)
+                .help(
+                    "Distribution the USR workload draws keys from, modulo the key count, \
+                     instead of uniformly, as --distribution takes it: zipf:N,S makes key 1 \
+                     the most popular",
+                ),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .long("ttl")
+                .takes_value(true)
+                .default_value("0")
+                .help("Expiration time in seconds for memcached SETs and GATs (0 = never)"),
+        )
+        .arg(
+            Arg::with_name("flags")
+                .long("flags")
+                .value_name("FLAGS")
+                .takes_value(true)
+                .default_value("0")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("bad flags '{}', must be a 32-bit integer", s)),
+                })
+                .help(
+                    "Flags stored with every memcached SET, to tag the objects. With --verify, \
+                     GETs must return them",
+                ),
+        )
+        .arg(
+            Arg::with_name("data-type")
+                .long("data-type")
+                .value_name("TYPE")
+                .takes_value(true)
+                .default_value("0")
+                .validator(|s| match s.parse::<u8>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("bad data type '{}', must be an 8-bit integer", s)),
+                })
+                .help(
+                    "Data type of every memcached SET header, e.g. 1 to mark values \
+                     compressed. If nonzero, GET hits that do not carry it are counted",
+                ),
+        )
+        .arg(
+            Arg::with_name("value-source")
+                .long("value-source")
+                .takes_value(true)
+                .value_name("SPEC")
+                .default_value("deterministic")
+                .validator(|s| ValueSource::parse(&s).map(|_| ()))
+                .help(
+                    "Bytes of memcached SET values: deterministic, pattern:TEXT or file:PATH, \
+                     repeated up to the value size",
+                ),
+        )
+        .arg(
+            Arg::with_name("warmup")
+                .long("warmup")
+                .takes_value(false)
+                .help("Run the warmup routine"),
+        )
+        .arg(
+            Arg::with_name("session-length")
+                .long("session-length")
+                .value_name("SPEC")
+                .takes_value(true)
+                .validator(|s| Distribution::parse(&s, 1.0, 1.0, 1.0).map(|_| ()))
+                .help(
+                    "Close each closed-loop connection after a number of requests drawn from \
+                     SPEC and open a new one in its place",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-reconnect")
+                .long("no-reconnect")
+                .takes_value(false)
+                .help(
+                    "Fail fast: leave the rest of a failed connection's requests unsent \
+                     instead of reconnecting. A pool of connections per thread never \
+                     reconnects, so --conns-per-thread and an open-loop --connections need it",
+                ),
+        )
+        .arg(
+            Arg::with_name("handshake")
+                .long("handshake")
+                .takes_value(false)
+                .help(
+                    "Open every connection with a memcached VERSION, timed as part of connecting",
+                ),
+        )
+        .arg(
+            Arg::with_name("preflight")
+                .long("preflight")
+                .takes_value(false)
+                .help(
+                    "Before the run, check over one connection to each target that the \
+                     server answers, with a SET and a GET for memcached, exiting if not",
+                ),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("N")
+                .takes_value(true)
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("bad seed '{}', must be an integer", s)),
+                })
+                .help(
+                    "Seed the generator that draws each request's arrival time, randomness \
+                     and service time, so that the same seed and configuration send the same \
+                     stream of requests in every run",
+                ),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .takes_value(true)
+                .validator(|s| read_trace(&s).map(|_| ()))
+                .help(
+                    "Replay the memcached requests of FILE in order, looping, instead of \
+                     drawing them from the workload. Each line is 'get KEY' or \
+                     'set KEY [VALUE_SIZE]'; blank lines and lines starting with # are skipped. \
+                     Lines led by the time they were recorded at, in microseconds, replay at \
+                     those times rather than at the configured rate",
+                ),
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .long("replay-speed")
+                .value_name("X")
+                .takes_value(true)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(x) if x > 0.0 && x.is_finite() => Ok(()),
+                    _ => Err(format!("bad replay speed '{}', must be positive", s)),
+                })
+                .help(
+                    "Replay a --trace with times X times faster than it was recorded, \
+                     dividing the gaps between its requests by X",
+                ),
+        )
+        .arg(
+            Arg::with_name("latency-breakdown")
+                .long("latency-breakdown")
+                .takes_value(false)
+                .conflicts_with("no-read")
+                .help(
+                    "Report how long completed requests queued in the client between their \
+                     scheduled and actual sends, how long they then took to be answered, and \
+                     how long they took from their scheduled sends, which is free of \
+                     coordinated omission, as three distributions, with the longest send lag",
+                ),
+        )
+        .arg(
+            Arg::with_name("self-profile")
+                .long("self-profile")
+                .takes_value(false)
+                .help(
+                    "Time how long the client spends building requests, writing them and \
+                     reading responses, waits included, and report each as a share of the wall \
+                     time of the three at the end. Untimed runs skip the clock reads",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-read")
+                .long("no-read")
+                .takes_value(false)
+                .conflicts_with_all(&["slo-latency", "stop-on-saturation"])
+                .help(
+                    "Only write requests, never reading responses, to measure the send rate \
+                     alone. No latency is measured, and over TCP a server stalls once the \
+                     unread responses fill its socket",
+                ),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .value_name("RPS")
+                .takes_value(true)
+                .help(
+                    "Cap open-loop sends with a token bucket refilled at RPS, holding back \
+                     requests that arrive faster",
+                ),
+        )
+        .arg(
+            Arg::with_name("burst")
+                .long("burst")
+                .value_name("N")
+                .takes_value(true)
+                .requires("rate-limit")
+                .help("Requests the rate limit lets through at once [default: one per connection]"),
+        )
+        .arg(
+            Arg::with_name("drain-timeout")
+                .long("drain-timeout")
+                .value_name("MS")
+                .takes_value(true)
+                .default_value("500")
+                .help(
+                    "How long to keep collecting responses after the last request is due, \
+                     before counting the unanswered ones as timeouts",
+                ),
+        )
+        .arg(
+            Arg::with_name("request-timeout")
+                .long("request-timeout")
+                .value_name("MS")
+                .takes_value(true)
+                .conflicts_with("no-read")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(ms) if ms > 0 => Ok(()),
+                    _ => Err(format!("bad timeout '{}', must be a positive number of ms", s)),
+                })
+                .help(
+                    "Time out a request left unanswered this long after it is sent. An open-loop \
+                     request that times out frees its slot in the --max-outstanding window, and \
+                     a response that comes later is counted as late rather than as a completion",
+                ),
+        )
+        .arg(
+            Arg::with_name("retransmits")
+                .long("retransmits")
+                .value_name("K")
+                .takes_value(true)
+                .help(
+                    "Send a UDP request left unanswered for the request timeout again, with the \
+                     same opaque, up to K times before it times out. Its latency is measured \
+                     from the first transmission. Off by default, since it adds to the offered \
+                     load",
+                ),
+        )
+        .arg(
+            Arg::with_name("watchdog")
+                .long("watchdog")
+                .value_name("MS")
+                .takes_value(true)
+                .conflicts_with("no-read")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(ms) if ms > 0 => Ok(()),
+                    _ => Err(format!("bad watchdog '{}', must be a positive number of ms", s)),
+                })
+                .help(
+                    "Abort a run once requests have been in flight for MS with no response, as \
+                     from a hung server, and report what it measured until then. The run counts \
+                     as failed",
+                ),
+        )
+        .arg(
+            Arg::with_name("send-batch")
+                .long("send-batch")
+                .value_name("B")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(b) if b > 0 => Ok(()),
+                    _ => Err(format!("bad send batch '{}', must be a positive integer", s)),
+                })
+                .help(
+                    "Coalesce up to B requests that a pipelined connection can send back to \
+                     back into a single write, to save syscalls at high rates. Each is stamped \
+                     with the time of the write. Needs tcp or a unix socket, as a datagram \
+                     carries one request",
+                ),
+        )
+        .arg(
+            Arg::with_name("generators")
+                .long("generators")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("0")
+                .help(
+                    "Build the requests of each open-loop connection on N threads of their \
+                     own, ahead of when they are due, so the sender only has to pace and \
+                     write them. 0 builds them on the sender",
+                ),
+        )
+        .arg(
+            Arg::with_name("generator-lead")
+                .long("generator-lead")
+                .value_name("US")
+                .takes_value(true)
+                .default_value("100")
+                .help("Build a request no more than US before it is due, with --generators"),
+        )
+        .arg(
+            Arg::with_name("read-buffer")
+                .long("read-buffer")
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("4096")
+                .help(
+                    "Preallocate each connection's receive buffer with BYTES, which is reused \
+                     for every response and only grows for a longer read. A stream connection \
+                     holds the larger of BYTES and its largest response, up to 64 KiB, as \
+                     longer bodies are read in parts; a udp connection holds 64 KiB for each \
+                     datagram of a --udp-batch",
+                ),
+        )
+        .arg(
+            Arg::with_name("udp-batch")
+                .long("udp-batch")
+                .value_name("B")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(b) if b > 0 => Ok(()),
+                    _ => Err(format!("bad udp batch '{}', must be a positive integer", s)),
+                })
+                .help(
+                    "Send up to B due requests of an open-loop udp connection with one \
+                     sendmmsg, and receive up to B responses with one recvmmsg. Requests are \
+                     stamped as they are batched and responses as their batch is received",
+                ),
+        )
+        .arg(
+            Arg::with_name("udp-gso")
+                .long("udp-gso")
+                .help(
+                    "Send each run of equal-size requests of a udp batch as one send the \
+                     kernel segments, falling back to sendmmsg where it cannot. Requests are \
+                     still stamped as they are batched, so the report gives the longest one \
+                     waited for its send",
+                ),
+        )
+        .arg(
+            Arg::with_name("udp-gro")
+                .long("udp-gro")
+                .help(
+                    "Have the kernel coalesce responses a udp batch receives together, \
+                     splitting them back up as they are read, where it can",
+                ),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Secure client tcp and unix socket connections with TLS"),
+        )
+        .arg(
+            Arg::with_name("tls-server-name")
+                .long("tls-server-name")
+                .value_name("NAME")
+                .takes_value(true)
+                .requires("tls")
+                .help(
+                    "Name the server's certificate must be valid for. Defaults to the address \
+                     of the first server",
+                ),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .value_name("PATH")
+                .takes_value(true)
+                .help(
+                    "PEM bundle of the CAs the server's certificate may be issued by. \
+                     Defaults to the system's",
+                ),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("PATH")
+                .takes_value(true)
+                .requires_all(&["tls", "tls-key"])
+                .help("PEM client certificate chain, for servers that require one"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("PATH")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("PEM private key of --tls-cert"),
+        )
+        .arg(
+            Arg::with_name("tls-no-resume")
+                .long("tls-no-resume")
+                .requires("tls")
+                .help(
+                    "Make a full handshake on every connection, rather than resuming the \
+                     session of an earlier one",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-nodelay")
+                .long("no-nodelay")
+                .help(
+                    "Leave Nagle's algorithm on for client TCP connections, which otherwise set \
+                     TCP_NODELAY so that small requests are not held back",
+                ),
+        )
+        .arg(
+            Arg::with_name("send-buffer")
+                .long("send-buffer")
+                .value_name("BYTES")
+                .takes_value(true)
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(format!("bad send buffer '{}', must be a positive size", s)),
+                })
+                .help("Set SO_SNDBUF on every client connection"),
+        )
+        .arg(
+            Arg::with_name("recv-buffer")
+                .long("recv-buffer")
+                .value_name("BYTES")
+                .takes_value(true)
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(format!("bad receive buffer '{}', must be a positive size", s)),
+                })
+                .help("Set SO_RCVBUF on every client connection"),
+        )
+        .arg(
+            Arg::with_name("tos")
+                .long("tos")
+                .value_name("BYTE")
+                .takes_value(true)
+                .validator(|s| match s.parse::<u8>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("bad ToS '{}', must be from 0 to 255", s)),
+                })
+                .help(
+                    "Set the IP type of service byte of every client connection. The DSCP is \
+                     its upper six bits, so DSCP 46 is 184",
+                ),
+        )
+        .arg(
+            Arg::with_name("io-uring")
+                .long("io-uring")
+                .takes_value(false)
+                .help(
+                    "Do the socket I/O of client connections through io_uring, submitting \
+                     sends and taking responses from multishot receives. Falls back to \
+                     blocking sockets on kernels without it, before 6.0. The io-bench mode \
+                     compares the client CPU each request takes either way",
+                ),
+        )
+        .arg(
+            Arg::with_name("epoll")
+                .long("epoll")
+                .takes_value(false)
+                .help(
+                    "Read the responses of each thread's connections on one thread, which \
+                     waits on them all with epoll, instead of on a thread per connection. \
+                     Needs several connections per thread",
+                ),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECS")
+                .takes_value(true)
+                .default_value("1")
+                .help(
+                    "Print the requests sent and completed every SECS during each run, with \
+                     the latencies, misses, errors and timeouts of that interval alone and \
+                     the requests outstanding at its end, or list them in the JSON output",
+                ),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .takes_value(false)
+                .help(
+                    "Print no intervals during the run, for scripted runs. Unless --interval, \
+                     --csv-out or --metrics-addr is given, they are not counted at all",
+                ),
+        )
+        .arg(
+            Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .value_name("ADDR")
+                .takes_value(true)
+                .help(
+                    "Serve Prometheus metrics at http://ADDR/metrics while the client runs: \
+                     requests sent, responses, misses, errors, timeouts and reconnects by \
+                     server and opcode, and the requests outstanding, p99 latency and rate \
+                     of the last interval",
+                ),
+        )
+        .arg(
+            Arg::with_name("csv-out")
+                .long("csv-out")
+                .value_name("PATH")
+                .takes_value(true)
+                .help(
+                    "Write a CSV row per --interval, for plotting: its load offered, sent and \
+                     achieved, latency percentiles, misses, errors, timeouts and bytes written \
+                     and read. Each row is flushed as its interval closes",
+                ),
+        )
+        .arg(
+            Arg::with_name("warmup-time")
+                .long("warmup-time")
+                .value_name("SECS")
+                .takes_value(true)
+                .default_value("0")
+                .help(
+                    "Leave requests sent in the first SECS of each run out of its results, \
+                     counting them separately",
+                ),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("output mode")
+                .possible_values(&["silent", "normal", "buckets", "trace"])
+                .default_value("normal")
+                .help("How to display loadgen results"),
+        )
+        .arg(
+            Arg::with_name("csv-samples")
+                .long("csv-samples")
+                .takes_value(true)
+                .help(
+                    "Write timestamp, opcode, key, latency and status of each request to a \
+                     CSV file",
+                ),
+        )
+        .arg(
+            Arg::with_name("binary-samples")
+                .long("binary-samples")
+                .value_name("PATH")
+                .takes_value(true)
+                .help(
+                    "Write the send time, latency, opcode and status of each request to a \
+                     compact binary file, for runs with too many to keep as CSV",
+                ),
+        )
+        .arg(
+            Arg::with_name("binary-samples-max-mb")
+                .long("binary-samples-max-mb")
+                .value_name("MB")
+                .help(
+                    "Refuse a run whose requests could take the --binary-samples file past \
+                     this many MB, 1024 by default",
+                ),
+        )
+        .arg(
+            Arg::with_name("request-log")
+                .long("request-log")
+                .value_name("PATH")
+                .takes_value(true)
+                .help(
+                    "Write a CSV line per request to PATH.N for each send thread N, with its \
+                     send time, connection, opaque, opcode, key, key and value length, status \
+                     and latency, for debugging",
+                ),
+        )
+        .arg(
+            Arg::with_name("response-gaps")
+                .long("response-gaps")
+                .value_name("N")
+                .takes_value(true)
+                .conflicts_with("no-read")
+                .help(
+                    "Track the gaps between consecutive responses on each connection, and \
+                     report the longest of each and the N longest of all, with when they \
+                     began, to catch stalls of the server that the percentiles hide",
+                ),
+        )
+        .arg(
+            Arg::with_name("request-log-every")
+                .long("request-log-every")
+                .value_name("N")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(format!("bad sampling '{}', must be a positive integer", s)),
+                })
+                .help(
+                    "Log only one in every N requests of each thread, for runs at high load. \
+                     Every request is logged by default",
+                ),
+        )
+        .arg(
+            Arg::with_name("samples-to-csv")
+                .long("samples-to-csv")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Print a --binary-samples file as CSV, then exit"),
+        )
+        .arg(
+            Arg::with_name("histogram-csv")
+                .long("histogram-csv")
+                .value_name("PATH")
+                .takes_value(true)
+                .help(
+                    "Write the bounds and count of every bucket of each sample's latency \
+                     histogram to a CSV file",
+                ),
+        )
+        .arg(
+            Arg::with_name("histogram-digits")
+                .long("histogram-digits")
+                .value_name("D")
+                .takes_value(true)
+                .default_value("3")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(d) if (1..=histogram::MAX_DIGITS).contains(&d) => Ok(()),
+                    _ => Err(format!(
+                        "bad histogram digits '{}', must be from 1 to {}",
+                        s,
+                        histogram::MAX_DIGITS
+                    )),
+                })
+                .help(
+                    "Significant digits latencies are kept to in the histograms percentiles \
+                     are read from, which take memory growing tenfold with each digit but \
+                     not with the length of the run",
+                ),
+        )
+        .arg(
+            Arg::with_name("histogram-min")
+                .long("histogram-min")
+                .value_name("X")
+                .takes_value(true)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(x) if x >= 0.0 => Ok(()),
+                    _ => Err(format!("bad histogram min '{}', must be a non-negative number", s)),
+                })
+                .help(
+                    "Lowest latency the histograms keep, in microseconds or slowdown. Lower \
+                     ones are counted at it, and how many is reported",
+                ),
+        )
+        .arg(
+            Arg::with_name("histogram-max")
+                .long("histogram-max")
+                .value_name("X")
+                .takes_value(true)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(x) if x > 0.0 => Ok(()),
+                    _ => Err(format!("bad histogram max '{}', must be a positive number", s)),
+                })
+                .help(
+                    "Highest latency the histograms keep, in microseconds or slowdown, which \
+                     also bounds their memory. Higher ones are counted at it, and how many is \
+                     reported, as percentiles among them are the max rather than the latency",
+                ),
+        )
+        .arg(
+            Arg::with_name("throughput-interval")
+                .long("throughput-interval")
+                .value_name("MS")
+                .takes_value(true)
+                .help(
+                    "Count completions in buckets of this many milliseconds and report the \
+                     throughput of each",
+                ),
         )
         .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .takes_value(true),
+            Arg::with_name("connect-latency")
+                .long("connect-latency")
+                .takes_value(false)
+                .help(
+                    "Time how long each TCP connection takes to open and report a summary of them",
+                ),
         )
         .arg(
-            Arg::with_name("protocol")
-                .short("p")
-                .long("protocol")
-                .value_name("PROTOCOL")
-                .possible_values(&["synthetic", "memcached", "dns"])
-                .default_value("synthetic")
-                .help("Server protocol"),
+            Arg::with_name("throughput-csv")
+                .long("throughput-csv")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("throughput-interval")
+                .help("Also write the throughput of each bucket to a CSV file"),
         )
         .arg(
-            Arg::with_name("warmup")
-                .long("warmup")
-                .takes_value(false)
-                .help("Run the warmup routine"),
+            Arg::with_name("percentiles")
+                .long("percentiles")
+                .value_name("LIST")
+                .takes_value(true)
+                .validator(|s| parse_percentiles(&s).map(|_| ()))
+                .help(
+                    "Comma-separated latency percentiles to report besides the min, mean, \
+                     median, 90th, 99th, 99.9th, 99.99th and max, which always are",
+                ),
         )
         .arg(
-            Arg::with_name("output")
-                .short("o")
-                .long("output")
-                .value_name("output mode")
-                .possible_values(&["silent", "normal", "buckets", "trace"])
-                .default_value("normal")
-                .help("How to display loadgen results"),
+            Arg::with_name("output-format")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help(
+                    "Print results as text lines or as a single JSON object, whose \
+                     format_version changes with any field that is renamed, removed or \
+                     changes meaning",
+                ),
         )
         .arg(
             Arg::with_name("distribution")
                 .long("distribution")
                 .short("d")
                 .takes_value(true)
-                .possible_values(&[
-                    "zero",
-                    "constant",
-                    "exponential",
-                    "bimodal1",
-                    "bimodal2",
-                    "rocksdb",
-                ])
+                .validator(|s| Distribution::parse(&s, 1.0, 1.0, 1.0).map(|_| ()))
                 .default_value("zero")
-                .help("Distribution of request lengths to use"),
+                .help(
+                    "Distribution of request lengths to use: zero, rocksdb, a constant, one of \
+                     constant, exponential, bimodal1, bimodal2, lognormal or weibull scaled to \
+                     --mean, or constant:V, exp:MEAN, bimodal1:MEAN, bimodal2:MEAN, \
+                     gev:LOC,SCALE,SHAPE, gpareto:LOC,SCALE,SHAPE, lognormal:MU,SIGMA, \
+                     weibull:SHAPE,SCALE, bpareto:ALPHA,MIN,MAX, bimodal:P,V1,V2, \
+                     uniform:MIN,MAX, zipf:N,S, clamp:MIN,MAX,SPEC, empirical:CDF_FILE or \
+                     mixture:FILE",
+                ),
+        )
+        .arg(
+            Arg::with_name("describe-dist")
+                .long("describe-dist")
+                .takes_value(true)
+                .value_name("SPEC")
+                .validator(|s| Distribution::parse(&s, 1.0, 1.0, 1.0).map(|_| ()))
+                .help("Print the mean and quantiles of a distribution spec, then exit"),
         )
         .arg(
             Arg::with_name("mean")
@@ -874,6 +5713,20 @@ fn main() {
                 .default_value("167")
                 .help("Mean number of work iterations per request"),
         )
+        .arg(
+            Arg::with_name("sigma")
+                .long("sigma")
+                .takes_value(true)
+                .default_value("1.0")
+                .help("Shape parameter for the lognormal distribution"),
+        )
+        .arg(
+            Arg::with_name("shape")
+                .long("shape")
+                .takes_value(true)
+                .default_value("1.0")
+                .help("Shape parameter for the weibull distribution"),
+        )
         .arg(
             Arg::with_name("barrier-peers")
                 .long("barrier-peers")
@@ -902,231 +5755,4410 @@ fn main() {
                 .default_value("stridedmem:1024:7")
                 .help("fake worker spec"),
         )
-        .arg(
-            Arg::with_name("transport")
-                .long("transport")
-                .takes_value(true)
-                .default_value("udp")
-                .help("udp or tcp"),
+        .arg(
+            Arg::with_name("transport")
+                .long("transport")
+                .takes_value(true)
+                .default_value("udp")
+                .possible_values(&["udp", "tcp"])
+                .case_insensitive(true)
+                .help("udp or tcp"),
+        )
+        .arg(
+            Arg::with_name("ipv4")
+                .long("ipv4")
+                .short("4")
+                .conflicts_with("ipv6")
+                .help("Connect to the IPv4 address of a server whose name has both kinds"),
+        )
+        .arg(
+            Arg::with_name("ipv6")
+                .long("ipv6")
+                .short("6")
+                .help("Connect to the IPv6 address of a server whose name has both kinds"),
+        )
+        .arg(
+            Arg::with_name("source-ips")
+                .long("source-ips")
+                .value_name("IP,...")
+                .takes_value(true)
+                .conflicts_with("unix-socket")
+                .validator(|s| parse_source_ips(&s).map(|_| ()))
+                .help(
+                    "Bind client connections to these local addresses in turn, each \
+                     connection to one of its target's family. linux-client only",
+                ),
+        )
+        .arg(
+            Arg::with_name("source-ports")
+                .long("source-ports")
+                .value_name("FIRST-LAST")
+                .takes_value(true)
+                .conflicts_with("unix-socket")
+                .validator(|s| parse_port_range(&s).map(|_| ()))
+                .help(
+                    "Bind client connections to the ports of this range in turn, moving \
+                     to the next port once every source IP has one",
+                ),
+        )
+        .arg(
+            Arg::with_name("cpu-list")
+                .long("cpu-list")
+                .value_name("CPUS")
+                .takes_value(true)
+                .validator(|s| parse_cpu_list(&s).map(|_| ()))
+                .help(
+                    "Pin each client thread, with the threads receiving its responses, to \
+                     the next core of this list in turn, as 0-3,8. linux-client only",
+                ),
+        )
+        .arg(
+            Arg::with_name("unix-socket")
+                .long("unix-socket")
+                .value_name("PATH")
+                .takes_value(true)
+                .conflicts_with("ADDR")
+                .help(
+                    "Connect to the server listening on the unix socket at PATH, in place of \
+                     ADDR, with the same stream of requests as tcp. linux-client only",
+                ),
+        )
+        .arg(
+            Arg::with_name("rampup")
+                .long("rampup")
+                .takes_value(true)
+                .default_value("4")
+                .help("per-sample ramp up seconds"),
+        )
+        .arg(
+            Arg::with_name("load-shape")
+                .long("load-shape")
+                .value_name("SHAPE")
+                .takes_value(true)
+                .conflicts_with_all(&["count", "ramp", "slo-latency"])
+                .validator(|s| LoadShape::parse(&s).map(|_| ()))
+                .help(
+                    "Modulate each sample's rate with sine:AMPLITUDE,PERIOD_MS or \
+                     square:AMPLITUDE,PERIOD_MS, the amplitude a fraction of the rate, \
+                     reporting every --shape-bucket",
+                ),
+        )
+        .arg(
+            Arg::with_name("shape-bucket")
+                .long("shape-bucket")
+                .value_name("MS")
+                .takes_value(true)
+                .default_value("100")
+                .help("Milliseconds covered by each result of a shaped sample"),
+        )
+        .arg(
+            Arg::with_name("ramp")
+                .long("ramp")
+                .value_name("RATES")
+                .takes_value(true)
+                .conflicts_with_all(&["count", "loadshift", "slo-latency"])
+                .validator(|s| parse_rates(&s).map(|_| ()))
+                .help(
+                    "Run every rate, as R1,R2,... or START:STOP:STEP requests per second, \
+                     back to back in one sample of --duration each",
+                ),
+        )
+        .arg(
+            Arg::with_name("sweep")
+                .long("sweep")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with_all(&["count", "ramp", "loadshift", "slo-latency", "load-shape"])
+                .help(
+                    "Run the samples back to back over the same connections, each after \
+                     --rampup seconds of ramp up, and write a CSV row per sample to FILE",
+                ),
+        )
+        .arg(
+            Arg::with_name("sweep-points")
+                .long("sweep-points")
+                .value_name("RATES")
+                .takes_value(true)
+                .requires("sweep")
+                .validator(|s| parse_rates(&s).map(|_| ()))
+                .help(
+                    "Sweep through these rates in requests per second, as a list or \
+                     START:STOP:STEP, instead of --samples steps up to --mpps",
+                ),
+        )
+        .arg(
+            Arg::with_name("saturation")
+                .long("saturation")
+                .value_name("FRACTION")
+                .takes_value(true)
+                .default_value("0.9")
+                .help(
+                    "A sweep point is saturated once it achieves less than this fraction of its \
+                     offered load",
+                ),
+        )
+        .arg(
+            Arg::with_name("stop-on-saturation")
+                .long("stop-on-saturation")
+                .requires("sweep")
+                .help("Stop a sweep after its first saturated point"),
+        )
+        .arg(
+            Arg::with_name("loadshift")
+                .long("loadshift")
+                .takes_value(true)
+                .default_value("")
+                .help("loadshift spec"),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+
+    let mean = value_t_or_exit!(matches, "mean", f64);
+    let sigma = value_t_or_exit!(matches, "sigma", f64);
+    let shape = value_t_or_exit!(matches, "shape", f64);
+    assert!(shape > 0.0, "weibull shape must be positive");
+    let parse_distribution = |spec: &str| Distribution::parse(spec, mean, sigma, shape).unwrap();
+    if let Some(spec) = matches.value_of("describe-dist") {
+        describe_distribution(parse_distribution(spec));
+        return;
+    }
+    if let Some(path) = matches.value_of("samples-to-csv") {
+        let converted = File::open(path).and_then(|dump| samples_to_csv(dump, io::stdout()));
+        if let Err(e) = converted {
+            println!("Could not convert {}: {}", path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let family = if matches.is_present("ipv4") {
+        AddressFamily::V4
+    } else if matches.is_present("ipv6") {
+        AddressFamily::V6
+    } else {
+        AddressFamily::Any
+    };
+    // A unix socket is a single server, which has no address.
+    let addrs = match matches.value_of("ADDR") {
+        Some(spec) => Targets::parse(spec, family).unwrap_or_else(|e| panic!("{}", e)),
+        None => vec!["0.0.0.0:0".parse().unwrap()],
+    };
+    let weights = match matches.value_of("weights") {
+        Some(spec) => parse_weights(spec).unwrap(),
+        None => vec![1; addrs.len()],
+    };
+    assert!(
+        weights.len() == addrs.len(),
+        "--weights needs a weight for each of the {} servers",
+        addrs.len()
+    );
+    let targets = Targets {
+        addrs: Box::leak(addrs.into_boxed_slice()),
+        weights: Box::leak(weights.into_boxed_slice()),
+        key_hash: matches.is_present("key-hash"),
+    };
+    let addr = targets.addrs[0];
+    let mut nthreads = value_t_or_exit!(matches, "threads", usize);
+    let run_length = if matches.is_present("count") {
+        RunLength::Count(value_t_or_exit!(matches, "count", usize))
+    } else if matches.is_present("duration") {
+        RunLength::Duration(Duration::from_secs_f64(value_t_or_exit!(
+            matches, "duration", f64
+        )))
+    } else {
+        RunLength::Duration(Duration::from_nanos(value_t!(matches, "runtime",u64).unwrap()))
+    };
+    let packets_per_second = (1.0e6 * value_t_or_exit!(matches, "mpps", f32)) as usize;
+    let start_packets_per_second = (1.0e6 * value_t_or_exit!(matches, "start_mpps", f32)) as usize;
+    assert!(start_packets_per_second <= packets_per_second);
+    let arrival = match matches.value_of("interarrival") {
+        Some(spec) => Arrival::Spec(Box::leak(spec.to_string().into_boxed_str()), sigma, shape),
+        None => match matches.value_of("arrival").unwrap() {
+            "exponential" => Arrival::Exponential,
+            "constant" => Arrival::Constant,
+            "uniform" => Arrival::Uniform,
+            _ => unreachable!(),
+        },
+    };
+    let config = matches.value_of("config");
+    let dowarmup = matches.is_present("warmup");
+    if matches.is_present("self-profile") {
+        profile::enable();
+    }
+    let output = value_t_or_exit!(matches, "output", OutputMode);
+    let output_format = value_t_or_exit!(matches, "output-format", OutputFormat);
+    let tport = match matches.value_of("unix-socket") {
+        Some(path) => {
+            assert!(
+                matches.occurrences_of("transport") == 0,
+                "--unix-socket replaces --transport"
+            );
+            Transport::Unix(Box::leak(path.to_string().into_boxed_str()))
+        }
+        None => match &*matches.value_of("transport").unwrap().to_lowercase() {
+            "udp" => Transport::Udp,
+            "tcp" => Transport::Tcp,
+            _ => unreachable!(),
+        },
+    };
+    let distribution = parse_distribution(matches.value_of("distribution").unwrap());
+    let value_source = ValueSource::parse(matches.value_of("value-source").unwrap()).unwrap();
+    let mix = matches.value_of("mix").map(|s| s.parse::<OpMix>().unwrap());
+    let hot_key = matches.value_of("hot-key-fraction").map(|_| HotKey {
+        fraction: value_t_or_exit!(matches, "hot-key-fraction", f64),
+        key: value_t_or_exit!(matches, "hot-key", u64),
+    });
+    let proto = match matches.value_of("protocol").unwrap() {
+        "synthetic" => Protocol::Synthetic,
+        "memcached" => Protocol::Memcached(MemcachedProtocol {
+            workload: value_t_or_exit!(matches, "workload", Workload),
+            etc_value_size: matches.value_of("etc-value-size").map(parse_distribution),
+            etc_key_size: parse_distribution(matches.value_of("etc-key-size").unwrap()),
+            max_value_size: match tport {
+                // Every SET has to fit in a single datagram.
+                Transport::Udp => usize::min(
+                    value_t_or_exit!(matches, "max-value-size", usize),
+                    max_udp_value_size(targets.addrs),
+                ),
+                _ => value_t_or_exit!(matches, "max-value-size", usize),
+            },
+            verify: matches.is_present("verify"),
+            fanout: value_t_or_exit!(matches, "fanout", usize),
+            write_batch: value_t_or_exit!(matches, "write-batch", usize),
+            value_source,
+            hot_key,
+            key_distribution: matches.value_of("key-distribution").map(parse_distribution),
+            flags: value_t_or_exit!(matches, "flags", u32),
+            data_type: value_t_or_exit!(matches, "data-type", u8),
+            exptime: value_t_or_exit!(matches, "ttl", u32),
+            mix,
+            vbuckets: value_t_or_exit!(matches, "vbuckets", usize),
+            cas_writes: matches
+                .value_of("cas-writes")
+                .map(|_| value_t_or_exit!(matches, "cas-writes", f64)),
+            values_apart: false,
+        }),
+        "memcached-meta" => Protocol::MemcachedMeta(MetaProtocol {
+            ttl: value_t_or_exit!(matches, "ttl", u32),
+            value_source,
+            hot_key,
+            mix,
+        }),
+        "dns" => Protocol::Dns,
+        _ => unreachable!(),
+    };
+    if let Protocol::Memcached(m) = proto {
+        if let Workload::Etc = m.workload {
+            assert!(m.write_batch <= 1, "--write-batch needs the USR workload");
+            assert!(
+                m.key_distribution.is_none(),
+                "--key-distribution needs the USR workload"
+            );
+            m.init_etc_key_sizes();
+        }
+    }
+    if matches.is_present("key-distribution") {
+        assert!(
+            matches.value_of("protocol") == Some("memcached"),
+            "--key-distribution needs the memcached protocol"
+        );
+    }
+    if value_t_or_exit!(matches, "vbuckets", usize) > 0 {
+        assert!(
+            matches.value_of("protocol") == Some("memcached"),
+            "--vbuckets needs the memcached protocol"
+        );
+    }
+    if matches.is_present("cas-writes") {
+        assert!(
+            matches.value_of("protocol") == Some("memcached"),
+            "--cas-writes needs the memcached protocol"
+        );
+    }
+    let samples = value_t_or_exit!(matches, "samples", usize);
+    let rampup = value_t_or_exit!(matches, "rampup", usize);
+    let mode = matches.value_of("mode").unwrap();
+    let slowdown = matches.is_present("slowdown");
+    let quiet = matches.is_present("quiet");
+    let metrics = matches.value_of("metrics-addr").map(|addr| {
+        assert!(
+            mode == "linux-client" || mode == "runtime-client",
+            "--metrics-addr needs linux-client or runtime-client, as only they count requests"
+        );
+        let listener = std::net::TcpListener::bind(addr).unwrap_or_else(|e| {
+            println!("Could not listen for metrics on {}: {}", addr, e);
+            std::process::exit(1)
+        });
+        let protocol = matches.value_of("protocol").unwrap().to_string();
+        let metrics: &'static Metrics =
+            Box::leak(Box::new(Metrics::new(Box::leak(protocol.into_boxed_str()))));
+        metrics.serve(listener);
+        metrics
+    });
+    let mut client_opts = ClientOptions {
+        max_outstanding: value_t_or_exit!(matches, "max-outstanding", usize),
+        window_policy: value_t_or_exit!(matches, "window-policy", WindowPolicy),
+        timestamp: match matches.value_of("timestamp").unwrap() {
+            "pre-write" => SendTimestamp::PreWrite,
+            "post-write" => SendTimestamp::PostWrite,
+            _ => unreachable!(),
+        },
+        pipeline_depth: match value_t_or_exit!(matches, "load-mode", LoadMode) {
+            LoadMode::Open => value_t_or_exit!(matches, "pipeline-depth", usize),
+            LoadMode::Closed => usize::max(value_t_or_exit!(matches, "pipeline-depth", usize), 1),
+        },
+        think_time: parse_distribution(matches.value_of("think-time").unwrap()),
+        run_length,
+        stop_on_saturation: if matches.is_present("stop-on-saturation") {
+            Some(value_t_or_exit!(matches, "saturation", f64))
+        } else {
+            None
+        },
+        warmup: Duration::from_secs_f64(value_t_or_exit!(matches, "warmup-time", f64)),
+        drain_timeout: Duration::from_millis(value_t_or_exit!(matches, "drain-timeout", u64)),
+        interval: match quiet
+            && matches.occurrences_of("interval") == 0
+            && !matches.is_present("csv-out")
+            && metrics.is_none()
+        {
+            true => None,
+            false => Some(Duration::from_secs_f64(value_t_or_exit!(
+                matches, "interval", f64
+            ))),
+        },
+        quiet,
+        metrics,
+        rate_limit: matches.value_of("rate-limit").map(|_| {
+            (
+                value_t_or_exit!(matches, "rate-limit", f64),
+                match matches.value_of("burst") {
+                    Some(_) => value_t_or_exit!(matches, "burst", usize),
+                    None => nthreads,
+                },
+            )
+        }),
+        session_length: matches.value_of("session-length").map(parse_distribution),
+        handshake: matches.is_present("handshake"),
+        no_read: matches.is_present("no-read"),
+        trace: matches
+            .value_of("trace")
+            .map(|path| read_trace(path).unwrap()),
+        replay_speed: matches
+            .value_of("replay-speed")
+            .map_or(1.0, |_| value_t_or_exit!(matches, "replay-speed", f64)),
+        conns_per_thread: usize::max(value_t_or_exit!(matches, "conns-per-thread", usize), 1),
+        pool_policy: value_t_or_exit!(matches, "pool-policy", PoolPolicy),
+        reconnect: !matches.is_present("no-reconnect"),
+        request_timeout: matches
+            .value_of("request-timeout")
+            .map(|_| Duration::from_millis(value_t_or_exit!(matches, "request-timeout", u64))),
+        retransmits: matches
+            .value_of("retransmits")
+            .map_or(0, |_| value_t_or_exit!(matches, "retransmits", usize)),
+        watchdog: matches
+            .value_of("watchdog")
+            .map(|_| Duration::from_millis(value_t_or_exit!(matches, "watchdog", u64))),
+        sockets: SocketOptions {
+            nodelay: !matches.is_present("no-nodelay"),
+            send_buffer: matches
+                .value_of("send-buffer")
+                .map(|_| value_t_or_exit!(matches, "send-buffer", usize)),
+            recv_buffer: matches
+                .value_of("recv-buffer")
+                .map(|_| value_t_or_exit!(matches, "recv-buffer", usize)),
+            tos: matches
+                .value_of("tos")
+                .map(|_| value_t_or_exit!(matches, "tos", u8)),
+        },
+        io_uring: matches.is_present("io-uring"),
+        epoll: matches.is_present("epoll"),
+        send_batch: value_t_or_exit!(matches, "send-batch", usize),
+        udp_batch: value_t_or_exit!(matches, "udp-batch", usize),
+        udp_gso: matches.is_present("udp-gso"),
+        udp_gro: matches.is_present("udp-gro"),
+        generators: value_t_or_exit!(matches, "generators", usize),
+        generator_lead: Duration::from_micros(value_t_or_exit!(matches, "generator-lead", u64)),
+        read_buffer: value_t_or_exit!(matches, "read-buffer", usize),
+        tls: if matches.is_present("tls") {
+            // A unix socket has no address to stand in for the name. A host
+            // name given for the server is the name expected, not the address
+            // it resolved to.
+            let name = match matches.value_of("tls-server-name") {
+                Some(name) => name.to_string(),
+                None if matches.is_present("unix-socket") => {
+                    panic!("--tls over a unix socket needs --tls-server-name")
+                }
+                None => {
+                    let server = matches.value_of("ADDR").unwrap().split(',').next().unwrap();
+                    match server.trim().rsplit_once(':').map(|(host, _)| host) {
+                        // Neither an IPv4 nor a bracketed IPv6 literal.
+                        Some(host)
+                            if !host.starts_with('[') && host.parse::<Ipv4Addr>().is_err() =>
+                        {
+                            host.to_string()
+                        }
+                        _ => addr.ip().to_string(),
+                    }
+                }
+            };
+            let identity = matches
+                .value_of("tls-cert")
+                .map(|cert| (cert, matches.value_of("tls-key").unwrap()));
+            let client = TlsClient::new(
+                &name,
+                matches.value_of("tls-ca"),
+                identity,
+                !matches.is_present("tls-no-resume"),
+            )
+            .unwrap_or_else(|e| panic!("{}", e));
+            Some(&*Box::leak(Box::new(client)))
+        } else {
+            None
+        },
+        sources: Sources::new(
+            matches
+                .value_of("source-ips")
+                .map_or_else(Vec::new, |spec| parse_source_ips(spec).unwrap()),
+            matches
+                .value_of("source-ports")
+                .map(|spec| parse_port_range(spec).unwrap()),
+        ),
+        cpus: CpuList::new(
+            matches
+                .value_of("cpu-list")
+                .map_or_else(Vec::new, |spec| parse_cpu_list(spec).unwrap()),
+        ),
+        seed: matches
+            .value_of("seed")
+            .map(|_| value_t_or_exit!(matches, "seed", u64)),
+        slowdown,
+    };
+    // Connection i of thread t is connection t * conns_per_thread + i of the
+    // run, so every run opens and numbers them the same way.
+    if matches.is_present("connections") {
+        let connections = value_t_or_exit!(matches, "connections", usize);
+        assert!(connections > 0, "--connections must be positive");
+        if client_opts.pipeline_depth > 0 {
+            nthreads = connections;
+        } else {
+            assert!(
+                connections % nthreads == 0,
+                "--connections must be a multiple of --threads"
+            );
+            client_opts.conns_per_thread = connections / nthreads;
+        }
+    }
+    // Warmup and loadshift schedules run for their own fixed times.
+    let timed_opts = ClientOptions {
+        run_length: RunLength::Duration(Duration::from_secs(0)),
+        stop_on_saturation: None,
+        ..client_opts
+    };
+    // Requests coalesced into one datagram would read as a single request.
+    if let (Transport::Udp, true) = (tport, client_opts.send_batch > 1) {
+        panic!("--send-batch needs tcp or a unix socket");
+    }
+    if let Transport::Udp = tport {
+        assert!(client_opts.pipeline_depth == 0, "pipelining requires tcp");
+    }
+    if client_opts.tls.is_some() {
+        if let Transport::Udp = tport {
+            panic!("--tls needs tcp or a unix socket");
+        }
+    }
+    if client_opts.send_batch > 1 {
+        assert!(
+            client_opts.pipeline_depth > 0,
+            "--send-batch needs a closed-loop or pipelined tcp run"
+        );
+    }
+    if client_opts.session_length.is_some() {
+        assert!(
+            client_opts.pipeline_depth > 0,
+            "--session-length needs a closed-loop or pipelined tcp run"
+        );
+    }
+    if matches.is_present("cas-writes") {
+        assert!(
+            client_opts.pipeline_depth > 0,
+            "--cas-writes takes each key's CAS from the response to its GET, so it needs a run \
+             whose connections match their own responses to requests: closed-loop or pipelined"
+        );
+    }
+    if let (Some(_), Some(_)) = (
+        matches.value_of("histogram-min"),
+        matches.value_of("histogram-max"),
+    ) {
+        assert!(
+            value_t_or_exit!(matches, "histogram-min", f64)
+                < value_t_or_exit!(matches, "histogram-max", f64),
+            "--histogram-min must be below --histogram-max"
+        );
+    }
+    if client_opts.generators > 0 {
+        assert!(
+            client_opts.pipeline_depth == 0 && client_opts.conns_per_thread == 1,
+            "--generators needs open-loop sends over one connection per thread"
+        );
+    }
+    if client_opts.udp_batch > 1 {
+        let udp = matches!(tport, Transport::Udp);
+        assert!(
+            udp && client_opts.pipeline_depth == 0 && client_opts.conns_per_thread == 1,
+            "--udp-batch needs an open-loop udp run with one connection per thread"
+        );
+    }
+    if client_opts.udp_gso || client_opts.udp_gro {
+        assert!(
+            client_opts.udp_batch > 1,
+            "--udp-gso and --udp-gro need --udp-batch above 1"
+        );
+    }
+    if client_opts.handshake {
+        assert!(
+            matches.value_of("protocol") == Some("memcached"),
+            "--handshake needs the memcached protocol"
+        );
+    }
+    if client_opts.no_read {
+        assert!(
+            client_opts.pipeline_depth == 0 && client_opts.max_outstanding == 0,
+            "--no-read needs open-loop sends with no window, which responses would free"
+        );
+    }
+    if client_opts.conns_per_thread > 1 {
+        assert!(
+            client_opts.pipeline_depth == 0 && client_opts.max_outstanding == 0,
+            "--conns-per-thread needs open-loop sends with no window"
+        );
+        assert!(
+            targets.addrs.len() == 1,
+            "--conns-per-thread needs a single server"
+        );
+        // A pool leaves a failed connection's share of the requests unsent.
+        assert!(
+            !client_opts.reconnect,
+            "--conns-per-thread does not reconnect, so it needs --no-reconnect"
+        );
+    }
+    if client_opts.retransmits > 0 {
+        match tport {
+            Transport::Udp => (),
+            _ => panic!("--retransmits needs udp, where requests can be lost"),
+        }
+        assert!(
+            client_opts.request_timeout.is_some(),
+            "--retransmits needs --request-timeout"
+        );
+        assert!(
+            client_opts.conns_per_thread == 1,
+            "--retransmits needs a single connection per thread"
+        );
+    }
+    if client_opts.rate_limit.is_some() {
+        assert!(
+            client_opts.pipeline_depth == 0,
+            "--rate-limit applies to open-loop sends"
+        );
+    }
+    if targets.addrs.len() > 1 {
+        assert!(
+            mode == "linux-client" || mode == "runtime-client",
+            "only a client can have several servers"
+        );
+        assert!(
+            nthreads >= targets.addrs.len(),
+            "each server needs a connection of its own, so at least as many threads"
+        );
+    }
+    if client_opts.trace.is_some() {
+        match proto {
+            Protocol::Memcached(_) | Protocol::MemcachedMeta(_) => {}
+            _ => panic!("--trace needs a memcached protocol"),
+        }
+    }
+    if matches.is_present("replay-speed") {
+        assert!(client_opts.trace.is_some(), "--replay-speed needs --trace");
+        if client_opts.trace.and_then(trace_period).is_none() {
+            println!(
+                "Warning: --replay-speed applies only to a trace with times, so the trace is \
+                 replayed at the configured rate"
+            );
+        }
+    }
+    if hot_key.is_some() {
+        match proto {
+            Protocol::Memcached(_) | Protocol::MemcachedMeta(_) => {}
+            _ => panic!("--hot-key-fraction needs a memcached protocol"),
+        }
+    }
+    if targets.key_hash {
+        match proto {
+            Protocol::Memcached(m) => assert!(
+                m.fanout <= 1 && m.write_batch <= 1,
+                "--key-hash cannot route a multiget or write batch, whose keys live on \
+                 different servers"
+            ),
+            Protocol::MemcachedMeta(_) => {}
+            _ => panic!("--key-hash needs a memcached protocol"),
+        }
+    }
+    if let RunLength::Count(_) = run_length {
+        assert!(mode != "local-client", "local-client runs are timed only");
+    }
+    if matches.is_present("size-classes") {
+        match proto {
+            Protocol::Memcached(MemcachedProtocol {
+                workload: Workload::Etc,
+                ..
+            }) => {}
+            _ => panic!("--size-classes needs the memcached etc workload"),
+        }
+    }
+    if mix.is_some() {
+        match proto {
+            Protocol::Memcached(_) | Protocol::MemcachedMeta(_) => {}
+            _ => panic!("--mix needs a memcached protocol"),
+        }
+    }
+    if let Transport::Unix(_) = tport {
+        assert!(
+            mode == "linux-client",
+            "--unix-socket needs linux-client, as the runtime has no unix sockets"
+        );
+    }
+    if client_opts.watchdog.is_some() {
+        assert!(
+            mode == "linux-client",
+            "--watchdog needs linux-client, whose sockets can time out reads"
+        );
+    }
+    if let (Transport::Udp, Some(trace)) = (tport, client_opts.trace) {
+        let fits = max_udp_value_size(targets.addrs);
+        if let Some(size) = trace.iter().filter_map(|r| r.value_size).max() {
+            assert!(
+                size <= fits,
+                "--trace sets a {} byte value, but a datagram holds at most {}",
+                size,
+                fits
+            );
+        }
+    }
+    if matches.is_present("source-ips") {
+        assert!(
+            mode == "linux-client",
+            "--source-ips needs linux-client, as the runtime has a single address"
+        );
+        client_opts
+            .sources
+            .check(targets.addrs)
+            .unwrap_or_else(|e| panic!("--source-ips: {}", e));
+    }
+    if client_opts.cpus.is_set() {
+        assert!(
+            mode == "linux-client",
+            "--cpu-list needs linux-client, as the runtime places its own threads"
+        );
+        match client_opts.cpus.check() {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                panic!("--cpu-list: {}", e)
+            }
+            Err(e) => {
+                println!("Warning: not pinning client threads: {}", e);
+                client_opts.cpus = CpuList::default();
+            }
+            Ok(()) => {}
+        }
+    }
+    if client_opts.io_uring {
+        assert!(
+            mode == "linux-client",
+            "--io-uring needs linux-client, as the runtime has sockets of its own"
+        );
+        if let Err(e) = uring::check_support() {
+            println!(
+                "Warning: using blocking sockets, io_uring is unavailable: {}",
+                e
+            );
+            client_opts.io_uring = false;
+        }
+    }
+    if client_opts.epoll {
+        assert!(
+            mode == "linux-client",
+            "--epoll needs linux-client, as the runtime has sockets of its own"
+        );
+        assert!(
+            client_opts.conns_per_thread > 1,
+            "--epoll needs several connections per thread"
+        );
+        assert!(
+            client_opts.tls.is_none() && !client_opts.io_uring,
+            "--epoll reads plain sockets, not through --tls or --io-uring"
+        );
+    }
+    if targets.addrs.iter().any(|a| a.is_ipv6()) {
+        assert!(
+            mode.starts_with("linux"),
+            "IPv6 needs linux-client or linux-server, as the runtime has no IPv6"
+        );
+    }
+    let backend = match mode {
+        "linux-server" | "linux-client" | "io-bench" => Backend::Linux,
+        "spawner-server" | "runtime-client" | "work-bench" | "local-client" => Backend::Runtime,
+        _ => unreachable!(),
+    };
+    let mut barrier_group = matches.value_of("barrier-leader").map(|leader| {
+        lockstep::Group::from_hostname(
+            leader,
+            23232,
+            value_t_or_exit!(matches, "barrier-peers", usize),
+        )
+        .unwrap()
+    });
+    let (key_size, value_size) = match proto {
+        Protocol::Memcached(m) => {
+            let (k, v) = m.sizes();
+            (Some(k), Some(v))
+        }
+        Protocol::MemcachedMeta(_) => (
+            Some(memcached::KEY_SIZE.to_string()),
+            Some(memcached::VALUE_SIZE.to_string()),
+        ),
+        Protocol::Synthetic | Protocol::Dns => (None, None),
+    };
+    let metadata = RunMetadata {
+        protocol: matches.value_of("protocol").unwrap().to_string(),
+        workload: match proto {
+            Protocol::Memcached(m) => Some(m.workload.to_string().to_lowercase()),
+            Protocol::MemcachedMeta(_) => Some("usr".to_string()),
+            Protocol::Synthetic | Protocol::Dns => None,
+        },
+        transport: tport.name().to_string(),
+        targets: match tport {
+            Transport::Unix(path) => vec![path.to_string()],
+            _ => targets.addrs.iter().map(|a| a.to_string()).collect(),
+        },
+        // Filled in once the connections are open.
+        address_families: None,
+        source_ips: client_opts.sources.to_strings().0,
+        source_ports: client_opts.sources.to_strings().1,
+        cpu_list: client_opts.cpus.to_string(),
+        source_addresses: None,
+        weights: matches
+            .value_of("weights")
+            .map(|_| targets.weights.to_vec()),
+        routing: if targets.key_hash {
+            Some("key-hash".to_string())
+        } else {
+            None
+        },
+        offered_rate: packets_per_second as u64,
+        arrival: matches
+            .value_of("interarrival")
+            .or(matches.value_of("arrival"))
+            .map(String::from),
+        service: matches
+            .value_of("distribution")
+            .map(|spec| distribution_spec(spec, distribution)),
+        duration_ns: match run_length {
+            RunLength::Duration(d) => Some(duration_to_ns(d)),
+            RunLength::Count(_) => None,
+        },
+        count: match run_length {
+            RunLength::Duration(_) => None,
+            RunLength::Count(n) => Some(n as u64),
+        },
+        outstanding: match client_opts.pipeline_depth {
+            0 => None,
+            depth => Some(depth as u64),
+        },
+        connections: match client_opts.pipeline_depth {
+            0 if client_opts.conns_per_thread == 1 => None,
+            _ => Some((nthreads * client_opts.conns_per_thread) as u64),
+        },
+        session_length: client_opts.session_length.map(|d| format!("{:?}", d)),
+        no_read: client_opts.no_read,
+        latency_breakdown: matches.is_present("latency-breakdown"),
+        histogram_digits: match value_t_or_exit!(matches, "histogram-digits", u32) {
+            histogram::DEFAULT_DIGITS => None,
+            d => Some(d),
+        },
+        histogram_min: matches
+            .value_of("histogram-min")
+            .map(|_| value_t_or_exit!(matches, "histogram-min", f64)),
+        histogram_max: matches
+            .value_of("histogram-max")
+            .map(|_| value_t_or_exit!(matches, "histogram-max", f64)),
+        trace: matches.value_of("trace").map(String::from),
+        replay_speed: client_opts
+            .trace
+            .and_then(trace_period)
+            .map(|_| client_opts.replay_speed),
+        hot_key: hot_key.map(|h| h.key),
+        hot_key_fraction: hot_key.map(|h| h.fraction),
+        key_distribution: matches.value_of("key-distribution").map(|s| s.to_string()),
+        op_mix: matches.value_of("mix").map(|s| s.to_string()),
+        cas_writes: match proto {
+            Protocol::Memcached(ref m) => m.cas_writes,
+            _ => None,
+        },
+        max_retransmits: match client_opts.retransmits {
+            0 => None,
+            k => Some(k),
+        },
+        request_timeout_ms: client_opts
+            .request_timeout
+            .map(|t| duration_to_ns(t) / 1_000_000),
+        watchdog_ms: client_opts.watchdog.map(|t| duration_to_ns(t) / 1_000_000),
+        tcp_nodelay: match tport {
+            Transport::Tcp => Some(client_opts.sockets.nodelay),
+            _ => None,
+        },
+        send_batch: match client_opts.send_batch {
+            1 => None,
+            b => Some(b),
+        },
+        udp_batch: match client_opts.udp_batch {
+            1 => None,
+            b => Some(b),
+        },
+        generators: match client_opts.generators {
+            0 => None,
+            n => Some(n),
+        },
+        generator_lead_us: match client_opts.generators {
+            0 => None,
+            _ => Some(client_opts.generator_lead.as_micros() as u64),
+        },
+        read_buffer: Some(client_opts.read_buffer),
+        io_uring: client_opts.io_uring,
+        epoll: client_opts.epoll,
+        send_buffer: client_opts.sockets.send_buffer,
+        recv_buffer: client_opts.sockets.recv_buffer,
+        tos: client_opts.sockets.tos,
+        tls_server_name: client_opts.tls.map(|tls| tls.name().to_string()),
+        tls_resumption: client_opts
+            .tls
+            .map(|_| !matches.is_present("tls-no-resume")),
+        tls_client_cert: matches.value_of("tls-cert").map(|s| s.to_string()),
+        size_classes: matches
+            .value_of("size-classes")
+            .map(|s| parse_size_classes(s).unwrap()),
+        conns_per_thread: match client_opts.conns_per_thread {
+            1 => None,
+            n => Some(n as u64),
+        },
+        think_time: match client_opts.pipeline_depth {
+            0 => None,
+            _ => Some(client_opts.think_time.to_string()),
+        },
+        seed: client_opts.seed,
+        key_size,
+        value_size,
+    };
+    let sample_writer = matches.value_of("csv-samples").map(|path| {
+        SampleWriter::create(path).unwrap_or_else(|e| {
+            println!("Could not create {}: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+    if matches.is_present("binary-samples-max-mb") {
+        assert!(
+            matches.is_present("binary-samples"),
+            "--binary-samples-max-mb needs --binary-samples"
+        );
+    }
+    let binary_sample_writer = matches.value_of("binary-samples").map(|path| {
+        let cap = matches.value_of("binary-samples-max-mb").map_or(1024, |_| {
+            value_t_or_exit!(matches, "binary-samples-max-mb", u64)
+        }) << 20;
+        BinarySampleWriter::create(path, cap).unwrap_or_else(|e| {
+            println!("Could not create {}: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+    if matches.is_present("request-log-every") {
+        assert!(
+            matches.is_present("request-log"),
+            "--request-log-every needs --request-log"
+        );
+    }
+    let request_log = matches.value_of("request-log").map(|path| {
+        assert!(
+            mode == "linux-client" || mode == "runtime-client",
+            "--request-log needs linux-client or runtime-client, as requests are only logged there"
+        );
+        let every = matches
+            .value_of("request-log-every")
+            .map_or(1, |_| value_t_or_exit!(matches, "request-log-every", usize));
+        RequestLogWriter::create(path, every).unwrap_or_else(|e| {
+            println!("Could not create {}.0: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+    let histogram_writer = matches.value_of("histogram-csv").map(|path| {
+        HistogramWriter::create(path).unwrap_or_else(|e| {
+            println!("Could not create {}: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+    let response_gaps = matches.value_of("response-gaps").map(|_| {
+        assert!(
+            mode == "linux-client" || mode == "runtime-client",
+            "--response-gaps needs linux-client or runtime-client, as only they read responses"
+        );
+        value_t_or_exit!(matches, "response-gaps", usize)
+    });
+    let preflight_check = matches.is_present("preflight");
+    assert!(
+        !preflight_check || mode == "linux-client" || mode == "runtime-client",
+        "--preflight needs linux-client or runtime-client, as only they have a server to check"
+    );
+    let interval_writer = matches.value_of("csv-out").map(|path| {
+        assert!(
+            mode == "linux-client" || mode == "runtime-client",
+            "--csv-out needs linux-client or runtime-client, as intervals are only counted there"
+        );
+        IntervalWriter::create(path).unwrap_or_else(|e| {
+            println!("Could not create {}: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+    let throughput = matches.value_of("throughput-interval").map(|_| {
+        let csv = matches.value_of("throughput-csv").map(|path| {
+            File::create(path).unwrap_or_else(|e| {
+                println!("Could not create {}: {}", path, e);
+                std::process::exit(1)
+            })
+        });
+        ThroughputSeries::new(
+            Duration::from_millis(value_t_or_exit!(matches, "throughput-interval", u64)),
+            csv,
+        )
+    });
+    let connects = if matches.is_present("connect-latency") {
+        Some(ConnectLatencies::new())
+    } else {
+        None
+    };
+    if let OutputFormat::Text = output_format {
+        println!("Slowdown: {}", slowdown);
+    }
+
+    let percentiles = reported_percentiles(
+        &matches
+            .value_of("percentiles")
+            .map_or(Ok(Vec::new()), parse_percentiles)
+            .unwrap(),
+    );
+
+    let slo_percentile = value_t_or_exit!(matches, "slo-percentile", f32);
+    let slo_confirm = Duration::from_secs_f64(value_t_or_exit!(matches, "slo-confirm", f64));
+    let slo = if matches.is_present("slo-latency") {
+        if let OutputMode::Silent = output {
+            panic!("SLO search needs results, so cannot run silent");
+        }
+        if !percentiles.contains(&slo_percentile) {
+            panic!("SLO search needs {} in --percentiles", slo_percentile);
+        }
+        let target = value_t_or_exit!(matches, "slo-latency", f32);
+        Some(match matches.value_of("slo-search").unwrap() {
+            "aimd" => SloSearch::Aimd(SloController::new(
+                target,
+                start_packets_per_second,
+                (1.0e6 * value_t_or_exit!(matches, "slo-step", f64)) as usize,
+                value_t_or_exit!(matches, "slo-backoff", f64),
+                packets_per_second,
+            )),
+            "bisect" => SloSearch::Bisect(Bisection::new(
+                target,
+                start_packets_per_second,
+                packets_per_second,
+                (1.0e6 * value_t_or_exit!(matches, "slo-tolerance", f64)) as usize,
+                value_t_or_exit!(matches, "slo-repeats", usize),
+            )),
+            _ => unreachable!(),
+        })
+    } else {
+        None
+    };
+
+    let load_shape =
+        LoadShape::parse(matches.value_of("load-shape").unwrap_or("constant")).unwrap();
+    let shape_bucket = Duration::from_millis(value_t_or_exit!(matches, "shape-bucket", u64));
+    let ramp = matches
+        .value_of("ramp")
+        .map(|spec| parse_rates(spec).unwrap());
+    if ramp.is_some() {
+        assert!(mode != "local-client", "local-client runs cannot ramp");
+    }
+    let sweep = matches.value_of("sweep").map(|path| {
+        assert!(mode != "local-client", "local-client runs cannot sweep");
+        if let OutputMode::Silent = output {
+            panic!("A sweep needs results, so cannot run silent");
+        }
+        let writer = SweepWriter::create(
+            path,
+            &percentiles,
+            value_t_or_exit!(matches, "saturation", f64),
+        )
+        .unwrap_or_else(|e| {
+            println!("Could not create {}: {}", path, e);
+            std::process::exit(1)
+        });
+        let step_size = (packets_per_second - start_packets_per_second) / samples;
+        let rates = match matches.value_of("sweep-points") {
+            Some(spec) => parse_rates(spec).unwrap(),
+            None => (1..=samples)
+                .map(|j| start_packets_per_second + step_size * j)
+                .collect(),
+        };
+        (rates, writer)
+    });
+    let loadshift_spec = value_t_or_exit!(matches, "loadshift", String);
+    let fakeworker = FakeWorker::create(matches.value_of("fakework").unwrap()).unwrap();
+
+    match mode {
+        "work-bench" => {
+            let iterations = 100_000_000;
+            println!("Timing {} iterations of work()", iterations);
+            let start = Instant::now();
+            fakeworker.work(iterations);
+            let elapsed = duration_to_ns(start.elapsed());
+            println!("Rate = {} ns/iteration", elapsed as f64 / iterations as f64);
+        }
+        "io-bench" => {
+            let requests = 100_000;
+            println!(
+                "Timing {} requests to {} over each I/O path",
+                requests, addr
+            );
+            let mut paths = vec![false];
+            match uring::check_support() {
+                Ok(()) => paths.push(true),
+                Err(e) => println!("Skipping io_uring, which is unavailable: {}", e),
+            }
+            for io_uring in paths {
+                let opts = ClientOptions {
+                    io_uring,
+                    ..client_opts
+                };
+                let (cpu, rate) = io_bench(proto, tport, addr, opts, requests)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                println!(
+                    "{}: {:.0} ns CPU/request, {:.0} requests/s",
+                    if io_uring { "io_uring" } else { "blocking" },
+                    cpu,
+                    rate
+                );
+            }
+        }
+        "spawner-server" => match tport {
+            Transport::Udp => {
+                let addr = match addr {
+                    SocketAddr::V4(addr) => addr,
+                    SocketAddr::V6(_) => unreachable!(),
+                };
+                backend.init_and_run(config, move || run_spawner_server(addr, fakeworker))
+            }
+            Transport::Tcp => {
+                backend.init_and_run(config, move || run_tcp_server(backend, addr, fakeworker))
+            }
+            Transport::Unix(_) => unreachable!(),
+        },
+        "linux-server" => match tport {
+            Transport::Udp => backend.init_and_run(config, move || {
+                run_linux_udp_server(backend, addr, nthreads, fakeworker)
+            }),
+            Transport::Tcp => {
+                backend.init_and_run(config, move || run_tcp_server(backend, addr, fakeworker))
+            }
+            Transport::Unix(_) => unreachable!(),
+        },
+        "local-client" => {
+            backend.init_and_run(config, move || {
+                let mut report = Report::new(
+                    output_format,
+                    metadata,
+                    sample_writer,
+                    None,
+                    throughput,
+                    None,
+                    percentiles,
+                );
+                if let Some(writer) = histogram_writer {
+                    report.write_histograms(writer);
+                }
+                if let Some(writer) = binary_sample_writer {
+                    report.write_binary_samples(writer);
+                }
+                if dowarmup {
+                    for packets_per_second in (1..3).map(|i| i * 100000) {
+                        let sched = gen_classic_packet_schedule(
+                            Duration::from_secs(1),
+                            packets_per_second,
+                            arrival,
+                            OutputMode::Silent,
+                            distribution,
+                            0,
+                            nthreads,
+                        );
+                        run_local(backend, nthreads, fakeworker.clone(), &sched, &mut report);
+                    }
+                }
+                let step_size = (packets_per_second - start_packets_per_second) / samples;
+                for j in 1..=samples {
+                    let rate = start_packets_per_second + step_size * j;
+                    let sched = shape_schedule(
+                        gen_classic_packet_schedule(
+                            run_length.runtime(rate),
+                            rate,
+                            arrival,
+                            output,
+                            distribution,
+                            0,
+                            nthreads,
+                        ),
+                        load_shape,
+                        shape_bucket,
+                    );
+                    run_local(backend, nthreads, fakeworker.clone(), &sched, &mut report);
+                    backend.sleep(Duration::from_secs(3));
+                }
+                report.finish();
+            });
+        }
+        "linux-client" | "runtime-client" => {
+            backend.init_and_run(config, move || {
+                let (sweep_rates, sweep_writer) = match sweep {
+                    Some((rates, writer)) => (Some(rates), Some(writer)),
+                    None => (None, None),
+                };
+                let mut report = Report::new(
+                    output_format,
+                    metadata,
+                    sample_writer,
+                    sweep_writer,
+                    throughput,
+                    connects,
+                    percentiles,
+                );
+                if let Some(writer) = histogram_writer {
+                    report.write_histograms(writer);
+                }
+                if let Some(writer) = binary_sample_writer {
+                    report.write_binary_samples(writer);
+                }
+                if let Some(writer) = interval_writer {
+                    report.write_intervals(writer);
+                }
+                if let Some(log) = request_log {
+                    report.write_request_log(log);
+                }
+                if let Some(top) = response_gaps {
+                    report.track_response_gaps(top);
+                }
+                if preflight_check {
+                    for &addr in targets.addrs {
+                        if let Err(e) = preflight(proto, backend, tport, addr, client_opts) {
+                            println!("Preflight check of {} failed: {}", addr, e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                match (proto, &barrier_group) {
+                    (_, Some(lockstep::Group::Client(ref _c))) => (),
+                    (Protocol::Memcached(m), _) => {
+                        preload_targets(m, backend, tport, client_opts.tls, targets, nthreads);
+                    }
+                    // The server speaks both protocols, so preload over the binary one.
+                    (Protocol::MemcachedMeta(meta), _) => {
+                        let m = MemcachedProtocol {
+                            value_source: meta.value_source,
+                            exptime: meta.ttl,
+                            ..Default::default()
+                        };
+                        preload_targets(m, backend, tport, client_opts.tls, targets, nthreads);
+                    }
+                    _ => (),
+                };
+
+                let client = Client {
+                    backend,
+                    protocol: proto,
+                    tport,
+                    opts: client_opts,
+                };
+                let timed = Client {
+                    opts: timed_opts,
+                    ..client
+                };
+                if !loadshift_spec.is_empty() {
+                    let sched =
+                        gen_loadshift_experiment(&loadshift_spec, arrival, distribution, nthreads);
+                    run_client(
+                        timed,
+                        targets,
+                        nthreads,
+                        &mut barrier_group,
+                        &sched,
+                        0,
+                        &mut report,
+                    );
+                    report.record_malformed(memcached::malformed_datagrams());
+                    report.record_clamped_values(memcached::clamped_values());
+                    report.record_quiet_failures(memcached::quiet_failures());
+                    report.record_data_type_mismatches(memcached::data_type_mismatches());
+                    report.record_datagram_sizes(datagram_sizes());
+                    report.record_udp_batches(udp_batches());
+                    report.record_generators(generator::stats());
+                    report.record_profile(profile::stats());
+                    report.record_udp_offload(
+                        batch::gso_stats(),
+                        batch::gro_stats(),
+                        batch::gso_delay(),
+                    );
+                    report.record_tls_handshakes(&tls::handshake_times());
+                    report.finish();
+                    return;
+                }
+
+                if dowarmup {
+                    // Run at full pps 3 times for 20 seconds
+                    let sched = gen_classic_packet_schedule(
+                        Duration::from_secs(20),
+                        packets_per_second,
+                        arrival,
+                        OutputMode::Silent,
+                        distribution,
+                        rampup,
+                        nthreads,
+                    );
+
+                    for _ in 0..3 {
+                        run_client(
+                            timed,
+                            targets,
+                            nthreads,
+                            &mut barrier_group,
+                            &sched,
+                            0,
+                            &mut report,
+                        );
+                        backend.sleep(Duration::from_secs(5));
+                    }
+                }
+                if report.is_text() {
+                    println!("finish warmup");
+                }
+                let mut slo = slo;
+                if let Some(ref rates) = ramp {
+                    let step = match run_length {
+                        RunLength::Duration(d) => d,
+                        RunLength::Count(_) => unreachable!(),
+                    };
+                    let sched =
+                        gen_ramp_experiment(rates, step, arrival, output, distribution, nthreads);
+                    run_client(
+                        timed,
+                        targets,
+                        nthreads,
+                        &mut barrier_group,
+                        &sched,
+                        0,
+                        &mut report,
+                    );
+                } else if let Some(ref rates) = sweep_rates {
+                    let sched = gen_sweep_experiment(
+                        rates,
+                        run_length.runtime(packets_per_second),
+                        arrival,
+                        output,
+                        distribution,
+                        rampup,
+                        nthreads,
+                    );
+                    run_client(
+                        client,
+                        targets,
+                        nthreads,
+                        &mut barrier_group,
+                        &sched,
+                        0,
+                        &mut report,
+                    );
+                } else {
+                    let step_size = (packets_per_second - start_packets_per_second) / samples;
+                    for j in 1..=samples {
+                        backend.sleep(Duration::from_secs(5));
+                        let rate = match slo {
+                            Some(ref slo) => slo.rate(),
+                            None => start_packets_per_second + step_size * j,
+                        };
+                        let sched = shape_schedule(
+                            gen_classic_packet_schedule(
+                                run_length.runtime(rate),
+                                rate,
+                                arrival,
+                                output,
+                                distribution,
+                                rampup,
+                                nthreads,
+                            ),
+                            load_shape,
+                            shape_bucket,
+                        );
+                        run_client(
+                            client,
+                            targets,
+                            nthreads,
+                            &mut barrier_group,
+                            &sched,
+                            j,
+                            &mut report,
+                        );
+                        if let Some(ref mut slo) = slo {
+                            slo.update(report.last_percentile(slo_percentile));
+                            if slo.converged() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                if let Some(slo) = slo {
+                    // A bisect search measures the rate it settles on once more, for
+                    // longer, so the run ends with its full set of percentiles.
+                    match (slo, slo.best()) {
+                        (SloSearch::Bisect(_), Some(rate))
+                            if slo_confirm > Duration::from_secs(0) =>
+                        {
+                            backend.sleep(Duration::from_secs(5));
+                            let sched = gen_classic_packet_schedule(
+                                slo_confirm,
+                                rate,
+                                arrival,
+                                output,
+                                distribution,
+                                rampup,
+                                nthreads,
+                            );
+                            run_client(
+                                timed,
+                                targets,
+                                nthreads,
+                                &mut barrier_group,
+                                &sched,
+                                samples + 1,
+                                &mut report,
+                            );
+                        }
+                        _ => {}
+                    }
+                    report.record_converged_rate(slo.best().map(|r| r as u64));
+                }
+                report.record_malformed(memcached::malformed_datagrams());
+                report.record_clamped_values(memcached::clamped_values());
+                report.record_quiet_failures(memcached::quiet_failures());
+                report.record_data_type_mismatches(memcached::data_type_mismatches());
+                report.record_datagram_sizes(datagram_sizes());
+                report.record_udp_batches(udp_batches());
+                report.record_generators(generator::stats());
+                report.record_profile(profile::stats());
+                report.record_udp_offload(
+                    batch::gso_stats(),
+                    batch::gro_stats(),
+                    batch::gso_delay(),
+                );
+                report.record_tls_handshakes(&tls::handshake_times());
+                report.finish();
+                if let Some(ref mut g) = barrier_group {
+                    g.barrier();
+                }
+
+                let mut stat_sock = backend.create_udp_connection(any_addr(addr, 0), Some(SocketAddr::new(addr.ip(), 40))).unwrap();
+                stat_sock.write_all(b"stat\n");
+
+                use std::io::Read;
+                let mut buf = [0u8; 1024];
+                let mut response = String::new();
+                stat_sock.read(&mut buf);
+                println!("{}", core::str::from_utf8(&buf).unwrap());
+            });
+        }
+        _ => unreachable!(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlimited() -> SendLimit {
+        SendLimit::new(
+            RunLength::Duration(Duration::from_secs(3600)),
+            &gen_classic_packet_schedule(
+                Duration::from_secs(3600),
+                1000,
+                Arrival::Constant,
+                OutputMode::Silent,
+                Distribution::Zero,
+                0,
+                1,
+            ),
         )
-        .arg(
-            Arg::with_name("rampup")
-                .long("rampup")
-                .takes_value(true)
-                .default_value("4")
-                .help("per-sample ramp up seconds"),
+    }
+
+    fn closed_loop(depth: usize, think_time: Distribution) -> ClientOptions {
+        ClientOptions {
+            max_outstanding: 0,
+            window_policy: WindowPolicy::Drop,
+            timestamp: SendTimestamp::PreWrite,
+            pipeline_depth: depth,
+            think_time,
+            run_length: RunLength::Duration(Duration::from_secs(3600)),
+            stop_on_saturation: None,
+            warmup: Duration::from_secs(0),
+            drain_timeout: Duration::from_millis(500),
+            interval: None,
+            quiet: false,
+            metrics: None,
+            rate_limit: None,
+            session_length: None,
+            handshake: false,
+            no_read: false,
+            trace: None,
+            conns_per_thread: 1,
+            pool_policy: PoolPolicy::RoundRobin,
+            reconnect: true,
+            request_timeout: None,
+            retransmits: 0,
+            watchdog: None,
+            sockets: SocketOptions::default(),
+            io_uring: false,
+            epoll: false,
+            send_batch: 1,
+            udp_batch: 1,
+            udp_gso: false,
+            udp_gro: false,
+            generators: 0,
+            generator_lead: Duration::from_micros(100),
+            read_buffer: 4096,
+            tls: None,
+            sources: Sources::default(),
+            cpus: CpuList::default(),
+            seed: None,
+            replay_speed: 1.0,
+            slowdown: false,
+        }
+    }
+
+    fn client(protocol: Protocol, tport: Transport, opts: ClientOptions) -> Client {
+        Client {
+            backend: Backend::Linux,
+            protocol,
+            tport,
+            opts,
+        }
+    }
+
+    fn link(client: Client, addr: SocketAddr) -> Link {
+        Link {
+            client,
+            addr,
+            start: Instant::now(),
+        }
+    }
+
+    fn silent_server() -> (std::net::TcpListener, SocketAddr) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    /// A UDP server bound to `local` that echoes every datagram it is sent.
+    fn echo_udp_server(local: &str) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind(local).unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                socket.send_to(&buf[..len], from).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn constant_arrivals_evenly_spaced() {
+        let sched = gen_classic_packet_schedule(
+            Duration::from_millis(10),
+            100_000,
+            Arrival::Constant,
+            OutputMode::Silent,
+            Distribution::Zero,
+            0,
+            1,
+        );
+        let packets = gen_packets(&sched, &mut rand::thread_rng());
+        assert_eq!(packets.len(), 1000);
+        assert!(packets
+            .windows(2)
+            .all(|w| w[1].target_start - w[0].target_start == Duration::from_micros(10)));
+    }
+
+    #[test]
+    fn seeded_packets_repeat() {
+        let sched = gen_classic_packet_schedule(
+            Duration::from_millis(10),
+            100_000,
+            Arrival::Exponential,
+            OutputMode::Silent,
+            Distribution::Exponential(10.0),
+            0,
+            1,
+        );
+        let first = |seed, index| -> Vec<(u64, Duration, u64)> {
+            gen_packets(&sched, &mut packet_rng(seed, index))
+                .iter()
+                .take(100)
+                .map(|p| (p.randomness, p.target_start, p.work_iterations))
+                .collect()
+        };
+        assert_eq!(first(Some(7), 0), first(Some(7), 0));
+        // Another seed, or another sample of the run, draws other requests.
+        assert_ne!(first(Some(7), 0), first(Some(8), 0));
+        assert_ne!(first(Some(7), 0), first(Some(7), 1));
+        assert_ne!(first(None, 0), first(None, 0));
+    }
+
+    #[test]
+    fn interarrival_spec_follows_rate() {
+        // Heavy-tailed gaps stray from the rate now and then, so the draws
+        // are seeded.
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for &spec in &["exponential", "weibull", "lognormal", "exp:1000"] {
+            let sched = gen_classic_packet_schedule(
+                Duration::from_secs(1),
+                100_000,
+                Arrival::Spec(spec, 1.0, 0.5),
+                OutputMode::Silent,
+                Distribution::Zero,
+                0,
+                1,
+            );
+            let packets = gen_packets(&sched, &mut rng);
+            // An explicit spec keeps its own mean gap.
+            let expected = if spec == "exp:1000" {
+                1_000_000
+            } else {
+                100_000
+            };
+            let error = (packets.len() as f64 - expected as f64).abs() / expected as f64;
+            assert!(error < 0.02, "{} packets for {}", packets.len(), spec);
+        }
+    }
+
+    #[test]
+    fn distribution_spec_writes_the_parameters_it_took() {
+        let spec = |s| distribution_spec(s, Distribution::parse(s, 10.0, 0.5, 2.0).unwrap());
+        assert_eq!(spec("exponential"), "exponential:10");
+        assert_eq!(spec("constant"), "constant:10");
+        // A spec with its own parameters is kept as written.
+        assert_eq!(spec("exp:20"), "exp:20");
+        assert_eq!(spec("zero"), "zero");
+        // Those whose parameters are derived parse back to the same ones.
+        for &name in &["lognormal", "weibull"] {
+            let written = spec(name);
+            assert!(written.starts_with(&format!("{}:", name)), "{}", written);
+            assert_eq!(
+                format!("{}", Distribution::parse(&written, 1.0, 1.0, 1.0).unwrap()),
+                format!("{}", Distribution::parse(name, 10.0, 0.5, 2.0).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn plain_client_command_line_parses() {
+        let matches = app()
+            .get_matches_from_safe(vec![
+                "synthetic",
+                "127.0.0.1:11211",
+                "--mode",
+                "linux-client",
+            ])
+            .unwrap();
+        assert_eq!(matches.value_of("mode"), Some("linux-client"));
+        // Options that refine another are absent rather than defaulted, so
+        // they need nothing else on the command line.
+        for arg in &[
+            "retransmits",
+            "tls-ca",
+            "replay-speed",
+            "binary-samples-max-mb",
+            "request-log-every",
+        ] {
+            assert!(!matches.is_present(arg), "--{} is present", arg);
+        }
+        assert!(app()
+            .get_matches_from_safe(vec![
+                "synthetic",
+                "127.0.0.1:11211",
+                "--mode",
+                "linux-client",
+                "--retransmits",
+                "2",
+            ])
+            .is_ok());
+    }
+
+    #[test]
+    fn slo_p99_still_sets_the_slo_latency() {
+        for flag in &["--slo-latency", "--slo-p99"] {
+            let matches = app()
+                .get_matches_from_safe(vec![
+                    "synthetic",
+                    "127.0.0.1:11211",
+                    "--mode",
+                    "linux-client",
+                    flag,
+                    "500",
+                ])
+                .unwrap();
+            assert_eq!(matches.value_of("slo-latency"), Some("500"));
+        }
+    }
+
+    #[test]
+    fn ramp_steps() {
+        assert_eq!(
+            parse_rates("1000:5000:2000").unwrap(),
+            vec![1000, 3000, 5000]
+        );
+        assert_eq!(parse_rates("300,100").unwrap(), vec![300, 100]);
+        assert!(parse_rates("5000:1000:1000").is_err());
+        assert!(parse_rates("1000:5000").is_err());
+        assert!(parse_rates("0,100").is_err());
+        assert!(parse_rates("100,x").is_err());
+
+        let step = Duration::from_millis(100);
+        let sched = gen_ramp_experiment(
+            &[10_000, 50_000],
+            step,
+            Arrival::Constant,
+            OutputMode::Normal,
+            Distribution::Zero,
+            1,
+        );
+        let packets = gen_packets(&sched, &mut rand::thread_rng());
+        let boundary = Duration::from_millis(100) + step;
+        let first = packets.iter().filter(|p| p.target_start < boundary).count();
+        // The last gap of a step can land exactly on the next one.
+        assert!(first == 999 || first == 1000, "{} in the first step", first);
+        assert_eq!(packets.len(), 6000);
+    }
+
+    #[test]
+    fn shaped_buckets() {
+        let sched = shape_schedule(
+            gen_classic_packet_schedule(
+                Duration::from_millis(1000),
+                100_000,
+                Arrival::Exponential,
+                OutputMode::Normal,
+                Distribution::Zero,
+                0,
+                1,
+            ),
+            LoadShape::Square(0.5, 200e6),
+            Duration::from_millis(100),
+        );
+        assert_eq!(sched.len(), 10);
+        assert!(sched
+            .iter()
+            .all(|s| s.runtime == Duration::from_millis(100)));
+
+        // Buckets alternate between 1.5x and 0.5x the rate, averaging to it.
+        let packets = gen_packets(&sched, &mut rand::thread_rng());
+        let bucket = |i: u64| {
+            let from = Duration::from_millis(100 + 100 * i);
+            packets
+                .iter()
+                .filter(|p| p.target_start >= from && p.target_start < from + sched[0].runtime)
+                .count() as f64
+        };
+        assert!((bucket(0) / 15_000.0 - 1.0).abs() < 0.05);
+        assert!((bucket(1) / 5_000.0 - 1.0).abs() < 0.05);
+        assert!((packets.len() as f64 / 100_000.0 - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn window_caps_outstanding() {
+        let backend = Backend::Linux;
+        let window = Window::new(4, WindowPolicy::Drop);
+        let in_flight = InFlight::new(6, 0);
+        for i in 0..4 {
+            assert!(window.acquire(backend));
+            in_flight.send(i, Duration::from_micros(i as u64), &[b"request"]);
+        }
+        // A full window drops the request rather than wait.
+        assert!(!window.acquire(backend));
+        in_flight.skip(4);
+        assert_eq!(window.outstanding.load(Ordering::SeqCst), 4);
+
+        // Only the first answer to a request frees its slot.
+        assert_eq!(in_flight.answer(1), Answer::First);
+        window.release();
+        assert_eq!(in_flight.answer(1), Answer::Duplicate);
+        assert_eq!(in_flight.answer(4), Answer::Spurious);
+        assert!(window.acquire(backend));
+        in_flight.send(5, Duration::from_micros(5), &[b"request"]);
+        assert!(!window.acquire(backend));
+        assert_eq!(window.outstanding.load(Ordering::SeqCst), 4);
+
+        // Once the connection stops answering, nothing more is sent.
+        window.release();
+        window.close();
+        assert!(!window.acquire(backend));
+    }
+
+    #[test]
+    fn send_timestamp_sides() {
+        use std::cell::Cell;
+
+        struct MockClock(Cell<Duration>);
+        impl Clock for MockClock {
+            fn now(&self) -> Duration {
+                self.0.get()
+            }
+        }
+
+        // Each write blocks for 50us of mock time.
+        let clock = MockClock(Cell::new(Duration::from_micros(100)));
+        let blocking_write = || {
+            clock.0.set(clock.0.get() + Duration::from_micros(50));
+            Ok(())
+        };
+        let pre = timed_write(&clock, SendTimestamp::PreWrite, blocking_write).unwrap();
+        assert_eq!(pre, Duration::from_micros(100));
+        let post = timed_write(&clock, SendTimestamp::PostWrite, blocking_write).unwrap();
+        assert_eq!(post, Duration::from_micros(200));
+
+        let failed = timed_write(&clock, SendTimestamp::PostWrite, || {
+            Err(io::Error::other("closed"))
+        });
+        assert!(failed.is_err());
+    }
+
+    #[test]
+    fn pipeline_fills_before_reading() {
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::thread;
+
+        let depth = 4;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Echoes synthetic requests back, after checking that exactly `depth`
+        // of them arrive before the first response is sent.
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut first = vec![0; 16 * depth];
+            conn.read_exact(&mut first).unwrap();
+            conn.set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+            let mut extra = [0; 16];
+            let early = conn.read(&mut extra).is_ok();
+            conn.set_read_timeout(None).unwrap();
+            conn.write_all(&first).unwrap();
+
+            let mut request = [0; 16];
+            while conn.read_exact(&mut request).is_ok() {
+                conn.write_all(&request).unwrap();
+            }
+            early
+        });
+
+        let socket = Backend::Linux.create_tcp_connection(None, addr).unwrap();
+        let mut packets: Vec<Packet> = (0..3 * depth).map(|_| Packet::default()).collect();
+        let end = pipeline_packets(
+            link(
+                client(
+                    Protocol::Synthetic,
+                    Transport::Tcp,
+                    closed_loop(depth, Distribution::Zero),
+                ),
+                addr,
+            ),
+            &socket,
+            &mut packets,
+            &unlimited(),
+            None,
+            &mut 0,
         )
-        .arg(
-            Arg::with_name("loadshift")
-                .long("loadshift")
-                .takes_value(true)
-                .default_value("")
-                .help("loadshift spec"),
+        .unwrap();
+        socket.shutdown();
+        assert_eq!(end, packets.len());
+
+        assert!(!server.join().unwrap());
+        assert!(packets
+            .iter()
+            .all(|p| p.completion_time.unwrap() >= p.actual_start.unwrap()));
+    }
+
+    #[test]
+    fn send_batch_coalesces_writes() {
+        use std::io::Read;
+        use std::thread;
+
+        let (depth, batch) = (4, 4);
+        let (listener, addr) = silent_server();
+        // Records what each read brings, and echoes it.
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reads = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let len = conn.read(&mut buf).unwrap();
+                if len == 0 {
+                    return reads;
+                }
+                reads.push(buf[..len].to_vec());
+                conn.write_all(&buf[..len]).unwrap();
+            }
+        });
+
+        let socket = Backend::Linux.create_tcp_connection(None, addr).unwrap();
+        let mut packets: Vec<Packet> = (0..3 * depth).map(|_| Packet::default()).collect();
+        let opts = ClientOptions {
+            send_batch: batch,
+            ..closed_loop(depth, Distribution::Zero)
+        };
+        let sent = pipeline_packets(
+            link(client(Protocol::Synthetic, Transport::Tcp, opts), addr),
+            &socket,
+            &mut packets,
+            &unlimited(),
+            None,
+            &mut 0,
+        );
+        assert_eq!(sent, Ok(packets.len()));
+        socket.shutdown();
+
+        // The first batch arrives whole, as its requests one after another.
+        let reads = server.join().unwrap();
+        assert_eq!(reads[0].len(), 16 * batch, "{:?}", reads);
+        for (i, request) in reads[0].chunks(16).enumerate() {
+            let payload = Payload::deserialize(&mut &request[..]).unwrap();
+            assert_eq!(payload.index, i as u64);
+        }
+        // The requests of a batch share the time of its write.
+        assert!(packets[..batch]
+            .iter()
+            .all(|p| p.actual_start == packets[0].actual_start));
+        assert!(packets
+            .iter()
+            .all(|p| p.completion_time.unwrap() >= p.actual_start.unwrap()));
+    }
+
+    #[test]
+    fn count_excludes_ramp_up() {
+        let sched = gen_classic_packet_schedule(
+            Duration::from_millis(50),
+            10_000,
+            Arrival::Constant,
+            OutputMode::Silent,
+            Distribution::Zero,
+            1,
+            1,
+        );
+        let limit = SendLimit::new(RunLength::Count(5), &sched);
+        let measured_from = Duration::from_millis(100 + 9 * 100);
+        assert_eq!(limit.counted_from, measured_from);
+        assert_eq!(limit.deadline, measured_from + Duration::from_millis(50));
+
+        let ramp_up = Packet {
+            target_start: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let measured = Packet {
+            target_start: measured_from,
+            ..Default::default()
+        };
+        for _ in 0..10 {
+            assert!(limit.admit(&ramp_up, ramp_up.target_start));
+        }
+        let admitted = (0..10)
+            .filter(|_| limit.admit(&measured, measured.target_start))
+            .count();
+        assert_eq!(admitted, 5);
+        assert!(!limit.admit(&ramp_up, limit.deadline));
+    }
+
+    #[test]
+    fn live_windows_reset_each_interval() {
+        let server: SocketAddr = "127.0.0.1:11211".parse().unwrap();
+        let mut limit = SendLimit::new(RunLength::Duration(Duration::from_secs(1)), &[]);
+        assert!(limit.live_times(4, server).is_none());
+        limit.live = Some(Mutex::new(Vec::new()));
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new("memcached")));
+        limit.metrics = Some(metrics);
+        let a = limit.live_times(4, server).unwrap();
+        let b = limit.live_times(1, server).unwrap();
+        a.sent(0, Duration::from_micros(10), "get");
+        a.sent(1, Duration::from_micros(20), "get");
+        b.sent(0, Duration::from_micros(30), "set");
+        a.answered(0, Duration::from_micros(15), Outcome::Ok);
+        a.answered(1, Duration::from_micros(120), Outcome::Miss);
+        b.answered(0, Duration::from_micros(35), Outcome::Ok);
+        let window = limit.take_window();
+        assert_eq!((window.latencies.count(), window.misses), (3, 1));
+        assert_eq!(window.latencies.min(), Some(5000));
+        assert_eq!(window.latencies.max(), Some(100_000));
+        // Nothing answered since is carried into the next window.
+        let window = limit.take_window();
+        assert_eq!((window.latencies.count(), window.misses), (0, 0));
+        a.sent(2, Duration::from_micros(40), "get");
+        a.answered(2, Duration::from_micros(41), Outcome::Ok);
+        assert_eq!(limit.take_window().latencies.max(), Some(1000));
+        // The same responses are counted for the metrics, by opcode.
+        a.counts.time_out(1);
+        assert_eq!(limit.failures(), (0, 1));
+        let labels = "protocol=\"memcached\",server=\"127.0.0.1:11211\"";
+        let rendered = metrics.render();
+        for line in &[
+            format!("synthetic_misses_total{{{},opcode=\"get\"}} 1", labels),
+            format!("synthetic_responses_total{{{},opcode=\"get\"}} 3", labels),
+            format!("synthetic_responses_total{{{},opcode=\"set\"}} 1", labels),
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{}\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn vectored_sends_match_built_datagrams() {
+        // Values lent by their source and written apart, behind quiet SETs
+        // and fanned out GETs.
+        let mix = Some("get:1,set:1".parse().unwrap());
+        for &protocol in &[
+            Protocol::Memcached(MemcachedProtocol::default()),
+            Protocol::Memcached(MemcachedProtocol {
+                value_source: ValueSource::Pattern(b"abc"),
+                mix,
+                ..Default::default()
+            }),
+            Protocol::Memcached(MemcachedProtocol {
+                value_source: ValueSource::Pattern(b"a"),
+                mix,
+                fanout: 3,
+                write_batch: 3,
+                ..Default::default()
+            }),
+            Protocol::MemcachedMeta(MetaProtocol::default()),
+        ] {
+            let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            server
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let socket = Backend::Linux
+                .create_udp_connection(any_addr(addr, 0), Some(addr))
+                .unwrap();
+            let sched = gen_classic_packet_schedule(
+                RunLength::Count(20).runtime(1000),
+                1000,
+                Arrival::Constant,
+                OutputMode::Silent,
+                Distribution::Zero,
+                0,
+                1,
+            );
+            let mut packets = gen_packets(&sched, &mut rand::thread_rng());
+            let in_flight = InFlight::new(packets.len(), 1);
+            let end = send_packets(
+                link(
+                    client(protocol, Transport::Udp, closed_loop(0, Distribution::Zero)),
+                    addr,
+                ),
+                &socket,
+                &mut packets,
+                Tracking {
+                    window: &Window::new(0, WindowPolicy::Drop),
+                    limit: &SendLimit::new(RunLength::Count(20), &sched),
+                    in_flight: Some(&in_flight),
+                    live: None,
+                    order: None,
+                },
+                &mut None,
+                None,
+            )
+            .unwrap();
+
+            // Each datagram is the request built behind its frame header in
+            // one buffer, as kept for retransmission. Requests too late to
+            // send were skipped.
+            let mut datagram = vec![0; MAX_DATAGRAM];
+            let mut expected = Vec::new();
+            let mut sent = 0;
+            for (i, p) in packets[..end].iter().enumerate() {
+                if p.actual_start.is_none() {
+                    continue;
+                }
+                sent += 1;
+                let len = server.recv(&mut datagram).unwrap();
+                expected.clear();
+                protocol.gen_request(i, p, &mut expected, Transport::Udp);
+                assert_eq!(&datagram[..len], &expected[..]);
+                assert_eq!(*in_flight.payloads[i].lock().unwrap(), expected);
+            }
+            assert!(sent > 0);
+        }
+    }
+
+    #[test]
+    fn count_run_sends_exactly_n() {
+        let (listener, addr) = silent_server();
+        let backend = Backend::Linux;
+        let socket = backend.create_tcp_connection(None, addr).unwrap();
+        let _server = listener.accept().unwrap();
+
+        let sched = gen_classic_packet_schedule(
+            RunLength::Count(10).runtime(1000),
+            1000,
+            Arrival::Constant,
+            OutputMode::Silent,
+            Distribution::Zero,
+            0,
+            1,
+        );
+        let mut packets = gen_packets(&sched, &mut rand::thread_rng());
+        assert_eq!(packets.len(), 20);
+        let end = send_packets(
+            link(
+                client(
+                    Protocol::Synthetic,
+                    Transport::Tcp,
+                    closed_loop(0, Distribution::Zero),
+                ),
+                addr,
+            ),
+            &socket,
+            &mut packets,
+            Tracking {
+                window: &Window::new(0, WindowPolicy::Drop),
+                limit: &SendLimit::new(RunLength::Count(10), &sched),
+                in_flight: None,
+                live: None,
+                order: None,
+            },
+            &mut None,
+            None,
         )
-        .get_matches();
+        .unwrap();
 
-    let addr: SocketAddrV4 = FromStr::from_str(matches.value_of("ADDR").unwrap()).unwrap();
-    let nthreads = value_t_or_exit!(matches, "threads", usize);
-    let runtime = Duration::from_nanos(value_t!(matches, "runtime",u64).unwrap());
-    let packets_per_second = (1.0e6 * value_t_or_exit!(matches, "mpps", f32)) as usize;
-    let start_packets_per_second = (1.0e6 * value_t_or_exit!(matches, "start_mpps", f32)) as usize;
-    assert!(start_packets_per_second <= packets_per_second);
-    let config = matches.value_of("config");
-    let dowarmup = matches.is_present("warmup");
-    let proto = value_t_or_exit!(matches, "protocol", Protocol);
-    let output = value_t_or_exit!(matches, "output", OutputMode);
-    let tport = value_t_or_exit!(matches, "transport", Transport);
-    let mean = value_t_or_exit!(matches, "mean", f64);
-    let distribution = match matches.value_of("distribution").unwrap() {
-        "zero" => Distribution::Zero,
-        "constant" => Distribution::Constant(mean as u64),
-        "exponential" => Distribution::Exponential(mean),
-        "bimodal1" => Distribution::Bimodal1(mean),
-        "bimodal2" => Distribution::Bimodal2(mean),
-        "rocksdb" => Distribution::RocksDB,
-        _ => unreachable!(),
-    };
-    let samples = value_t_or_exit!(matches, "samples", usize);
-    let rampup = value_t_or_exit!(matches, "rampup", usize);
-    let mode = matches.value_of("mode").unwrap();
-    let slowdown = matches.is_present("slowdown");
-    let backend = match mode {
-        "linux-server" | "linux-client" => Backend::Linux,
-        "spawner-server" | "runtime-client" | "work-bench" | "local-client" => Backend::Runtime,
-        _ => unreachable!(),
-    };
-    let mut barrier_group = matches.value_of("barrier-leader").map(|leader| {
-        lockstep::Group::from_hostname(
-            leader,
-            23232,
-            value_t_or_exit!(matches, "barrier-peers", usize),
+        assert!(end < packets.len());
+        let sent = |p: &[Packet]| p.iter().filter(|p| p.actual_start.is_some()).count();
+        assert_eq!(sent(&packets[..end]), 10);
+        assert_eq!(sent(&packets[end..]), 0);
+    }
+
+    #[test]
+    fn duration_run_stops_at_deadline() {
+        let (listener, addr) = silent_server();
+        let backend = Backend::Linux;
+        let socket = backend.create_tcp_connection(None, addr).unwrap();
+        let _server = listener.accept().unwrap();
+
+        let runtime = Duration::from_millis(50);
+        let sched = gen_classic_packet_schedule(
+            runtime,
+            1000,
+            Arrival::Constant,
+            OutputMode::Silent,
+            Distribution::Zero,
+            0,
+            1,
+        );
+        // Requests keep coming well past the end of the schedule.
+        let mut packets: Vec<Packet> = (101..=200)
+            .map(|i| Packet {
+                target_start: Duration::from_millis(i),
+                ..Default::default()
+            })
+            .collect();
+        let start = Instant::now();
+        let end = send_packets(
+            Link {
+                start,
+                ..link(
+                    client(
+                        Protocol::Synthetic,
+                        Transport::Tcp,
+                        closed_loop(0, Distribution::Zero),
+                    ),
+                    addr,
+                )
+            },
+            &socket,
+            &mut packets,
+            Tracking {
+                window: &Window::new(0, WindowPolicy::Drop),
+                limit: &SendLimit::new(RunLength::Duration(runtime), &sched),
+                in_flight: None,
+                live: None,
+                order: None,
+            },
+            &mut None,
+            None,
         )
-        .unwrap()
-    });
-    println!("Slowdown: {}", slowdown);
+        .unwrap();
 
-    let loadshift_spec = value_t_or_exit!(matches, "loadshift", String);
-    let fakeworker = FakeWorker::create(matches.value_of("fakework").unwrap()).unwrap();
+        let deadline = Duration::from_millis(150);
+        assert_eq!(packets[end].target_start, deadline);
+        assert!(start.elapsed() < deadline + Duration::from_millis(10));
+        assert!(packets[end..].iter().all(|p| p.actual_start.is_none()));
+        assert!(packets
+            .iter()
+            .filter_map(|p| p.actual_start)
+            .all(|t| t < deadline));
+    }
 
-    match mode {
-        "work-bench" => {
-            let iterations = 100_000_000;
-            println!("Timing {} iterations of work()", iterations);
-            let start = Instant::now();
-            fakeworker.work(iterations);
-            let elapsed = duration_to_ns(start.elapsed());
-            println!("Rate = {} ns/iteration", elapsed as f64 / iterations as f64);
+    #[test]
+    fn saturation_stops_the_run() {
+        let (listener, addr) = silent_server();
+        let backend = Backend::Linux;
+        let socket = backend.create_tcp_connection(None, addr).unwrap();
+        let _server = listener.accept().unwrap();
+
+        let sched = gen_sweep_experiment(
+            &[1000, 2000],
+            Duration::from_millis(50),
+            Arrival::Constant,
+            OutputMode::Normal,
+            Distribution::Zero,
+            0,
+            1,
+        );
+        assert_eq!(sched.len(), 2);
+        let mut packets = gen_packets(&sched, &mut rand::thread_rng());
+        let mut limit = SendLimit::new(RunLength::Duration(Duration::from_secs(1)), &sched);
+        limit.saturation = Some(0.9);
+        let limit = Arc::new(limit);
+
+        // Nothing is answered, so the first point saturates.
+        let start = Instant::now();
+        let watcher = {
+            let limit = limit.clone();
+            std::thread::spawn(move || {
+                limit.watch(
+                    backend,
+                    start,
+                    &[
+                        (Duration::from_millis(100), Duration::from_millis(150)),
+                        (Duration::from_millis(150), Duration::from_millis(200)),
+                    ],
+                )
+            })
+        };
+        let end = send_packets(
+            Link {
+                start,
+                ..link(
+                    client(
+                        Protocol::Synthetic,
+                        Transport::Tcp,
+                        closed_loop(0, Distribution::Zero),
+                    ),
+                    addr,
+                )
+            },
+            &socket,
+            &mut packets,
+            Tracking {
+                window: &Window::new(0, WindowPolicy::Drop),
+                limit: &limit,
+                in_flight: None,
+                live: None,
+                order: None,
+            },
+            &mut None,
+            None,
+        )
+        .unwrap();
+        watcher.join().unwrap();
+
+        assert!(end < packets.len());
+        assert!(packets[end].target_start < Duration::from_millis(160));
+        assert!(packets[end..].iter().all(|p| p.actual_start.is_none()));
+    }
+
+    #[test]
+    fn warmup_left_out_by_send_time() {
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(20),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        // Requests sent in the warmup take 50ms, so any that leaked in would
+        // show up in the tail.
+        let mut packets: Vec<Packet> = (0..20)
+            .map(|i| {
+                let sent = Duration::from_millis(100 + i);
+                let latency = Duration::from_millis(if i < 5 { 50 } else { 1 });
+                Packet {
+                    target_start: sent,
+                    actual_start: Some(sent),
+                    completion_time: Some(sent + latency),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            reported_percentiles(&[]),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(105),
+            false,
+            &mut report,
+        ));
+        let result = report.last_result().unwrap();
+        assert_eq!(result.warmup, 5);
+        assert_eq!(result.dropped, 0);
+        assert_eq!(report.last_percentile(99.99), Some(1000.0));
+    }
+
+    #[test]
+    fn timeouts_counted_apart_from_drops() {
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(20),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        // Of 20 requests, 2 are lost before the drain and 3 outlast it.
+        let mut packets: Vec<Packet> = (0..20)
+            .map(|i| {
+                let sent = Duration::from_millis(100 + i);
+                Packet {
+                    target_start: sent,
+                    actual_start: Some(sent),
+                    completion_time: if i < 15 {
+                        Some(sent + Duration::from_millis(1))
+                    } else {
+                        None
+                    },
+                    timed_out: i >= 17,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50,90").unwrap(),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        ));
+        let result = report.last_result().unwrap();
+        assert_eq!(result.timed_out, 3);
+        assert_eq!(result.dropped, 2);
+        // Timed out requests still count towards the tail.
+        assert_eq!(report.last_percentile(50.0), Some(1000.0));
+        assert_eq!(report.last_percentile(90.0), Some(f32::INFINITY));
+    }
+
+    #[test]
+    fn latencies_read_from_histograms() {
+        use std::fs;
+
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(100),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        // Request i is answered i * 10.1us after it goes out.
+        let packets = || -> Vec<Packet> {
+            (1..=100)
+                .map(|i| {
+                    let sent = Duration::from_millis(100 + i);
+                    Packet {
+                        target_start: sent,
+                        actual_start: Some(sent),
+                        completion_time: Some(sent + Duration::from_nanos(i * 10_100)),
+                        ..Default::default()
+                    }
+                })
+                .collect()
+        };
+        let path = std::env::temp_dir().join(format!("histograms-{}.csv", std::process::id()));
+        for &digits in &[None, Some(1)] {
+            let mut report = Report::new(
+                OutputFormat::Json,
+                RunMetadata {
+                    histogram_digits: digits,
+                    ..Default::default()
+                },
+                None,
+                None,
+                None,
+                None,
+                parse_percentiles("50,100").unwrap(),
+            );
+            report.write_histograms(HistogramWriter::create(path.to_str().unwrap()).unwrap());
+            assert!(process_result(
+                &sched,
+                &mut packets(),
+                UNIX_EPOCH,
+                Duration::from_millis(0),
+                false,
+                &mut report,
+            ));
+            // The median is kept to the digits asked for, and the slowest
+            // exactly.
+            let median = report.last_percentile(50.0).unwrap();
+            let error = 10f32.powi(-(digits.unwrap_or(3) as i32));
+            assert!(
+                median >= 515.1 && median <= 515.1 * (1.0 + error),
+                "{}",
+                median
+            );
+            assert_eq!(report.last_percentile(100.0), Some(1010.0));
+            // The lowest, mean and highest are exact, and every request sent
+            // is a sample.
+            let result = report.last_result().unwrap();
+            assert_eq!(result.samples, 100);
+            assert_eq!(result.latency_min, Some(10.1));
+            assert_eq!(result.latency_max, Some(1010.0));
+            let mean = result.latency_mean.unwrap();
+            assert!((mean - 510.05).abs() < 0.01, "{}", mean);
+
+            let text = fs::read_to_string(&path).unwrap();
+            let lines: Vec<&str> = text.lines().collect();
+            assert_eq!(lines[0], "start,distribution,target,low,high,count");
+            let rows: Vec<Vec<&str>> = lines[1..].iter().map(|l| l.split(',').collect()).collect();
+            let count: u64 = rows.iter().map(|r| r[5].parse::<u64>().unwrap()).sum();
+            assert_eq!(count, 100);
+            match digits {
+                None => assert_eq!(rows.len(), 100),
+                _ => assert!(rows.len() < 100),
+            }
+            let bounds: Vec<f32> = rows[0][3..5].iter().map(|b| b.parse().unwrap()).collect();
+            assert!(bounds[0] <= 10.1 && bounds[1] >= 10.1, "{:?}", bounds);
         }
-        "spawner-server" => match tport {
-            Transport::Udp => {
-                backend.init_and_run(config, move || run_spawner_server(addr, fakeworker))
+        fs::remove_file(&path).unwrap();
+
+        // Latencies past a bound are counted at it, and how many were.
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata {
+                histogram_min: Some(20.0),
+                histogram_max: Some(500.0),
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("0.5,100").unwrap(),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets(),
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        ));
+        let result = report.last_result().unwrap();
+        assert_eq!(
+            (result.latency_overflows, result.latency_underflows),
+            (51, 1)
+        );
+        let lowest = report.last_percentile(0.5).unwrap();
+        assert!((20.0..=20.02).contains(&lowest), "{}", lowest);
+        assert_eq!(report.last_percentile(100.0), Some(500.0));
+    }
+
+    #[test]
+    fn send_delays_count_as_queueing() {
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(20),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        // Every fourth request goes out 5ms late, and each is answered 1ms
+        // after it goes out.
+        let mut packets: Vec<Packet> = (0..20)
+            .map(|i| {
+                let target = Duration::from_millis(100 + i);
+                let sent = match i % 4 {
+                    0 => target + Duration::from_millis(5),
+                    _ => target,
+                };
+                Packet {
+                    target_start: target,
+                    actual_start: Some(sent),
+                    completion_time: Some(sent + Duration::from_millis(1)),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let metadata = RunMetadata {
+            latency_breakdown: true,
+            ..Default::default()
+        };
+        let mut report = Report::new(
+            OutputFormat::Json,
+            metadata,
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50,90").unwrap(),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        ));
+        let result = report.last_result().unwrap();
+        let queueing = result.queueing.as_ref().unwrap();
+        assert_eq!(queueing.get(50.0), Some(0.0));
+        assert_eq!(queueing.get(90.0), Some(5000.0));
+        let service = result.service.as_ref().unwrap();
+        assert_eq!(service.get(50.0), Some(1000.0));
+        assert_eq!(service.get(90.0), Some(1000.0));
+        // Measured from their intended sends, the late requests take their
+        // delay too.
+        let response = result.response.as_ref().unwrap();
+        let median = response.get(50.0).unwrap();
+        assert!((1000.0..1001.0).contains(&median), "{}", median);
+        assert_eq!(response.get(90.0), Some(6000.0));
+        assert_eq!(result.send_lag_max, Some(5000.0));
+
+        // Without the breakdown neither is reported.
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50,90").unwrap(),
+        );
+        process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        );
+        assert!(report.last_result().unwrap().queueing.is_none());
+    }
+
+    #[test]
+    fn results_broken_out_by_op() {
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(40),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        // GETs take 1ms and SETs 3ms, and the last SET goes unanswered. A
+        // GET's value comes back, and a SET's goes out.
+        let mut packets: Vec<Packet> = (0..40)
+            .map(|i| {
+                let sent = Duration::from_millis(100 + i);
+                let (op, latency, bytes, response_bytes) = match i % 2 {
+                    0 => ("get", 1, 40, 124),
+                    _ => ("set", 3, 140, 24),
+                };
+                Packet {
+                    target_start: sent,
+                    actual_start: Some(sent),
+                    completion_time: Some(sent + Duration::from_millis(latency))
+                        .filter(|_| i != 39),
+                    response_bytes,
+                    info: RequestInfo {
+                        bytes,
+                        ..RequestInfo::new(op, i)
+                    },
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50").unwrap(),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        ));
+        let ops: Vec<(&str, usize, usize, Option<f32>)> = report
+            .last_result()
+            .unwrap()
+            .ops
+            .iter()
+            .map(|o| (&o.op[..], o.sent, o.completed, o.latencies.get(50.0)))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![("get", 20, 20, Some(1000.0)), ("set", 20, 19, Some(3000.0))]
+        );
+        let ops = &report.last_result().unwrap().ops;
+        // The unanswered SET's response is not counted.
+        assert_eq!((ops[0].bytes_out, ops[0].bytes_in), (800, 2480));
+        assert_eq!((ops[1].bytes_out, ops[1].bytes_in), (2800, 456));
+        // Over the 39ms the requests were sent in.
+        assert!((ops[1].out_gbps - 2800.0 * 8.0 / 0.039 / 1e9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn requests_balanced_over_targets() {
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(60),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        let packets: Vec<Packet> = (0..60)
+            .map(|i| Packet {
+                target_start: Duration::from_millis(100 + i),
+                ..Default::default()
+            })
+            .collect();
+        // The third target refused its connection, so its share is left out.
+        let balanced = balance_packets(packets, &[0, 1, 0], &[3, 1, 2], false, Protocol::Synthetic);
+        let lens: Vec<usize> = balanced.iter().map(|c| c.len()).collect();
+        assert_eq!(lens, vec![23, 15, 22]);
+        for (c, &server) in balanced.iter().zip(&[0, 1, 0]) {
+            assert!(c.iter().all(|p| p.server == server));
+            assert!(c.windows(2).all(|w| w[0].target_start < w[1].target_start));
+        }
+
+        let mut packets: Vec<Packet> = balanced
+            .into_iter()
+            .flatten()
+            .map(|p| {
+                let latency = Duration::from_millis(1 + p.server as u64);
+                Packet {
+                    actual_start: Some(p.target_start),
+                    completion_time: Some(p.target_start + latency),
+                    info: RequestInfo::new("get", 0),
+                    miss: p.server == 1,
+                    ..p
+                }
+            })
+            .collect();
+        packets.sort_by_key(|p| p.target_start);
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata {
+                targets: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                weights: Some(vec![3, 1, 2]),
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50").unwrap(),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        ));
+        let result = report.last_result().unwrap();
+        let sent: Vec<usize> = result.servers.iter().map(|s| s.sent).collect();
+        assert_eq!(sent, vec![45, 15, 0]);
+        assert_eq!(
+            result.servers[0].latencies.as_ref().unwrap().get(50.0),
+            Some(1000.0)
+        );
+        assert_eq!(
+            result.servers[1].latencies.as_ref().unwrap().get(50.0),
+            Some(2000.0)
+        );
+        assert!(result.servers[2].latencies.is_none());
+        let misses: Vec<Option<f32>> = result.servers.iter().map(|s| s.miss_ratio).collect();
+        assert_eq!(misses, vec![Some(0.0), Some(1.0), None]);
+        // Both reachable targets took half again their weighted share.
+        assert!((result.imbalance.unwrap() - 1.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn latencies_by_value_size() {
+        let sched = RequestSchedule {
+            arrival: Distribution::Constant(1_000_000),
+            service: Distribution::Zero,
+            output: OutputMode::Normal,
+            runtime: Duration::from_millis(80),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        };
+        // Latency grows with the value size, and a fourth of the requests
+        // are GETs of keys of unknown size. The largest of each class goes
+        // unanswered.
+        let sizes = [Some(10), Some(1000), Some(100_000), None];
+        let mut packets: Vec<Packet> = (0..80)
+            .map(|i| {
+                let start = Duration::from_millis(100 + i);
+                let n = i as usize % 4;
+                Packet {
+                    target_start: start,
+                    actual_start: Some(start),
+                    completion_time: if i >= 76 {
+                        None
+                    } else {
+                        Some(start + Duration::from_millis(1 + n as u64))
+                    },
+                    info: RequestInfo::new("set", i).with_value_size(sizes[n]),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata {
+                size_classes: Some(parse_size_classes("100,10000").unwrap()),
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50,99").unwrap(),
+        );
+        assert!(process_result(
+            &sched,
+            &mut packets,
+            UNIX_EPOCH,
+            Duration::from_millis(0),
+            false,
+            &mut report,
+        ));
+        let result = report.last_result().unwrap();
+        let counts: Vec<(&str, usize, usize)> = result
+            .sizes
+            .iter()
+            .map(|s| (&s.class[..], s.sent, s.completed))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("small", 20, 19), ("medium", 20, 19), ("large", 20, 19)]
+        );
+        for (size, ms) in result.sizes.iter().zip(1..) {
+            let l = size.latencies.as_ref().unwrap();
+            assert_eq!(l.get(50.0), Some(ms as f32 * 1000.0));
+            assert_eq!(l.get(99.0), Some(f32::INFINITY));
+        }
+
+        assert_eq!(size_class(99, &[100, 10000]), 0);
+        assert_eq!(size_class(100, &[100, 10000]), 1);
+        assert_eq!(size_class(10000, &[100, 10000]), 2);
+        assert!(parse_size_classes("100").is_err());
+        assert!(parse_size_classes("100,100").is_err());
+        assert!(parse_size_classes("0,100").is_err());
+    }
+
+    #[test]
+    fn keys_routed_to_their_server() {
+        let mut rng = rand::thread_rng();
+        let mut packets = || -> Vec<Packet> {
+            (0..3000)
+                .map(|i| Packet {
+                    target_start: Duration::from_micros(i),
+                    randomness: rng.gen::<u64>(),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let usr = MemcachedProtocol::default();
+        let etc = MemcachedProtocol {
+            workload: Workload::Etc,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        for (connections, reachable) in [
+            (vec![0, 1, 2, 0], [true, true, true]),
+            // The second server refused its connection.
+            (vec![0, 2], [true, false, true]),
+        ] {
+            let balanced = balance_packets(
+                packets(),
+                &connections,
+                &[1, 1, 1],
+                true,
+                Protocol::Memcached(usr),
+            );
+            for (c, &server) in balanced.iter().zip(&connections) {
+                for (i, p) in c.iter().enumerate() {
+                    assert_eq!(p.server, server);
+                    let key = memcached::request_key(p, None, None);
+                    assert_eq!(server, balance::key_server(key, &reachable));
+                    // SETs and GETs of either workload are for the key routed on.
+                    for proto in &[usr, etc] {
+                        buf.clear();
+                        let info = proto.gen_request(i, p, &mut buf, Transport::Tcp);
+                        assert_eq!(info.key, Some(key));
+                    }
+                }
             }
-            Transport::Tcp => {
-                backend.init_and_run(config, move || run_tcp_server(backend, addr, fakeworker))
+            let sizes: Vec<usize> = balanced.iter().map(|c| c.len()).collect();
+            assert!(sizes.iter().all(|&n| n > 0), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn trace_replayed_in_order() {
+        let trace = trace::parse_trace("get 5\nset 7 100\nget 7\n").unwrap();
+        // Two threads whose send times interleave.
+        let mut thread_packets: Vec<Vec<Packet>> = (0..2)
+            .map(|t| {
+                (0..4)
+                    .map(|i| Packet {
+                        target_start: Duration::from_micros(2 * i + t),
+                        randomness: 0xffff_ffff_ffff_ffff,
+                        ..Default::default()
+                    })
+                    .collect()
+            })
+            .collect();
+        assign_trace(&mut thread_packets, &trace);
+
+        let proto = MetaProtocol::default();
+        let mut sent = Vec::new();
+        let mut expected = Vec::new();
+        let mut packets: Vec<&Packet> = thread_packets.iter().flatten().collect();
+        packets.sort_by_key(|p| p.target_start);
+        for (n, p) in packets.into_iter().enumerate() {
+            let mut buf = Vec::new();
+            let info = proto.gen_request(n, p, &mut buf, Transport::Tcp);
+            sent.push((info.op, info.key.unwrap(), buf));
+
+            let mut buf = Vec::new();
+            let record = trace[n % trace.len()];
+            if record.set {
+                proto.store_request(
+                    mix::Op::Set,
+                    record.key,
+                    record.value_size,
+                    n as u32,
+                    &mut buf,
+                    Transport::Tcp,
+                );
+            } else {
+                MetaProtocol::get_request(record.key, n as u32, &mut buf, Transport::Tcp);
             }
-        },
-        "linux-server" => match tport {
-            Transport::Udp => backend.init_and_run(config, move || {
-                run_linux_udp_server(backend, addr, nthreads, fakeworker)
-            }),
-            Transport::Tcp => {
-                backend.init_and_run(config, move || run_tcp_server(backend, addr, fakeworker))
+            expected.push((if record.set { "set" } else { "get" }, record.key, buf));
+        }
+        assert_eq!(sent, expected);
+        let ops: Vec<(&str, u64)> = sent.iter().map(|&(op, key, _)| (op, key)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                ("get", 5),
+                ("set", 7),
+                ("get", 7),
+                ("get", 5),
+                ("set", 7),
+                ("get", 7),
+                ("get", 5),
+                ("set", 7),
+            ]
+        );
+        assert!(sent[1].2.starts_with(b"ms 7"));
+        assert!(sent[1].2.len() > 100);
+    }
+
+    #[test]
+    fn timed_trace_replays_scaled_gaps() {
+        let trace = trace::parse_trace("0 get 1\n100 get 2\n400 set 3 10\n").unwrap();
+        let period = trace_period(&trace).unwrap();
+        let schedules = [RequestSchedule {
+            arrival: Distribution::Zero,
+            service: Distribution::Zero,
+            output: OutputMode::Silent,
+            runtime: Duration::from_millis(3),
+            discard_pct: 0,
+            shape: LoadShape::Constant,
+        }];
+        for &speed in &[1.0, 2.0, 0.5] {
+            let thread_packets = replay_trace(
+                &schedules,
+                &trace,
+                period,
+                speed,
+                2,
+                &mut rand::thread_rng(),
+            );
+            // The threads take the requests in turn.
+            let mut packets: Vec<&Packet> = Vec::new();
+            for n in 0..thread_packets[0].len() {
+                packets.extend(thread_packets.iter().filter_map(|t| t.get(n)));
             }
-        },
-        "local-client" => {
-            backend.init_and_run(config, move || {
-                println!("Distribution, Target, Actual, Dropped, Never Sent, Median, 90th, 99th, 99.9th, 99.99th, Start");
-                if dowarmup {
-                    for packets_per_second in (1..3).map(|i| i * 100000) {
-                        let sched = gen_classic_packet_schedule(
-                            Duration::from_secs(1),
-                            packets_per_second,
-                            OutputMode::Silent,
-                            distribution,
-                            0,
-                            nthreads,
-                        );
-                        run_local(
-                            backend,
-                            nthreads,
-                            fakeworker.clone(),
-                            &sched,
-                        );
+            let expected = (3000.0 * speed / 600.0).ceil() as usize * 3;
+            assert!(packets.len() >= expected - 2 && packets.len() <= expected);
+            assert_eq!(packets[0].target_start, Duration::from_millis(100));
+            // Gaps are the trace's divided by the speed, with the mean gap
+            // again between one replay and the next.
+            let gaps = [100.0, 300.0, 200.0];
+            for (n, pair) in packets.windows(2).enumerate() {
+                let gap = pair[1].target_start - pair[0].target_start;
+                let want = gaps[n % 3] / speed;
+                assert!(
+                    (gap.as_secs_f64() * 1e6 - want).abs() < 0.01,
+                    "{} {:?}",
+                    n,
+                    gap
+                );
+                assert_eq!(pair[0].trace, Some(trace[n % 3]));
+            }
+            assert!(packets.last().unwrap().target_start < Duration::from_millis(103));
+        }
+    }
+
+    #[test]
+    fn no_read_only_counts_sends() {
+        use std::io::Read;
+
+        // An echo server, so that a client reading responses would time them.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 16];
+            let mut requests = 0;
+            while conn.read_exact(&mut buf).is_ok() {
+                conn.write_all(&buf).unwrap();
+                requests += 1;
+            }
+            requests
+        });
+
+        let schedules = gen_classic_packet_schedule(
+            Duration::from_millis(200),
+            10_000,
+            Arrival::Constant,
+            OutputMode::Normal,
+            Distribution::Zero,
+            0,
+            1,
+        );
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata {
+                no_read: true,
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50").unwrap(),
+        );
+        let targets = Targets {
+            addrs: Box::leak(vec![addr].into_boxed_slice()),
+            weights: &[1],
+            key_hash: false,
+        };
+        let opts = ClientOptions {
+            no_read: true,
+            ..closed_loop(0, Distribution::Zero)
+        };
+        let start = Instant::now();
+        assert!(run_client(
+            client(Protocol::Synthetic, Transport::Tcp, opts),
+            targets,
+            1,
+            &mut None,
+            &schedules,
+            400,
+            &mut report,
+        ));
+        // Nothing waited out the drain for responses.
+        assert!(start.elapsed() < Duration::from_millis(100 + 200 + 500));
+        let requests = server.join().unwrap();
+
+        let result = report.last_result().unwrap();
+        assert!(result.latencies.is_none());
+        assert_eq!((result.dropped, result.timed_out), (0, 0));
+        // The first tenth of the schedule is discarded.
+        let sent = result.sent.unwrap();
+        assert!(sent > 0 && sent <= requests && sent >= requests * 8 / 10);
+        assert_eq!(
+            result.actual,
+            sent as u64 * 1_000_000_000 / (duration_to_ns(Duration::from_millis(180)) - 100_000)
+        );
+    }
+
+    /// A server that reads 16-byte requests from `conns` connections, echoing
+    /// them on those `answers` picks by accept order, and returns how many
+    /// requests each one sent.
+    fn counting_server(
+        conns: usize,
+        answers: fn(usize) -> bool,
+    ) -> (SocketAddr, std::thread::JoinHandle<Vec<usize>>) {
+        use std::io::Read;
+
+        let (listener, addr) = silent_server();
+        let server = std::thread::spawn(move || {
+            let handlers: Vec<_> = (0..conns)
+                .map(|c| {
+                    let (mut conn, _) = listener.accept().unwrap();
+                    std::thread::spawn(move || {
+                        let mut buf = [0u8; 16];
+                        let mut requests = 0;
+                        while conn.read_exact(&mut buf).is_ok() {
+                            if answers(c) {
+                                conn.write_all(&buf).unwrap();
+                            }
+                            requests += 1;
+                        }
+                        requests
+                    })
+                })
+                .collect();
+            handlers.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn preflight_fails_on_a_dead_port() {
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let opts = closed_loop(0, Distribution::Zero);
+        let check = |addr| {
+            preflight(
+                Protocol::Synthetic,
+                Backend::Linux,
+                Transport::Tcp,
+                addr,
+                opts,
+            )
+        };
+        let e = check(dead).unwrap_err();
+        assert!(e.starts_with("could not connect: "), "{}", e);
+
+        let (addr, server) = counting_server(1, |_| true);
+        assert_eq!(check(addr), Ok(()));
+        assert_eq!(server.join().unwrap(), vec![1]);
+    }
+
+    /// Runs 100ms of open-loop requests at 10k/s from `nthreads` threads.
+    fn run_pooled(addr: SocketAddr, nthreads: usize, opts: ClientOptions) -> Report {
+        run_open(addr, nthreads, Transport::Tcp, opts)
+    }
+
+    fn run_open(
+        addr: SocketAddr,
+        nthreads: usize,
+        tport: Transport,
+        opts: ClientOptions,
+    ) -> Report {
+        let schedules = gen_classic_packet_schedule(
+            Duration::from_millis(100),
+            10_000,
+            Arrival::Constant,
+            OutputMode::Normal,
+            Distribution::Zero,
+            0,
+            nthreads,
+        );
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            parse_percentiles("50,99").unwrap(),
+        );
+        let targets = Targets {
+            addrs: Box::leak(vec![addr].into_boxed_slice()),
+            weights: &[1],
+            key_hash: false,
+        };
+        run_client(
+            client(Protocol::Synthetic, tport, opts),
+            targets,
+            nthreads,
+            &mut None,
+            &schedules,
+            410,
+            &mut report,
+        );
+        report
+    }
+
+    #[test]
+    fn self_profile_times_each_phase() {
+        let (addr, server) = counting_server(1, |_| true);
+        profile::enable();
+        let report = run_open(addr, 1, Transport::Tcp, closed_loop(0, Distribution::Zero));
+        assert!(report.last_result().unwrap().actual > 0);
+        let requests = server.join().unwrap()[0] as u64;
+        // Every worker has exited, so its phases are counted.
+        let phases = profile::stats().unwrap();
+        for p in &phases {
+            assert!(
+                p.calls >= requests && p.total > Duration::from_nanos(0),
+                "{}",
+                p.phase.name()
+            );
+        }
+    }
+
+    #[test]
+    fn bandwidth_counts_requests_and_responses() {
+        let (addr, server) = counting_server(1, |_| true);
+        let report = run_open(addr, 1, Transport::Tcp, closed_loop(0, Distribution::Zero));
+        let requests = server.join().unwrap()[0] as u64;
+        // Each synthetic request and its echo are 16 bytes, and only the
+        // run's own connection counts.
+        let bandwidth = report.bandwidth().unwrap();
+        assert!(requests > 0);
+        assert_eq!(bandwidth.bytes_out, 16 * requests);
+        assert_eq!(bandwidth.bytes_in, 16 * requests);
+        assert!(bandwidth.seconds > 0.0);
+        let mbps = bandwidth.bytes_out as f64 / bandwidth.seconds / 1e6;
+        assert!((bandwidth.out_mbps - mbps).abs() < 1e-9);
+        // Every request was written in a segment of its own, and each
+        // response counts one however many reads it took.
+        assert_eq!(bandwidth.headers_out, 52 * requests);
+        assert_eq!(bandwidth.headers_in, 52 * requests);
+        assert!(bandwidth.out_gbps > bandwidth.out_mbps * 8.0 / 1000.0);
+        // Each response was read on its own, so its bytes are its op's.
+        let work = &report.last_result().unwrap().ops[0];
+        assert_eq!(work.op, "work");
+        assert_eq!(work.bytes_out, 16 * work.sent as u64);
+        assert_eq!(work.bytes_in, 16 * work.completed as u64);
+
+        let mut idle = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            Vec::new(),
+        );
+        assert!(idle.bandwidth().is_none());
+        idle.record_bytes((3_000_000, 1_000_000), (750_000, 0), Duration::from_secs(2));
+        let bandwidth = idle.bandwidth().unwrap();
+        assert_eq!((bandwidth.out_mbps, bandwidth.in_mbps), (1.5, 0.5));
+        // Headers count on the wire, but not towards the bytes written.
+        assert!((bandwidth.out_gbps - 0.015).abs() < 1e-12);
+        assert!((bandwidth.in_gbps - 0.004).abs() < 1e-12);
+    }
+
+    #[test]
+    fn pool_avoids_stuck_connection() {
+        // The first connection is read but never answered, the second echoes.
+        let run = |policy| {
+            let (addr, server) = counting_server(2, |c| c == 1);
+            let opts = ClientOptions {
+                conns_per_thread: 2,
+                pool_policy: policy,
+                drain_timeout: Duration::from_millis(50),
+                ..closed_loop(0, Distribution::Zero)
+            };
+            let report = run_pooled(addr, 1, opts);
+            let requests = server.join().unwrap();
+            assert_eq!(report.connection_requests(), &requests[..]);
+            (requests, report.last_result().unwrap().timed_out)
+        };
+
+        // Turns split the requests evenly, and half of them never come back.
+        let (requests, timed_out) = run(PoolPolicy::RoundRobin);
+        assert!((requests[0] as i64 - requests[1] as i64).abs() <= 1);
+        assert!(timed_out > 0);
+
+        // The stuck connection keeps its first request outstanding, so the
+        // least loaded one takes the rest.
+        let (requests, _) = run(PoolPolicy::LeastOutstanding);
+        assert!(requests[0] >= 1 && requests[0] < 10, "{:?}", requests);
+        assert!(requests[1] > 500, "{:?}", requests);
+    }
+
+    #[test]
+    fn load_spread_over_connections() {
+        // Six connections over two threads, all answered.
+        let (addr, server) = counting_server(6, |_| true);
+        let opts = ClientOptions {
+            conns_per_thread: 3,
+            ..closed_loop(0, Distribution::Zero)
+        };
+        let report = run_pooled(addr, 2, opts);
+        let requests = server.join().unwrap();
+        assert_eq!(report.connection_requests(), &requests[..]);
+
+        // Each thread takes its turns over its own three, and the threads
+        // split the schedule between them.
+        for pool in requests.chunks(3) {
+            let (min, max) = (pool.iter().min().unwrap(), pool.iter().max().unwrap());
+            assert!(*min > 0 && max - min <= 1, "{:?}", requests);
+        }
+        let (first, second) = (requests[0] as f64, requests[3] as f64);
+        assert!((first / second - 1.0).abs() < 0.3, "{:?}", requests);
+        let result = report.last_result().unwrap();
+        assert_eq!((result.dropped, result.timed_out), (0, 0));
+        assert!(result.latencies.is_some());
+    }
+
+    #[test]
+    fn epoll_reads_many_connections() {
+        // One of the 64 is never answered, so its requests time out while
+        // the receive thread goes on with the rest.
+        let (addr, server) = counting_server(64, |c| c != 5);
+        let opts = ClientOptions {
+            conns_per_thread: 64,
+            epoll: true,
+            drain_timeout: Duration::from_millis(50),
+            ..closed_loop(0, Distribution::Zero)
+        };
+        let report = run_pooled(addr, 1, opts);
+        let requests = server.join().unwrap();
+        assert_eq!(report.connection_requests(), &requests[..]);
+        let result = report.last_result().unwrap();
+        assert!(result.timed_out > 0 && result.timed_out <= requests[5]);
+        assert_eq!(result.dropped, 0);
+        assert!(result.latencies.is_some());
+
+        // Shutting down datagram sockets ends the receive thread too.
+        let addr = echo_udp_server("127.0.0.1:0");
+        let report = run_open(addr, 1, Transport::Udp, opts);
+        let result = report.last_result().unwrap();
+        assert!(result.actual > 0);
+        assert_eq!(result.dropped, 0);
+    }
+
+    #[test]
+    fn connect_latency_per_connection() {
+        let (listener, addr) = silent_server();
+        let mut times = Vec::new();
+        let _sockets: Vec<Connection> = (0..3)
+            .map(|_| {
+                open_connection(
+                    Backend::Linux,
+                    Protocol::Synthetic,
+                    Transport::Tcp,
+                    None,
+                    addr,
+                    closed_loop(1, Distribution::Zero),
+                    &mut times,
+                )
+                .unwrap()
+            })
+            .collect();
+        // Nothing is connected over UDP.
+        open_connection(
+            Backend::Linux,
+            Protocol::Synthetic,
+            Transport::Udp,
+            None,
+            addr,
+            closed_loop(1, Distribution::Zero),
+            &mut times,
+        )
+        .unwrap();
+        assert_eq!(times.len(), 3);
+        drop(listener);
+
+        let mut connects = ConnectLatencies::new();
+        connects.add(&times);
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            Some(connects),
+            parse_percentiles("50,99").unwrap(),
+        );
+        report.record_connects(&times[..1]);
+        let summary = report.connect_summary().unwrap();
+        assert_eq!(summary.connections, 4);
+        let slowest = times.iter().max().unwrap();
+        assert_eq!(
+            summary.latencies.get(99.0),
+            Some(duration_to_ns(*slowest) as f32 / 1000.0)
+        );
+    }
+
+    #[test]
+    fn think_time_follows_completion() {
+        use std::io::Read;
+        use std::thread;
+
+        let (listener, addr) = silent_server();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = [0; 16];
+            while conn.read_exact(&mut request).is_ok() {
+                conn.write_all(&request).unwrap();
+            }
+        });
+
+        let think = Duration::from_millis(2);
+        let socket = Backend::Linux.create_tcp_connection(None, addr).unwrap();
+        let mut packets: Vec<Packet> = (0..10).map(|_| Packet::default()).collect();
+        let end = pipeline_packets(
+            link(
+                client(
+                    Protocol::Synthetic,
+                    Transport::Tcp,
+                    closed_loop(1, Distribution::Constant(duration_to_ns(think))),
+                ),
+                addr,
+            ),
+            &socket,
+            &mut packets,
+            &unlimited(),
+            None,
+            &mut 0,
+        );
+        socket.shutdown();
+        server.join().unwrap();
+
+        // With one request in flight, each send waits out the think time after
+        // the previous response, which is when it was meant to go out.
+        assert_eq!(end, Ok(packets.len()));
+        for w in packets.windows(2) {
+            let completed = w[0].completion_time.unwrap();
+            assert_eq!(w[1].target_start, completed + think);
+            assert!(w[1].actual_start.unwrap() >= completed + think);
+        }
+    }
+
+    #[test]
+    fn sessions_reconnect() {
+        use std::io::Read;
+        use std::thread;
+
+        // Echoes every connection, counting them.
+        let (listener, addr) = silent_server();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted2 = accepted.clone();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                accepted2.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut request = [0; 16];
+                    while conn.read_exact(&mut request).is_ok() {
+                        conn.write_all(&request).unwrap();
+                    }
+                });
+            }
+        });
+
+        let start = Instant::now();
+        let socket = Arc::new(Backend::Linux.create_tcp_connection(None, addr).unwrap());
+        let receiving = Arc::new(AtomicBool::new(true));
+        let mut packets: Vec<Packet> = (0..30).map(|_| Packet::default()).collect();
+        let opts = ClientOptions {
+            session_length: Some(Distribution::Constant(4)),
+            ..closed_loop(2, Distribution::Zero)
+        };
+        let mut stats = ConnStats::default();
+        let (end, timed_out) = run_sessions(
+            Link {
+                start,
+                ..link(client(Protocol::Synthetic, Transport::Tcp, opts), addr)
+            },
+            &socket,
+            &receiving,
+            &mut packets,
+            &unlimited(),
+            &Arc::new(AtomicU64::new(u64::MAX)),
+            &mut stats,
+        );
+
+        assert_eq!((end, timed_out), (30, false));
+        assert!(!receiving.load(Ordering::SeqCst));
+        assert!(packets.iter().all(|p| p.completion_time.is_some()));
+        // The first session is staggered, and every later one is a full four
+        // requests on a new connection.
+        let starts: Vec<usize> = (0..30).filter(|&i| packets[i].reconnected).collect();
+        assert!(starts[0] >= 1 && starts[0] <= 4);
+        assert!(starts.windows(2).all(|w| w[1] - w[0] == 4));
+        assert_eq!(stats.connect_times.len(), starts.len());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1 + starts.len());
+    }
+
+    /// A meta server that answers every GET with a miss and every store with
+    /// HD, leaving out the opaque.
+    fn opaqueless_meta_server() -> SocketAddr {
+        use std::io::{BufRead, BufReader};
+
+        let (listener, addr) = silent_server();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let conn = conn.unwrap();
+                std::thread::spawn(move || {
+                    let mut writer = conn.try_clone().unwrap();
+                    let mut reader = BufReader::new(conn);
+                    let mut line = Vec::new();
+                    while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                        let response: &[u8] = match &line[..2] {
+                            b"mg" => b"EN\r\n",
+                            _ => b"HD\r\n",
+                        };
+                        // A store's value follows on a line of its own.
+                        if line.starts_with(b"ms") {
+                            reader.read_until(b'\n', &mut Vec::new()).unwrap();
+                        }
+                        line.clear();
+                        if writer.write_all(response).is_err() {
+                            return;
+                        }
                     }
-                }
-                let step_size = (packets_per_second - start_packets_per_second) / samples;
-                for j in 1..=samples {
-                    let sched = gen_classic_packet_schedule(
-                        runtime,
-                        start_packets_per_second + step_size * j,
-                        output,
-                        distribution,
-                        0,
-                        nthreads,
-                    );
-                    run_local(
-                        backend,
-                        nthreads,
-                        fakeworker.clone(),
-                        &sched,
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn send_order_matches_the_oldest() {
+        let order = SendOrder::default();
+        for i in [3, 5, 7, 8] {
+            order.send(i);
+        }
+        assert_eq!(order.answer(UNMATCHED), 3);
+        assert_eq!(order.answer(7), 7);
+        assert_eq!(order.answer(UNMATCHED), 5);
+        assert_eq!(order.answer(UNMATCHED), 8);
+        assert_eq!(order.answer(UNMATCHED), UNMATCHED);
+        // A response to a request no longer in line is left as it is.
+        assert_eq!(order.answer(3), 3);
+    }
+
+    #[test]
+    fn pipelined_meta_misses_matched_by_order() {
+        let addr = opaqueless_meta_server();
+        let socket = Backend::Linux.create_tcp_connection(None, addr).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut packets: Vec<Packet> = (0..40)
+            .map(|_| Packet {
+                randomness: rng.gen(),
+                ..Default::default()
+            })
+            .collect();
+        let mut spurious = 0;
+        let protocol = Protocol::MemcachedMeta(MetaProtocol::default());
+        let end = pipeline_packets(
+            link(
+                client(protocol, Transport::Tcp, closed_loop(4, Distribution::Zero)),
+                addr,
+            ),
+            &socket,
+            &mut packets,
+            &unlimited(),
+            None,
+            &mut spurious,
+        );
+        socket.shutdown();
+
+        assert_eq!((end, spurious), (Ok(40), 0));
+        assert!(packets.iter().all(|p| p.completion_time.is_some()));
+        assert!(packets.iter().all(|p| p.miss == (p.info.op == "get")));
+        assert!(packets.iter().any(|p| p.miss));
+    }
+
+    #[test]
+    fn open_loop_meta_misses_matched_by_order() {
+        let addr = opaqueless_meta_server();
+        let protocol = Protocol::MemcachedMeta(MetaProtocol::default());
+        let mut rng = rand::thread_rng();
+        // One connection, a pool read by a thread each, and one read by epoll.
+        // Requests sent too late to count are skipped, so only those sent are
+        // checked. The drains end once every request is answered, so long
+        // ones only matter on a loaded machine.
+        for &(conns, epoll) in &[(1, false), (2, false), (2, true)] {
+            let packets: Vec<Packet> = (0..200)
+                .map(|i| Packet {
+                    target_start: Duration::from_micros(100 * i),
+                    randomness: rng.gen(),
+                    ..Default::default()
+                })
+                .collect();
+            let opts = ClientOptions {
+                epoll,
+                drain_timeout: Duration::from_secs(5),
+                ..closed_loop(0, Distribution::Zero)
+            };
+            let link = link(client(protocol, Transport::Tcp, opts), addr);
+            let mut sockets: Vec<Connection> = (0..conns)
+                .map(|_| Backend::Linux.create_tcp_connection(None, addr).unwrap())
+                .collect();
+            let limit = Arc::new(unlimited());
+            let (packets, spurious) = match conns {
+                1 => {
+                    let mut packets = packets;
+                    let drain_until = Arc::new(AtomicU64::new(5_000_000_000));
+                    let mut stats = ConnStats::default();
+                    let socket = sockets.pop().unwrap();
+                    let end = run_open_loop(
+                        link,
+                        socket,
+                        &mut packets,
+                        &limit,
+                        None,
+                        &drain_until,
+                        &mut stats,
                     );
-                    backend.sleep(Duration::from_secs(3));
+                    packets.truncate(end);
+                    (packets, stats.spurious)
                 }
-            });
+                _ => {
+                    let (packets, _, spurious) = run_pool(link, sockets, packets, &limit, None);
+                    (packets, spurious)
+                }
+            };
+            assert_eq!(spurious, 0);
+            let sent: Vec<&Packet> = packets
+                .iter()
+                .filter(|p| p.actual_start.is_some())
+                .collect();
+            assert!(!sent.is_empty());
+            assert!(sent.iter().all(|p| p.completion_time.is_some()));
+            assert!(sent.iter().all(|p| p.miss == (p.info.op == "get")));
         }
-        "linux-client" | "runtime-client" => {
-            backend.init_and_run(config, move || {
-                println!("Distribution, Target, Actual, Dropped, Never Sent, Median, 90th, 99th, 99.9th, 99.99th, Start");
-                match (proto, &barrier_group) {
-                    (_, Some(lockstep::Group::Client(ref _c))) => (),
-                    (Protocol::Memcached, _) => {
-                        if !run_memcached_preload(backend, Transport::Tcp, addr, nthreads) {
-                            panic!("Could not preload memcached");
+    }
+
+    /// A binary memcached server that fails every third request it is sent,
+    /// by opaque, with NotStored, and answers the rest with no body.
+    fn failing_server() -> SocketAddr {
+        use std::io::Read;
+
+        let (listener, addr) = silent_server();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                std::thread::spawn(move || {
+                    let mut header = [0; 24];
+                    while conn.read_exact(&mut header).is_ok() {
+                        let body =
+                            u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+                        if conn.read_exact(&mut vec![0; body as usize]).is_err() {
+                            return;
                         }
-                    },
-                    _ => (),
-                };
+                        let opaque =
+                            u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+                        let mut response = [0; 24];
+                        response[0] = 0x81;
+                        response[1] = header[1];
+                        if opaque % 3 == 0 {
+                            response[7] = 0x05;
+                        }
+                        response[12..16].copy_from_slice(&header[12..16]);
+                        if conn.write_all(&response).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
 
-                if !loadshift_spec.is_empty() {
-                    let sched = gen_loadshift_experiment(&loadshift_spec, distribution, nthreads);
-                    run_client(
-                        backend,
-                        addr,
-                        nthreads,
-                        proto,
-                        tport,
-                        &mut barrier_group,
-                        &sched,
-                        0,
-                        slowdown,
+    #[test]
+    fn error_statuses_reject_requests_and_reading_goes_on() {
+        let addr = failing_server();
+        let protocol = Protocol::Memcached(MemcachedProtocol::default());
+        // One connection, a pool read by a thread each, and one read by epoll.
+        for &(conns, epoll) in &[(1, false), (2, false), (2, true)] {
+            let packets: Vec<Packet> = (0..60)
+                .map(|i| Packet {
+                    target_start: Duration::from_micros(100 * i),
+                    ..Default::default()
+                })
+                .collect();
+            let opts = ClientOptions {
+                epoll,
+                ..closed_loop(0, Distribution::Zero)
+            };
+            let link = link(client(protocol, Transport::Tcp, opts), addr);
+            let mut sockets: Vec<Connection> = (0..conns)
+                .map(|_| Backend::Linux.create_tcp_connection(None, addr).unwrap())
+                .collect();
+            let limit = Arc::new(unlimited());
+            let packets = match conns {
+                1 => {
+                    let mut packets = packets;
+                    let drain_until = Arc::new(AtomicU64::new(300_000_000));
+                    let socket = sockets.pop().unwrap();
+                    let end = run_open_loop(
+                        link,
+                        socket,
+                        &mut packets,
+                        &limit,
+                        None,
+                        &drain_until,
+                        &mut ConnStats::default(),
                     );
-                    return;
+                    packets.truncate(end);
+                    packets
                 }
+                _ => run_pool(link, sockets, packets, &limit, None).0,
+            };
+            // Requests sent too late to count are skipped.
+            let sent: Vec<(usize, &Packet)> = packets
+                .iter()
+                .enumerate()
+                .filter(|&(_, p)| p.actual_start.is_some())
+                .collect();
+            assert!(sent.iter().any(|&(i, _)| i % 3 == 0));
+            for &(i, p) in &sent {
+                assert!(p.completion_time.is_some(), "{}", i);
+                assert_eq!(p.rejected, i % 3 == 0, "{}", i);
+            }
+        }
+    }
 
-                if dowarmup {
-                    // Run at full pps 3 times for 20 seconds
-                    let sched = gen_classic_packet_schedule(
-                        Duration::from_secs(20),
-                        packets_per_second,
-                        OutputMode::Silent,
-                        distribution,
-                        rampup,
-                        nthreads,
-                    );
+    /// A server whose first connection answers `answered` requests, reads one
+    /// more and closes, while every later one echoes all it is sent. Returns
+    /// how many connections it has accepted so far.
+    fn dropping_server(answered: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        use std::io::Read;
 
-                    for _ in 0..3 {
-                        run_client(
-                            backend,
-                            addr,
-                            nthreads,
-                            proto,
-                            tport,
-                            &mut barrier_group,
-                            &sched,
-                            0,
-                            slowdown,
-                        );
-                        backend.sleep(Duration::from_secs(5));
+        let (listener, addr) = silent_server();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted2 = accepted.clone();
+        std::thread::spawn(move || {
+            for (c, conn) in listener.incoming().enumerate() {
+                let mut conn = conn.unwrap();
+                accepted2.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    let mut request = [0; 16];
+                    let mut requests = 0;
+                    while conn.read_exact(&mut request).is_ok() {
+                        if c == 0 && requests == answered {
+                            return;
+                        }
+                        conn.write_all(&request).unwrap();
+                        requests += 1;
                     }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[test]
+    fn closed_loop_reconnects_after_drop() {
+        let (addr, accepted) = dropping_server(10);
+        let socket = Arc::new(Backend::Linux.create_tcp_connection(None, addr).unwrap());
+        let mut packets: Vec<Packet> = (0..30).map(|_| Packet::default()).collect();
+        let mut stats = ConnStats::default();
+        let (end, timed_out) = run_sessions(
+            link(
+                client(
+                    Protocol::Synthetic,
+                    Transport::Tcp,
+                    closed_loop(1, Distribution::Zero),
+                ),
+                addr,
+            ),
+            &socket,
+            &Arc::new(AtomicBool::new(true)),
+            &mut packets,
+            &unlimited(),
+            &Arc::new(AtomicU64::new(u64::MAX)),
+            &mut stats,
+        );
+
+        // Only the request in flight when the connection closed is lost.
+        assert_eq!((end, timed_out), (30, false));
+        assert_eq!((stats.reconnects, stats.connect_times.len()), (1, 1));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        let failed: Vec<usize> = (0..30).filter(|&i| packets[i].failed).collect();
+        assert_eq!(failed, vec![10]);
+        assert!(packets
+            .iter()
+            .enumerate()
+            .all(|(i, p)| i == 10 || p.completion_time.is_some()));
+    }
+
+    #[test]
+    fn open_loop_reconnects_after_drop() {
+        let run = |reconnect| {
+            let (addr, accepted) = dropping_server(300);
+            let opts = ClientOptions {
+                reconnect,
+                ..closed_loop(0, Distribution::Zero)
+            };
+            let report = run_pooled(addr, 1, opts);
+            let result = report.last_result().unwrap().clone();
+            (report.reconnects(), accepted.load(Ordering::SeqCst), result)
+        };
+
+        // The lost requests are counted apart, and the rest go out on a new
+        // connection.
+        let (reconnects, accepted, result) = run(true);
+        assert_eq!((reconnects, accepted), (1, 2));
+        assert!(result.failed >= 1, "{:?}", result);
+        assert_eq!((result.dropped, result.timed_out), (0, 0));
+        assert!(result.latencies.is_some());
+
+        // Failing fast leaves the rest of the run unsent.
+        let (reconnects, accepted, result) = run(false);
+        assert_eq!((reconnects, accepted), (0, 1));
+        assert!(result.failed >= 1, "{:?}", result);
+        assert_eq!((result.dropped, result.timed_out), (0, 0));
+        assert!(result.never_sent > 500, "{:?}", result);
+    }
+
+    /// Echoes datagrams, holding every 20th back for 30ms.
+    fn stalling_server() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut request = [0; 16];
+            for requests in 0.. {
+                let (_, from) = socket.recv_from(&mut request).unwrap();
+                if requests % 20 == 19 {
+                    let socket = socket.try_clone().unwrap();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(Duration::from_millis(30));
+                        socket.send_to(&request, from).unwrap();
+                    });
+                } else {
+                    socket.send_to(&request, from).unwrap();
                 }
-                println!("finish warmup");
-                let step_size = (packets_per_second - start_packets_per_second) / samples;
-                for j in 1..=samples {
-                    backend.sleep(Duration::from_secs(5));
-                    let sched = gen_classic_packet_schedule(
-                        runtime,
-                        start_packets_per_second + step_size * j,
-                        output,
-                        distribution,
-                        rampup,
-                        nthreads,
-                    );
-                    run_client(
-                        backend,
-                        addr,
-                        nthreads,
-                        proto,
-                        tport,
-                        &mut barrier_group,
-                        &sched,
-                        j,
-                        slowdown,
-                    );
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn timeouts_free_window() {
+        let run = |timeout| {
+            let opts = ClientOptions {
+                max_outstanding: 4,
+                request_timeout: timeout,
+                ..closed_loop(0, Distribution::Zero)
+            };
+            let report = run_open(stalling_server(), 1, Transport::Udp, opts);
+            let result = report.last_result().unwrap().clone();
+            (result, report.timeouts_per_second().iter().sum::<u64>())
+        };
+
+        // Stalled requests time out and free their slots, and their responses
+        // are late.
+        let (result, per_second) = run(Some(Duration::from_millis(3)));
+        assert!(result.timed_out > 0, "{:?}", result);
+        assert_eq!(result.late, result.timed_out);
+        assert!(per_second >= result.timed_out as u64);
+        assert!(result.window_dropped < 100, "{:?}", result);
+        assert_eq!(result.dropped, 0);
+
+        // Without a timeout they hold the window until they are answered.
+        let (result, per_second) = run(None);
+        assert_eq!((result.timed_out, result.late, per_second), (0, 0, 0));
+        assert!(result.window_dropped > 300, "{:?}", result);
+    }
+
+    /// Echoes datagrams, except that it drops the first transmission of every
+    /// tenth request and answers the first transmission of the request five
+    /// before it 20ms late.
+    fn lossy_server() -> SocketAddr {
+        use byteorder::ReadBytesExt;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut seen = std::collections::HashSet::new();
+            let mut request = [0; 16];
+            loop {
+                let (_, from) = socket.recv_from(&mut request).unwrap();
+                let index = (&request[8..]).read_u64::<byteorder::BigEndian>().unwrap();
+                let first = seen.insert(index);
+                match index % 10 {
+                    9 if first => {}
+                    4 if first => {
+                        let socket = socket.try_clone().unwrap();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(20));
+                            socket.send_to(&request, from).unwrap();
+                        });
+                    }
+                    _ => {
+                        socket.send_to(&request, from).unwrap();
+                    }
                 }
-                if let Some(ref mut g) = barrier_group {
-                    g.barrier();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn udp_retransmits() {
+        let run = |retransmits| {
+            let opts = ClientOptions {
+                request_timeout: Some(Duration::from_millis(5)),
+                retransmits,
+                ..closed_loop(0, Distribution::Zero)
+            };
+            let report = run_open(lossy_server(), 1, Transport::Udp, opts);
+            report.last_result().unwrap().clone()
+        };
+
+        // Lost and slow requests are sent again and answered, and the slow
+        // ones' first answers are duplicates.
+        let result = run(2);
+        assert_eq!(result.timed_out, 0, "{:?}", result);
+        assert!(result.duplicates > 0, "{:?}", result);
+        assert!(result.retransmits > result.duplicates, "{:?}", result);
+        // Latencies count from the first transmission.
+        let p99 = result.latencies.as_ref().unwrap().get(99.0).unwrap();
+        assert!(p99 > 5_000.0, "{:?}", result);
+
+        // Without retransmits, they time out.
+        let result = run(0);
+        assert!(result.timed_out > 0, "{:?}", result);
+        assert_eq!((result.retransmits, result.duplicates), (0, 0));
+    }
+
+    #[test]
+    fn target_addresses() {
+        let addrs = Targets::parse("[::1]:11211, 127.0.0.1:80", AddressFamily::Any).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "[::1]:11211".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:80".parse().unwrap()
+            ]
+        );
+        let localhost = Targets::parse("localhost:80", AddressFamily::V4).unwrap();
+        assert_eq!(localhost, vec!["127.0.0.1:80".parse().unwrap()]);
+
+        let e = Targets::parse("127.0.0.1:80", AddressFamily::V6).unwrap_err();
+        assert!(e.contains("no ipv6 address"), "{}", e);
+        assert!(Targets::parse("127.0.0.1", AddressFamily::Any).is_err());
+
+        // Datagrams to IPv6 servers have less room for the value.
+        let v4 = max_udp_value_size(&["127.0.0.1:80".parse().unwrap()]);
+        let v6 = max_udp_value_size(&addrs);
+        assert_eq!(v4, 65507 - memcached::UDP_SET_OVERHEAD);
+        assert_eq!(v6, v4 - 20);
+    }
+
+    #[test]
+    fn ipv6_targets() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        let tcp = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 16];
+                    while conn.read_exact(&mut buf).is_ok() {
+                        conn.write_all(&buf).unwrap();
+                    }
+                });
+            }
+        });
+        let udp = echo_udp_server("[::1]:0");
+
+        for &(addr, tport) in &[(tcp, Transport::Tcp), (udp, Transport::Udp)] {
+            let report = run_open(addr, 2, tport, closed_loop(0, Distribution::Zero));
+            let result = report.last_result().unwrap();
+            assert!(result.actual > 0, "{:?}", result);
+            assert_eq!(
+                report.metadata().address_families,
+                Some(vec!["ipv6".to_string(); 2])
+            );
+        }
+    }
+
+    #[test]
+    fn connections_bound_to_sources() {
+        let addr = echo_udp_server("127.0.0.1:0");
+        // A port free a moment ago, for both source IPs.
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut opts = closed_loop(0, Distribution::Zero);
+        opts.sources = Sources::new(
+            parse_source_ips("127.0.0.1,127.0.0.2").unwrap(),
+            Some((port, port)),
+        );
+        let report = run_open(addr, 2, Transport::Udp, opts);
+        assert!(report.last_result().unwrap().actual > 0);
+        assert_eq!(
+            report.metadata().source_addresses,
+            Some(vec![
+                format!("127.0.0.1:{}", port),
+                format!("127.0.0.2:{}", port)
+            ])
+        );
+
+        // A bind that fails says why.
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let open = |local: &str| {
+            open_connection(
+                Backend::Linux,
+                Protocol::Synthetic,
+                Transport::Udp,
+                Some(local.parse().unwrap()),
+                addr,
+                opts,
+                &mut Vec::new(),
+            )
+            .err()
+            .unwrap()
+        };
+        let e = open(&taken.local_addr().unwrap().to_string());
+        assert!(e.to_string().contains("the port is in use"), "{}", e);
+        let e = open("192.0.2.1:0");
+        assert!(e.to_string().contains("not one of this host's"), "{}", e);
+    }
+
+    /// Sends requests due eight at a time open loop over a connection of its
+    /// own to `addr`, whose traffic it counts, and checks that every one
+    /// sent went out and was answered in a batch of at most
+    /// `opts.udp_batch`, and that some batches carried several.
+    fn check_batched_run(addr: SocketAddr, opts: ClientOptions) {
+        let traffic = Traffic::default();
+        let _counting = traffic.count_here();
+        let socket = Backend::Linux
+            .create_udp_connection(any_addr(addr, 0), Some(addr))
+            .unwrap();
+        let socket = io_path(socket, addr, opts);
+        let mut rng = rand::thread_rng();
+        let mut packets: Vec<Packet> = (0..400)
+            .map(|i| Packet {
+                target_start: Duration::from_micros(1000 + 200 * (i / 8)),
+                randomness: rng.gen(),
+                ..Default::default()
+            })
+            .collect();
+        let drain_until = Arc::new(AtomicU64::new(1_000_000_000));
+        let end = run_open_loop(
+            link(client(Protocol::Synthetic, Transport::Udp, opts), addr),
+            socket,
+            &mut packets,
+            &Arc::new(unlimited()),
+            None,
+            &drain_until,
+            &mut ConnStats::default(),
+        );
+
+        let sent = packets[..end]
+            .iter()
+            .filter(|p| p.actual_start.is_some())
+            .count() as u64;
+        let answered = packets[..end]
+            .iter()
+            .filter(|p| p.completion_time.is_some())
+            .count() as u64;
+        let (out, received) = traffic.counts().batches;
+        assert!(sent > 0);
+        assert_eq!((out.datagrams, answered), (sent, sent));
+        // Requests skipped for running late leave the receiver waiting for
+        // them until the connection is shut down, which it reads as one
+        // empty datagram.
+        let shut_down = (sent < end as u64) as u64;
+        assert_eq!(received.datagrams, sent + shut_down);
+        let size = opts.udp_batch as u64;
+        assert!(out.calls < sent && out.calls * size >= sent, "{:?}", out);
+        assert!(received.calls * size >= sent, "{:?}", received);
+    }
+
+    #[test]
+    fn udp_batches_complete_requests() {
+        let addr = echo_udp_server("127.0.0.1:0");
+
+        let mut opts = closed_loop(0, Distribution::Zero);
+        opts.udp_batch = 8;
+        check_batched_run(addr, opts);
+
+        // Offloads the kernel lacks fall back to sendmmsg and recvmmsg, and
+        // either way a batch counts once, with all its datagrams.
+        opts.udp_gso = true;
+        opts.udp_gro = true;
+        check_batched_run(addr, opts);
+        assert!(batch::gso_stats().unwrap().wanted >= 1);
+        assert!(batch::gro_stats().unwrap().wanted >= 1);
+    }
+
+    #[test]
+    fn generators_feed_open_loop_sends() {
+        let addr = echo_udp_server("127.0.0.1:0");
+
+        let opts = ClientOptions {
+            generators: 2,
+            ..closed_loop(0, Distribution::Zero)
+        };
+        let report = run_open(addr, 1, Transport::Udp, opts);
+        let result = report.last_result().unwrap();
+        assert!(result.actual > 0);
+        assert_eq!(result.dropped, 0);
+        // Most of the 1000 requests are built ahead of the sender.
+        assert!(generator::stats().unwrap().requests >= 900);
+    }
+
+    #[test]
+    fn datagram_size_histogram() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let client = Connection::LinuxUdp(client);
+        let to = client.local_addr();
+
+        let traffic = Traffic::default();
+        let _counting = traffic.count_here();
+        let mut scratch = Vec::new();
+        for &len in &[16, 31, 100, 3000] {
+            server.send_to(&vec![0; len], to).unwrap();
+            Protocol::Synthetic
+                .read_response(&client, Transport::Udp, &mut scratch)
+                .unwrap();
+        }
+        // A truncated response is counted even though it cannot be parsed.
+        server.send_to(&[0; 8], to).unwrap();
+        assert!(Protocol::Synthetic
+            .read_response(&client, Transport::Udp, &mut scratch)
+            .is_err());
+
+        assert_eq!(
+            traffic.counts().datagram_sizes(),
+            vec![(8, 15, 1), (16, 31, 2), (64, 127, 1), (2048, 4095, 1)]
+        );
+    }
+
+    #[test]
+    fn io_uring_completes_requests() {
+        if let Err(e) = uring::check_support() {
+            println!("Skipping, io_uring is unavailable: {}", e);
+            return;
+        }
+        let addr = echo_udp_server("127.0.0.1:0");
+
+        let mut opts = closed_loop(0, Distribution::Zero);
+        for &io_uring in &[false, true] {
+            opts.io_uring = io_uring;
+            let (cpu, rate) =
+                io_bench(Protocol::Synthetic, Transport::Udp, addr, opts, 1000).unwrap();
+            assert!(cpu > 0.0 && rate > 0.0);
+        }
+        opts.udp_batch = 8;
+        check_batched_run(addr, opts);
+    }
+
+    /// A TCP server that answers the first `answered` requests on each
+    /// connection and then reads the rest without answering, as if it hung.
+    fn hanging_server(answered: usize) -> SocketAddr {
+        use std::io::Read;
+
+        let (listener, addr) = silent_server();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 16];
+                    let mut requests = 0;
+                    while conn.read_exact(&mut buf).is_ok() {
+                        if requests < answered {
+                            conn.write_all(&buf).unwrap();
+                        }
+                        requests += 1;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// A TCP server that answers requests whose index ends in 3 twice.
+    fn doubling_server() -> SocketAddr {
+        use byteorder::ReadBytesExt;
+        use std::io::Read;
+
+        let (listener, addr) = silent_server();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 16];
+                    while conn.read_exact(&mut buf).is_ok() {
+                        let index = (&buf[8..]).read_u64::<byteorder::BigEndian>().unwrap();
+                        // Both copies go in one write, which Nagle would
+                        // otherwise hold up.
+                        let copies = if index % 10 == 3 { 2 } else { 1 };
+                        conn.write_all(&buf.repeat(copies)).unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn tcp_duplicates() {
+        // Open loop on one connection and on a pool, and closed loop with a
+        // think time that spreads its requests past the start of the run.
+        for &(conns, depth) in &[(1, 0), (2, 0), (1, 1)] {
+            let opts = ClientOptions {
+                conns_per_thread: conns,
+                ..closed_loop(depth, Distribution::Constant(100_000))
+            };
+            let report = run_pooled(doubling_server(), 1, opts);
+            let result = report.last_result().unwrap();
+            assert!(result.duplicates > 0, "{:?}", result);
+            assert_eq!((result.dropped, result.unanswered), (0, 0), "{:?}", result);
+            assert_eq!(report.spurious(), 0);
+        }
+    }
+
+    #[test]
+    fn watchdog_aborts_hung_run() {
+        for &depth in &[0, 4] {
+            let schedules = gen_classic_packet_schedule(
+                Duration::from_secs(4),
+                10_000,
+                Arrival::Constant,
+                OutputMode::Normal,
+                Distribution::Zero,
+                0,
+                1,
+            );
+            let mut report = Report::new(
+                OutputFormat::Json,
+                RunMetadata::default(),
+                None,
+                None,
+                None,
+                None,
+                parse_percentiles("50,99").unwrap(),
+            );
+            let targets = Targets {
+                addrs: Box::leak(vec![hanging_server(5000)].into_boxed_slice()),
+                weights: &[1],
+                key_hash: false,
+            };
+            let opts = ClientOptions {
+                watchdog: Some(Duration::from_millis(200)),
+                ..closed_loop(depth, Distribution::Zero)
+            };
+            let started = Instant::now();
+            let ok = run_client(
+                client(Protocol::Synthetic, Transport::Tcp, opts),
+                targets,
+                1,
+                &mut None,
+                &schedules,
+                420 + depth,
+                &mut report,
+            );
+            // The run stops soon after responses do, well before its 4s.
+            assert!(!ok);
+            assert!(
+                started.elapsed() < Duration::from_secs(2),
+                "{:?}",
+                started.elapsed()
+            );
+            let result = report.last_result().unwrap();
+            assert_eq!(result.failed, 0, "{:?}", result);
+            // The open-loop run measured requests past the discarded first
+            // 10%, while the closed loop ran through its answers in warmup.
+            if depth == 0 {
+                assert!(result.latencies.is_some(), "{:?}", result);
+                assert!(result.timed_out > 0, "{:?}", result);
+            }
+        }
+    }
+
+    #[test]
+    fn read_timed_out_partway_closes_connection() {
+        let (listener, addr) = silent_server();
+        // Half a response once the first read has timed out, and the rest
+        // once the second has.
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            conn.write_all(&[0; 8]).unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+            let _ = conn.write_all(&[0; 8]);
+        });
+        let sock = Backend::Linux.create_tcp_connection(None, addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let read = || Protocol::Synthetic.read_response(&sock, Transport::Tcp, &mut Vec::new());
+        // A timeout between responses leaves the connection to be read on.
+        assert!(read_timed_out(&read().unwrap_err()));
+        // One partway through a response closes it, so the rest of that one
+        // is never read as the start of another.
+        let err = read().unwrap_err();
+        assert!(!read_timed_out(&err), "{}", err);
+        assert_eq!(read().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        server.join().unwrap();
+    }
+
+    /// A UDP server that never answers requests whose index ends in 9,
+    /// answers those ending in 3 twice, and answers the first with a request
+    /// of its own.
+    fn unreliable_server() -> SocketAddr {
+        use byteorder::{ReadBytesExt, WriteBytesExt};
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut request = [0; 16];
+            let mut first = true;
+            loop {
+                let (_, from) = socket.recv_from(&mut request).unwrap();
+                let index = (&request[8..]).read_u64::<byteorder::BigEndian>().unwrap();
+                if first {
+                    let mut foreign = request;
+                    (&mut foreign[8..])
+                        .write_u64::<byteorder::BigEndian>(1 << 40)
+                        .unwrap();
+                    socket.send_to(&foreign, from).unwrap();
+                    first = false;
+                }
+                let copies = match index % 10 {
+                    9 => 0,
+                    3 => 2,
+                    _ => 1,
+                };
+                for _ in 0..copies {
+                    socket.send_to(&request, from).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn unanswered_and_spurious() {
+        let report = run_open(
+            unreliable_server(),
+            1,
+            Transport::Udp,
+            closed_loop(0, Distribution::Zero),
+        );
+        let result = report.last_result().unwrap().clone();
+        assert!(result.unanswered >= 80, "{:?}", result);
+        let ratio = result.unanswered_ratio.unwrap();
+        assert!(ratio > 0.09 && ratio < 0.11, "{:?}", result);
+        let per_second: u64 = report.unanswered_per_second().iter().sum();
+        assert!(per_second >= result.unanswered as u64);
+        let per_second: u64 = report.duplicates_per_second().iter().sum();
+        assert!(per_second >= result.duplicates as u64);
+        // Copies of answered requests are duplicates, and the foreign
+        // response answers nothing sent.
+        assert!(result.duplicates >= 80, "{:?}", result);
+        assert_eq!(report.spurious(), 1);
+    }
+
+    #[test]
+    fn socket_options_set_on_connect() {
+        use std::os::unix::io::AsRawFd;
+
+        fn get(fd: i32, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    level,
+                    name,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0);
+            value
+        }
+
+        let (_listener, addr) = silent_server();
+        let mut opts = closed_loop(1, Distribution::Zero);
+        opts.sockets = SocketOptions {
+            nodelay: true,
+            send_buffer: Some(65536),
+            recv_buffer: Some(32768),
+            tos: Some(184),
+        };
+        for &tport in &[Transport::Tcp, Transport::Udp] {
+            let socket = open_connection(
+                Backend::Linux,
+                Protocol::Synthetic,
+                tport,
+                None,
+                addr,
+                opts,
+                &mut Vec::new(),
+            )
+            .unwrap();
+            let fd = match socket {
+                Connection::LinuxTcp(ref s) => {
+                    assert!(s.nodelay().unwrap());
+                    s.as_raw_fd()
                 }
+                Connection::LinuxUdp(ref s) => s.as_raw_fd(),
+                _ => unreachable!(),
+            };
+            // Linux doubles the buffer sizes asked for, to leave room for its
+            // own bookkeeping.
+            assert!(get(fd, libc::SOL_SOCKET, libc::SO_SNDBUF) >= 65536);
+            assert!(get(fd, libc::SOL_SOCKET, libc::SO_RCVBUF) >= 32768);
+            assert_eq!(get(fd, libc::IPPROTO_IP, libc::IP_TOS), 184);
+        }
 
-                let mut stat_sock = backend.create_udp_connection("0.0.0.0:0".parse().unwrap(), Some(SocketAddrV4::new(*addr.ip(), 40))).unwrap();
-                stat_sock.write_all(b"stat\n");
+        // Options the socket refuses are reported rather than dropped.
+        let socket = Backend::Linux
+            .create_udp_connection(any_addr(addr, 0), Some(addr))
+            .unwrap();
+        let failed = socket.set_options(SocketOptions {
+            send_buffer: Some(usize::MAX),
+            ..SocketOptions::default()
+        });
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "SO_SNDBUF");
+        assert_eq!(failed[0].1.raw_os_error(), Some(libc::EINVAL));
+    }
 
-                use std::io::Read;
-                let mut buf = [0u8; 1024];
-                let mut response = String::new();
-                stat_sock.read(&mut buf);
-                println!("{}", core::str::from_utf8(&buf).unwrap());
-            });
+    #[test]
+    fn unix_socket_runs_like_tcp() {
+        use std::io::Read;
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("synthetic-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut conn, _) = listener.accept().unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 16];
+                    while conn.read_exact(&mut buf).is_ok() {
+                        conn.write_all(&buf).unwrap();
+                    }
+                });
+            }
+        });
+
+        let path: &'static str = Box::leak(path.to_str().unwrap().to_string().into_boxed_str());
+        let targets = Targets {
+            addrs: Box::leak(vec!["0.0.0.0:0".parse().unwrap()].into_boxed_slice()),
+            weights: &[1],
+            key_hash: false,
+        };
+        let schedules = gen_classic_packet_schedule(
+            Duration::from_millis(200),
+            10_000,
+            Arrival::Constant,
+            OutputMode::Normal,
+            Distribution::Zero,
+            0,
+            1,
+        );
+        // Open and closed loops both read responses framed as over TCP.
+        for &depth in &[0, 1] {
+            let mut report = Report::new(
+                OutputFormat::Json,
+                RunMetadata::default(),
+                None,
+                None,
+                None,
+                None,
+                parse_percentiles("50").unwrap(),
+            );
+            assert!(run_client(
+                client(
+                    Protocol::Synthetic,
+                    Transport::Unix(path),
+                    closed_loop(depth, Distribution::Constant(100_000))
+                ),
+                targets,
+                1,
+                &mut None,
+                &schedules,
+                0,
+                &mut report,
+            ));
+            let result = report.last_result().unwrap();
+            assert!(result.latencies.is_some(), "{:?}", result);
+            assert_eq!((result.dropped, result.timed_out), (0, 0));
+            assert_eq!(report.spurious(), 0);
         }
-        _ => unreachable!(),
-    };
+        server.join().unwrap();
+
+        // A socket that is not there is named in the error.
+        let _ = std::fs::remove_file(path);
+        let err = Backend::Linux.create_unix_connection(path).err().unwrap();
+        assert!(err.to_string().contains(path), "{}", err);
+    }
 }
@@ -1,13 +1,25 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rand::distributions::{Exp, IndependentSample};
-use rand::{Rng, ThreadRng};
+use rand::{SeedableRng, ThreadRng, XorShiftRng};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
 use std::io;
 use std::io::{Error, ErrorKind, Read};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
 
 use super::Distribution;
+use mix::{Op, OpMix};
+use poll::Head;
 use Connection;
+use Outcome;
 use Packet;
+use RequestInfo;
 use Transport;
+use {record_datagram_size, scratch_space, MAX_DATAGRAM};
 
 /** Packet code from https://github.com/aisk/rust-memcache **/
 
@@ -23,10 +35,14 @@ enum Opcode {
     Flush = 0x08,
     Noop = 0x0a,
     Version = 0x0b,
+    GetK = 0x0c,
     GetKQ = 0x0d,
     Append = 0x0e,
     Prepend = 0x0f,
+    SetQ = 0x11,
+    DeleteQ = 0x14,
     Touch = 0x1c,
+    Gat = 0x1d,
 }
 
 enum Magic {
@@ -34,13 +50,110 @@ enum Magic {
     Response = 0x81,
 }
 
-#[allow(dead_code)]
-enum ResponseStatus {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ResponseStatus {
     NoError = 0x00,
     KeyNotFound = 0x01,
     KeyExists = 0x02,
     ValueTooLarge = 0x03,
     InvalidArguments = 0x04,
+    ItemNotStored = 0x05,
+    NonNumeric = 0x06,
+    UnknownCommand = 0x81,
+    OutOfMemory = 0x82,
+    NotSupported = 0x83,
+    InternalError = 0x84,
+    Busy = 0x85,
+    TemporaryFailure = 0x86,
+}
+
+impl ResponseStatus {
+    fn from_u16(status: u16) -> Option<ResponseStatus> {
+        use self::ResponseStatus::*;
+        [
+            NoError,
+            KeyNotFound,
+            KeyExists,
+            ValueTooLarge,
+            InvalidArguments,
+            ItemNotStored,
+            NonNumeric,
+            UnknownCommand,
+            OutOfMemory,
+            NotSupported,
+            InternalError,
+            Busy,
+            TemporaryFailure,
+        ]
+        .iter()
+        .cloned()
+        .find(|&s| s as u16 == status)
+    }
+}
+
+/// Memcached items are at most 1GB, so a longer body means a corrupt header.
+const MAX_BODY_LENGTH: u32 = 1 << 30;
+
+/// The most of a body read into scratch at once; longer values are streamed.
+const BODY_CHUNK: usize = 64 * 1024;
+
+/// Why a response could not be used. Carried inside the `io::Error`s the
+/// protocol returns, from which callers can downcast it.
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    /// The header did not start with the response magic number.
+    BadMagic(u8),
+    /// A datagram too short to hold the frame and response headers, of this
+    /// many bytes.
+    ShortPacket(usize),
+    /// The server failed the request of the given index.
+    Status(ResponseStatus, usize),
+    /// A status the binary protocol does not define, in the response to the
+    /// request of the given index.
+    UnknownStatus(u16, usize),
+    /// A body of more bytes than any item.
+    BodyTooLarge(u32),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolError::BadMagic(magic) => {
+                write!(f, "Bad magic number in response header: {}", magic)
+            }
+            ProtocolError::ShortPacket(len) => write!(f, "Short packet received: {} bytes", len),
+            ProtocolError::Status(status, _) => write!(f, "Response status {:?}", status),
+            ProtocolError::UnknownStatus(status, _) => {
+                write!(f, "Unknown response status {}", status)
+            }
+            ProtocolError::BodyTooLarge(len) => write!(f, "Response body too large: {} bytes", len),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+/// The request that an error status in its response failed, if that is all
+/// `e` carries. The whole response has been read, so a stream connection is
+/// still in step and can go on being read.
+pub fn failed_request(e: &io::Error) -> Option<usize> {
+    match e.get_ref().and_then(|e| e.downcast_ref()) {
+        Some(&ProtocolError::Status(_, idx)) | Some(&ProtocolError::UnknownStatus(_, idx)) => {
+            Some(idx)
+        }
+        _ => None,
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> io::Error {
+        // Receivers skip a malformed datagram, as later ones may be fine.
+        let kind = match e {
+            ProtocolError::ShortPacket(_) => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        };
+        Error::new(kind, e)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -73,10 +186,7 @@ impl PacketHeader {
     fn read<R: io::Read>(reader: &mut R) -> io::Result<PacketHeader> {
         let magic = reader.read_u8()?;
         if magic != Magic::Response as u8 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Bad magic number in response header: {}", magic),
-            ));
+            return Err(ProtocolError::BadMagic(magic).into());
         }
         let header = PacketHeader {
             magic: magic,
@@ -89,41 +199,79 @@ impl PacketHeader {
             opaque: reader.read_u32::<BigEndian>()?,
             cas: reader.read_u64::<BigEndian>()?,
         };
+        if header.total_body_length > MAX_BODY_LENGTH {
+            return Err(ProtocolError::BodyTooLarge(header.total_body_length).into());
+        }
         return Ok(header);
     }
 }
 
 pub const NVALUES: usize = 100000;
 // USR
-static PCT_SET: u64 = 2; // out of 1000
-static VALUE_SIZE: usize = 2;
-static KEY_SIZE: usize = 20;
+pub static VALUE_SIZE: usize = 2;
+pub const KEY_SIZE: usize = 20;
 
 // ETC
-static ETC_PCT_SET: u64 = 30; // out of 1000
-static ETC_KEY_DISTR: Distribution = Distribution::GEV(30.7984, 8.20449, 0.078688);
+/// Default `--etc-key-size`.
+pub const ETC_KEY_SPEC: &str = "gev:30.7984,8.20449,0.078688";
+/// Longest ETC key.
+const MAX_ETC_KEY_SIZE: usize = 256;
+/// Default `--max-value-size`, memcached's default item size limit.
+pub const MAX_VALUE_SIZE: usize = 1024 * 1024;
+/// Bytes of a SET datagram besides its value, at most: the UDP frame
+/// header, the request header and extras, and the longest key. A meta SET's
+/// command line is shorter.
+pub const UDP_SET_OVERHEAD: usize = 8 + 24 + 8 + MAX_ETC_KEY_SIZE;
 static mut ETC_KEY_PRELOAD: [usize; NVALUES] = [0; NVALUES];
-static ETC_VALUE_DISTR1: [(f64, usize); 15] = [
-    (0.00536, 0),
-    (0.00047, 1),
-    (0.17820, 2),
-    (0.09239, 3),
-    (0.00018, 4),
-    (0.02740, 5),
-    (0.00065, 6),
-    (0.00606, 7),
-    (0.00023, 8),
-    (0.00837, 9),
-    (0.00837, 10),
-    (0.08989, 11),
-    (0.00092, 12),
-    (0.00326, 13),
-    (0.01980, 14),
+/// Value size each ETC key was last set to, or 0 if it has not been. Every
+/// thread sets and reads them, so each is an atomic.
+static ETC_VALUE_SIZES: OnceLock<Box<[AtomicUsize]>> = OnceLock::new();
+
+/// The table of `ETC_VALUE_SIZES`, built on first use.
+fn etc_value_sizes() -> &'static [AtomicUsize] {
+    ETC_VALUE_SIZES.get_or_init(|| (0..NVALUES).map(|_| AtomicUsize::new(0)).collect())
+}
+
+// Step CDF of values up to 14 bytes, which make up 44.155% of the values.
+static ETC_VALUE_DISTR1: [(f64, f64); 30] = [
+    (0.0, 0.00000),
+    (0.0, 0.00536),
+    (1.0, 0.00536),
+    (1.0, 0.00583),
+    (2.0, 0.00583),
+    (2.0, 0.18403),
+    (3.0, 0.18403),
+    (3.0, 0.27642),
+    (4.0, 0.27642),
+    (4.0, 0.27660),
+    (5.0, 0.27660),
+    (5.0, 0.30400),
+    (6.0, 0.30400),
+    (6.0, 0.30465),
+    (7.0, 0.30465),
+    (7.0, 0.31071),
+    (8.0, 0.31071),
+    (8.0, 0.31094),
+    (9.0, 0.31094),
+    (9.0, 0.31931),
+    (10.0, 0.31931),
+    (10.0, 0.32768),
+    (11.0, 0.32768),
+    (11.0, 0.41757),
+    (12.0, 0.41757),
+    (12.0, 0.41849),
+    (13.0, 0.41849),
+    (13.0, 0.42175),
+    (14.0, 0.42175),
+    (14.0, 0.44155),
 ];
-static ETC_VALUE_DISTR2: Distribution = Distribution::GPerato(15.0, 214.476, 0.348238);
+static ETC_VALUE_DISTR: Distribution = Distribution::Mixture(&[
+    (0.44155, Distribution::Empirical(&ETC_VALUE_DISTR1)),
+    (0.55845, Distribution::GPerato(15.0, 214.476, 0.348238)),
+]);
 
 #[inline(always)]
-fn write_key(buf: &mut Vec<u8>, key: u64, key_size: usize) {
+pub fn write_key(buf: &mut Vec<u8>, key: u64, key_size: usize) {
     let mut pushed = 0;
     let mut k = key;
     loop {
@@ -139,96 +287,611 @@ fn write_key(buf: &mut Vec<u8>, key: u64, key_size: usize) {
     }
 }
 
-static UDP_HEADER: &'static [u8] = &[0, 0, 0, 0, 0, 1, 0, 0];
+#[inline(always)]
+fn read_key(key: &[u8]) -> u64 {
+    key.iter()
+        .take_while(|c| c.is_ascii_digit())
+        .enumerate()
+        .map(|(idx, c)| (*c - 48) as u64 * 10u64.pow(idx as u32))
+        .sum()
+}
+
+/// Byte `i` of the value stored under `key`. Values depend only on the key so
+/// that any GET can be checked without knowing which SET last wrote it.
+#[inline(always)]
+fn value_byte(key: u64, i: usize) -> u8 {
+    let mut x = key.wrapping_mul(0x9e3779b97f4a7c15) ^ i as u64;
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    (x & 0xff) as u8
+}
+
+#[inline(always)]
+pub fn write_value(buf: &mut Vec<u8>, key: u64, value_size: usize) {
+    for i in 0..value_size {
+        buf.push(value_byte(key, i));
+    }
+}
+
+/// Where the bytes of SET values come from. Every source is a function of the
+/// key and offset alone, so values can always be verified.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum ValueSource {
+    /// Pseudo-random bytes seeded by the key, which do not compress.
+    #[default]
+    Deterministic,
+    /// A pattern repeated up to the value size.
+    Pattern(&'static [u8]),
+    /// A file's contents, from its path, repeated up to the value size.
+    File(&'static str, &'static [u8]),
+}
+
+impl ValueSource {
+    /// Parses `deterministic`, `pattern:TEXT` or `file:PATH`, reading the file
+    /// once up front.
+    pub fn parse(spec: &str) -> Result<ValueSource, String> {
+        let leak = |bytes: Vec<u8>| -> &'static [u8] { Box::leak(bytes.into_boxed_slice()) };
+        let source = if spec == "deterministic" {
+            return Ok(ValueSource::Deterministic);
+        } else if let Some(text) = spec.strip_prefix("pattern:") {
+            ValueSource::Pattern(leak(text.as_bytes().to_vec()))
+        } else if let Some(path) = spec.strip_prefix("file:") {
+            let bytes = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+            ValueSource::File(Box::leak(path.to_string().into_boxed_str()), leak(bytes))
+        } else {
+            return Err(format!("unknown value source '{}'", spec));
+        };
+        match source {
+            ValueSource::Pattern(b) | ValueSource::File(_, b) if b.is_empty() => {
+                Err(format!("value source '{}' has no bytes", spec))
+            }
+            source => Ok(source),
+        }
+    }
+
+    #[inline(always)]
+    fn byte(&self, key: u64, i: usize) -> u8 {
+        match *self {
+            ValueSource::Deterministic => value_byte(key, i),
+            ValueSource::Pattern(bytes) | ValueSource::File(_, bytes) => bytes[i % bytes.len()],
+        }
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>, key: u64, value_size: usize) {
+        match *self {
+            ValueSource::Deterministic => write_value(buf, key, value_size),
+            ValueSource::Pattern(bytes) | ValueSource::File(_, bytes) => {
+                let mut remaining = value_size;
+                while remaining > 0 {
+                    let chunk = usize::min(remaining, bytes.len());
+                    buf.extend_from_slice(&bytes[..chunk]);
+                    remaining -= chunk;
+                }
+            }
+        }
+    }
+
+    /// The first `value_size` bytes of every value, if the source holds them
+    /// in one piece, so that they can be sent without being copied.
+    fn lend(&self, value_size: usize) -> Option<&'static [u8]> {
+        match *self {
+            ValueSource::Pattern(bytes) | ValueSource::File(_, bytes)
+                if value_size <= bytes.len() =>
+            {
+                Some(&bytes[..value_size])
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks `value` against bytes `offset..` of the value stored under `key`.
+    fn verify(&self, key: u64, offset: usize, value: &[u8]) -> bool {
+        value
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == self.byte(key, offset + i))
+    }
+}
+
+/// A single key that a fixed fraction of the requests go to, whatever the
+/// workload would have picked, to reproduce hot-key contention.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HotKey {
+    pub fraction: f64,
+    pub key: u64,
+}
+
+impl HotKey {
+    /// Whether a request goes to the hot key. This draws on all of the
+    /// request's randomness, so it is independent of the key and operation
+    /// picked from its halves.
+    fn takes(&self, p: &Packet) -> bool {
+        let draw = p.randomness.wrapping_mul(0x9e3779b97f4a7c15) >> 11;
+        (draw as f64 / (1u64 << 53) as f64) < self.fraction
+    }
+}
+
+/// The key a request is for, whichever workload generates it and whether it
+/// is a SET or a GET, drawn from `popularity` if given and uniformly
+/// otherwise. Requests can be routed on it before they are generated.
+pub fn request_key(p: &Packet, hot_key: Option<HotKey>, popularity: Option<Distribution>) -> u64 {
+    match (p.trace, hot_key, popularity) {
+        (Some(record), _, _) => record.key,
+        (None, Some(hot), _) if hot.takes(p) => hot.key,
+        (None, _, Some(d)) => {
+            // Seeded by the key's half of the randomness, so that a request
+            // draws the same key however often it is generated.
+            let mut rng = XorShiftRng::from_seed([(p.randomness >> 32) as u32, 0, 0, 0x9e37_79b9]);
+            d.sample(&mut rng) % NVALUES as u64
+        }
+        (None, _, None) => (p.randomness >> 32) % NVALUES as u64,
+    }
+}
+
+/// Picks the operation of a request and its key.
+pub fn choose_request(
+    p: &Packet,
+    hot_key: Option<HotKey>,
+    popularity: Option<Distribution>,
+    mix: OpMix,
+) -> (Op, u64) {
+    if let Some(record) = p.trace {
+        return (if record.set { Op::Set } else { Op::Get }, record.key);
+    }
+    // Use first 32 bits of randomness to determine the operation
+    let low32 = p.randomness & 0xffffffff;
+    (mix.pick(low32 as u32), request_key(p, hot_key, popularity))
+}
+
+/// Value size a traced SET asks for, if it gives one.
+pub fn trace_value_size(p: &Packet) -> Option<usize> {
+    p.trace.and_then(|record| record.value_size)
+}
+
+/// Writes memcached's UDP frame header: request id, sequence number, datagram
+/// count and a reserved field, as big endian u16s. Requests always fit in one
+/// datagram. The server echoes the request id, so responses can be matched to
+/// requests before the memcached header is parsed.
+pub fn write_udp_header(buf: &mut Vec<u8>, request_id: u16) {
+    buf.extend_from_slice(&udp_frame(request_id));
+}
+
+/// The UDP frame header of request `request_id`, for a sender that writes it
+/// apart from the request.
+pub fn udp_frame(request_id: u16) -> [u8; 8] {
+    let [hi, lo] = request_id.to_be_bytes();
+    [hi, lo, 0, 0, 0, 1, 0, 0]
+}
+
+/// Datagrams too short to hold a response header, across all connections.
+static MALFORMED_DATAGRAMS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn malformed_datagrams() -> usize {
+    MALFORMED_DATAGRAMS.load(Ordering::SeqCst)
+}
+
+/// ETC value sizes sampled above the maximum and clamped to it, across all
+/// connections.
+static CLAMPED_VALUES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn clamped_values() -> usize {
+    CLAMPED_VALUES.load(Ordering::SeqCst)
+}
+
+/// Quiet SETQs and DELETEQs the server answered, which it only does when one
+/// fails or, for a DELETEQ, misses, across all connections.
+static QUIET_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn quiet_failures() -> usize {
+    QUIET_FAILURES.load(Ordering::SeqCst)
+}
+
+/// GET hits whose data type was not the one SETs store, for runs that set
+/// one, across all connections.
+static DATA_TYPE_MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn data_type_mismatches() -> usize {
+    DATA_TYPE_MISMATCHES.load(Ordering::SeqCst)
+}
+
+/// The request id of a UDP response frame.
+pub fn udp_request_id(frame: &[u8]) -> io::Result<u16> {
+    (&frame[..2]).read_u16::<BigEndian>()
+}
+
+/// Checks the request id of a UDP response frame against the one the request
+/// was sent with, which is the low 16 bits of its opaque.
+pub fn check_udp_request_id(frame: &[u8], opaque: u32) -> io::Result<()> {
+    let request_id = udp_request_id(frame)?;
+    if request_id != opaque as u16 {
+        return Err(Error::other(format!(
+            "UDP request id {} does not match opaque {}",
+            request_id, opaque
+        )));
+    }
+    Ok(())
+}
+
+arg_enum! {
+#[derive(Copy, Clone, Debug)]
+pub enum Workload {
+    Usr,
+    Etc,
+}}
 
 #[derive(Copy, Clone, Debug)]
-pub struct MemcachedProtocol;
+pub struct MemcachedProtocol {
+    pub workload: Workload,
+    /// Replaces the ETC value size distribution, e.g. to run ETC keys with
+    /// fixed-size values.
+    pub etc_value_size: Option<Distribution>,
+    /// ETC key size distribution. Sizes are clamped to [`KEY_SIZE`,
+    /// `MAX_ETC_KEY_SIZE`].
+    pub etc_key_size: Distribution,
+    /// Largest ETC value size. Larger samples of the heavy-tailed
+    /// distribution are clamped to it, and counted.
+    pub max_value_size: usize,
+    /// Issue GETs as GETKs and check every returned value against its key.
+    pub verify: bool,
+    /// Number of consecutive keys fetched by each USR GET. Values above one send
+    /// a multiget of quiet GETKQs terminated by a regular GET, which is timed as
+    /// a single request.
+    pub fanout: usize,
+    /// Number of consecutive keys written by each USR SET or DELETE. Values
+    /// above one send quiet SETQs or DELETEQs terminated by a regular SET or
+    /// DELETE, which is timed as a single request. The server answers a quiet
+    /// write only if it fails, so a batch's latency is that of its terminator.
+    pub write_batch: usize,
+    pub value_source: ValueSource,
+    pub hot_key: Option<HotKey>,
+    /// How popular each USR key is: keys are drawn from it, modulo NVALUES,
+    /// rather than uniformly. Zipf ranks start at 1, so key 1 is the most
+    /// popular.
+    pub key_distribution: Option<Distribution>,
+    /// Flags stored with every SET, which `verify` checks GETs return.
+    pub flags: u32,
+    /// Data type every store is sent with, such as 1 for a compressed value,
+    /// or 0 for raw bytes. GET hits that do not echo a nonzero one are
+    /// counted.
+    pub data_type: u8,
+    /// Expiration time of every SET, and the one every GAT sets, in seconds,
+    /// or 0 for never.
+    pub exptime: u32,
+    /// Operations requests pick between, or None for the workload's own.
+    pub mix: Option<OpMix>,
+    /// vBuckets the server shards keys over, Couchbase-style, with every
+    /// request carrying its key's, or 0 to leave every request's at 0.
+    pub vbuckets: usize,
+    /// Fraction of requests that are CAS SETs, for a workload in which each
+    /// connection keeps the CAS it last saw of each key and writes with it,
+    /// so that a write loses to any other since; the rest are GETs, which
+    /// read the CAS. None for the workload's own operations.
+    pub cas_writes: Option<f64>,
+    /// Leaves the value of a request's last store out of the buffer it is
+    /// built in, for `gen_parts` to send apart. Set only by `gen_parts`.
+    pub values_apart: bool,
+}
+
+impl Default for MemcachedProtocol {
+    fn default() -> MemcachedProtocol {
+        MemcachedProtocol {
+            workload: Workload::Usr,
+            etc_value_size: None,
+            etc_key_size: ETC_KEY_SPEC.parse().unwrap(),
+            max_value_size: MAX_VALUE_SIZE,
+            verify: false,
+            fanout: 0,
+            write_batch: 0,
+            value_source: ValueSource::default(),
+            hot_key: None,
+            key_distribution: None,
+            flags: 0,
+            data_type: 0,
+            exptime: 0,
+            mix: None,
+            vbuckets: 0,
+            cas_writes: None,
+            values_apart: false,
+        }
+    }
+}
+
+/// The CAS one client of the CAS workload last saw each key with.
+#[derive(Debug, Default)]
+pub struct CasMap {
+    cas: HashMap<u64, u64>,
+}
+
+impl CasMap {
+    /// Takes in the response to a request for `key`. A GET hit or a CAS SET
+    /// that went through gives the key's CAS; after a miss or a rejected
+    /// SET it is unknown until the key is read again.
+    pub fn record(&mut self, key: u64, outcome: Outcome, cas: u64) {
+        match outcome {
+            Outcome::Ok if cas != 0 => {
+                self.cas.insert(key, cas);
+            }
+            _ => {
+                self.cas.remove(&key);
+            }
+        }
+    }
+}
 
 impl MemcachedProtocol {
-    pub fn usr_set_request(key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
-        if let Transport::Udp = tport {
-            buf.extend_from_slice(UDP_HEADER);
+    fn mix(&self) -> OpMix {
+        self.mix.unwrap_or_else(|| match self.workload {
+            Workload::Usr => OpMix::usr(),
+            Workload::Etc => OpMix::etc(),
+        })
+    }
+
+    fn get_opcode(&self) -> Opcode {
+        if self.verify {
+            Opcode::GetK
+        } else {
+            Opcode::Get
         }
+    }
 
+    /// A VERSION request, which a new connection can open with to check that
+    /// the server answers before any timed request is sent. TCP only.
+    pub fn version_request(buf: &mut Vec<u8>) {
         PacketHeader {
             magic: Magic::Request as u8,
-            opcode: Opcode::Set as u8,
-            key_length: KEY_SIZE as u16,
-            extras_length: 8,
-            total_body_length: (8 + KEY_SIZE + VALUE_SIZE) as u32,
-            opaque,
+            opcode: Opcode::Version as u8,
             ..Default::default()
         }
         .write(buf)
         .unwrap();
+    }
 
-        buf.write_u64::<BigEndian>(0).unwrap();
-
-        write_key(buf, key, KEY_SIZE);
-
-        for i in 0..VALUE_SIZE {
-            buf.push((((key * i as u64) >> (i % 4)) & 0xff) as u8);
+    /// Writes the value of a store, unless it ends the request and
+    /// `values_apart` leaves it for the sender.
+    fn write_store_value(&self, buf: &mut Vec<u8>, key: u64, value_size: usize, last: bool) {
+        if !(last && self.values_apart) {
+            self.value_source.write(buf, key, value_size);
         }
     }
 
-    pub fn gen_usr_request(i: usize, p: &Packet, buf: &mut Vec<u8>, tport: Transport) {
-        // Use first 32 bits of randomness to determine if this is a SET or GET req
-        let low32 = p.randomness & 0xffffffff;
-        let key = (p.randomness >> 32) % NVALUES as u64;
+    /// The 8 bytes of extras a SET starts its body with.
+    fn write_set_extras(&self, buf: &mut Vec<u8>) {
+        buf.write_u32::<BigEndian>(self.flags).unwrap();
+        buf.write_u32::<BigEndian>(self.exptime).unwrap();
+    }
 
-        if low32 % 1000 < PCT_SET {
-            MemcachedProtocol::usr_set_request(key, i as u32, buf, tport);
-            return;
+    /// A SET of `value_size` bytes, or of the USR size if None.
+    pub fn usr_set_request(
+        &self,
+        key: u64,
+        value_size: Option<usize>,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) {
+        self.usr_store_request(Op::Set, key, value_size, opaque, buf, tport)
+    }
+
+    /// A SET, ADD or REPLACE of `value_size` bytes, or of the USR size if
+    /// None.
+    fn usr_store_request(
+        &self,
+        op: Op,
+        key: u64,
+        value_size: Option<usize>,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) {
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
         }
+        let value_size = value_size.unwrap_or(VALUE_SIZE);
+        self.write_usr_store(store_opcode(op), key, value_size, opaque, buf);
+    }
 
+    /// A SET of `count` consecutive keys from `key`, of `value_size` bytes or
+    /// the USR size if None: quiet SETQs terminated by a regular SET, which
+    /// is all there is when `count` is one.
+    pub fn quiet_set_request(
+        &self,
+        key: u64,
+        count: usize,
+        value_size: Option<usize>,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) {
         if let Transport::Udp = tport {
-            buf.extend_from_slice(UDP_HEADER);
+            write_udp_header(buf, opaque as u16);
         }
+        let value_size = value_size.unwrap_or(VALUE_SIZE);
+        for k in 0..count as u64 {
+            let opcode = if k + 1 < count as u64 {
+                Opcode::SetQ
+            } else {
+                Opcode::Set
+            };
+            self.write_usr_store(opcode, (key + k) % NVALUES as u64, value_size, opaque, buf);
+        }
+    }
 
+    /// The packet of a USR store without its UDP frame header.
+    fn write_usr_store(
+        &self,
+        opcode: Opcode,
+        key: u64,
+        value_size: usize,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+    ) {
+        let last = !matches!(opcode, Opcode::SetQ);
         PacketHeader {
             magic: Magic::Request as u8,
-            opcode: Opcode::Get as u8,
+            opcode: opcode as u8,
             key_length: KEY_SIZE as u16,
-            total_body_length: KEY_SIZE as u32,
-            opaque: i as u32,
+            extras_length: 8,
+            data_type: self.data_type,
+            total_body_length: (8 + KEY_SIZE + value_size) as u32,
+            opaque,
             ..Default::default()
         }
         .write(buf)
         .unwrap();
 
+        self.write_set_extras(buf);
+
         write_key(buf, key, KEY_SIZE);
+        self.write_store_value(buf, key, value_size, last);
     }
 
-    pub fn etc_value_size(rng: &mut ThreadRng) -> usize {
-        let mut sum = 0.0;
-        let rand = rng.gen::<f64>();
-        for (p, size) in ETC_VALUE_DISTR1 {
-            sum += p;
-            if rand < sum {
-                return size;
+    pub fn gen_usr_request(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> RequestInfo {
+        let (op, key) = choose_request(p, self.hot_key, self.key_distribution, self.mix());
+        let write_batch = usize::max(self.write_batch, 1);
+        match op {
+            Op::Get => {}
+            Op::Delete => {
+                quiet_delete_request(key, write_batch, i as u32, buf, tport);
+                return RequestInfo::new(op.name(), key);
+            }
+            Op::Set => {
+                let value_size = trace_value_size(p);
+                self.quiet_set_request(key, write_batch, value_size, i as u32, buf, tport);
+                return RequestInfo::new(op.name(), key);
+            }
+            Op::Gat => {
+                self.gat_request(key, self.exptime, i as u32, buf, tport);
+                return RequestInfo::new(op.name(), key);
+            }
+            _ => {
+                self.usr_store_request(op, key, trace_value_size(p), i as u32, buf, tport);
+                return RequestInfo::new(op.name(), key);
+            }
+        }
+
+        if let Transport::Udp = tport {
+            write_udp_header(buf, i as u16);
+        }
+
+        let fanout = usize::max(self.fanout, 1) as u64;
+        for k in 0..fanout {
+            let opcode = if k + 1 < fanout {
+                Opcode::GetKQ
+            } else {
+                self.get_opcode()
+            };
+            PacketHeader {
+                magic: Magic::Request as u8,
+                opcode: opcode as u8,
+                key_length: KEY_SIZE as u16,
+                total_body_length: KEY_SIZE as u32,
+                opaque: i as u32,
+                ..Default::default()
             }
+            .write(buf)
+            .unwrap();
+
+            write_key(buf, (key + k) % NVALUES as u64, KEY_SIZE);
+        }
+        RequestInfo::new("get", key)
+    }
+
+    /// Key and value sizes, as recorded in the run metadata.
+    pub fn sizes(&self) -> (String, String) {
+        match self.workload {
+            Workload::Usr => (KEY_SIZE.to_string(), VALUE_SIZE.to_string()),
+            Workload::Etc => (
+                format!("{:?}", self.etc_key_size),
+                match self.etc_value_size {
+                    Some(d) => format!("{:?}", d),
+                    None => "etc".to_string(),
+                },
+            ),
+        }
+    }
+
+    pub fn etc_value_size(&self, rng: &mut ThreadRng) -> usize {
+        let size = self.etc_value_size.unwrap_or(ETC_VALUE_DISTR).sample(rng);
+        if size > self.max_value_size as u64 {
+            CLAMPED_VALUES.fetch_add(1, Ordering::SeqCst);
+            return self.max_value_size;
+        }
+        size as usize
+    }
+
+    fn etc_key_size(&self, rng: &mut ThreadRng) -> usize {
+        u64::max(
+            u64::min(self.etc_key_size.sample(rng), MAX_ETC_KEY_SIZE as u64),
+            KEY_SIZE as u64,
+        ) as usize
+    }
+
+    /// Samples a size for every ETC key, so GETs of keys that have not been
+    /// SET yet use a valid key. Client-only members of a barrier group never
+    /// preload, so this must run before any requests are generated.
+    pub fn init_etc_key_sizes(&self) {
+        let mut rng = rand::thread_rng();
+        let sizes = unsafe { &mut *ptr::addr_of_mut!(ETC_KEY_PRELOAD) };
+        for size in sizes.iter_mut() {
+            *size = self.etc_key_size(&mut rng);
         }
-        ETC_VALUE_DISTR2.sample(rng) as usize
     }
 
-    pub fn etc_set_request(key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+    /// A SET of `value_size` bytes, or of a size drawn for ETC if None.
+    /// Returns the size set, which later GETs of the key are recorded with.
+    pub fn etc_set_request(
+        &self,
+        key: u64,
+        value_size: Option<usize>,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> usize {
+        self.etc_store_request(Op::Set, key, value_size, opaque, buf, tport)
+    }
+
+    /// A SET, ADD or REPLACE of `value_size` bytes, or of a size drawn for
+    /// ETC if None, and returns the size. Only a SET draws a new key size and
+    /// records the value size, as the others may not be stored.
+    fn etc_store_request(
+        &self,
+        op: Op,
+        key: u64,
+        value_size: Option<usize>,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> usize {
         if let Transport::Udp = tport {
-            buf.extend_from_slice(UDP_HEADER);
+            write_udp_header(buf, opaque as u16);
         }
         let mut rng = rand::thread_rng();
-        let value_size = MemcachedProtocol::etc_value_size(&mut rng);
-        let key_size = unsafe {
-            ETC_KEY_PRELOAD[key as usize % NVALUES] =
-                usize::max(usize::min(ETC_KEY_DISTR.sample(&mut rng) as usize, 256), KEY_SIZE);
-            ETC_KEY_PRELOAD[key as usize % NVALUES]
+        let value_size = value_size.unwrap_or_else(|| self.etc_value_size(&mut rng));
+        let key_size = match op {
+            Op::Set => {
+                let key_size = self.etc_key_size(&mut rng);
+                unsafe {
+                    ETC_KEY_PRELOAD[key as usize % NVALUES] = key_size;
+                }
+                etc_value_sizes()[key as usize % NVALUES].store(value_size, Ordering::SeqCst);
+                key_size
+            }
+            _ => unsafe { ETC_KEY_PRELOAD[key as usize % NVALUES] },
         };
-        println!("set {} {} {}", key, key_size, value_size);
 
         PacketHeader {
             magic: Magic::Request as u8,
-            opcode: Opcode::Set as u8,
+            opcode: store_opcode(op) as u8,
             key_length: key_size as u16,
             extras_length: 8,
+            data_type: self.data_type,
             total_body_length: (8 + key_size + value_size) as u32,
             opaque,
             ..Default::default()
@@ -236,34 +899,49 @@ impl MemcachedProtocol {
         .write(buf)
         .unwrap();
 
-        buf.write_u64::<BigEndian>(0).unwrap();
+        self.write_set_extras(buf);
 
         write_key(buf, key, key_size as usize);
-
-        for i in 0..value_size {
-            buf.push((((key * i as u64) >> (i % 4)) & 0xff) as u8);
-        }
+        self.write_store_value(buf, key, value_size, true);
+        value_size
     }
 
-    pub fn gen_etc_request(i: usize, p: &Packet, buf: &mut Vec<u8>, tport: Transport) {
-        // Use first 32 bits of randomness to determine if this is a SET or GET req
-        let low32 = p.randomness & 0xffffffff;
-        let key = (p.randomness >> 32) % NVALUES as u64;
-
-        if low32 % 1000 < ETC_PCT_SET {
-            MemcachedProtocol::etc_set_request(key, i as u32, buf, tport);
-            return;
+    pub fn gen_etc_request(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> RequestInfo {
+        let (op, key) = choose_request(p, self.hot_key, None, self.mix());
+        let key_size = unsafe { ETC_KEY_PRELOAD[key as usize % NVALUES] } as u16;
+        match op {
+            Op::Get => {}
+            Op::Delete => {
+                delete_request(key, key_size as usize, i as u32, buf, tport);
+                return RequestInfo::new(op.name(), key);
+            }
+            Op::Gat => {
+                self.gat_request(key, self.exptime, i as u32, buf, tport);
+                let value_size = etc_value_sizes()[key as usize % NVALUES].load(Ordering::SeqCst);
+                return RequestInfo::new(op.name(), key)
+                    .with_value_size((value_size > 0).then_some(value_size));
+            }
+            _ => {
+                let value_size =
+                    self.etc_store_request(op, key, trace_value_size(p), i as u32, buf, tport);
+                return RequestInfo::new(op.name(), key).with_value_size(Some(value_size));
+            }
         }
 
         if let Transport::Udp = tport {
-            buf.extend_from_slice(UDP_HEADER);
+            write_udp_header(buf, i as u16);
         }
 
-        let key_size = unsafe { ETC_KEY_PRELOAD[key as usize % NVALUES] } as u16;
-        // println!("get {} {}", key, key_size);
+        let value_size = etc_value_sizes()[key as usize % NVALUES].load(Ordering::SeqCst);
         PacketHeader {
             magic: Magic::Request as u8,
-            opcode: Opcode::Get as u8,
+            opcode: self.get_opcode() as u8,
             key_length: key_size,
             total_body_length: key_size as u32,
             opaque: i as u32,
@@ -273,56 +951,1784 @@ impl MemcachedProtocol {
         .unwrap();
 
         write_key(buf, key, key_size as usize);
+        RequestInfo::new("get", key).with_value_size((value_size > 0).then_some(value_size))
+    }
+
+    pub fn set_request(&self, key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+        let from = buf.len();
+        match self.workload {
+            Workload::Usr => self.usr_set_request(key, None, opaque, buf, tport),
+            Workload::Etc => {
+                self.etc_set_request(key, None, opaque, buf, tport);
+            }
+        }
+        self.write_vbuckets(&mut buf[from..], tport);
+    }
+
+    /// The bytes of `key` in the workload.
+    fn key_size(&self, key: u64) -> usize {
+        match self.workload {
+            Workload::Usr => KEY_SIZE,
+            Workload::Etc => unsafe { ETC_KEY_PRELOAD[key as usize % NVALUES] },
+        }
+    }
+
+    /// A GET of `key` alone, with the key size of the workload.
+    pub fn get_request(&self, key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+        let from = buf.len();
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
+        }
+        let key_size = self.key_size(key);
+        PacketHeader {
+            magic: Magic::Request as u8,
+            opcode: Opcode::Get as u8,
+            key_length: key_size as u16,
+            total_body_length: key_size as u32,
+            opaque,
+            ..Default::default()
+        }
+        .write(buf)
+        .unwrap();
+        write_key(buf, key, key_size);
+        self.write_vbuckets(&mut buf[from..], tport);
+    }
+
+    /// A GAT of `key`, which reads its value as a GET does and sets its
+    /// expiration to `exptime`, carried in 4 bytes of extras.
+    pub fn gat_request(
+        &self,
+        key: u64,
+        exptime: u32,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) {
+        let from = buf.len();
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
+        }
+        let key_size = self.key_size(key);
+        PacketHeader {
+            magic: Magic::Request as u8,
+            opcode: Opcode::Gat as u8,
+            key_length: key_size as u16,
+            extras_length: 4,
+            total_body_length: 4 + key_size as u32,
+            opaque,
+            ..Default::default()
+        }
+        .write(buf)
+        .unwrap();
+        buf.write_u32::<BigEndian>(exptime).unwrap();
+        write_key(buf, key, key_size);
+        self.write_vbuckets(&mut buf[from..], tport);
+    }
+
+    /// A request of the CAS workload for `p`'s key: for `cas_writes` of
+    /// them a SET carrying the CAS `expected` has of the key, which the
+    /// server rejects if the key was written since, and otherwise a GET to
+    /// read it. A write of a key whose CAS is not known is sent as the GET
+    /// it needs first.
+    pub fn gen_cas_request(
+        &self,
+        i: usize,
+        p: &Packet,
+        expected: &CasMap,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> RequestInfo {
+        let key = request_key(p, self.hot_key, self.key_distribution);
+        let draw = (p.randomness & 0xffffffff) as f64 / (1u64 << 32) as f64;
+        let write = draw < self.cas_writes.unwrap_or(0.0);
+        match expected.cas.get(&key) {
+            Some(&cas) if write => {
+                let from = buf.len();
+                self.set_request(key, i as u32, buf, tport);
+                let hdr = match tport {
+                    Transport::Udp => from + 8,
+                    _ => from,
+                };
+                (&mut buf[hdr + 16..hdr + 24])
+                    .write_u64::<BigEndian>(cas)
+                    .unwrap();
+                RequestInfo::new("cas", key)
+            }
+            _ => {
+                self.get_request(key, i as u32, buf, tport);
+                RequestInfo::new("get", key)
+            }
+        }
+    }
+
+    pub fn gen_request(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> RequestInfo {
+        let from = buf.len();
+        let info = match self.workload {
+            Workload::Usr => self
+                .gen_usr_request(i, p, buf, tport)
+                .with_key_size(KEY_SIZE),
+            Workload::Etc => {
+                let info = self.gen_etc_request(i, p, buf, tport);
+                let key = info.key.unwrap() as usize % NVALUES;
+                info.with_key_size(unsafe { ETC_KEY_PRELOAD[key] })
+            }
+        };
+        self.write_vbuckets(&mut buf[from..], tport);
+        info
     }
 
-    pub fn set_request(key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
-        // MemcachedProtocol::etc_set_request(key, opaque, buf, tport);
-        MemcachedProtocol::usr_set_request(key, opaque, buf, tport);
+    /// Builds request `i` as `gen_request` does for a stream, but in the parts
+    /// a vectored send writes without copying them together: everything up
+    /// to the key of the last request in `buf`, that key, and the value of a
+    /// store, which is left out of `buf`. The value is lent by the value
+    /// source where it holds the bytes, and written to `value` otherwise.
+    pub fn gen_parts<'a>(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &'a mut Vec<u8>,
+        value: &'a mut Vec<u8>,
+    ) -> (RequestInfo, [&'a [u8]; 3]) {
+        let apart = MemcachedProtocol {
+            values_apart: true,
+            ..*self
+        };
+        let info = apart.gen_request(i, p, buf, Transport::Tcp);
+        // Only the last request claims more of its body than `buf` holds.
+        let mut pos = 0;
+        let end = loop {
+            let end = pos + 24 + (&buf[pos + 8..]).read_u32::<BigEndian>().unwrap() as usize;
+            if end >= buf.len() {
+                break end;
+            }
+            pos = end;
+        };
+        let key_start = pos + 24 + buf[pos + 4] as usize;
+        let key_end = key_start + (&buf[pos + 2..]).read_u16::<BigEndian>().unwrap() as usize;
+        let value_size = end - key_end;
+        value.clear();
+        let value = match self.value_source.lend(value_size) {
+            Some(lent) => lent,
+            None => {
+                let key = read_key(&buf[key_start..key_end]);
+                self.value_source.write(value, key, value_size);
+                &value[..]
+            }
+        };
+        (info, [&buf[..key_start], &buf[key_start..key_end], value])
     }
 
-    pub fn gen_request(i: usize, p: &Packet, buf: &mut Vec<u8>, tport: Transport) {
-        // MemcachedProtocol::gen_etc_request(i, p, buf, tport);
-        MemcachedProtocol::gen_usr_request(i, p, buf, tport);
+    /// Sets the vbucket of each request header in `requests` to its key's,
+    /// if the server shards by vbucket. Responses carry their status in the
+    /// same field, so it is only ever set on requests.
+    fn write_vbuckets(&self, requests: &mut [u8], tport: Transport) {
+        if self.vbuckets == 0 {
+            return;
+        }
+        let mut pos = match tport {
+            Transport::Udp => 8,
+            _ => 0,
+        };
+        while pos + 24 <= requests.len() {
+            let key_length = (&requests[pos + 2..]).read_u16::<BigEndian>().unwrap() as usize;
+            let key_start = pos + 24 + requests[pos + 4] as usize;
+            let body_length = (&requests[pos + 8..]).read_u32::<BigEndian>().unwrap() as usize;
+            let vbucket = vbucket(&requests[key_start..key_start + key_length], self.vbuckets);
+            (&mut requests[pos + 6..pos + 8])
+                .write_u16::<BigEndian>(vbucket)
+                .unwrap();
+            pos += 24 + body_length;
+        }
     }
 
     pub fn read_response(
+        &self,
+        sock: &Connection,
+        tport: Transport,
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<(usize, Outcome)> {
+        self.read_cas_response(sock, tport, scratch)
+            .map(|(idx, outcome, _)| (idx, outcome))
+    }
+
+    /// As `read_response`, with the CAS the response carried. In the CAS
+    /// workload a SET whose key's CAS has changed, or that has gone, is
+    /// rejected, and completes as a miss.
+    pub fn read_cas_response(
+        &self,
+        sock: &Connection,
+        tport: Transport,
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<(usize, Outcome, u64)> {
+        loop {
+            let hdr = self.read_packet(sock, tport, scratch)?;
+            if self.is_rejected_cas(&hdr) {
+                return Ok((hdr.opaque as usize, Outcome::Miss, 0));
+            }
+            // Hits on the quiet GETKQs of a multiget, and failures of the
+            // quiet writes of a write batch, are consumed here; the request
+            // completes with the response to its terminating GET, SET or
+            // DELETE.
+            if let Some((idx, outcome)) = completion(&hdr) {
+                return Ok((idx, outcome, hdr.cas));
+            }
+        }
+    }
+
+    /// Whether a response rejects a SET of the CAS workload, every one of
+    /// which carries a CAS.
+    fn is_rejected_cas(&self, hdr: &PacketHeader) -> bool {
+        let status = hdr.vbucket_id_or_status;
+        self.cas_writes.is_some()
+            && hdr.opcode == Opcode::Set as u8
+            && (status == ResponseStatus::KeyExists as u16
+                || status == ResponseStatus::KeyNotFound as u16)
+    }
+
+    /// Parses a response datagram already received, as one of a batch.
+    /// Returns None for the hit of a quiet GETKQ or the failure of a quiet
+    /// write, which complete nothing.
+    pub fn read_datagram(&self, datagram: &[u8]) -> io::Result<Option<(usize, Outcome)>> {
+        let hdr = self.udp_header(datagram)?;
+        check_status(&hdr)?;
+        self.check_data_type(&hdr);
+        Ok(completion(&hdr))
+    }
+
+    /// Parses a TCP response from the start of what a connection has
+    /// received, for a receive loop that cannot block for the rest of it.
+    /// Only a body being verified must have arrived before it is parsed.
+    pub fn parse_head(&self, head: &[u8]) -> io::Result<Head> {
+        if head.len() < 24 {
+            return Ok(Head::Partial);
+        }
+        let hdr = PacketHeader::read(&mut &head[..24])?;
+        let body_len = hdr.total_body_length as usize;
+        let mut len = 24;
+        if self.verifies(&hdr) {
+            if head.len() < len + body_len {
+                return Ok(Head::Partial);
+            }
+            self.verify_body(&hdr, &head[len..len + body_len])?;
+            len += body_len;
+        }
+        // The body of a failed response must still be skipped, so the failure
+        // completes its request rather than ending the read.
+        let completes = match check_status(&hdr) {
+            Ok(()) => completion(&hdr),
+            Err(_) => Some((hdr.opaque as usize, Outcome::Rejected)),
+        };
+        self.check_data_type(&hdr);
+        Ok(Head::Whole {
+            len,
+            body: 24 + body_len - len,
+            completes,
+        })
+    }
+
+    fn read_packet(
+        &self,
         mut sock: &Connection,
         tport: Transport,
-        scratch: &mut [u8],
-    ) -> io::Result<usize> {
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<PacketHeader> {
         let hdr = match tport {
             Transport::Udp => {
-                let len = sock.read(&mut scratch[..32])?;
+                // The whole datagram is read, even where only the header is
+                // needed, so that its size is recorded.
+                let len = sock.read(scratch_space(scratch, MAX_DATAGRAM))?;
                 if len == 0 {
                     return Err(Error::new(ErrorKind::UnexpectedEof, "eof"));
                 }
-                if len < 8 {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("Short packet received: {} bytes", len),
-                    ));
-                }
-                PacketHeader::read(&mut &scratch[8..])?
-            }
-            Transport::Tcp => {
-                sock.read_exact(&mut scratch[..24])?;
-                let hdr = PacketHeader::read(&mut &scratch[..])?;
-                if let Err(e) = sock.read_exact(&mut scratch[..hdr.total_body_length as usize]) {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("{} {}", e, hdr.total_body_length),
-                    ));
-                };
+                self.udp_header(&scratch[..len])?
+            }
+            Transport::Tcp | Transport::Unix(_) => {
+                sock.read_exact(scratch_space(scratch, 24))?;
+                let hdr = PacketHeader::read(&mut &scratch[..24])?;
+                self.read_tcp_body(&mut sock, &hdr, scratch)?;
                 hdr
             }
         };
+        if !self.is_rejected_cas(&hdr) {
+            check_status(&hdr)?;
+        }
+        self.check_data_type(&hdr);
+        Ok(hdr)
+    }
 
-        if hdr.vbucket_id_or_status != ResponseStatus::NoError as u16 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Not NoError {}", hdr.vbucket_id_or_status),
-            ));
+    /// Counts a GET hit that does not carry the data type its key was SET
+    /// with, if SETs set one.
+    fn check_data_type(&self, hdr: &PacketHeader) {
+        if self.data_type != 0
+            && reads_value(hdr.opcode)
+            && hdr.vbucket_id_or_status == ResponseStatus::NoError as u16
+            && hdr.data_type != self.data_type
+        {
+            DATA_TYPE_MISMATCHES.fetch_add(1, Ordering::SeqCst);
         }
-        Ok(hdr.opaque as usize)
+    }
+
+    /// The header of a response datagram, checked against the frame header
+    /// and with its value verified if needed.
+    fn udp_header(&self, datagram: &[u8]) -> io::Result<PacketHeader> {
+        let len = datagram.len();
+        record_datagram_size(len);
+        // The UDP frame header and the response header must both be whole
+        // before anything is parsed.
+        if len < 8 + 24 {
+            MALFORMED_DATAGRAMS.fetch_add(1, Ordering::SeqCst);
+            return Err(ProtocolError::ShortPacket(len).into());
+        }
+        // A datagram with a bad header is as malformed as a short one, and is
+        // skipped in the same way, as later ones may be fine.
+        let hdr = PacketHeader::read(&mut &datagram[8..]).map_err(|e| {
+            MALFORMED_DATAGRAMS.fetch_add(1, Ordering::SeqCst);
+            match e.into_inner() {
+                Some(e) => Error::new(ErrorKind::InvalidData, e),
+                None => ErrorKind::InvalidData.into(),
+            }
+        })?;
+        check_udp_request_id(datagram, hdr.opaque)?;
+        if self.verifies(&hdr) {
+            self.verify_body(&hdr, &datagram[32..])?;
+        }
+        Ok(hdr)
+    }
+
+    /// Checks the flags and value in the whole body of a GETK response.
+    fn verify_body(&self, hdr: &PacketHeader, body: &[u8]) -> io::Result<()> {
+        let key_start = hdr.extras_length as usize;
+        let value_start = key_start + hdr.key_length as usize;
+        if body.len() < value_start {
+            return Err(Error::other(format!(
+                "Truncated GETK response: {} bytes",
+                body.len()
+            )));
+        }
+        let key = read_key(&body[key_start..value_start]);
+        self.verify_flags(key, &body[..key_start])?;
+        if !self.value_source.verify(key, 0, &body[value_start..]) {
+            return Err(Error::other(format!("Value mismatch for key {}", key)));
+        }
+        Ok(())
+    }
+
+    /// Checks that a GET response's extras carry the flags the key was SET
+    /// with.
+    fn verify_flags(&self, key: u64, extras: &[u8]) -> io::Result<()> {
+        match get_response_flags(extras) {
+            Some(flags) if flags == self.flags => Ok(()),
+            Some(flags) => Err(Error::other(format!(
+                "Flags mismatch for key {}: {:#x}",
+                key, flags
+            ))),
+            None => Err(Error::other(format!(
+                "Bad GET response extras: {} bytes",
+                extras.len()
+            ))),
+        }
+    }
+
+    /// Whether the value in this response should be checked.
+    fn verifies(&self, hdr: &PacketHeader) -> bool {
+        self.verify
+            && hdr.vbucket_id_or_status == ResponseStatus::NoError as u16
+            && (hdr.opcode == Opcode::GetK as u8 || hdr.opcode == Opcode::GetKQ as u8)
+    }
+
+    /// Consumes a response body of any length through `scratch`, at most
+    /// `BODY_CHUNK` bytes at a time, verifying the value on the way if needed.
+    /// `scratch` grows to hold the extras and key at once.
+    fn read_tcp_body(
+        &self,
+        sock: &mut &Connection,
+        hdr: &PacketHeader,
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let body_len = hdr.total_body_length as usize;
+        let read_exact = |sock: &mut &Connection, buf: &mut [u8]| {
+            sock.read_exact(buf)
+                .map_err(|e| Error::other(format!("{} {}", e, body_len)))
+        };
+
+        let verify = self.verifies(hdr);
+        let key_start = hdr.extras_length as usize;
+        let value_start = key_start + hdr.key_length as usize;
+        let mut key = 0;
+        let mut offset = 0;
+        if verify {
+            if body_len < value_start {
+                return Err(Error::other(format!(
+                    "Truncated GETK response: {} bytes",
+                    body_len
+                )));
+            }
+            read_exact(sock, scratch_space(scratch, value_start))?;
+            key = read_key(&scratch[key_start..value_start]);
+            self.verify_flags(key, &scratch[..key_start])?;
+            offset = value_start;
+        }
+
+        while offset < body_len {
+            let chunk = usize::min(body_len - offset, BODY_CHUNK);
+            read_exact(sock, scratch_space(scratch, chunk))?;
+            if verify
+                && !self
+                    .value_source
+                    .verify(key, offset - value_start, &scratch[..chunk])
+            {
+                return Err(Error::other(format!("Value mismatch for key {}", key)));
+            }
+            offset += chunk;
+        }
+        Ok(())
+    }
+}
+
+/// The CRC-32 of each byte value, for `crc32`.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32 of `bytes`, as zlib computes it.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The vbucket of `vbuckets` that `key` maps to, as Couchbase clients map
+/// it: bits 16 to 30 of the key's CRC-32, modulo the count, which for a
+/// power of two count is the mask they apply.
+pub fn vbucket(key: &[u8], vbuckets: usize) -> u16 {
+    (((crc32(key) >> 16) & 0x7fff) as usize % vbuckets) as u16
+}
+
+/// The opcode that stores a value for `op`.
+fn store_opcode(op: Op) -> Opcode {
+    match op {
+        Op::Set => Opcode::Set,
+        Op::Add => Opcode::Add,
+        Op::Replace => Opcode::Replace,
+        Op::Get | Op::Delete | Op::Gat => unreachable!(),
+    }
+}
+
+/// A DELETE of the key of `key_size` bytes.
+fn delete_request(key: u64, key_size: usize, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+    if let Transport::Udp = tport {
+        write_udp_header(buf, opaque as u16);
+    }
+    write_delete(Opcode::Delete, key, key_size, opaque, buf);
+}
+
+/// A DELETE of `count` consecutive USR keys from `key`: quiet DELETEQs
+/// terminated by a regular DELETE, which is all there is when `count` is one.
+pub fn quiet_delete_request(
+    key: u64,
+    count: usize,
+    opaque: u32,
+    buf: &mut Vec<u8>,
+    tport: Transport,
+) {
+    if let Transport::Udp = tport {
+        write_udp_header(buf, opaque as u16);
+    }
+    for k in 0..count as u64 {
+        let opcode = if k + 1 < count as u64 {
+            Opcode::DeleteQ
+        } else {
+            Opcode::Delete
+        };
+        write_delete(opcode, (key + k) % NVALUES as u64, KEY_SIZE, opaque, buf);
+    }
+}
+
+/// The packet of a DELETE without its UDP frame header.
+fn write_delete(opcode: Opcode, key: u64, key_size: usize, opaque: u32, buf: &mut Vec<u8>) {
+    PacketHeader {
+        magic: Magic::Request as u8,
+        opcode: opcode as u8,
+        key_length: key_size as u16,
+        total_body_length: key_size as u32,
+        opaque,
+        ..Default::default()
+    }
+    .write(buf)
+    .unwrap();
+    write_key(buf, key, key_size);
+}
+
+/// Fails a response whose status is an error. A GET that misses still
+/// completes, and is counted as a miss, as do an ADD of a key that exists and
+/// a REPLACE or DELETE of one that does not.
+///
+/// A quiet write is only answered when it fails, which is counted instead, as
+/// its batch still completes with the response to its terminator.
+fn check_status(hdr: &PacketHeader) -> io::Result<()> {
+    if hdr.vbucket_id_or_status != ResponseStatus::NoError as u16
+        && !is_miss(hdr)
+        && !is_quiet_write(hdr)
+    {
+        return Err(match ResponseStatus::from_u16(hdr.vbucket_id_or_status) {
+            Some(status) => ProtocolError::Status(status, hdr.opaque as usize),
+            None => ProtocolError::UnknownStatus(hdr.vbucket_id_or_status, hdr.opaque as usize),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Whether a response answers a read of its key's value, which a GAT's does
+/// as much as a GET's.
+fn reads_value(opcode: u8) -> bool {
+    opcode == Opcode::Get as u8
+        || opcode == Opcode::GetK as u8
+        || opcode == Opcode::GetKQ as u8
+        || opcode == Opcode::Gat as u8
+}
+
+/// Whether a response says its request found nothing to act on.
+fn is_miss(hdr: &PacketHeader) -> bool {
+    let status = hdr.vbucket_id_or_status;
+    let opcode = hdr.opcode;
+    let not_found = status == ResponseStatus::KeyNotFound as u16
+        && (reads_value(opcode)
+            || opcode == Opcode::Replace as u8
+            || opcode == Opcode::Delete as u8);
+    not_found || (status == ResponseStatus::KeyExists as u16 && opcode == Opcode::Add as u8)
+}
+
+/// The request a response completes, and how. The hit of a quiet GETKQ and
+/// the failure of a quiet write complete nothing.
+fn completion(hdr: &PacketHeader) -> Option<(usize, Outcome)> {
+    if is_quiet_write(hdr) {
+        QUIET_FAILURES.fetch_add(1, Ordering::SeqCst);
+        return None;
+    }
+    if hdr.opcode == Opcode::GetKQ as u8 {
+        return None;
+    }
+    let outcome = if is_miss(hdr) {
+        Outcome::Miss
+    } else {
+        Outcome::Ok
+    };
+    Some((hdr.opaque as usize, outcome))
+}
+
+/// Whether a response answers a quiet SETQ or DELETEQ.
+fn is_quiet_write(hdr: &PacketHeader) -> bool {
+    hdr.opcode == Opcode::SetQ as u8 || hdr.opcode == Opcode::DeleteQ as u8
+}
+
+/// The flags in the extras of a GET response, which are those of the SET.
+pub fn get_response_flags(mut extras: &[u8]) -> Option<u32> {
+    match extras.len() {
+        4 => extras.read_u32::<BigEndian>().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn connection_with(data: &[u8]) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(data).unwrap();
+        Connection::LinuxTcp(client)
+    }
+
+    fn getk_response(opaque: u32, key: &[u8], value: &[u8]) -> Vec<u8> {
+        get_response(Opcode::GetK, opaque, key, value)
+    }
+
+    fn get_response(opcode: Opcode, opaque: u32, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        PacketHeader {
+            magic: Magic::Response as u8,
+            opcode: opcode as u8,
+            key_length: key.len() as u16,
+            extras_length: 4,
+            total_body_length: (4 + key.len() + value.len()) as u32,
+            opaque,
+            ..Default::default()
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.write_u32::<BigEndian>(0).unwrap();
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    fn miss_response(opcode: Opcode, opaque: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        PacketHeader {
+            magic: Magic::Response as u8,
+            opcode: opcode as u8,
+            vbucket_id_or_status: ResponseStatus::KeyNotFound as u16,
+            total_body_length: 9,
+            opaque,
+            ..Default::default()
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.extend_from_slice(b"Not found");
+        buf
+    }
+
+    #[test]
+    fn get_misses_counted() {
+        use miss_ratio;
+        use std::time::Duration;
+
+        let mut responses = Vec::new();
+        let misses = [false, true, false, false, true, true, false, false];
+        for (i, &miss) in misses.iter().enumerate() {
+            if miss {
+                responses.extend(miss_response(Opcode::Get, i as u32));
+            } else {
+                responses.extend(get_response(Opcode::Get, i as u32, b"", b"xx"));
+            }
+        }
+        // A SET's completion says nothing about the hit rate.
+        responses.extend(get_response(Opcode::Set, 8, b"", b""));
+
+        let proto = MemcachedProtocol::default();
+        let sock = connection_with(&responses);
+        let mut scratch = vec![0; 4096];
+        let mut packets: Vec<Packet> = (0..9)
+            .map(|i| Packet {
+                info: RequestInfo::new(if i < 8 { "get" } else { "set" }, i),
+                ..Default::default()
+            })
+            .collect();
+        for _ in 0..9 {
+            let (idx, outcome) = proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap();
+            packets[idx].completion_time = Some(Duration::from_micros(1));
+            packets[idx].miss = outcome == Outcome::Miss;
+        }
+        assert_eq!(
+            packets.iter().map(|p| p.miss).collect::<Vec<_>>(),
+            misses
+                .iter()
+                .cloned()
+                .chain(Some(false))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(miss_ratio(packets.iter()), Some(3.0 / 8.0));
+        assert_eq!(miss_ratio(packets[8..].iter()), None);
+
+        // Anything else that fails is still an error.
+        let mut exists = miss_response(Opcode::Set, 1);
+        exists[7] = ResponseStatus::KeyExists as u8;
+        let sock = connection_with(&exists);
+        assert!(proto
+            .read_response(&sock, Transport::Tcp, &mut scratch)
+            .is_err());
+    }
+
+    #[test]
+    fn parts_are_the_request_with_the_value_apart() {
+        static PATTERN: &[u8] = b"abc";
+        let p = Packet {
+            randomness: 77 << 32,
+            ..Default::default()
+        };
+        for &(spec, source, write_batch) in &[
+            ("set:1", ValueSource::Pattern(PATTERN), 1),
+            ("set:1", ValueSource::Deterministic, 1),
+            ("set:1", ValueSource::Pattern(b"x"), 3),
+            ("get:1", ValueSource::Pattern(PATTERN), 1),
+        ] {
+            let proto = MemcachedProtocol {
+                mix: Some(spec.parse().unwrap()),
+                value_source: source,
+                write_batch,
+                ..Default::default()
+            };
+            let mut whole = Vec::new();
+            proto.gen_request(3, &p, &mut whole, Transport::Tcp);
+            let (mut buf, mut value) = (Vec::new(), Vec::new());
+            let (info, parts) = proto.gen_parts(3, &p, &mut buf, &mut value);
+            assert_eq!(info.key, Some(77));
+            assert_eq!(parts.concat(), whole);
+            let [head, key, value] = parts;
+            assert_eq!(read_key(key), 77 + write_batch as u64 - 1);
+            // A value the pattern holds in one piece is sent from it.
+            let lent = spec == "set:1" && source == ValueSource::Pattern(PATTERN);
+            assert_eq!(
+                !value.is_empty() && value.as_ptr() == PATTERN.as_ptr(),
+                lent
+            );
+            assert_eq!(value.len(), if spec == "set:1" { VALUE_SIZE } else { 0 });
+            let built = head.len() + key.len();
+            assert_eq!(buf.len(), built);
+        }
+    }
+
+    #[test]
+    fn mix_picks_opcodes() {
+        let p = Packet {
+            randomness: 77 << 32,
+            ..Default::default()
+        };
+        for &(spec, op, opcode, body) in &[
+            ("add:1", "add", Opcode::Add as u8, 8 + KEY_SIZE + VALUE_SIZE),
+            (
+                "replace:1",
+                "replace",
+                Opcode::Replace as u8,
+                8 + KEY_SIZE + VALUE_SIZE,
+            ),
+            ("delete:1", "delete", Opcode::Delete as u8, KEY_SIZE),
+            ("gat:1", "gat", Opcode::Gat as u8, 4 + KEY_SIZE),
+        ] {
+            let proto = MemcachedProtocol {
+                mix: Some(spec.parse().unwrap()),
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            let info = proto.gen_request(3, &p, &mut buf, Transport::Tcp);
+            assert_eq!((info.op, info.key), (op, Some(77)));
+            assert_eq!(buf[1], opcode);
+            assert_eq!(buf.len(), 24 + body);
+            let extras = match op {
+                "delete" => 0,
+                "gat" => 4,
+                _ => 8,
+            };
+            assert_eq!(read_key(&buf[24 + extras..]), 77);
+        }
+
+        // An ADD of a key that exists and a REPLACE or DELETE of one that
+        // does not are misses.
+        let mut responses = miss_response(Opcode::Add, 1);
+        responses[7] = ResponseStatus::KeyExists as u8;
+        responses.extend(miss_response(Opcode::Replace, 2));
+        responses.extend(miss_response(Opcode::Delete, 3));
+        responses.extend(get_response(Opcode::Delete, 4, b"", b""));
+        let sock = connection_with(&responses);
+        let mut scratch = vec![0; 4096];
+        let proto = MemcachedProtocol::default();
+        for &expected in &[
+            (1, Outcome::Miss),
+            (2, Outcome::Miss),
+            (3, Outcome::Miss),
+            (4, Outcome::Ok),
+        ] {
+            let outcome = proto.read_response(&sock, Transport::Tcp, &mut scratch);
+            assert_eq!(outcome.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn gat_touches_and_reads_like_get() {
+        let proto = MemcachedProtocol::default();
+        let mut buf = Vec::new();
+        proto.gat_request(42, 300, 7, &mut buf, Transport::Tcp);
+        assert_eq!(buf[1], 0x1d);
+        // 4 bytes of extras, the expiration, then the key.
+        assert_eq!(buf[4], 4);
+        assert_eq!(
+            (&buf[8..]).read_u32::<BigEndian>().unwrap() as usize,
+            4 + KEY_SIZE
+        );
+        assert_eq!((&buf[24..]).read_u32::<BigEndian>().unwrap(), 300);
+        assert_eq!(read_key(&buf[28..]), 42);
+        assert_eq!(buf.len(), 28 + KEY_SIZE);
+        let mut datagram = Vec::new();
+        proto.gat_request(42, 300, 7, &mut datagram, Transport::Udp);
+        assert_eq!(&datagram[8..], &buf[..]);
+
+        // Its hits and misses are read as a GET's are.
+        let mut responses = get_response(Opcode::Gat, 1, b"", b"xx");
+        responses.extend(miss_response(Opcode::Gat, 2));
+        responses.extend(get_response(Opcode::Get, 3, b"", b"xx"));
+        let sock = connection_with(&responses);
+        let mut scratch = vec![0; 4096];
+        for &expected in &[(1, Outcome::Ok), (2, Outcome::Miss), (3, Outcome::Ok)] {
+            let outcome = proto.read_response(&sock, Transport::Tcp, &mut scratch);
+            assert_eq!(outcome.unwrap(), expected);
+        }
+        assert!(proto
+            .read_datagram(&[&datagram[..8], &get_response(Opcode::Gat, 7, b"", b"")[..]].concat())
+            .unwrap()
+            .is_some());
+    }
+
+    fn protocol_error(e: &io::Error) -> Option<&ProtocolError> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    #[test]
+    fn response_errors_by_variant() {
+        let proto = MemcachedProtocol::default();
+        let mut scratch = vec![0; 4096];
+        let read = |response: &[u8]| {
+            let mut scratch = vec![0; 4096];
+            proto
+                .read_response(&connection_with(response), Transport::Tcp, &mut scratch)
+                .unwrap_err()
+        };
+
+        let mut bad_magic = get_response(Opcode::Get, 1, b"", b"xx");
+        bad_magic[0] = Magic::Request as u8;
+        let err = read(&bad_magic);
+        assert_eq!(
+            protocol_error(&err),
+            Some(&ProtocolError::BadMagic(Magic::Request as u8))
+        );
+
+        let mut failed = miss_response(Opcode::Set, 1);
+        failed[7] = ResponseStatus::OutOfMemory as u8;
+        let err = read(&failed);
+        assert_eq!(
+            protocol_error(&err),
+            Some(&ProtocolError::Status(ResponseStatus::OutOfMemory, 1))
+        );
+        failed[7] = 0x7f;
+        let err = read(&failed);
+        assert_eq!(
+            protocol_error(&err),
+            Some(&ProtocolError::UnknownStatus(0x7f, 1))
+        );
+        assert_eq!(failed_request(&err), Some(1));
+
+        // The length is refused before any of the body is read.
+        let mut huge = get_response(Opcode::Get, 1, b"", b"xx");
+        (&mut huge[8..12]).write_u32::<BigEndian>(u32::MAX).unwrap();
+        let err = read(&huge);
+        assert_eq!(
+            protocol_error(&err),
+            Some(&ProtocolError::BodyTooLarge(u32::MAX))
+        );
+        assert_eq!(err.kind(), ErrorKind::Other);
+
+        // Socket errors carry no protocol error.
+        let err = proto
+            .read_response(&connection_with(b""), Transport::Tcp, &mut scratch)
+            .unwrap_err();
+        assert_eq!(protocol_error(&err), None);
+    }
+
+    #[test]
+    fn set_then_get_verifies() {
+        let proto = MemcachedProtocol {
+            verify: true,
+            ..Default::default()
+        };
+        let mut set = Vec::new();
+        proto.usr_set_request(4321, None, 0, &mut set, Transport::Tcp);
+        let key = &set[32..32 + KEY_SIZE];
+        let value = &set[32 + KEY_SIZE..];
+        assert_eq!(read_key(key), 4321);
+
+        let mut scratch = vec![0; 4096];
+        let sock = connection_with(&getk_response(7, key, value));
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (7, Outcome::Ok)
+        );
+
+        let mut corrupted = value.to_vec();
+        corrupted[VALUE_SIZE - 1] ^= 0x1;
+        let sock = connection_with(&getk_response(7, key, &corrupted));
+        assert!(proto
+            .read_response(&sock, Transport::Tcp, &mut scratch)
+            .is_err());
+    }
+
+    #[test]
+    fn set_flags_come_back_on_get() {
+        let proto = MemcachedProtocol {
+            verify: true,
+            flags: 0xdead_beef,
+            exptime: 30,
+            ..Default::default()
+        };
+        let mut set = Vec::new();
+        proto.usr_set_request(4321, None, 0, &mut set, Transport::Tcp);
+        let extras = &set[24..32];
+        assert_eq!(get_response_flags(&extras[..4]), Some(0xdead_beef));
+        assert_eq!((&extras[4..]).read_u32::<BigEndian>().unwrap(), 30);
+
+        // A server returns the flags it stored as the extras of a GET.
+        let key = &set[32..32 + KEY_SIZE];
+        let value = &set[32 + KEY_SIZE..];
+        let mut response = getk_response(7, key, value);
+        response[24..28].copy_from_slice(&extras[..4]);
+        let mut scratch = vec![0; 4096];
+        assert_eq!(
+            proto
+                .read_response(&connection_with(&response), Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (7, Outcome::Ok)
+        );
+
+        // Flags the SET did not store fail verification.
+        let err = proto
+            .read_response(
+                &connection_with(&getk_response(7, key, value)),
+                Transport::Tcp,
+                &mut scratch,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("Flags mismatch"), "{}", err);
+        assert_eq!(get_response_flags(&[0; 8]), None);
+    }
+
+    #[test]
+    fn data_type_checked_on_get() {
+        let proto = MemcachedProtocol {
+            data_type: 1,
+            ..Default::default()
+        };
+        let mut set = Vec::new();
+        proto.quiet_set_request(5, 2, None, 0, &mut set, Transport::Tcp);
+        // Every store of the batch carries it.
+        let len = set.len() / 2;
+        assert_eq!((set[5], set[len + 5]), (1, 1));
+
+        // Hits that do not echo it are counted, and misses are not.
+        let key = &set[32..32 + KEY_SIZE];
+        let mut echoed = get_response(Opcode::Get, 1, key, b"xx");
+        echoed[5] = 1;
+        let mut scratch = vec![0; 4096];
+        for (response, counted) in [
+            (echoed, 0),
+            (get_response(Opcode::Get, 2, key, b"xx"), 1),
+            (miss_response(Opcode::Get, 3), 0),
+        ] {
+            let before = data_type_mismatches();
+            proto
+                .read_response(&connection_with(&response), Transport::Tcp, &mut scratch)
+                .unwrap();
+            assert_eq!(data_type_mismatches() - before, counted);
+        }
+        let before = data_type_mismatches();
+        let mut datagram = vec![0; 8];
+        datagram.extend(get_response(Opcode::Get, 0, key, b"xx"));
+        proto.read_datagram(&datagram).unwrap();
+        assert_eq!(data_type_mismatches() - before, 1);
+    }
+
+    #[test]
+    fn body_larger_than_scratch() {
+        let mut key = Vec::new();
+        write_key(&mut key, 99, KEY_SIZE);
+        let mut value = Vec::new();
+        write_value(&mut value, 99, 1000);
+
+        let mut data = get_response(Opcode::Get, 1, &[], &value);
+        data.extend(getk_response(2, &key, &value));
+        data.extend(get_response(Opcode::Get, 3, &[], &[]));
+
+        let mut scratch = vec![0; 64];
+        for &verify in &[false, true] {
+            let proto = MemcachedProtocol {
+                verify,
+                ..Default::default()
+            };
+            let sock = connection_with(&data);
+            for opaque in 1..4 {
+                assert_eq!(
+                    proto
+                        .read_response(&sock, Transport::Tcp, &mut scratch)
+                        .unwrap(),
+                    (opaque, Outcome::Ok)
+                );
+            }
+        }
+
+        let mut corrupted = value.clone();
+        corrupted[900] ^= 0x1;
+        let proto = MemcachedProtocol {
+            verify: true,
+            ..Default::default()
+        };
+        let sock = connection_with(&getk_response(2, &key, &corrupted));
+        assert!(proto
+            .read_response(&sock, Transport::Tcp, &mut scratch)
+            .is_err());
+    }
+
+    #[test]
+    fn scratch_grows_for_large_values() {
+        let mut key = Vec::new();
+        write_key(&mut key, 7, KEY_SIZE);
+        let mut value = Vec::new();
+        write_value(&mut value, 7, 4 * MAX_VALUE_SIZE);
+        let mut data = getk_response(1, &key, &value);
+        data.extend(get_response(Opcode::Get, 2, &[], &value));
+
+        // The responses outgrow the socket buffers, so they are written while
+        // they are read.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let writer = thread::spawn(move || server.write_all(&data).unwrap());
+
+        let proto = MemcachedProtocol {
+            verify: true,
+            ..Default::default()
+        };
+        let sock = Connection::LinuxTcp(client);
+        let mut scratch = Vec::new();
+        for opaque in 1..3 {
+            assert_eq!(
+                proto
+                    .read_response(&sock, Transport::Tcp, &mut scratch)
+                    .unwrap(),
+                (opaque, Outcome::Ok)
+            );
+        }
+        writer.join().unwrap();
+        // Values are streamed through a bounded chunk, not held whole.
+        assert_eq!(scratch.len(), BODY_CHUNK);
+    }
+
+    #[test]
+    fn set_and_get_round_trip_over_unix_socket() {
+        use std::collections::HashMap;
+        use std::io::Read;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!("memcached-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        // Holds what each key was SET to, and answers GETs from it.
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut stored: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+            let mut hdr = [0u8; 24];
+            while conn.read_exact(&mut hdr).is_ok() {
+                // Requests come framed as over TCP, with no UDP header.
+                assert_eq!(hdr[0], Magic::Request as u8);
+                let key_length = (&hdr[2..4]).read_u16::<BigEndian>().unwrap() as usize;
+                let extras_length = hdr[4] as usize;
+                let body_length = (&hdr[8..12]).read_u32::<BigEndian>().unwrap() as usize;
+                let opaque = (&hdr[12..16]).read_u32::<BigEndian>().unwrap();
+                let mut body = vec![0; body_length];
+                conn.read_exact(&mut body).unwrap();
+                let key = body[extras_length..extras_length + key_length].to_vec();
+                let response = if hdr[1] == Opcode::Set as u8 {
+                    stored.insert(key, body[extras_length + key_length..].to_vec());
+                    let mut buf = Vec::new();
+                    PacketHeader {
+                        magic: Magic::Response as u8,
+                        opcode: Opcode::Set as u8,
+                        opaque,
+                        ..Default::default()
+                    }
+                    .write(&mut buf)
+                    .unwrap();
+                    buf
+                } else {
+                    match stored.get(&key) {
+                        Some(value) => get_response(Opcode::Get, opaque, &[], value),
+                        None => {
+                            let mut buf = Vec::new();
+                            PacketHeader {
+                                magic: Magic::Response as u8,
+                                opcode: Opcode::Get as u8,
+                                vbucket_id_or_status: ResponseStatus::KeyNotFound as u16,
+                                opaque,
+                                ..Default::default()
+                            }
+                            .write(&mut buf)
+                            .unwrap();
+                            buf
+                        }
+                    }
+                };
+                conn.write_all(&response).unwrap();
+            }
+            stored
+        });
+
+        let tport = Transport::Unix(Box::leak(
+            path.to_str().unwrap().to_string().into_boxed_str(),
+        ));
+        let sock = Connection::LinuxUnix(UnixStream::connect(&path).unwrap());
+        let proto = MemcachedProtocol::default();
+        let mut scratch = Vec::new();
+        let mut buf = Vec::new();
+        proto.set_request(7, 1, &mut buf, tport);
+        proto.get_request(7, 2, &mut buf, tport);
+        proto.get_request(8, 3, &mut buf, tport);
+        (&sock).write_all(&buf).unwrap();
+        for &expected in &[(1, Outcome::Ok), (2, Outcome::Ok), (3, Outcome::Miss)] {
+            assert_eq!(
+                proto.read_response(&sock, tport, &mut scratch).unwrap(),
+                expected
+            );
+        }
+        drop(sock);
+        let stored = server.join().unwrap();
+        let mut key = Vec::new();
+        write_key(&mut key, 7, KEY_SIZE);
+        let mut value = Vec::new();
+        proto.value_source.write(&mut value, 7, VALUE_SIZE);
+        assert_eq!(stored.get(&key), Some(&value));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn contended_cas_writes_rejected() {
+        use std::io::Read;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use {cas_success_ratio, miss_ratio};
+
+        // Bumps a key's CAS on every SET that carries its current one, and
+        // rejects the rest. Keys not yet written start at CAS 1, as though
+        // preloaded.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let items: Arc<Mutex<HashMap<Vec<u8>, u64>>> = Arc::new(Mutex::new(HashMap::new()));
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let items = items.clone();
+                thread::spawn(move || {
+                    let mut hdr = [0u8; 24];
+                    while conn.read_exact(&mut hdr).is_ok() {
+                        let key_length = (&hdr[2..4]).read_u16::<BigEndian>().unwrap() as usize;
+                        let key_start = hdr[4] as usize;
+                        let body_length = (&hdr[8..12]).read_u32::<BigEndian>().unwrap();
+                        let opaque = (&hdr[12..16]).read_u32::<BigEndian>().unwrap();
+                        let expected = (&hdr[16..24]).read_u64::<BigEndian>().unwrap();
+                        let mut body = vec![0; body_length as usize];
+                        conn.read_exact(&mut body).unwrap();
+                        let key = body[key_start..key_start + key_length].to_vec();
+                        let mut items = items.lock().unwrap();
+                        let cas = items.entry(key).or_insert(1);
+                        let status = if hdr[1] == Opcode::Set as u8 {
+                            if expected == *cas {
+                                *cas += 1;
+                                ResponseStatus::NoError
+                            } else {
+                                ResponseStatus::KeyExists
+                            }
+                        } else {
+                            ResponseStatus::NoError
+                        };
+                        let mut response = Vec::new();
+                        PacketHeader {
+                            magic: Magic::Response as u8,
+                            opcode: hdr[1],
+                            vbucket_id_or_status: status as u16,
+                            opaque,
+                            cas: match status {
+                                ResponseStatus::NoError => *cas,
+                                _ => 0,
+                            },
+                            ..Default::default()
+                        }
+                        .write(&mut response)
+                        .unwrap();
+                        conn.write_all(&response).unwrap();
+                    }
+                });
+            }
+        });
+
+        let proto = MemcachedProtocol {
+            cas_writes: Some(1.0),
+            ..Default::default()
+        };
+        // Two clients, each with its own view of the key's CAS.
+        let mut clients: Vec<(Connection, CasMap)> = (0..2)
+            .map(|_| {
+                let sock = Connection::LinuxTcp(TcpStream::connect(addr).unwrap());
+                (sock, CasMap::default())
+            })
+            .collect();
+        let mut packets: Vec<Packet> = Vec::new();
+        let mut scratch = Vec::new();
+        // Both read the key, then both write it: the second write was
+        // overtaken by the first and is rejected, so its client reads the
+        // key again before its next write goes through.
+        for &(client, op) in &[
+            (0, "get"),
+            (1, "get"),
+            (0, "cas"),
+            (1, "cas"),
+            (1, "get"),
+            (1, "cas"),
+        ] {
+            let (ref sock, ref mut cas) = clients[client];
+            let i = packets.len();
+            let mut p = Packet {
+                randomness: 42 << 32,
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            p.info = proto.gen_cas_request(i, &p, cas, &mut buf, Transport::Tcp);
+            assert_eq!(p.info.op, op);
+            (&*sock).write_all(&buf).unwrap();
+            let (idx, outcome, value) = proto
+                .read_cas_response(sock, Transport::Tcp, &mut scratch)
+                .unwrap();
+            assert_eq!(idx, i);
+            cas.record(42, outcome, value);
+            p.completion_time = Some(Duration::from_micros(1));
+            p.miss = outcome == Outcome::Miss;
+            packets.push(p);
+        }
+        let rejected: Vec<bool> = packets.iter().map(|p| p.miss).collect();
+        assert_eq!(rejected, vec![false, false, false, true, false, false]);
+        assert_eq!(cas_success_ratio(packets.iter()), Some(2.0 / 3.0));
+        // A rejected write is no GET miss.
+        assert_eq!(miss_ratio(packets.iter()), Some(0.0));
+        assert_eq!(cas_success_ratio(packets[..2].iter()), None);
+    }
+
+    #[test]
+    fn etc_constant_value_override() {
+        let proto = MemcachedProtocol {
+            workload: Workload::Etc,
+            etc_value_size: Some(Distribution::Constant(64)),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let mut buf = Vec::new();
+            proto.set_request(17, 0, &mut buf, Transport::Tcp);
+            let key_size = (&buf[2..4]).read_u16::<BigEndian>().unwrap() as usize;
+            assert_eq!(buf.len(), 24 + 8 + key_size + 64);
+        }
+    }
+
+    #[test]
+    fn etc_value_size_capped() {
+        // A tail so heavy that every sample is beyond the cap.
+        let proto = MemcachedProtocol {
+            workload: Workload::Etc,
+            etc_value_size: Some(Distribution::GPerato(1e12, 1e12, 2.0)),
+            max_value_size: 4096,
+            ..Default::default()
+        };
+        let before = clamped_values();
+        for _ in 0..1000 {
+            let mut buf = Vec::new();
+            proto.set_request(17, 0, &mut buf, Transport::Tcp);
+            let key_size = (&buf[2..4]).read_u16::<BigEndian>().unwrap() as usize;
+            assert!(buf.len() <= 24 + 8 + key_size + 4096, "{}", buf.len());
+            assert!(buf.capacity() <= 2 * (24 + 8 + MAX_ETC_KEY_SIZE + 4096));
+        }
+        assert!(clamped_values() - before >= 1000);
+    }
+
+    #[test]
+    fn etc_value_mixture_matches_table() {
+        // The ETC value model as it was sampled before it became a mixture.
+        let old = |rng: &mut ThreadRng| {
+            let rand = rng.gen::<f64>();
+            if rand < ETC_VALUE_DISTR1[ETC_VALUE_DISTR1.len() - 1].1 {
+                return ::distribution::quantile(&ETC_VALUE_DISTR1, rand) as usize;
+            }
+            Distribution::GPerato(15.0, 214.476, 0.348238).sample(rng) as usize
+        };
+        let bucket = |size: usize| match size {
+            0..=14 => size,
+            15..=99 => 15,
+            100..=999 => 16,
+            _ => 17,
+        };
+
+        let n = 1_000_000;
+        let mut rng = rand::thread_rng();
+        let proto = MemcachedProtocol::default();
+        let mut before = vec![0f64; 18];
+        let mut after = vec![0f64; 18];
+        for _ in 0..n {
+            before[bucket(old(&mut rng))] += 1.0 / n as f64;
+            after[bucket(proto.etc_value_size(&mut rng))] += 1.0 / n as f64;
+        }
+        for (b, a) in before.iter().zip(after.iter()) {
+            assert!((b - a).abs() < 0.003, "{:?} vs {:?}", before, after);
+        }
+    }
+
+    #[test]
+    fn etc_uniform_key_override() {
+        let proto = MemcachedProtocol {
+            workload: Workload::Etc,
+            etc_key_size: Distribution::Uniform(30, 40),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let mut buf = Vec::new();
+            proto.set_request(18, 0, &mut buf, Transport::Tcp);
+            let key_size = (&buf[2..4]).read_u16::<BigEndian>().unwrap() as usize;
+            assert!((30..=40).contains(&key_size));
+        }
+    }
+
+    #[test]
+    fn etc_key_sizes_initialized() {
+        let proto = MemcachedProtocol {
+            workload: Workload::Etc,
+            ..Default::default()
+        };
+        proto.init_etc_key_sizes();
+        assert!((0..NVALUES).all(|k| unsafe { ETC_KEY_PRELOAD[k] } >= KEY_SIZE));
+
+        // A GET of a key that was never SET still carries a full key.
+        let p = Packet {
+            randomness: (12345 << 32) | 999,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        proto.gen_request(0, &p, &mut buf, Transport::Tcp);
+        let key_size = (&buf[2..4]).read_u16::<BigEndian>().unwrap() as usize;
+        assert!(key_size >= KEY_SIZE);
+        assert_eq!(buf.len(), 24 + key_size);
+    }
+
+    #[test]
+    fn etc_requests_carry_value_sizes() {
+        let proto = MemcachedProtocol {
+            workload: Workload::Etc,
+            etc_value_size: Some(Distribution::Constant(300)),
+            ..Default::default()
+        };
+        let get = Packet {
+            randomness: 54321 << 32,
+            ..Default::default()
+        };
+        let set = Packet {
+            randomness: (54321 << 32) | 0xffffffff,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        // A GET is recorded with the size its key was last set to, if any.
+        let info = proto.gen_request(0, &get, &mut buf, Transport::Tcp);
+        assert_eq!((info.op, info.value_size), ("get", None));
+        let info = proto.gen_request(1, &set, &mut buf, Transport::Tcp);
+        assert_eq!((info.op, info.value_size), ("set", Some(300)));
+        let info = proto.gen_request(2, &get, &mut buf, Transport::Tcp);
+        assert_eq!(info.value_size, Some(300));
+    }
+
+    #[test]
+    fn hot_key_share() {
+        let mut rng = rand::thread_rng();
+        let hot = HotKey {
+            fraction: 0.2,
+            key: 42,
+        };
+        let proto = MemcachedProtocol {
+            hot_key: Some(hot),
+            ..Default::default()
+        };
+        let n = 100_000;
+        let (mut hits, mut sets) = (0, 0);
+        let mut buf = Vec::new();
+        for i in 0..n {
+            let p = Packet {
+                randomness: rng.gen::<u64>(),
+                ..Default::default()
+            };
+            buf.clear();
+            let info = proto.gen_request(i, &p, &mut buf, Transport::Tcp);
+            assert_eq!(info.key, Some(request_key(&p, Some(hot), None)));
+            if info.key == Some(42) {
+                hits += 1;
+                sets += (info.op == "set") as usize;
+            }
+        }
+        let share = hits as f64 / n as f64;
+        assert!((share - 0.2).abs() < 0.01, "{}", share);
+        // The hot key is SET as often as any other.
+        let set_share = sets as f64 / hits as f64;
+        assert!(set_share < 0.01, "{}", set_share);
+
+        // The rest keep their own keys.
+        let p = Packet {
+            randomness: (7 << 32) | 999,
+            ..Default::default()
+        };
+        let never = HotKey {
+            fraction: 0.0,
+            key: 42,
+        };
+        assert_eq!(request_key(&p, Some(never), None), 7);
+    }
+
+    #[test]
+    fn usr_keys_follow_their_distribution() {
+        let mut rng = rand::thread_rng();
+        let zipf: Distribution = "zipf:1000,1".parse().unwrap();
+        let proto = MemcachedProtocol {
+            key_distribution: Some(zipf),
+            ..Default::default()
+        };
+        let n = 100_000;
+        let mut counts = vec![0usize; 1001];
+        let mut buf = Vec::new();
+        for i in 0..n {
+            let p = Packet {
+                randomness: rng.gen::<u64>(),
+                ..Default::default()
+            };
+            buf.clear();
+            let info = proto.gen_usr_request(i, &p, &mut buf, Transport::Tcp);
+            // Requests are routed on the key they are generated with.
+            let key = request_key(&p, None, Some(zipf));
+            assert_eq!(info.key, Some(key));
+            counts[key as usize] += 1;
+        }
+        assert_eq!(counts[0], 0);
+        // Rank k is drawn in proportion to 1 / k, out of H(1000, 1).
+        let harmonic: f64 = (1..=1000).map(|k| 1.0 / k as f64).sum();
+        for &k in &[1, 2, 10] {
+            let share = counts[k] as f64 / n as f64;
+            let expected = 1.0 / (k as f64 * harmonic);
+            assert!((share - expected).abs() < 0.01, "{} {}", k, share);
+        }
+        let tail: usize = counts[501..].iter().sum();
+        assert!(tail < n / 10, "{}", tail);
+    }
+
+    #[test]
+    fn udp_request_id_from_opaque() {
+        let proto = MemcachedProtocol::default();
+        let mut buf = Vec::new();
+        proto.set_request(5, 0x12345, &mut buf, Transport::Udp);
+        assert_eq!(&buf[..8], &[0x23, 0x45, 0, 0, 0, 1, 0, 0]);
+
+        buf.clear();
+        let p = Packet {
+            randomness: (5 << 32) | 999,
+            ..Default::default()
+        };
+        proto.gen_request(7, &p, &mut buf, Transport::Udp);
+        assert_eq!(&buf[..2], &[0, 7]);
+        assert!(check_udp_request_id(&buf, 7).is_ok());
+        assert!(check_udp_request_id(&buf, 0x10007).is_ok());
+        assert!(check_udp_request_id(&buf, 8).is_err());
+    }
+
+    #[test]
+    fn multiget_fanout() {
+        let proto = MemcachedProtocol {
+            fanout: 4,
+            ..Default::default()
+        };
+        let p = Packet {
+            // GET of key NVALUES - 2, so the fan-out wraps around.
+            randomness: ((NVALUES as u64 - 2) << 32) | 999,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        proto.gen_request(5, &p, &mut buf, Transport::Tcp);
+
+        let mut requests = Vec::new();
+        let mut rest = &buf[..];
+        while !rest.is_empty() {
+            let body_len = (&rest[8..12]).read_u32::<BigEndian>().unwrap() as usize;
+            let opaque = (&rest[12..16]).read_u32::<BigEndian>().unwrap();
+            assert_eq!(opaque, 5);
+            requests.push((rest[1], read_key(&rest[24..24 + body_len])));
+            rest = &rest[24 + body_len..];
+        }
+        let kq = Opcode::GetKQ as u8;
+        let get = Opcode::Get as u8;
+        let last = NVALUES as u64 - 1;
+        assert_eq!(
+            requests,
+            vec![(kq, last - 1), (kq, last), (kq, 0), (get, 1)]
+        );
+
+        // Two quiet hits (one key missed) followed by the terminating GET, then
+        // an unrelated response: the fan-out completes exactly once.
+        let mut responses = get_response(Opcode::GetKQ, 5, b"1", b"xx");
+        responses.extend(get_response(Opcode::GetKQ, 5, b"2", b"xx"));
+        responses.extend(get_response(Opcode::Get, 5, b"", b"xx"));
+        responses.extend(get_response(Opcode::Get, 6, b"", b"xx"));
+        let sock = connection_with(&responses);
+        let mut scratch = vec![0; 4096];
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (5, Outcome::Ok)
+        );
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (6, Outcome::Ok)
+        );
+    }
+
+    #[test]
+    fn parse_head_before_body() {
+        let mut buf = Vec::new();
+        PacketHeader {
+            magic: Magic::Response as u8,
+            opcode: Opcode::Get as u8,
+            extras_length: 4,
+            total_body_length: 10,
+            opaque: 9,
+            ..Default::default()
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.extend_from_slice(&[0; 10]);
+
+        // Only the header must have arrived, and the body is left to skip.
+        let proto = MemcachedProtocol::default();
+        assert_eq!(proto.parse_head(&buf[..23]).unwrap(), Head::Partial);
+        assert_eq!(
+            proto.parse_head(&buf[..26]).unwrap(),
+            Head::Whole {
+                len: 24,
+                body: 10,
+                completes: Some((9, Outcome::Ok)),
+            }
+        );
+
+        // A failed response completes its request, and its body is skipped.
+        buf[7] = ResponseStatus::Busy as u8;
+        assert_eq!(
+            proto.parse_head(&buf[..24]).unwrap(),
+            Head::Whole {
+                len: 24,
+                body: 10,
+                completes: Some((9, Outcome::Rejected)),
+            }
+        );
+    }
+
+    #[test]
+    fn vbuckets_from_keys() {
+        assert_eq!(crc32(b"hello"), 0x3610_a686);
+        assert_eq!(vbucket(b"hello", 1024), 528);
+        assert_eq!(vbucket(b"7AAAAAAAAAAAAAAAAAAA", 1024), 705);
+
+        let p = Packet {
+            randomness: 7 << 32,
+            ..Default::default()
+        };
+        let gets = MemcachedProtocol {
+            mix: Some("get:1".parse().unwrap()),
+            fanout: 2,
+            ..Default::default()
+        };
+        let vbucket_at = |buf: &[u8], at: usize| (&buf[at + 6..]).read_u16::<BigEndian>().unwrap();
+        let vbuckets = |buf: &[u8], from: usize| {
+            (vbucket_at(buf, from), vbucket_at(buf, from + 24 + KEY_SIZE))
+        };
+        // Left at 0 unless the server has vbuckets.
+        let mut buf = Vec::new();
+        gets.gen_request(3, &p, &mut buf, Transport::Tcp);
+        assert_eq!(vbuckets(&buf, 0), (0, 0));
+
+        // Every request of a multiget carries its own key's.
+        let sharded = MemcachedProtocol {
+            vbuckets: 1024,
+            ..gets
+        };
+        buf.clear();
+        sharded.gen_request(3, &p, &mut buf, Transport::Tcp);
+        assert_eq!(vbuckets(&buf, 0), (705, 606));
+        let mut datagram = Vec::new();
+        sharded.gen_request(3, &p, &mut datagram, Transport::Udp);
+        assert_eq!(vbuckets(&datagram, 8), (705, 606));
+
+        // Requests already in the buffer are left as they are.
+        let mut set = Vec::new();
+        gets.set_request(7, 1, &mut set, Transport::Tcp);
+        let len = set.len();
+        sharded.set_request(7, 2, &mut set, Transport::Tcp);
+        assert_eq!(vbucket_at(&set, 0), 0);
+        assert_eq!(vbucket_at(&set, len), 705);
+    }
+
+    #[test]
+    fn quiet_write_batches() {
+        // The quiet opcodes are those of the binary protocol.
+        assert_eq!(Opcode::SetQ as u8, 0x11);
+        assert_eq!(Opcode::DeleteQ as u8, 0x14);
+
+        let proto = MemcachedProtocol {
+            write_batch: 3,
+            mix: Some("set:1".parse().unwrap()),
+            ..Default::default()
+        };
+        let requests = |buf: &[u8]| {
+            let mut requests = Vec::new();
+            let mut rest = buf;
+            while !rest.is_empty() {
+                let extras = rest[4] as usize;
+                let body_len = (&rest[8..12]).read_u32::<BigEndian>().unwrap() as usize;
+                let key = &rest[24 + extras..24 + extras + KEY_SIZE];
+                requests.push((rest[1], read_key(key)));
+                rest = &rest[24 + body_len..];
+            }
+            requests
+        };
+        let p = Packet {
+            randomness: (NVALUES as u64 - 1) << 32,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        proto.gen_request(5, &p, &mut buf, Transport::Tcp);
+        let (setq, set) = (Opcode::SetQ as u8, Opcode::Set as u8);
+        let last = NVALUES as u64 - 1;
+        assert_eq!(requests(&buf), vec![(setq, last), (setq, 0), (set, 1)]);
+
+        let mut buf = Vec::new();
+        quiet_delete_request(7, 2, 5, &mut buf, Transport::Tcp);
+        let (deleteq, delete) = (Opcode::DeleteQ as u8, Opcode::Delete as u8);
+        assert_eq!(requests(&buf), vec![(deleteq, 7), (delete, 8)]);
+
+        // Only a quiet write that fails is answered, and is counted; the
+        // batch completes once, with the response to its terminator.
+        let mut failed = Vec::new();
+        PacketHeader {
+            magic: Magic::Response as u8,
+            opcode: Opcode::SetQ as u8,
+            vbucket_id_or_status: ResponseStatus::OutOfMemory as u16,
+            opaque: 5,
+            ..Default::default()
+        }
+        .write(&mut failed)
+        .unwrap();
+        let mut responses = failed.clone();
+        responses.extend(get_response(Opcode::Set, 5, b"", b""));
+        responses.extend(miss_response(Opcode::Delete, 6));
+        let before = quiet_failures();
+        let sock = connection_with(&responses);
+        let mut scratch = Vec::new();
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (5, Outcome::Ok)
+        );
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (6, Outcome::Miss)
+        );
+        assert!(quiet_failures() > before);
+
+        let mut datagram = Vec::new();
+        write_udp_header(&mut datagram, 5);
+        datagram.extend(failed);
+        assert_eq!(proto.read_datagram(&datagram).unwrap(), None);
+    }
+
+    #[test]
+    fn value_sources() {
+        let body = |source: ValueSource, size| {
+            let mut buf = Vec::new();
+            source.write(&mut buf, 7, size);
+            buf
+        };
+        let mut expected = Vec::new();
+        write_value(&mut expected, 7, 5);
+        assert_eq!(body(ValueSource::Deterministic, 5), expected);
+
+        let pattern = ValueSource::parse("pattern:abc").unwrap();
+        assert_eq!(pattern, ValueSource::Pattern(b"abc"));
+        assert_eq!(body(pattern, 7), b"abcabca");
+        assert_eq!(body(pattern, 2), b"ab");
+
+        let path = std::env::temp_dir().join(format!("value-{}.bin", std::process::id()));
+        fs::write(&path, b"\x00\xffxyz").unwrap();
+        let file = ValueSource::parse(&format!("file:{}", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(body(file, 12), b"\x00\xffxyz\x00\xffxyz\x00\xff");
+
+        // Whatever the source, values verify from any offset.
+        for &source in &[ValueSource::Deterministic, pattern, file] {
+            let value = body(source, 100);
+            assert!(source.verify(7, 30, &value[30..]));
+            assert!(!source.verify(7, 31, &value[30..]));
+        }
+
+        assert!(ValueSource::parse("pattern:").is_err());
+        assert!(ValueSource::parse("file:/nonexistent/value").is_err());
+        assert!(ValueSource::parse("random").is_err());
+    }
+
+    #[test]
+    fn short_datagram_rejected() {
+        use std::net::UdpSocket;
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        let to = client.local_addr().unwrap();
+
+        // A UDP frame followed by only 12 bytes of the header, then a whole
+        // response.
+        let mut short = vec![0, 5, 0, 0, 0, 1, 0, 0];
+        short.extend(&get_response(Opcode::Get, 5, b"", b"xx")[..12]);
+        assert_eq!(short.len(), 20);
+        let mut whole = vec![0, 5, 0, 0, 0, 1, 0, 0];
+        whole.extend(get_response(Opcode::Get, 5, b"", b"xx"));
+        server.send_to(&short, to).unwrap();
+        server.send_to(&whole, to).unwrap();
+
+        let sock = Connection::LinuxUdp(client);
+        let proto = MemcachedProtocol::default();
+        let mut scratch = vec![0; 4096];
+        let before = malformed_datagrams();
+        let err = proto
+            .read_response(&sock, Transport::Udp, &mut scratch)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(protocol_error(&err), Some(&ProtocolError::ShortPacket(20)));
+        assert_eq!(err.to_string(), "Short packet received: 20 bytes");
+        assert!(malformed_datagrams() > before);
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Udp, &mut scratch)
+                .unwrap(),
+            (5, Outcome::Ok)
+        );
     }
 }
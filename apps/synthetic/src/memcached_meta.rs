@@ -0,0 +1,427 @@
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::str;
+
+use memcached::{
+    check_udp_request_id, choose_request, trace_value_size, udp_request_id, write_key,
+    write_udp_header, HotKey, ValueSource, KEY_SIZE, VALUE_SIZE,
+};
+use mix::{Op, OpMix};
+use poll::Head;
+use Connection;
+use Outcome;
+use Packet;
+use RequestInfo;
+use Transport;
+use {record_datagram_size, scratch_space, MAX_DATAGRAM};
+
+/** Text "meta" protocol: https://github.com/memcached/memcached/wiki/MetaCommands **/
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MetaStatus {
+    /// VA: value follows.
+    Value,
+    /// HD: success, no value.
+    Stored,
+    /// EN: GET miss.
+    Miss,
+    /// NF: key not found (delete/arithmetic).
+    NotFound,
+    /// NS: not stored, as by an add of a key that exists or a replace of one
+    /// that does not.
+    NotStored,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MetaResponse {
+    pub status: Option<MetaStatus>,
+    pub value_length: usize,
+    pub opaque: Option<u32>,
+    pub flags: Option<u32>,
+    /// Remaining TTL in seconds, -1 if the item never expires.
+    pub ttl: Option<i64>,
+}
+
+fn parse_token<T: str::FromStr>(token: &[u8]) -> io::Result<T> {
+    str::from_utf8(token)
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| Error::other(format!("Bad token in meta response: {:?}", token)))
+}
+
+/// Parses a response line, without its trailing "\r\n".
+pub fn parse_meta_line(line: &[u8]) -> io::Result<MetaResponse> {
+    let mut tokens = line.split(|c| *c == b' ').filter(|t| !t.is_empty());
+    let mut response = MetaResponse::default();
+    response.status = Some(match tokens.next() {
+        Some(b"VA") => {
+            response.value_length = match tokens.next() {
+                Some(t) => parse_token(t)?,
+                None => return Err(Error::other("VA without a size")),
+            };
+            MetaStatus::Value
+        }
+        Some(b"HD") => MetaStatus::Stored,
+        Some(b"EN") => MetaStatus::Miss,
+        Some(b"NF") => MetaStatus::NotFound,
+        Some(b"NS") => MetaStatus::NotStored,
+        _ => {
+            return Err(Error::other(format!(
+                "Unexpected meta response: {}",
+                String::from_utf8_lossy(line)
+            )))
+        }
+    });
+
+    for token in tokens {
+        match token[0] {
+            b'O' => response.opaque = Some(parse_token(&token[1..])?),
+            b'f' => response.flags = Some(parse_token(&token[1..])?),
+            b't' => response.ttl = Some(parse_token(&token[1..])?),
+            _ => {}
+        }
+    }
+    Ok(response)
+}
+
+/// The request a response on a stream connection answers when it carries no
+/// opaque, as a miss need not. The server answers a connection's requests in
+/// turn, so it is the oldest one still unanswered there.
+pub const UNMATCHED: usize = usize::MAX;
+
+/// Meta response lines carry a key of at most 250 bytes and a few flags, so
+/// a longer line means the stream is out of step.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// The most of a value read into scratch at once while it is discarded.
+const VALUE_CHUNK: usize = 64 * 1024;
+
+/// Reads a single "\r\n" terminated line into `scratch`, growing it as
+/// needed, and returns its length without the terminator. Reads a byte at a
+/// time so nothing past the line is consumed from the stream.
+fn read_line(sock: &mut &Connection, scratch: &mut Vec<u8>) -> io::Result<usize> {
+    let mut len = 0;
+    loop {
+        if len == MAX_LINE_LENGTH {
+            return Err(Error::other("Meta response line too long"));
+        }
+        sock.read_exact(&mut scratch_space(scratch, len + 1)[len..])?;
+        len += 1;
+        if len >= 2 && &scratch[len - 2..len] == b"\r\n" {
+            return Ok(len - 2);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MetaProtocol {
+    /// TTL in seconds for stored values, 0 for no expiration.
+    pub ttl: u32,
+    pub value_source: ValueSource,
+    pub hot_key: Option<HotKey>,
+    /// Operations requests pick between, or None for USR's.
+    pub mix: Option<OpMix>,
+}
+
+impl MetaProtocol {
+    pub fn get_request(key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
+        }
+        buf.extend_from_slice(b"mg ");
+        write_key(buf, key, KEY_SIZE);
+        write!(buf, " v f t O{}\r\n", opaque).unwrap();
+    }
+
+    /// A GET that also sets the key's TTL to `ttl`, as GAT does, which is
+    /// answered as any other.
+    pub fn gat_request(key: u64, ttl: u32, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
+        }
+        buf.extend_from_slice(b"mg ");
+        write_key(buf, key, KEY_SIZE);
+        write!(buf, " v f t T{} O{}\r\n", ttl, opaque).unwrap();
+    }
+
+    /// A SET, ADD or REPLACE of `value_size` bytes, or of the USR size if
+    /// None. They differ only in the mode flag.
+    pub fn store_request(
+        &self,
+        op: Op,
+        key: u64,
+        value_size: Option<usize>,
+        opaque: u32,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) {
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
+        }
+        let value_size = value_size.unwrap_or(VALUE_SIZE);
+        let mode = match op {
+            Op::Add => " ME",
+            Op::Replace => " MR",
+            _ => "",
+        };
+        buf.extend_from_slice(b"ms ");
+        write_key(buf, key, KEY_SIZE);
+        write!(buf, " {} T{} O{}{}\r\n", value_size, self.ttl, opaque, mode).unwrap();
+        self.value_source.write(buf, key, value_size);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    pub fn gen_request(
+        &self,
+        i: usize,
+        p: &Packet,
+        buf: &mut Vec<u8>,
+        tport: Transport,
+    ) -> RequestInfo {
+        let (op, key) = choose_request(p, self.hot_key, None, self.mix.unwrap_or_else(OpMix::usr));
+        match op {
+            Op::Get => MetaProtocol::get_request(key, i as u32, buf, tport),
+            Op::Delete => MetaProtocol::delete_request(key, i as u32, buf, tport),
+            Op::Gat => MetaProtocol::gat_request(key, self.ttl, i as u32, buf, tport),
+            _ => self.store_request(op, key, trace_value_size(p), i as u32, buf, tport),
+        }
+        RequestInfo::new(op.name(), key).with_key_size(KEY_SIZE)
+    }
+
+    pub fn delete_request(key: u64, opaque: u32, buf: &mut Vec<u8>, tport: Transport) {
+        if let Transport::Udp = tport {
+            write_udp_header(buf, opaque as u16);
+        }
+        buf.extend_from_slice(b"md ");
+        write_key(buf, key, KEY_SIZE);
+        write!(buf, " O{}\r\n", opaque).unwrap();
+    }
+
+    pub fn read_response(
+        &self,
+        mut sock: &Connection,
+        tport: Transport,
+        scratch: &mut Vec<u8>,
+    ) -> io::Result<(usize, Outcome)> {
+        let response = match tport {
+            Transport::Udp => {
+                let len = sock.read(scratch_space(scratch, MAX_DATAGRAM))?;
+                if len == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "eof"));
+                }
+                udp_response(&scratch[..len])?
+            }
+            Transport::Tcp | Transport::Unix(_) => {
+                let len = read_line(&mut sock, scratch)?;
+                let response = parse_meta_line(&scratch[..len])?;
+                if response.status == Some(MetaStatus::Value) {
+                    // Discard the value and its trailing "\r\n".
+                    let mut remaining = response.value_length + 2;
+                    while remaining > 0 {
+                        let chunk = usize::min(remaining, VALUE_CHUNK);
+                        sock.read_exact(scratch_space(scratch, chunk))?;
+                        remaining -= chunk;
+                    }
+                }
+                response
+            }
+        };
+        Ok(completion(response))
+    }
+
+    /// Parses a response datagram already received, as one of a batch.
+    pub fn read_datagram(&self, datagram: &[u8]) -> io::Result<(usize, Outcome)> {
+        Ok(completion(udp_response(datagram)?))
+    }
+
+    /// Parses a response from the start of what a stream connection has
+    /// received, for a receive loop that cannot block for the rest of it.
+    /// A value is discarded as it arrives.
+    pub fn parse_head(&self, head: &[u8]) -> io::Result<Head> {
+        let searched = &head[..usize::min(head.len(), MAX_LINE_LENGTH)];
+        let end = match searched.windows(2).position(|w| w == b"\r\n") {
+            Some(end) => end,
+            None if searched.len() == MAX_LINE_LENGTH => {
+                return Err(Error::other("Meta response line too long"))
+            }
+            None => return Ok(Head::Partial),
+        };
+        let response = parse_meta_line(&head[..end])?;
+        let body = match response.status {
+            Some(MetaStatus::Value) => response.value_length + 2,
+            _ => 0,
+        };
+        Ok(Head::Whole {
+            len: end + 2,
+            body,
+            completes: Some(completion(response)),
+        })
+    }
+}
+
+/// Parses the response line of a datagram, after its frame header.
+fn udp_response(datagram: &[u8]) -> io::Result<MetaResponse> {
+    record_datagram_size(datagram.len());
+    let end = match datagram.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end >= 8 => end,
+        _ => {
+            return Err(Error::other(format!(
+                "Short packet received: {} bytes",
+                datagram.len()
+            )))
+        }
+    };
+    let mut response = parse_meta_line(&datagram[8..end])?;
+    match response.opaque {
+        Some(opaque) => check_udp_request_id(datagram, opaque)?,
+        // Without one, the frame's request id says which request it answers.
+        None => response.opaque = Some(udp_request_id(datagram)? as u32),
+    }
+    Ok(response)
+}
+
+/// The request a response completes, or UNMATCHED if it does not say, and
+/// how.
+fn completion(response: MetaResponse) -> (usize, Outcome) {
+    // Requests that found nothing to act on complete as misses.
+    let outcome = match response.status {
+        Some(MetaStatus::Miss) | Some(MetaStatus::NotFound) | Some(MetaStatus::NotStored) => {
+            Outcome::Miss
+        }
+        _ => Outcome::Ok,
+    };
+    (response.opaque.map_or(UNMATCHED, |o| o as usize), outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn request_formatting() {
+        let mut buf = Vec::new();
+        MetaProtocol::get_request(42, 7, &mut buf, Transport::Tcp);
+        assert_eq!(&buf[..], &b"mg 24AAAAAAAAAAAAAAAAAA v f t O7\r\n"[..]);
+
+        buf.clear();
+        MetaProtocol {
+            ttl: 30,
+            ..Default::default()
+        }
+        .store_request(Op::Set, 42, None, 8, &mut buf, Transport::Udp);
+        assert_eq!(&buf[..8], &[0, 8, 0, 0, 0, 1, 0, 0]);
+        assert!(buf[8..].starts_with(b"ms 24AAAAAAAAAAAAAAAAAA 2 T30 O8\r\n"));
+        assert_eq!(buf.len(), 8 + 34 + VALUE_SIZE + 2);
+        assert!(buf.ends_with(b"\r\n"));
+
+        buf.clear();
+        MetaProtocol::default().store_request(Op::Add, 42, None, 9, &mut buf, Transport::Tcp);
+        assert!(buf.starts_with(b"ms 24AAAAAAAAAAAAAAAAAA 2 T0 O9 ME\r\n"));
+        buf.clear();
+        MetaProtocol::delete_request(42, 10, &mut buf, Transport::Tcp);
+        assert_eq!(&buf[..], &b"md 24AAAAAAAAAAAAAAAAAA O10\r\n"[..]);
+        buf.clear();
+        MetaProtocol::gat_request(42, 60, 11, &mut buf, Transport::Tcp);
+        assert_eq!(&buf[..], &b"mg 24AAAAAAAAAAAAAAAAAA v f t T60 O11\r\n"[..]);
+    }
+
+    #[test]
+    fn parse_hit_and_miss() {
+        let hit = parse_meta_line(b"VA 2 f17 t-1 O7").unwrap();
+        assert_eq!(hit.status, Some(MetaStatus::Value));
+        assert_eq!(hit.value_length, 2);
+        assert_eq!(hit.flags, Some(17));
+        assert_eq!(hit.ttl, Some(-1));
+        assert_eq!(hit.opaque, Some(7));
+
+        let miss = parse_meta_line(b"EN").unwrap();
+        assert_eq!(miss.status, Some(MetaStatus::Miss));
+        assert_eq!(miss.opaque, None);
+        let not_stored = parse_meta_line(b"NS O9").unwrap();
+        assert_eq!(not_stored.status, Some(MetaStatus::NotStored));
+
+        assert!(parse_meta_line(b"XX").is_err());
+        assert!(parse_meta_line(b"VA two").is_err());
+    }
+
+    #[test]
+    fn read_response_consumes_value() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server
+            .write_all(b"VA 5 f0 t30 O3\r\nhello\r\nEN O4\r\n")
+            .unwrap();
+
+        let sock = Connection::LinuxTcp(client);
+        let mut scratch = vec![0; 64];
+        let proto = MetaProtocol::default();
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (3, Outcome::Ok)
+        );
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (4, Outcome::Miss)
+        );
+    }
+
+    #[test]
+    fn miss_without_opaque() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(b"EN\r\n").unwrap();
+
+        let sock = Connection::LinuxTcp(client);
+        let mut scratch = vec![0; 64];
+        let proto = MetaProtocol::default();
+        assert_eq!(
+            proto
+                .read_response(&sock, Transport::Tcp, &mut scratch)
+                .unwrap(),
+            (UNMATCHED, Outcome::Miss)
+        );
+        assert_eq!(
+            proto.parse_head(b"EN\r\n").unwrap(),
+            Head::Whole {
+                len: 4,
+                body: 0,
+                completes: Some((UNMATCHED, Outcome::Miss)),
+            }
+        );
+        // A datagram's frame says which request it answers.
+        let mut datagram = Vec::new();
+        write_udp_header(&mut datagram, 5);
+        datagram.extend_from_slice(b"EN\r\n");
+        assert_eq!(proto.read_datagram(&datagram).unwrap(), (5, Outcome::Miss));
+    }
+
+    #[test]
+    fn parse_head_skips_value() {
+        let proto = MetaProtocol::default();
+        assert_eq!(proto.parse_head(b"VA 5 O3\r").unwrap(), Head::Partial);
+        assert_eq!(
+            proto.parse_head(b"VA 5 O3\r\nhel").unwrap(),
+            Head::Whole {
+                len: 9,
+                body: 7,
+                completes: Some((3, Outcome::Ok)),
+            }
+        );
+        assert_eq!(
+            proto.parse_head(b"EN O4\r\n").unwrap(),
+            Head::Whole {
+                len: 7,
+                body: 0,
+                completes: Some((4, Outcome::Miss)),
+            }
+        );
+        assert!(proto.parse_head(&[b'V'; MAX_LINE_LENGTH]).is_err());
+    }
+}
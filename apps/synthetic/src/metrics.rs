@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use report::SAMPLE_OPS;
+
+/// Operations counted apart, as indices into `SAMPLE_OPS`.
+const OPS: usize = SAMPLE_OPS.len();
+
+/// The counts of one connection, by the operation of each request where it
+/// is known. Only the connection's own threads add to them, so none is
+/// shared between connections.
+#[derive(Default)]
+pub struct ConnCounts {
+    sent: [AtomicU64; OPS],
+    responses: [AtomicU64; OPS],
+    misses: [AtomicU64; OPS],
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl ConnCounts {
+    pub fn sent(&self, op: u8) {
+        self.sent[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response(&self, op: u8, miss: bool) {
+        self.responses[op as usize].fetch_add(1, Ordering::Relaxed);
+        if miss {
+            self.misses[op as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn time_out(&self, n: usize) {
+        self.timeouts.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Error responses and requests timed out so far.
+    pub fn failures(&self) -> (u64, u64) {
+        (
+            self.errors.load(Ordering::Relaxed),
+            self.timeouts.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A server's counts, summed over its connections.
+#[derive(Clone, Default)]
+struct Totals {
+    sent: [u64; OPS],
+    responses: [u64; OPS],
+    misses: [u64; OPS],
+    errors: u64,
+    timeouts: u64,
+    reconnects: u64,
+}
+
+impl Totals {
+    fn add(&mut self, counts: &ConnCounts) {
+        let load = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        for op in 0..OPS {
+            self.sent[op] += load(&counts.sent[op]);
+            self.responses[op] += load(&counts.responses[op]);
+            self.misses[op] += load(&counts.misses[op]);
+        }
+        self.errors += load(&counts.errors);
+        self.timeouts += load(&counts.timeouts);
+        self.reconnects += load(&counts.reconnects);
+    }
+}
+
+/// What the last interval of the run saw.
+#[derive(Default)]
+struct Gauges {
+    outstanding: usize,
+    /// In ns, None if nothing completed in the interval.
+    p99: Option<u64>,
+    rps: u64,
+}
+
+/// The client's metrics, counted over every run, for a Prometheus server to
+/// scrape while they go on. Counters are read from the connections' own as
+/// they are scraped; the gauges are those of the last interval reported.
+pub struct Metrics {
+    protocol: &'static str,
+    /// Connections of the run in progress, with the server of each.
+    conns: Mutex<Vec<(SocketAddr, Arc<ConnCounts>)>>,
+    /// Counts of the runs that are over, by server.
+    done: Mutex<BTreeMap<SocketAddr, Totals>>,
+    gauges: Mutex<Gauges>,
+}
+
+impl Metrics {
+    pub fn new(protocol: &'static str) -> Metrics {
+        Metrics {
+            protocol,
+            conns: Mutex::new(Vec::new()),
+            done: Mutex::new(BTreeMap::new()),
+            gauges: Mutex::new(Gauges::default()),
+        }
+    }
+
+    /// Counts for a new connection to `server`.
+    pub fn connection(&self, server: SocketAddr) -> Arc<ConnCounts> {
+        let counts = Arc::new(ConnCounts::default());
+        self.conns.lock().unwrap().push((server, counts.clone()));
+        counts
+    }
+
+    /// Folds the counts of the run's connections into the totals, once they
+    /// are done.
+    pub fn finish_run(&self) {
+        let mut done = self.done.lock().unwrap();
+        for (server, counts) in self.conns.lock().unwrap().drain(..) {
+            done.entry(server).or_default().add(&counts);
+        }
+    }
+
+    /// Sets the gauges to what the interval just over saw.
+    pub fn set_interval(&self, outstanding: usize, p99: Option<u64>, rps: u64) {
+        *self.gauges.lock().unwrap() = Gauges {
+            outstanding,
+            p99,
+            rps,
+        };
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut totals = self.done.lock().unwrap().clone();
+        for &(server, ref counts) in self.conns.lock().unwrap().iter() {
+            totals.entry(server).or_default().add(counts);
+        }
+        let mut out = String::new();
+        let by_op = |out: &mut String, name, help, count: &dyn Fn(&Totals, usize) -> u64| {
+            writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name).unwrap();
+            for (server, t) in &totals {
+                for op in (0..OPS).filter(|&op| t.sent[op] > 0 || t.responses[op] > 0) {
+                    let labels = format!(
+                        "protocol=\"{}\",server=\"{}\",opcode=\"{}\"",
+                        self.protocol,
+                        server,
+                        match SAMPLE_OPS[op] {
+                            "" => "other",
+                            name => name,
+                        }
+                    );
+                    writeln!(out, "{}{{{}}} {}", name, labels, count(t, op)).unwrap();
+                }
+            }
+        };
+        by_op(
+            &mut out,
+            "synthetic_requests_sent_total",
+            "Requests sent.",
+            &|t, op| t.sent[op],
+        );
+        by_op(
+            &mut out,
+            "synthetic_responses_total",
+            "Responses that completed a request.",
+            &|t, op| t.responses[op],
+        );
+        by_op(
+            &mut out,
+            "synthetic_misses_total",
+            "Responses that missed.",
+            &|t, op| t.misses[op],
+        );
+        let by_server = |out: &mut String, name, help, count: &dyn Fn(&Totals) -> u64| {
+            writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name).unwrap();
+            for (server, t) in &totals {
+                writeln!(
+                    out,
+                    "{}{{protocol=\"{}\",server=\"{}\"}} {}",
+                    name,
+                    self.protocol,
+                    server,
+                    count(t)
+                )
+                .unwrap();
+            }
+        };
+        by_server(
+            &mut out,
+            "synthetic_errors_total",
+            "Error responses.",
+            &|t| t.errors,
+        );
+        by_server(
+            &mut out,
+            "synthetic_timeouts_total",
+            "Requests timed out.",
+            &|t| t.timeouts,
+        );
+        by_server(
+            &mut out,
+            "synthetic_reconnects_total",
+            "Connections opened in place of failed ones.",
+            &|t| t.reconnects,
+        );
+        let gauges = self.gauges.lock().unwrap();
+        let mut gauge = |name, help, value: String| {
+            writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} gauge\n{}{{protocol=\"{}\"}} {}",
+                name, help, name, name, self.protocol, value
+            )
+            .unwrap();
+        };
+        gauge(
+            "synthetic_outstanding_requests",
+            "Requests in flight at the end of the last interval.",
+            gauges.outstanding.to_string(),
+        );
+        gauge(
+            "synthetic_interval_p99_seconds",
+            "The 99th percentile latency of the last interval.",
+            match gauges.p99 {
+                Some(ns) => (ns as f64 / 1e9).to_string(),
+                None => "NaN".to_string(),
+            },
+        );
+        gauge(
+            "synthetic_achieved_rps",
+            "Responses per second over the last interval.",
+            gauges.rps.to_string(),
+        );
+        out
+    }
+
+    /// Answers each request to `listener` for /metrics with them, on a thread
+    /// of its own, for as long as the client runs.
+    pub fn serve(&'static self, listener: TcpListener) {
+        thread::spawn(move || {
+            for conn in listener.incoming().flatten() {
+                if let Err(e) = self.answer(conn) {
+                    println!("Warning: could not serve metrics: {}", e);
+                }
+            }
+        });
+    }
+
+    fn answer(&self, mut conn: TcpStream) -> io::Result<()> {
+        conn.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut reader = BufReader::new(conn.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // The headers are of no interest.
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        let (status, body) = match path {
+            "/metrics" => ("200 OK", self.render()),
+            _ => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        };
+        write!(
+            conn,
+            "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn metrics_served_by_server_and_opcode() {
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new("memcached")));
+        let server: SocketAddr = "10.0.0.1:11211".parse().unwrap();
+        let get = SAMPLE_OPS.iter().position(|&op| op == "get").unwrap() as u8;
+        let earlier = metrics.connection(server);
+        earlier.sent(get);
+        metrics.finish_run();
+        let counts = metrics.connection(server);
+        for _ in 0..3 {
+            counts.sent(get);
+        }
+        counts.response(get, false);
+        counts.response(get, true);
+        counts.time_out(1);
+        counts.reconnected();
+        metrics.set_interval(2, Some(1_500_000), 900);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        metrics.serve(listener);
+        let scrape = |path: &str| {
+            let mut conn = TcpStream::connect(addr).unwrap();
+            write!(conn, "GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+        let labels = "protocol=\"memcached\",server=\"10.0.0.1:11211\"";
+        for line in &[
+            format!(
+                "synthetic_requests_sent_total{{{},opcode=\"get\"}} 4",
+                labels
+            ),
+            format!("synthetic_responses_total{{{},opcode=\"get\"}} 2", labels),
+            format!("synthetic_misses_total{{{},opcode=\"get\"}} 1", labels),
+            format!("synthetic_errors_total{{{}}} 0", labels),
+            format!("synthetic_timeouts_total{{{}}} 1", labels),
+            format!("synthetic_reconnects_total{{{}}} 1", labels),
+            "synthetic_outstanding_requests{protocol=\"memcached\"} 2".to_string(),
+            "synthetic_interval_p99_seconds{protocol=\"memcached\"} 0.0015".to_string(),
+            "synthetic_achieved_rps{protocol=\"memcached\"} 900".to_string(),
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{}\n{}",
+                line,
+                response
+            );
+        }
+        // Operations never sent have no series.
+        assert!(!response.contains("opcode=\"set\""));
+        assert!(scrape("/").starts_with("HTTP/1.0 404"));
+    }
+}
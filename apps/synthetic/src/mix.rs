@@ -0,0 +1,174 @@
+use std::str::FromStr;
+
+/// A memcached operation a mix picks between.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Get,
+    Set,
+    Add,
+    Replace,
+    Delete,
+    /// A GET that also sets the key's expiration, as GAT does.
+    Gat,
+}
+
+const OPS: [Op; 6] = [Op::Get, Op::Set, Op::Add, Op::Replace, Op::Delete, Op::Gat];
+
+impl Op {
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Add => "add",
+            Op::Replace => "replace",
+            Op::Delete => "delete",
+            Op::Gat => "gat",
+        }
+    }
+}
+
+/// The fraction of requests that perform each operation, which sum to one.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OpMix {
+    fractions: [f64; 6],
+}
+
+impl OpMix {
+    /// Normalizes `weights`, given in the order of `OPS`, to fractions. At
+    /// least one must be positive.
+    fn new(weights: [f64; 6]) -> OpMix {
+        let total: f64 = weights.iter().sum();
+        let mut fractions = [0.0; 6];
+        for i in 0..6 {
+            fractions[i] = weights[i] / total;
+        }
+        OpMix { fractions }
+    }
+
+    /// The USR workload's mix: 0.2% SETs, the rest GETs.
+    pub fn usr() -> OpMix {
+        OpMix::new([998.0, 2.0, 0.0, 0.0, 0.0, 0.0])
+    }
+
+    /// The ETC workload's mix: 3% SETs, the rest GETs.
+    pub fn etc() -> OpMix {
+        OpMix::new([970.0, 30.0, 0.0, 0.0, 0.0, 0.0])
+    }
+
+    pub fn fraction(&self, op: Op) -> f64 {
+        self.fractions[op as usize]
+    }
+
+    /// The operation of a request, from 32 bits of its randomness.
+    pub fn pick(&self, draw: u32) -> Op {
+        let draw = draw as f64 / (1u64 << 32) as f64;
+        let mut below = 0.0;
+        for &op in &OPS {
+            below += self.fraction(op);
+            if draw < below {
+                return op;
+            }
+        }
+        // Rounding can leave the fractions short of one; the last operation
+        // with any weight takes the remainder.
+        *OPS.iter()
+            .rev()
+            .find(|&&op| self.fraction(op) > 0.0)
+            .unwrap()
+    }
+}
+
+impl FromStr for OpMix {
+    type Err = String;
+
+    /// Parses comma-separated `op:weight` pairs, e.g.
+    /// `get:70,set:20,delete:10`. The weights need not sum to 100, and
+    /// operations left out are never picked.
+    fn from_str(spec: &str) -> Result<OpMix, String> {
+        let mut weights = [0.0; 6];
+        let mut given = [false; 6];
+        for entry in spec.split(',') {
+            let mut parts = entry.splitn(2, ':');
+            let name = parts.next().unwrap().trim();
+            let op = match OPS.iter().find(|op| op.name() == name) {
+                Some(&op) => op,
+                None => {
+                    return Err(format!(
+                        "unknown operation '{}', must be one of get, set, add, replace, delete \
+                         or gat",
+                        name
+                    ))
+                }
+            };
+            if given[op as usize] {
+                return Err(format!("{} is given more than once", name));
+            }
+            let weight = match parts.next().map(|w| w.trim().parse::<f64>()) {
+                Some(Ok(w)) if w >= 0.0 && w.is_finite() => w,
+                _ => return Err(format!("bad weight in '{}', must be op:weight", entry)),
+            };
+            weights[op as usize] = weight;
+            given[op as usize] = true;
+        }
+        if weights.iter().all(|&w| w == 0.0) {
+            return Err(format!("no operation in '{}' has any weight", spec));
+        }
+        Ok(OpMix::new(weights))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mix_format() {
+        let mix: OpMix = "get:70, set:20,delete:10".parse().unwrap();
+        assert_eq!(mix.fraction(Op::Get), 0.7);
+        assert_eq!(mix.fraction(Op::Set), 0.2);
+        assert_eq!(mix.fraction(Op::Add), 0.0);
+        assert_eq!(mix.fraction(Op::Delete), 0.1);
+
+        // Weights are relative.
+        let mix: OpMix = "get:3,add:1".parse().unwrap();
+        assert_eq!(mix.fraction(Op::Get), 0.75);
+        assert_eq!(mix.fraction(Op::Add), 0.25);
+        let mix: OpMix = "get:1,gat:1".parse().unwrap();
+        assert_eq!(mix.fraction(Op::Gat), 0.5);
+        assert_eq!("set:0.5".parse::<OpMix>().unwrap().fraction(Op::Set), 1.0);
+
+        for bad in &[
+            "",
+            "get",
+            "get:x",
+            "get:-1",
+            "get:inf",
+            "incr:5",
+            "get:1,get:2",
+            "get:0,set:0",
+        ] {
+            assert!(bad.parse::<OpMix>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn picks_follow_mix() {
+        let mix: OpMix = "get:50,set:25,replace:15,delete:10".parse().unwrap();
+        let n = 100_000u64;
+        let mut counts = [0; 6];
+        for i in 0..n {
+            // Spread the draws over the whole range, out of order.
+            let draw = (i.wrapping_mul(0x9e3779b97f4a7c15) >> 32) as u32;
+            counts[mix.pick(draw) as usize] += 1;
+        }
+        for &op in &OPS {
+            let seen = counts[op as usize] as f64 / n as f64;
+            assert!((seen - mix.fraction(op)).abs() < 0.005, "{:?}", counts);
+        }
+        assert_eq!(counts[Op::Add as usize], 0);
+
+        assert_eq!(mix.pick(0), Op::Get);
+        assert_eq!(mix.pick(u32::MAX), Op::Delete);
+        assert_eq!(OpMix::usr().pick(u32::MAX), Op::Set);
+    }
+}
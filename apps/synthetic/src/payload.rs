@@ -9,8 +9,11 @@ pub struct Payload {
     pub index: u64,
 }
 
+use poll::Head;
 use Connection;
+use Outcome;
 use Transport;
+use {record_datagram_size, scratch_space, MAX_DATAGRAM};
 
 #[derive(Clone, Copy)]
 pub struct SyntheticProtocol;
@@ -27,13 +30,41 @@ impl SyntheticProtocol {
 
     pub fn read_response(
         mut sock: &Connection,
-        _tport: Transport,
-        scratch: &mut [u8],
+        tport: Transport,
+        scratch: &mut Vec<u8>,
     ) -> io::Result<usize> {
-        sock.read_exact(&mut scratch[..16])?;
-        let payload = Payload::deserialize(&mut &scratch[..])?;
+        if let Transport::Udp = tport {
+            let len = sock.read(scratch_space(scratch, MAX_DATAGRAM))?;
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+            }
+            return SyntheticProtocol::read_datagram(&scratch[..len]);
+        }
+        let buf = scratch_space(scratch, 16);
+        sock.read_exact(buf)?;
+        let payload = Payload::deserialize(&mut &buf[..])?;
+        Ok(payload.index as usize)
+    }
+
+    /// Parses a response datagram already received, as one of a batch.
+    pub fn read_datagram(datagram: &[u8]) -> io::Result<usize> {
+        record_datagram_size(datagram.len());
+        let payload = Payload::deserialize(&mut &datagram[..])?;
         Ok(payload.index as usize)
     }
+
+    /// Parses a response from the start of what a stream connection has
+    /// received.
+    pub fn parse_head(head: &[u8]) -> io::Result<Head> {
+        if head.len() < 16 {
+            return Ok(Head::Partial);
+        }
+        Ok(Head::Whole {
+            len: 16,
+            body: 0,
+            completes: Some((SyntheticProtocol::read_datagram(head)?, Outcome::Ok)),
+        })
+    }
 }
 
 impl Payload {
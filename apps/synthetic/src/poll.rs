@@ -0,0 +1,257 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use libc;
+
+use Outcome;
+
+/// Connections reported ready by one wait, at most.
+const MAX_EVENTS: usize = 256;
+
+/// How much of a response a stream connection has received, as its
+/// protocol parses it.
+#[derive(Debug, PartialEq)]
+pub enum Head {
+    /// More bytes must arrive before the response can be parsed.
+    Partial,
+    /// The response starts with a head of `len` bytes, which is followed by
+    /// `body` bytes to discard without looking at them. It completes the
+    /// request given, if any.
+    Whole {
+        len: usize,
+        body: usize,
+        completes: Option<(usize, Outcome)>,
+    },
+}
+
+/// An epoll instance, waited on for the connections with something to read.
+pub struct Poller {
+    fd: RawFd,
+    events: Vec<libc::epoll_event>,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Poller {
+            fd,
+            events: vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS],
+        })
+    }
+
+    /// Waits for `fd` to be readable, reporting it as `token`.
+    pub fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64: token,
+        };
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, fd: RawFd) {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        unsafe {
+            libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, &mut event);
+        }
+    }
+
+    /// Waits up to `timeout` for connections to be readable, and fills
+    /// `ready` with the token of each, and whether its peer or a shutdown
+    /// has closed it for reading.
+    pub fn wait(&mut self, ready: &mut Vec<(u64, bool)>, timeout: Duration) -> io::Result<()> {
+        ready.clear();
+        let ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000;
+        let n = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                self.events.as_mut_ptr(),
+                self.events.len() as libc::c_int,
+                ms as libc::c_int,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            };
+        }
+        let closed = (libc::EPOLLRDHUP | libc::EPOLLHUP | libc::EPOLLERR) as u32;
+        for event in &self.events[..n as usize] {
+            ready.push((event.u64, event.events & closed != 0));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Receives what `fd` has into `buf` without blocking, failing with
+/// `WouldBlock` if it has nothing. Returns 0 once the stream is over.
+pub fn recv(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let n = unsafe {
+            libc::recv(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Where a stream connection read without blocking is in its responses: the
+/// bytes it has received that are not parsed yet, which start with the part
+/// of a head that has arrived, and how many bytes of the last body must
+/// still be discarded.
+pub struct StreamState {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    skip: usize,
+}
+
+impl StreamState {
+    /// A stream with room to receive `size` bytes at once at first. The
+    /// buffer grows when the head of a response is longer.
+    pub fn new(size: usize) -> StreamState {
+        StreamState {
+            buf: vec![0; usize::max(size, 1)],
+            start: 0,
+            end: 0,
+            skip: 0,
+        }
+    }
+
+    /// Receives what `fd` has without blocking, as `recv` does.
+    pub fn fill(&mut self, fd: RawFd) -> io::Result<usize> {
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        if self.end == self.buf.len() {
+            let len = self.buf.len();
+            self.buf.resize(2 * len, 0);
+        }
+        let n = recv(fd, &mut self.buf[self.end..])?;
+        self.end += n;
+        // A body is only left to discard once nothing else was buffered.
+        let skipped = usize::min(self.skip, n);
+        self.start += skipped;
+        self.skip -= skipped;
+        Ok(n)
+    }
+
+    /// Parses every response that has arrived whole, with `parse`, passing
+    /// each request one completes to `complete`, with the bytes of the
+    /// response. A body may still be arriving once its head has been parsed.
+    pub fn parse<P, C>(&mut self, parse: P, mut complete: C) -> io::Result<()>
+    where
+        P: Fn(&[u8]) -> io::Result<Head>,
+        C: FnMut(usize, Outcome, usize),
+    {
+        while self.skip == 0 && self.start < self.end {
+            match parse(&self.buf[self.start..self.end])? {
+                Head::Partial => break,
+                Head::Whole {
+                    len,
+                    body,
+                    completes,
+                } => {
+                    let skipped = usize::min(body, self.end - self.start - len);
+                    self.start += len + skipped;
+                    self.skip = body - skipped;
+                    if let Some((i, outcome)) = completes {
+                        complete(i, outcome, len + body);
+                    }
+                }
+            }
+        }
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Frames of a length byte, that many head bytes ending in the request
+    /// index, and as many body bytes again.
+    fn parse_frame(head: &[u8]) -> io::Result<Head> {
+        match head.first() {
+            Some(&n) if head.len() > n as usize => Ok(Head::Whole {
+                len: n as usize + 1,
+                body: n as usize,
+                completes: Some((head[n as usize] as usize, Outcome::Ok)),
+            }),
+            _ => Ok(Head::Partial),
+        }
+    }
+
+    #[test]
+    fn streams_resume_partial_responses() {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        let fd = client.as_raw_fd();
+        let mut poller = Poller::new().unwrap();
+        poller.add(fd, 7).unwrap();
+        let mut ready = Vec::new();
+        poller.wait(&mut ready, Duration::from_millis(0)).unwrap();
+        assert!(ready.is_empty());
+
+        let mut state = StreamState::new(4096);
+        let mut completed = Vec::new();
+        // A head split before its length is known, then after, then a body
+        // split between reads with the next response behind it.
+        let mut frame = vec![3, 0, 0, 1, 9, 9, 9];
+        frame.extend_from_slice(&[1, 2, 5]);
+        for piece in &[&frame[..1], &frame[1..3], &frame[3..5], &frame[5..]] {
+            server.write_all(piece).unwrap();
+            poller
+                .wait(&mut ready, Duration::from_millis(1000))
+                .unwrap();
+            assert_eq!(ready, vec![(7, false)]);
+            assert_eq!(state.fill(fd).unwrap(), piece.len());
+            state
+                .parse(parse_frame, |i, _, len| completed.push((i, len)))
+                .unwrap();
+        }
+        assert_eq!(completed, vec![(1, 7), (2, 3)]);
+        assert!(recv(fd, &mut [0; 8]).unwrap_err().kind() == io::ErrorKind::WouldBlock);
+
+        drop(server);
+        poller
+            .wait(&mut ready, Duration::from_millis(1000))
+            .unwrap();
+        assert_eq!(ready, vec![(7, true)]);
+        assert_eq!(state.fill(fd).unwrap(), 0);
+    }
+}
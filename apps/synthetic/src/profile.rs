@@ -0,0 +1,146 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The parts of sending requests and taking their responses that the client
+/// times itself in, when asked to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Phase {
+    /// Building a request into its buffer.
+    Build,
+    /// Writing requests to their socket.
+    Write,
+    /// Reading and parsing responses, including any wait for them, so that
+    /// a blocking read counts the server's latency too.
+    Read,
+}
+
+pub const PHASES: [Phase; 3] = [Phase::Build, Phase::Write, Phase::Read];
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Phase::Build => "build",
+            Phase::Write => "write",
+            Phase::Read => "read_wait",
+        }
+    }
+}
+
+/// Whether phases are timed at all. Off, timing a phase costs one load.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Calls and nanoseconds of each phase, summed over the threads that have
+/// finished or flushed their own.
+static CALLS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static NANOS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// The calls and nanoseconds of each phase on one thread, added to the
+/// totals when the thread exits, so that workers never share a counter.
+struct Local {
+    calls: [u64; 3],
+    nanos: [u64; 3],
+}
+
+impl Local {
+    fn flush(&mut self) {
+        for i in 0..PHASES.len() {
+            CALLS[i].fetch_add(self.calls[i], Ordering::SeqCst);
+            NANOS[i].fetch_add(self.nanos[i], Ordering::SeqCst);
+        }
+        self.calls = [0; 3];
+        self.nanos = [0; 3];
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+thread_local! {
+    static LOCAL: RefCell<Local> = const {
+        RefCell::new(Local {
+            calls: [0; 3],
+            nanos: [0; 3],
+        })
+    };
+}
+
+/// Times every phase from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Runs `f`, counting the time it takes towards `phase` if phases are timed.
+#[inline]
+pub fn timed<T, F: FnOnce() -> T>(phase: Phase, f: F) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let before = Instant::now();
+    let result = f();
+    let ns = before.elapsed().as_nanos() as u64;
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        local.calls[phase as usize] += 1;
+        local.nanos[phase as usize] += ns;
+    });
+    result
+}
+
+/// How many times one phase ran and how long it took over the whole run.
+pub struct PhaseTime {
+    pub phase: Phase,
+    pub calls: u64,
+    pub total: Duration,
+}
+
+/// The time of each phase over the run, counting the threads that have
+/// exited and the calling one, or None if phases were not timed.
+pub fn stats() -> Option<Vec<PhaseTime>> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    LOCAL.with(|local| local.borrow_mut().flush());
+    Some(
+        PHASES
+            .iter()
+            .enumerate()
+            .map(|(i, &phase)| PhaseTime {
+                phase,
+                calls: CALLS[i].load(Ordering::SeqCst),
+                total: Duration::from_nanos(NANOS[i].load(Ordering::SeqCst)),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn phases_counted_once_threads_exit() {
+        // Phases are only ever counted after the totals, as other tests may
+        // be timing phases too.
+        assert_eq!(timed(Phase::Write, || 7), 7);
+        enable();
+        let before: Vec<u64> = stats().unwrap().iter().map(|p| p.calls).collect();
+        thread::spawn(|| {
+            for _ in 0..3 {
+                timed(Phase::Read, || thread::sleep(Duration::from_millis(1)));
+            }
+        })
+        .join()
+        .unwrap();
+        timed(Phase::Build, || {});
+        let after = stats().unwrap();
+        assert!(after[0].calls > before[0]);
+        assert!(after[2].calls >= before[2] + 3);
+        assert!(after[2].total >= Duration::from_millis(3));
+        assert_eq!(after[1].phase.name(), "write");
+    }
+}
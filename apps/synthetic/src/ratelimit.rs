@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+/// Caps the instantaneous send rate of a connection. Tokens accrue at `rate`
+/// up to `burst`, and each send takes one, so over any window at most
+/// `rate * window + burst` requests go out however the arrivals bunch up.
+#[derive(Copy, Clone, Debug)]
+pub struct TokenBucket {
+    /// Tokens per ns.
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Duration,
+}
+
+impl TokenBucket {
+    /// A full bucket refilled at `per_second` tokens.
+    pub fn new(per_second: f64, burst: usize) -> TokenBucket {
+        assert!(per_second > 0.0, "the rate limit must be positive");
+        let burst = usize::max(burst, 1) as f64;
+        TokenBucket {
+            rate: per_second / 1e9,
+            burst,
+            tokens: burst,
+            last: Duration::from_nanos(0),
+        }
+    }
+
+    /// Takes a token at `now`, or returns how much longer to wait for one.
+    pub fn take(&mut self, now: Duration) -> Result<(), Duration> {
+        if now > self.last {
+            let elapsed = (now - self.last).as_nanos() as f64;
+            self.tokens = f64::min(self.tokens + elapsed * self.rate, self.burst);
+            self.last = now;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_nanos(
+                ((1.0 - self.tokens) / self.rate).ceil() as u64,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use distribution::Distribution;
+    use rand;
+
+    #[test]
+    fn sends_bounded_over_any_window() {
+        let (rate, burst) = (100_000.0, 8);
+        let mut bucket = TokenBucket::new(rate, burst);
+        // Poisson arrivals at twice the limit, then everything due at once.
+        let mut rng = rand::thread_rng();
+        let mut due = Duration::from_nanos(0);
+        let mut arrivals = Vec::new();
+        for _ in 0..10_000 {
+            due += Duration::from_nanos(Distribution::Exponential(5_000.0).sample(&mut rng));
+            arrivals.push(due);
+        }
+        arrivals.extend((0..1000).map(|_| due));
+
+        let mut sends = Vec::new();
+        let mut now = Duration::from_nanos(0);
+        for due in arrivals {
+            now = Duration::max(now, due);
+            while let Err(wait) = bucket.take(now) {
+                now += wait;
+            }
+            sends.push(now);
+        }
+
+        let bound = |window: Duration| (rate * window.as_nanos() as f64 / 1e9) as usize + burst;
+        for i in 0..sends.len() {
+            for &window in &[1_000, 10_000, 100_000, 1_000_000] {
+                let window = Duration::from_nanos(window);
+                let n = sends[i..]
+                    .iter()
+                    .take_while(|&&t| t - sends[i] < window)
+                    .count();
+                assert!(n <= bound(window), "{} sends in {:?}", n, window);
+            }
+        }
+        // The limiter smooths bursts rather than dropping them.
+        assert_eq!(sends.len(), 11_000);
+    }
+
+    #[test]
+    fn starts_full() {
+        let mut bucket = TokenBucket::new(1_000.0, 3);
+        let zero = Duration::from_nanos(0);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(zero), Ok(()));
+        }
+        assert_eq!(bucket.take(zero), Err(Duration::from_millis(1)));
+        assert_eq!(bucket.take(Duration::from_millis(1)), Ok(()));
+    }
+}
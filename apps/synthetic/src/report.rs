@@ -0,0 +1,3032 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::iter;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json;
+
+use backend::Batches;
+use batch::Offload;
+use duration_to_ns;
+use generator::GeneratorStats;
+use histogram::{Histogram, DEFAULT_DIGITS};
+use profile::PhaseTime;
+use Packet;
+
+arg_enum! {
+#[derive(Copy, Clone, Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}}
+
+/// The configuration a run was started with, so archived results can be
+/// traced back to it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub protocol: String,
+    /// Memcached key/value workload, if the protocol has one.
+    pub workload: Option<String>,
+    pub transport: String,
+    pub targets: Vec<String>,
+    /// Address family of each connection, in the order they were opened,
+    /// for runs over IP.
+    pub address_families: Option<Vec<String>>,
+    /// Source IPs and port range client connections were bound to, if
+    /// given, and the local address each connection got, in order.
+    pub source_ips: Option<Vec<String>>,
+    pub source_ports: Option<String>,
+    pub source_addresses: Option<Vec<String>>,
+    /// Cores the client threads were pinned to, in turn, if given.
+    pub cpu_list: Option<String>,
+    /// Share of the requests each target is sent, in the order they are
+    /// listed, if not an equal one.
+    pub weights: Option<Vec<u32>>,
+    /// How requests are routed to targets, if not by weight.
+    pub routing: Option<String>,
+    /// Peak offered load in requests per second. Samples step up to it from
+    /// `--start_mpps`. Closed-loop runs send at most this many, and their
+    /// achieved throughput is the actual rate of each result.
+    pub offered_rate: u64,
+    /// Distribution of the gaps between requests, and of the work each asks
+    /// the server for.
+    pub arrival: Option<String>,
+    pub service: Option<String>,
+    /// Measured duration of each sample, excluding ramp up, for timed runs.
+    pub duration_ns: Option<u64>,
+    /// Requests sent by each sample, excluding ramp up, for count runs.
+    pub count: Option<u64>,
+    /// Requests in flight per connection, for closed-loop runs.
+    pub outstanding: Option<u64>,
+    /// Connections each sample runs over, for closed-loop runs and runs whose
+    /// threads have several.
+    pub connections: Option<u64>,
+    /// Connections each thread spreads its requests over, if more than one.
+    pub conns_per_thread: Option<u64>,
+    /// Requests each connection makes before it reconnects, if it does.
+    pub session_length: Option<String>,
+    /// Whether responses went unread, so that nothing has a latency.
+    #[serde(default)]
+    pub no_read: bool,
+    /// Whether client queueing and service time were reported apart.
+    #[serde(default)]
+    pub latency_breakdown: bool,
+    /// Significant digits the latency histograms kept, if not the default.
+    pub histogram_digits: Option<u32>,
+    /// Latencies the histograms kept, in the unit they are reported in, if
+    /// bounded. Those outside were counted at the bound they passed.
+    pub histogram_min: Option<f64>,
+    pub histogram_max: Option<f64>,
+    /// File the requests were replayed from, if they were.
+    pub trace: Option<String>,
+    /// How many times faster than recorded a trace with times was replayed.
+    pub replay_speed: Option<f64>,
+    /// Key that a `hot_key_fraction` of the requests go to, if any.
+    pub hot_key: Option<u64>,
+    pub hot_key_fraction: Option<f64>,
+    /// Distribution the USR keys were drawn from, if not uniformly.
+    pub key_distribution: Option<String>,
+    /// Operation mix of memcached requests as given, if not the workload's.
+    pub op_mix: Option<String>,
+    /// Fraction of the requests of a CAS workload that were CAS SETs.
+    pub cas_writes: Option<f64>,
+    /// How long a request could go unanswered before it timed out, in ms.
+    pub request_timeout_ms: Option<u64>,
+    /// Times a UDP request could be sent again before it timed out.
+    pub max_retransmits: Option<usize>,
+    /// How long requests could go unanswered before the run was aborted, in
+    /// ms.
+    pub watchdog_ms: Option<u64>,
+    /// Requests coalesced into each write of a pipelined connection, if more
+    /// than one.
+    pub send_batch: Option<usize>,
+    /// Datagrams sent and received with each sendmmsg and recvmmsg, if more
+    /// than one.
+    pub udp_batch: Option<usize>,
+    /// Threads building each open-loop connection's requests ahead of its
+    /// sender, and how long before they were due they could be built, if
+    /// any.
+    pub generators: Option<usize>,
+    pub generator_lead_us: Option<u64>,
+    /// Bytes each connection's receive buffer was preallocated with.
+    pub read_buffer: Option<usize>,
+    /// Whether client connections did their I/O through io_uring.
+    #[serde(default)]
+    pub io_uring: bool,
+    /// Whether each thread read its connections' responses on one thread,
+    /// with epoll.
+    #[serde(default)]
+    pub epoll: bool,
+    /// Whether client TCP connections set TCP_NODELAY, for TCP runs.
+    pub tcp_nodelay: Option<bool>,
+    /// SO_SNDBUF and SO_RCVBUF set on client connections, in bytes, if any.
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// IP type of service byte set on client connections, if any.
+    pub tos: Option<u8>,
+    /// Name the server's TLS certificate was checked against, for TLS runs.
+    pub tls_server_name: Option<String>,
+    /// Whether reconnecting TLS connections resumed their sessions.
+    pub tls_resumption: Option<bool>,
+    /// Client certificate presented to the server, if any.
+    pub tls_client_cert: Option<String>,
+    /// Value sizes in bytes from which requests count as medium and as large,
+    /// if latencies were broken out by size.
+    pub size_classes: Option<Vec<usize>>,
+    /// Closed-loop think time distribution, with its parameters in ns, e.g.
+    /// `exponential mean=1000`, for closed-loop runs.
+    pub think_time: Option<String>,
+    pub seed: Option<u64>,
+    pub key_size: Option<String>,
+    pub value_size: Option<String>,
+}
+
+/// Version of the JSON document a run prints. It is raised whenever a field
+/// is renamed, removed or changes meaning, but not when one is added.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Percentiles every run reports, besides any `--percentiles` adds.
+pub const STANDARD_PERCENTILES: [f32; 5] = [50.0, 90.0, 99.0, 99.9, 99.99];
+
+/// The standard percentiles and `extra`, each once and from the lowest up.
+pub fn reported_percentiles(extra: &[f32]) -> Vec<f32> {
+    let mut percentiles = STANDARD_PERCENTILES.to_vec();
+    for &p in extra {
+        if !percentiles
+            .iter()
+            .any(|&q| percentile_key(q) == percentile_key(p))
+        {
+            percentiles.push(p);
+        }
+    }
+    percentiles.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    percentiles
+}
+
+/// Parses a comma-separated list of percentiles in (0, 100], keeping the order
+/// they are listed in.
+pub fn parse_percentiles(spec: &str) -> Result<Vec<f32>, String> {
+    let mut percentiles: Vec<f32> = Vec::new();
+    for s in spec.split(',') {
+        let p = match s.trim().parse::<f32>() {
+            Ok(p) if p > 0.0 && p <= 100.0 => p,
+            _ => return Err(format!("bad percentile '{}', must be in (0, 100]", s)),
+        };
+        if percentiles
+            .iter()
+            .any(|&q| percentile_key(q) == percentile_key(p))
+        {
+            return Err(format!("percentile '{}' is listed twice", s));
+        }
+        percentiles.push(p);
+    }
+    Ok(percentiles)
+}
+
+/// JSON key of a percentile, with the decimal point dropped: p999 for 99.9.
+fn percentile_key(p: f32) -> String {
+    format!("p{}", p).replace('.', "")
+}
+
+/// Text column heading of a percentile.
+fn percentile_column(p: f32) -> String {
+    if p == 50.0 {
+        "Median".to_string()
+    } else {
+        format!("{}th", p)
+    }
+}
+
+/// Values at each requested percentile, in the order they were requested.
+/// Serialized as an object keyed by `percentile_key`.
+#[derive(Clone, Debug)]
+pub struct Percentiles(pub Vec<(f32, f32)>);
+
+impl Percentiles {
+    pub fn get(&self, percentile: f32) -> Option<f32> {
+        self.0
+            .iter()
+            .find(|&&(p, _)| p == percentile)
+            .map(|&(_, value)| value)
+    }
+
+    /// Each value after its column name, as text output lists them.
+    fn to_text(&self) -> String {
+        self.0
+            .iter()
+            .map(|&(p, value)| format!("{} {:.1}", percentile_column(p), value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Serialize for Percentiles {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for &(p, value) in &self.0 {
+            map.serialize_entry(&percentile_key(p), &value)?;
+        }
+        map.end()
+    }
+}
+
+/// Summary of one measured schedule. Latencies are in microseconds, or in
+/// slowdown when requested, and are absent if too few responses arrived.
+#[derive(Clone, Debug, Serialize)]
+pub struct ScheduleResult {
+    pub distribution: String,
+    pub target: u64,
+    pub actual: u64,
+    pub dropped: usize,
+    /// Requests sent but still unanswered when the drain timed out, which
+    /// `dropped` does not count.
+    pub timed_out: usize,
+    /// Of the requests timed out, those answered after, which the latencies
+    /// do not count.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub late: usize,
+    /// UDP requests sent again after going unanswered.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub retransmits: usize,
+    /// Responses that came after their request was answered: to an earlier
+    /// transmission of a request sent again, which was not needed, or copied
+    /// on the way.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub duplicates: usize,
+    /// Requests sent that no response ever answered, among those `dropped`
+    /// and `timed_out` count, and their share of the requests sent.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub unanswered: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unanswered_ratio: Option<f64>,
+    /// Requests in flight on a connection that failed, which neither
+    /// `dropped` nor the latencies count.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub failed: usize,
+    /// Requests the server answered with an error status, which still count
+    /// as completed.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub rejected: usize,
+    pub never_sent: usize,
+    /// Requests sent during the warmup, which no other field counts.
+    pub warmup: usize,
+    pub window_dropped: usize,
+    /// Fraction of the completed GETs that missed, absent if none completed.
+    pub miss_ratio: Option<f32>,
+    /// Fraction of the completed CAS SETs that their key's CAS let through,
+    /// absent if none completed.
+    pub cas_success_ratio: Option<f32>,
+    /// Achieved rate of each connection of a closed-loop run, which is its
+    /// requests in flight over the think time plus the latency. Filled in
+    /// by the report.
+    pub per_connection: Option<u64>,
+    /// Connections opened per second to start new sessions, for runs whose
+    /// connections reconnect.
+    pub connect_rate: Option<u64>,
+    /// What each target saw, for runs with several.
+    pub servers: Vec<ServerResult>,
+    /// Requests of each value size class, for runs that break them out.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sizes: Vec<SizeResult>,
+    /// Requests of each operation, by name, for protocols that have several.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ops: Vec<OpResult>,
+    /// Completions of the busiest target over its weighted share of them, so
+    /// 1 for a perfect balance. Filled in by the report.
+    pub imbalance: Option<f64>,
+    /// Requests written, for runs that never read a response.
+    pub sent: Option<usize>,
+    pub latencies: Option<Percentiles>,
+    /// Requests the latency percentiles are taken over: those sent between
+    /// the warmup and the end of the drain that did not fail, of which any
+    /// unanswered count as slower than every answered one.
+    pub samples: usize,
+    /// The lowest, mean and highest latencies of the requests answered, out
+    /// of the `samples`.
+    pub latency_min: Option<f32>,
+    pub latency_mean: Option<f32>,
+    pub latency_max: Option<f32>,
+    /// Latencies above the histogram's maximum and below its minimum, which
+    /// it counted at the bound they passed, so that a percentile among them
+    /// is the bound rather than the latency.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub latency_overflows: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub latency_underflows: usize,
+    /// Microseconds between each request's intended and actual send times.
+    pub send_lag: Option<Percentiles>,
+    /// The longest of them, which says whether the client itself kept up.
+    pub send_lag_max: Option<f32>,
+    /// With the latency broken down, the microseconds each completed request
+    /// queued in the client between its intended and actual send times, the
+    /// microseconds it then took to be answered, and the microseconds from
+    /// its intended send to its response. The last is what a client that
+    /// never fell behind its schedule would have seen, where the latencies
+    /// leave out the time requests waited to go out.
+    pub queueing: Option<Percentiles>,
+    pub service: Option<Percentiles>,
+    pub response: Option<Percentiles>,
+    pub start: u64,
+}
+
+/// The part of a schedule's requests that went to one target.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerResult {
+    pub target: String,
+    pub sent: usize,
+    pub actual: u64,
+    /// Fraction of the target's completed GETs that missed.
+    pub miss_ratio: Option<f32>,
+    /// Absent if nothing was sent to the target.
+    pub latencies: Option<Percentiles>,
+}
+
+/// Names of the value size classes, smallest first, split at the run's
+/// `size_classes` thresholds.
+pub const SIZE_CLASSES: [&str; 3] = ["small", "medium", "large"];
+
+/// Parses the two comma-separated value sizes, in bytes, from which requests
+/// count as medium and as large.
+pub fn parse_size_classes(spec: &str) -> Result<Vec<usize>, String> {
+    let thresholds = spec
+        .split(',')
+        .map(|s| match s.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!(
+                "bad size '{}', must be a positive number of bytes",
+                s
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if thresholds.len() != SIZE_CLASSES.len() - 1 || thresholds[0] >= thresholds[1] {
+        return Err(format!(
+            "bad size classes '{}', must be a medium and a larger large size",
+            spec
+        ));
+    }
+    Ok(thresholds)
+}
+
+/// Which of `SIZE_CLASSES` a value of `size` bytes falls in.
+pub fn size_class(size: usize, thresholds: &[usize]) -> usize {
+    thresholds.iter().take_while(|&&t| size >= t).count()
+}
+
+/// The part of a schedule's requests whose values fell in one size class.
+/// Requests of unknown size, such as GETs of keys never set, are in none.
+#[derive(Clone, Debug, Serialize)]
+pub struct SizeResult {
+    pub class: String,
+    pub sent: usize,
+    pub completed: usize,
+    pub latencies: Option<Percentiles>,
+}
+
+/// The part of a schedule's requests that made one operation.
+#[derive(Clone, Debug, Serialize)]
+pub struct OpResult {
+    pub op: String,
+    pub sent: usize,
+    pub completed: usize,
+    pub latencies: Percentiles,
+    /// Bytes of the requests sent and of the responses that completed them,
+    /// as their protocol frames them, and the rate of each in Gbit/s over
+    /// the schedule.
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    pub out_gbps: f64,
+    pub in_gbps: f64,
+}
+
+/// What the client counted over one `--interval` of a run, as it went.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Interval {
+    /// Since the run started.
+    pub end: Duration,
+    pub sent: usize,
+    pub completed: usize,
+    /// Completions per second.
+    pub rps: u64,
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    /// Estimated bytes of the IP and transport headers that carried them.
+    pub headers_out: u64,
+    pub headers_in: u64,
+    /// Bytes with their headers, in Gbit/s.
+    pub out_gbps: f64,
+    pub in_gbps: f64,
+}
+
+/// Requests sent and completed over one `--interval` of a run, which ends
+/// `end_s` seconds after the run started at `start`, in Unix seconds, and
+/// the bytes written and read over it, as `Interval` counts them.
+#[derive(Clone, Debug, Serialize)]
+pub struct IntervalCounts {
+    pub start: u64,
+    pub end_s: f64,
+    pub sent: usize,
+    pub completed: usize,
+    pub rps: u64,
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    pub headers_out: u64,
+    pub headers_in: u64,
+    pub out_gbps: f64,
+    pub in_gbps: f64,
+}
+
+/// Raw per-request samples in CSV. Rows are written once a schedule has been
+/// measured, so the file is never touched while requests are in flight.
+pub struct SampleWriter {
+    out: BufWriter<File>,
+}
+
+impl SampleWriter {
+    pub fn create(path: &str) -> io::Result<SampleWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "timestamp_ns,opcode,key,latency_ns,status")?;
+        Ok(SampleWriter { out })
+    }
+
+    /// Writes a row for every request sent, timestamped with its send time.
+    /// One never answered has no latency, and ends in a timeout, or in a
+    /// failure if its connection failed.
+    pub fn write(&mut self, wct_start: SystemTime, packets: &[Packet]) -> io::Result<()> {
+        let start_ns = duration_to_ns(wct_start.duration_since(UNIX_EPOCH).unwrap());
+        for p in packets {
+            let start = match p.actual_start {
+                Some(start) => start,
+                None => continue,
+            };
+            write!(
+                self.out,
+                "{},{},",
+                start_ns + duration_to_ns(start),
+                p.info.op
+            )?;
+            if let Some(key) = p.info.key {
+                write!(self.out, "{}", key)?;
+            }
+            match p.completion_time {
+                Some(end) => writeln!(
+                    self.out,
+                    ",{},{}",
+                    duration_to_ns(end.checked_sub(start).unwrap_or_default()),
+                    if p.miss { "miss" } else { "ok" }
+                )?,
+                None if p.failed => writeln!(self.out, ",,failed")?,
+                None => writeln!(self.out, ",,timeout")?,
+            }
+        }
+        self.out.flush()
+    }
+}
+
+/// Bytes of each record of a binary sample dump: the send time in Unix
+/// nanoseconds and the latency in nanoseconds as u64s, then the opcode, as
+/// an index into `SAMPLE_OPS`, and the status, as an index into
+/// `SAMPLE_STATUSES`, as a byte each. Every number is little-endian. A
+/// request never answered has a latency of 0.
+pub const SAMPLE_RECORD: usize = 18;
+
+/// Opens a binary sample dump, so that the converter refuses anything else.
+const SAMPLE_MAGIC: &[u8; 8] = b"SYNSMP01";
+
+/// The operations a request can make: none, a synthetic request's work,
+/// a DNS query, or one of memcached's. New ones go at the end, so that
+/// earlier dumps still convert.
+pub const SAMPLE_OPS: [&str; 10] = [
+    "", "work", "query", "get", "set", "add", "replace", "delete", "gat", "cas",
+];
+
+/// How a request in a binary sample dump ended, as `SampleWriter` writes
+/// it. New ones go at the end, as for `SAMPLE_OPS`.
+pub const SAMPLE_STATUSES: [&str; 4] = ["ok", "miss", "timeout", "failed"];
+
+/// The index of `op` in `SAMPLE_OPS`, 0 for one not there.
+pub fn sample_op(op: &str) -> u8 {
+    SAMPLE_OPS.iter().position(|&o| o == op).unwrap_or(0) as u8
+}
+
+/// Raw per-request samples in the binary format of `SAMPLE_RECORD`, a fixed
+/// size record each after the magic, for runs with too many requests to keep
+/// as CSV. Like `SampleWriter`, records are written once a schedule has been
+/// measured, through a buffer large enough that each schedule takes few
+/// writes. A run that could take the dump past its cap is refused.
+pub struct BinarySampleWriter {
+    out: BufWriter<File>,
+    written: u64,
+    cap: u64,
+}
+
+impl BinarySampleWriter {
+    /// A dump at `path` of at most `cap` bytes.
+    pub fn create(path: &str, cap: u64) -> io::Result<BinarySampleWriter> {
+        let mut out = BufWriter::with_capacity(1 << 20, File::create(path)?);
+        out.write_all(SAMPLE_MAGIC)?;
+        out.flush()?;
+        Ok(BinarySampleWriter {
+            out,
+            written: SAMPLE_MAGIC.len() as u64,
+            cap,
+        })
+    }
+
+    /// Checks that a record for each of `requests` more would keep the dump
+    /// within its cap.
+    pub fn reserve(&self, requests: usize) -> Result<(), String> {
+        let estimate = self.written + (requests * SAMPLE_RECORD) as u64;
+        if estimate > self.cap {
+            return Err(format!(
+                "The binary samples of {} requests could take the dump to {} MB, past its cap \
+                 of {} MB",
+                requests,
+                estimate >> 20,
+                self.cap >> 20
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes a record for every request sent, as `SampleWriter` writes a
+    /// row.
+    pub fn write(&mut self, wct_start: SystemTime, packets: &[Packet]) -> io::Result<()> {
+        let start_ns = duration_to_ns(wct_start.duration_since(UNIX_EPOCH).unwrap());
+        for p in packets {
+            let start = match p.actual_start {
+                Some(start) => start,
+                None => continue,
+            };
+            let (latency, status) = match p.completion_time {
+                Some(end) => (end.checked_sub(start).unwrap_or_default(), p.miss as u8),
+                None if p.failed => (Duration::default(), 3),
+                None => (Duration::default(), 2),
+            };
+            let mut record = [0; SAMPLE_RECORD];
+            record[..8].copy_from_slice(&(start_ns + duration_to_ns(start)).to_le_bytes());
+            record[8..16].copy_from_slice(&duration_to_ns(latency).to_le_bytes());
+            record[16] = sample_op(p.info.op);
+            record[17] = status;
+            self.out.write_all(&record)?;
+            self.written += SAMPLE_RECORD as u64;
+        }
+        self.out.flush()
+    }
+}
+
+/// A CSV line for every request each send thread made, or one in every
+/// `every`, in a file of the thread's own, for debugging what was sent: when
+/// it was sent, in Unix nanoseconds, the connection, counted over every
+/// thread's, the opaque it was sent with, its opcode, key and the sizes of
+/// both that it was sent with, if known, and how it ended with its latency.
+/// A request that was never answered ends in a timeout, and one whose
+/// connection failed in a failure. Lines are written once a run is over, and
+/// requests never sent have none.
+pub struct RequestLogWriter {
+    path: String,
+    threads: Vec<BufWriter<File>>,
+    every: usize,
+}
+
+impl RequestLogWriter {
+    /// A log to a file at `path.N` for each thread N. Fails if the first
+    /// thread's cannot be created.
+    pub fn create(path: &str, every: usize) -> io::Result<RequestLogWriter> {
+        assert!(every > 0, "sampling must keep at least one request in each");
+        let mut log = RequestLogWriter {
+            path: path.to_string(),
+            threads: Vec::new(),
+            every,
+        };
+        log.thread(0)?;
+        Ok(log)
+    }
+
+    /// The file of `thread`, created with its header if it is new.
+    fn thread(&mut self, thread: usize) -> io::Result<&mut BufWriter<File>> {
+        while self.threads.len() <= thread {
+            let path = format!("{}.{}", self.path, self.threads.len());
+            let mut out = BufWriter::new(File::create(path)?);
+            writeln!(
+                out,
+                "timestamp_ns,conn,opaque,opcode,key,key_length,value_length,status,latency_ns"
+            )?;
+            out.flush()?;
+            self.threads.push(out);
+        }
+        Ok(&mut self.threads[thread])
+    }
+
+    /// Writes the lines of the `packets` one thread sent, each by its index
+    /// among them, over connections counted from `first_conn`.
+    pub fn write(
+        &mut self,
+        thread: usize,
+        first_conn: usize,
+        wct_start: SystemTime,
+        packets: &[Packet],
+    ) -> io::Result<()> {
+        let start_ns = duration_to_ns(wct_start.duration_since(UNIX_EPOCH).unwrap());
+        let every = self.every;
+        let out = self.thread(thread)?;
+        for (i, p) in packets.iter().enumerate().step_by(every) {
+            let start = match p.actual_start {
+                Some(start) => start,
+                None => continue,
+            };
+            write!(
+                out,
+                "{},{},{},{},",
+                start_ns + duration_to_ns(start),
+                first_conn + p.conn,
+                i,
+                p.info.op
+            )?;
+            for field in &[p.info.key, p.info.key_size.map(|n| n as u64)] {
+                if let Some(n) = *field {
+                    write!(out, "{}", n)?;
+                }
+                write!(out, ",")?;
+            }
+            if let Some(n) = p.info.value_size {
+                write!(out, "{}", n)?;
+            }
+            match p.completion_time {
+                Some(end) => writeln!(
+                    out,
+                    ",{},{}",
+                    if p.miss { "miss" } else { "ok" },
+                    duration_to_ns(end.checked_sub(start).unwrap_or_default())
+                )?,
+                None if p.failed => writeln!(out, ",failed,")?,
+                None => writeln!(out, ",timeout,")?,
+            }
+        }
+        out.flush()
+    }
+}
+
+/// Converts a binary sample dump to CSV rows of its send time, opcode,
+/// latency and status, as `SampleWriter` writes them but without keys, and
+/// returns how many there were.
+pub fn samples_to_csv<R: Read, W: Write>(mut dump: R, out: W) -> io::Result<usize> {
+    let bad = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut magic = [0; 8];
+    dump.read_exact(&mut magic)
+        .map_err(|_| bad("not a binary sample dump"))?;
+    if &magic != SAMPLE_MAGIC {
+        return Err(bad("not a binary sample dump"));
+    }
+    let mut out = BufWriter::new(out);
+    writeln!(out, "timestamp_ns,opcode,latency_ns,status")?;
+    let mut dump = io::BufReader::new(dump);
+    let mut record = [0; SAMPLE_RECORD];
+    let mut rows = 0;
+    loop {
+        let mut filled = 0;
+        while filled < SAMPLE_RECORD {
+            match dump.read(&mut record[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        match filled {
+            0 => break,
+            SAMPLE_RECORD => {}
+            _ => return Err(bad("the dump ends part way through a record")),
+        }
+        let mut word = [0; 8];
+        word.copy_from_slice(&record[..8]);
+        let start = u64::from_le_bytes(word);
+        word.copy_from_slice(&record[8..16]);
+        let latency = u64::from_le_bytes(word);
+        let op = SAMPLE_OPS
+            .get(record[16] as usize)
+            .ok_or_else(|| bad("a record has an unknown opcode"))?;
+        let status = SAMPLE_STATUSES
+            .get(record[17] as usize)
+            .ok_or_else(|| bad("a record has an unknown status"))?;
+        match record[17] {
+            0 | 1 => writeln!(out, "{},{},{},{}", start, op, latency, status)?,
+            _ => writeln!(out, "{},{},,{}", start, op, status)?,
+        }
+        rows += 1;
+    }
+    out.flush()?;
+    Ok(rows)
+}
+
+/// One CSV row per bucket of each measured schedule's latency histogram that
+/// counted any requests, for analysis offline. Bounds are in the unit of the
+/// latencies, microseconds or slowdown, to the thousandth the histograms
+/// count in.
+pub struct HistogramWriter {
+    out: BufWriter<File>,
+}
+
+impl HistogramWriter {
+    pub fn create(path: &str) -> io::Result<HistogramWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "start,distribution,target,low,high,count")?;
+        Ok(HistogramWriter { out })
+    }
+
+    /// Writes the rows of the histogram of `result`'s latencies.
+    pub fn write(&mut self, result: &ScheduleResult, histogram: &Histogram) -> io::Result<()> {
+        let thousandths = |v: u64| format!("{}.{:03}", v / 1000, v % 1000);
+        for (low, high, count) in histogram.buckets() {
+            writeln!(
+                self.out,
+                "{},{},{},{},{},{}",
+                result.start,
+                result.distribution,
+                result.target,
+                thousandths(low),
+                thousandths(high),
+                count
+            )?;
+        }
+        self.out.flush()
+    }
+}
+
+/// One CSV row per interval of each run, for plotting: when it ended, in
+/// Unix seconds, the load offered by the step it fell in and the load sent
+/// and achieved over it, in requests per second, the latency percentiles of
+/// the responses received over it, in microseconds, the misses among those,
+/// the errors and timeouts counted over it, and the bytes written and read,
+/// the headers estimated to have carried them, and the rate of both together
+/// in Gbit/s. Numbers are written the same in any locale. Each row is flushed
+/// as its interval closes, so a run that dies part way keeps the rows before.
+pub struct IntervalWriter {
+    out: BufWriter<File>,
+}
+
+/// What the row of an interval gives beyond its `Interval`, as the client
+/// saw it live.
+pub struct IntervalRow<'a> {
+    /// The load offered by the step the interval began in, if in one.
+    pub offered: Option<u64>,
+    pub latencies: &'a Histogram,
+    pub misses: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+}
+
+/// The latency percentiles of each interval.
+const INTERVAL_PERCENTILES: [f32; 3] = [50.0, 99.0, 99.9];
+
+impl IntervalWriter {
+    pub fn create(path: &str) -> io::Result<IntervalWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "time,step_offered,offered,achieved")?;
+        for &p in &INTERVAL_PERCENTILES {
+            write!(out, ",{}", percentile_key(p))?;
+        }
+        writeln!(
+            out,
+            ",misses,errors,timeouts,bytes_out,bytes_in,headers_out,headers_in,out_gbps,in_gbps"
+        )?;
+        out.flush()?;
+        Ok(IntervalWriter { out })
+    }
+
+    /// Writes the row of an interval, `length` long, of a run that started
+    /// at `wct_start`.
+    pub fn write(
+        &mut self,
+        wct_start: SystemTime,
+        length: Duration,
+        interval: &Interval,
+        row: &IntervalRow,
+    ) -> io::Result<()> {
+        let t = wct_start.duration_since(UNIX_EPOCH).unwrap() + interval.end;
+        write!(self.out, "{}.{:03},", t.as_secs(), t.subsec_millis())?;
+        if let Some(offered) = row.offered {
+            write!(self.out, "{}", offered)?;
+        }
+        write!(
+            self.out,
+            ",{:.0},{}",
+            interval.sent as f64 / length.as_secs_f64(),
+            interval.rps
+        )?;
+        for &p in &INTERVAL_PERCENTILES {
+            write!(self.out, ",")?;
+            if let Some(ns) = row.latencies.quantile(p as f64 / 100.0) {
+                write!(self.out, "{:.1}", ns as f64 / 1000.0)?;
+            }
+        }
+        writeln!(
+            self.out,
+            ",{},{},{},{},{},{},{},{:.6},{:.6}",
+            row.misses,
+            row.errors,
+            row.timeouts,
+            interval.bytes_out,
+            interval.bytes_in,
+            interval.headers_out,
+            interval.headers_in,
+            interval.out_gbps,
+            interval.in_gbps
+        )?;
+        self.out.flush()
+    }
+}
+
+/// One CSV row per point of a sweep. Rows are flushed as they are written, so
+/// a run that dies part way keeps the points measured before it.
+pub struct SweepWriter {
+    out: BufWriter<File>,
+    percentiles: usize,
+    saturation: f64,
+}
+
+impl SweepWriter {
+    pub fn create(path: &str, percentiles: &[f32], saturation: f64) -> io::Result<SweepWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "offered,achieved,dropped,never_sent,hit_rate")?;
+        for &p in percentiles {
+            write!(out, ",{}", percentile_key(p))?;
+        }
+        writeln!(out, ",status")?;
+        out.flush()?;
+        Ok(SweepWriter {
+            out,
+            percentiles: percentiles.len(),
+            saturation,
+        })
+    }
+
+    /// Writes the row of a point. Its status is failed if too few responses
+    /// arrived to measure it, and saturated if it achieved less than the
+    /// saturation fraction of the load it offered.
+    pub fn write(&mut self, result: &ScheduleResult) -> io::Result<()> {
+        write!(
+            self.out,
+            "{},{},{},{},",
+            result.target, result.actual, result.dropped, result.never_sent
+        )?;
+        if let Some(miss_ratio) = result.miss_ratio {
+            write!(self.out, "{:.4}", 1.0 - miss_ratio)?;
+        }
+        let status = match result.latencies {
+            None => {
+                for _ in 0..self.percentiles {
+                    write!(self.out, ",")?;
+                }
+                "failed"
+            }
+            Some(ref l) => {
+                for &(_, value) in &l.0 {
+                    write!(self.out, ",{:.1}", value)?;
+                }
+                if (result.actual as f64) < self.saturation * result.target as f64 {
+                    "saturated"
+                } else {
+                    "ok"
+                }
+            }
+        };
+        writeln!(self.out, ",{}", status)?;
+        self.out.flush()
+    }
+}
+
+/// Completed requests counted in fixed buckets of wall-clock time, starting
+/// when the first run recorded began, so transient dips show up rather than
+/// being averaged away.
+pub struct ThroughputSeries {
+    bucket: Duration,
+    origin: Option<SystemTime>,
+    completed: Vec<u64>,
+    /// Where to write the series as CSV when the report finishes.
+    csv: Option<File>,
+}
+
+#[derive(Serialize)]
+struct ThroughputJson {
+    start_ns: u64,
+    bucket_ns: u64,
+    /// Requests per second completed in each bucket.
+    rps: Vec<f64>,
+}
+
+impl ThroughputSeries {
+    pub fn new(bucket: Duration, csv: Option<File>) -> ThroughputSeries {
+        assert!(bucket > Duration::from_nanos(0), "buckets must be nonempty");
+        ThroughputSeries {
+            bucket,
+            origin: None,
+            completed: Vec::new(),
+            csv,
+        }
+    }
+
+    /// Counts the completions of a run's packets, whose times are relative to
+    /// `wct_start`.
+    pub fn add(&mut self, wct_start: SystemTime, packets: &[Packet]) {
+        self.count(wct_start, packets.iter().filter_map(|p| p.completion_time));
+    }
+
+    /// Counts events at `times` relative to `wct_start`.
+    fn count<I: Iterator<Item = Duration>>(&mut self, wct_start: SystemTime, times: I) {
+        let origin = *self.origin.get_or_insert(wct_start);
+        let offset = duration_to_ns(wct_start.duration_since(origin).unwrap_or_default());
+        let bucket_ns = duration_to_ns(self.bucket);
+        for t in times {
+            let i = ((offset + duration_to_ns(t)) / bucket_ns) as usize;
+            if i >= self.completed.len() {
+                self.completed.resize(i + 1, 0);
+            }
+            self.completed[i] += 1;
+        }
+    }
+
+    /// Requests per second completed in each bucket.
+    pub fn rates(&self) -> Vec<f64> {
+        let secs = duration_to_ns(self.bucket) as f64 / 1e9;
+        self.completed.iter().map(|&n| n as f64 / secs).collect()
+    }
+
+    fn start_ns(&self) -> u64 {
+        self.origin
+            .map(|t| duration_to_ns(t.duration_since(UNIX_EPOCH).unwrap()))
+            .unwrap_or(0)
+    }
+
+    fn to_json(&self) -> ThroughputJson {
+        ThroughputJson {
+            start_ns: self.start_ns(),
+            bucket_ns: duration_to_ns(self.bucket),
+            rps: self.rates(),
+        }
+    }
+
+    /// Writes a row per bucket, timestamped with the bucket's start.
+    pub fn write_csv<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+        writeln!(out, "timestamp_ns,completed,rps")?;
+        let bucket_ns = duration_to_ns(self.bucket);
+        for (i, (n, rps)) in self.completed.iter().zip(self.rates()).enumerate() {
+            writeln!(
+                out,
+                "{},{},{:.0}",
+                self.start_ns() + i as u64 * bucket_ns,
+                n,
+                rps
+            )?;
+        }
+        out.flush()
+    }
+}
+
+/// How long each TCP connection took to establish, summarized apart from the
+/// request latencies since connections are opened before any request is sent.
+pub struct ConnectLatencies {
+    /// In nanoseconds.
+    times: Histogram,
+}
+
+#[derive(Serialize)]
+pub struct ConnectSummary {
+    pub connections: usize,
+    /// In microseconds.
+    pub latencies: Percentiles,
+}
+
+impl ConnectLatencies {
+    pub fn new() -> ConnectLatencies {
+        ConnectLatencies {
+            times: Histogram::new(DEFAULT_DIGITS),
+        }
+    }
+
+    pub fn add(&mut self, times: &[Duration]) {
+        for &t in times {
+            self.times.record(duration_to_ns(t));
+        }
+    }
+
+    fn summary(&self, percentiles: &[f32]) -> Option<ConnectSummary> {
+        let n = self.times.count();
+        if n == 0 {
+            return None;
+        }
+        let at = |p: f32| self.times.quantile(p as f64 / 100.0).unwrap() as f32 / 1000.0;
+        Some(ConnectSummary {
+            connections: n as usize,
+            latencies: Percentiles(percentiles.iter().map(|&p| (p, at(p))).collect()),
+        })
+    }
+}
+
+/// Collects the results of a run. Text output is printed as it arrives, while
+/// JSON output is held back and printed as a single object by `finish`.
+pub struct Report {
+    format: OutputFormat,
+    metadata: RunMetadata,
+    results: Vec<ScheduleResult>,
+    samples: Option<SampleWriter>,
+    binary_samples: Option<BinarySampleWriter>,
+    request_log: Option<RequestLogWriter>,
+    sweep: Option<SweepWriter>,
+    histograms: Option<HistogramWriter>,
+    interval_rows: Option<IntervalWriter>,
+    throughput: Option<ThroughputSeries>,
+    connects: Option<ConnectLatencies>,
+    /// How long each TLS handshake took, which connect latencies include.
+    tls_handshakes: ConnectLatencies,
+    percentiles: Vec<f32>,
+    converged_rate: Option<Option<u64>>,
+    malformed: usize,
+    clamped_values: usize,
+    quiet_failures: usize,
+    data_type_mismatches: usize,
+    datagram_sizes: Vec<DatagramSizes>,
+    generators: Option<GeneratorSummary>,
+    self_profile: Option<Vec<PhaseSummary>>,
+    udp_gso: Option<OffloadSummary>,
+    udp_gro: Option<OffloadSummary>,
+    udp_batches: Option<BatchSummary>,
+    connection_requests: Vec<usize>,
+    reconnects: usize,
+    /// Requests timed out in each second, for runs with a request timeout.
+    timeouts: Option<ThroughputSeries>,
+    /// Requests never answered, and duplicate responses, in the second each
+    /// request was sent.
+    unanswered: Option<ThroughputSeries>,
+    duplicates: Option<ThroughputSeries>,
+    spurious: usize,
+    intervals: Vec<IntervalCounts>,
+    response_gaps: Option<ResponseGaps>,
+    /// Bytes written and read over every run, the headers estimated to have
+    /// carried them, and how long the runs took.
+    bytes_out: u64,
+    bytes_in: u64,
+    headers_out: u64,
+    headers_in: u64,
+    run_time: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    format_version: u32,
+    metadata: &'a RunMetadata,
+    results: &'a [ScheduleResult],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    throughput: Option<ThroughputJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect: Option<ConnectSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_handshake: Option<ConnectSummary>,
+    /// Highest rate that held the latency SLO, or null if none did, for
+    /// SLO-seeking runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    converged_rate: Option<Option<u64>>,
+    /// Datagrams dropped for being too short to parse.
+    #[serde(skip_serializing_if = "is_zero")]
+    malformed: usize,
+    /// ETC value sizes sampled above the maximum and clamped to it.
+    #[serde(skip_serializing_if = "is_zero")]
+    clamped_values: usize,
+    /// Quiet memcached writes that failed, within batches that completed.
+    #[serde(skip_serializing_if = "is_zero")]
+    quiet_failures: usize,
+    /// Memcached GET hits without the data type SETs were sent with.
+    #[serde(skip_serializing_if = "is_zero")]
+    data_type_mismatches: usize,
+    /// How many response datagrams were received of each size, for UDP runs.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    datagram_sizes: &'a [DatagramSizes],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generators: Option<&'a GeneratorSummary>,
+    /// Where the client's own time went, for runs with `--self-profile`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    self_profile: Option<&'a [PhaseSummary]>,
+    /// Whether UDP sends were segmented and receives coalesced, for runs
+    /// that asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_gso: Option<&'a OffloadSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_gro: Option<&'a OffloadSummary>,
+    /// How datagrams were batched into sends and receives, for runs that
+    /// batched them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp_batches: Option<&'a BatchSummary>,
+    /// Requests sent over each connection, across all samples, for runs whose
+    /// threads have several.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    connection_requests: &'a [usize],
+    /// Connections opened in place of ones that failed, across all samples.
+    #[serde(skip_serializing_if = "is_zero")]
+    reconnects: usize,
+    /// Requests timed out in each second since the first sample began, for
+    /// runs with a request timeout.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    timeouts_per_second: &'a [u64],
+    /// Requests never answered in each second they were sent in, since the
+    /// first sample began.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    unanswered_per_second: &'a [u64],
+    /// Duplicate responses in each second their requests were sent in.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    duplicates_per_second: &'a [u64],
+    /// Responses that matched no request in flight, across all samples.
+    #[serde(skip_serializing_if = "is_zero")]
+    spurious_responses: usize,
+    /// Requests sent and completed over each interval, for runs with
+    /// `--interval`.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    intervals: &'a [IntervalCounts],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_gaps: Option<&'a ResponseGaps>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bandwidth: Option<Bandwidth>,
+}
+
+/// A stretch in which a connection received nothing, between two responses.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResponseGap {
+    /// Index of the connection, across the run's threads.
+    pub connection: usize,
+    /// When the response before the gap arrived, in Unix seconds.
+    pub start: f64,
+    pub ms: f64,
+}
+
+/// The gaps between consecutive responses on each connection, for runs with
+/// `--response-gaps`: the longest of each connection, across all samples,
+/// and the `top` longest of all, which surface stalls of the server too
+/// brief to move the percentiles.
+#[derive(Serialize)]
+pub struct ResponseGaps {
+    #[serde(skip)]
+    top: usize,
+    /// In ms, 0 for a connection with fewer than two responses.
+    pub max_by_connection: Vec<f64>,
+    /// The longest first.
+    pub longest: Vec<ResponseGap>,
+}
+
+impl ResponseGaps {
+    fn new(top: usize) -> ResponseGaps {
+        ResponseGaps {
+            top,
+            max_by_connection: Vec::new(),
+            longest: Vec::new(),
+        }
+    }
+
+    /// Adds the gaps between the responses on each connection of `packets`,
+    /// numbered from `first_conn`, of a run that started at `wct_start`.
+    fn add(&mut self, first_conn: usize, wct_start: SystemTime, packets: &[Packet]) {
+        let start = wct_start.duration_since(UNIX_EPOCH).unwrap();
+        let mut by_conn: BTreeMap<usize, Vec<Duration>> = BTreeMap::new();
+        for p in packets {
+            if let Some(t) = p.completion_time {
+                by_conn.entry(p.conn).or_default().push(t);
+            }
+        }
+        for (conn, mut times) in by_conn {
+            let connection = first_conn + conn;
+            if self.max_by_connection.len() <= connection {
+                self.max_by_connection.resize(connection + 1, 0.0);
+            }
+            times.sort();
+            for pair in times.windows(2) {
+                let ms = (pair[1] - pair[0]).as_secs_f64() * 1e3;
+                let max = &mut self.max_by_connection[connection];
+                *max = f64::max(*max, ms);
+                self.longest.push(ResponseGap {
+                    connection,
+                    start: (start + pair[0]).as_secs_f64(),
+                    ms,
+                });
+            }
+            self.longest
+                .sort_by(|a, b| b.ms.partial_cmp(&a.ms).unwrap());
+            self.longest.truncate(self.top);
+        }
+    }
+}
+
+/// The bytes written to and read from the servers' sockets over every run,
+/// and the rate of each in MB/s, of 10^6 bytes. The headers that carried
+/// them are estimated from the size of each write and read, and counted in
+/// the rate of each direction on the wire, in Gbit/s.
+#[derive(Serialize)]
+pub struct Bandwidth {
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    pub headers_out: u64,
+    pub headers_in: u64,
+    pub seconds: f64,
+    pub out_mbps: f64,
+    pub in_mbps: f64,
+    pub out_gbps: f64,
+    pub in_gbps: f64,
+}
+
+/// How many response datagrams were received of sizes from `min` to `max`
+/// bytes.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DatagramSizes {
+    pub min: usize,
+    pub max: usize,
+    pub count: usize,
+}
+
+/// What the generator threads did over a run, for runs with them.
+#[derive(Serialize)]
+struct GeneratorSummary {
+    requests: usize,
+    /// Requests waiting in a generator's queue when the sender took one.
+    mean_queue_depth: f64,
+    /// Requests that were due before their generator had built them.
+    underruns: usize,
+    /// How long before it was due a request was built.
+    mean_lead_us: f64,
+}
+
+/// How long the client spent in one phase of its requests over a run, for
+/// runs that timed themselves.
+#[derive(Serialize)]
+struct PhaseSummary {
+    phase: &'static str,
+    calls: u64,
+    total_ms: f64,
+    /// Of the wall time spent in every phase, reads' including their waits.
+    share: f64,
+}
+
+/// What UDP segmentation or coalescing did over a run, for runs that asked
+/// for it.
+#[derive(Serialize)]
+struct OffloadSummary {
+    /// Connections that asked, and that the kernel did it for.
+    connections: usize,
+    active: usize,
+    /// Sends segmented or receives coalesced, and the datagrams of each on
+    /// average.
+    batches: usize,
+    mean_datagrams: f64,
+    /// The longest a request waited between being stamped and its
+    /// segmented send, for segmentation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_delay_us: Option<f64>,
+}
+
+impl OffloadSummary {
+    fn new(stats: Offload, delay: Option<Duration>) -> OffloadSummary {
+        OffloadSummary {
+            connections: stats.wanted,
+            active: stats.active,
+            batches: stats.batches,
+            mean_datagrams: match stats.batches {
+                0 => 0.0,
+                n => stats.datagrams as f64 / n as f64,
+            },
+            max_delay_us: delay.map(|d| d.as_nanos() as f64 / 1000.0),
+        }
+    }
+}
+
+/// The sends and receives a run's batched datagrams took, and the datagrams
+/// of each on average.
+#[derive(Serialize)]
+struct BatchSummary {
+    sends: u64,
+    mean_sent: f64,
+    receives: u64,
+    mean_received: f64,
+}
+
+/// The rate `bytes` over `time` makes, in Gbit/s, or 0 over no time.
+pub fn gbps(bytes: u64, time: Duration) -> f64 {
+    match time.as_secs_f64() {
+        secs if secs > 0.0 => bytes as f64 * 8.0 / secs / 1e9,
+        _ => 0.0,
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// A per-second series, or nothing if every second is zero.
+fn nonzero(series: &Option<ThroughputSeries>) -> &[u64] {
+    match *series {
+        Some(ref t) if t.completed.iter().any(|&n| n > 0) => &t.completed[..],
+        _ => &[],
+    }
+}
+
+fn text_header(percentiles: &[f32]) -> String {
+    let mut columns = vec![
+        "Distribution",
+        "Target",
+        "Actual",
+        "Dropped",
+        "Never Sent",
+        "Min",
+        "Mean",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect::<Vec<_>>();
+    columns.extend(percentiles.iter().map(|&p| percentile_column(p)));
+    columns.extend(vec!["Max".to_string(), "Samples".to_string()]);
+    columns.push("Start".to_string());
+    columns.join(", ")
+}
+
+fn print_field<T: Display>(name: &str, value: &Option<T>) {
+    if let Some(ref value) = *value {
+        println!("{}: {}", name, value);
+    }
+}
+
+impl Report {
+    pub fn new(
+        format: OutputFormat,
+        metadata: RunMetadata,
+        samples: Option<SampleWriter>,
+        sweep: Option<SweepWriter>,
+        throughput: Option<ThroughputSeries>,
+        connects: Option<ConnectLatencies>,
+        percentiles: Vec<f32>,
+    ) -> Report {
+        if let OutputFormat::Text = format {
+            println!("Protocol: {}", metadata.protocol);
+            print_field("Workload", &metadata.workload);
+            println!("Transport: {}", metadata.transport);
+            println!("Targets: {}", metadata.targets.join(" "));
+            println!("Offered rate: {}", metadata.offered_rate);
+            print_field("Arrival", &metadata.arrival);
+            print_field("Service", &metadata.service);
+            print_field("Duration (ns)", &metadata.duration_ns);
+            print_field("Count", &metadata.count);
+            if let Some(ref weights) = metadata.weights {
+                println!(
+                    "Weights: {}",
+                    weights
+                        .iter()
+                        .map(|w| w.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                );
+            }
+            print_field("Routing", &metadata.routing);
+            if let Some(ref ips) = metadata.source_ips {
+                println!("Source IPs: {}", ips.join(" "));
+            }
+            print_field("Source ports", &metadata.source_ports);
+            print_field("CPU list", &metadata.cpu_list);
+            if let Some(ref thresholds) = metadata.size_classes {
+                println!(
+                    "Size classes (bytes): {}",
+                    thresholds
+                        .iter()
+                        .map(|t| t.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                );
+            }
+            print_field("Outstanding", &metadata.outstanding);
+            print_field("Connections", &metadata.connections);
+            print_field("Connections per thread", &metadata.conns_per_thread);
+            print_field("Think time", &metadata.think_time);
+            print_field("Session length", &metadata.session_length);
+            print_field("Trace", &metadata.trace);
+            print_field("Replay speed", &metadata.replay_speed);
+            print_field("Hot key", &metadata.hot_key);
+            print_field("Hot key fraction", &metadata.hot_key_fraction);
+            print_field("Key distribution", &metadata.key_distribution);
+            print_field("Operation mix", &metadata.op_mix);
+            print_field("CAS writes", &metadata.cas_writes);
+            print_field("Request timeout (ms)", &metadata.request_timeout_ms);
+            print_field("Max retransmits", &metadata.max_retransmits);
+            print_field("Watchdog (ms)", &metadata.watchdog_ms);
+            print_field("Send batch", &metadata.send_batch);
+            print_field("UDP batch", &metadata.udp_batch);
+            print_field("Generators", &metadata.generators);
+            print_field("Generator lead (us)", &metadata.generator_lead_us);
+            print_field("Read buffer (bytes)", &metadata.read_buffer);
+            print_field("Histogram digits", &metadata.histogram_digits);
+            print_field("Histogram min", &metadata.histogram_min);
+            print_field("Histogram max", &metadata.histogram_max);
+            if metadata.io_uring {
+                println!("Socket I/O: io_uring");
+            }
+            if metadata.epoll {
+                println!("Receive loop: epoll");
+            }
+            print_field("TCP_NODELAY", &metadata.tcp_nodelay);
+            print_field("Send buffer (bytes)", &metadata.send_buffer);
+            print_field("Receive buffer (bytes)", &metadata.recv_buffer);
+            print_field("ToS", &metadata.tos);
+            print_field("TLS server name", &metadata.tls_server_name);
+            print_field("TLS resumption", &metadata.tls_resumption);
+            print_field("TLS client certificate", &metadata.tls_client_cert);
+            if metadata.no_read {
+                println!("Latency: unavailable, responses are not read");
+            }
+            print_field("Seed", &metadata.seed);
+            print_field("Key size", &metadata.key_size);
+            print_field("Value size", &metadata.value_size);
+            println!("{}", text_header(&percentiles));
+        }
+        Report {
+            format,
+            metadata,
+            results: Vec::new(),
+            samples,
+            binary_samples: None,
+            request_log: None,
+            sweep,
+            histograms: None,
+            interval_rows: None,
+            throughput,
+            connects,
+            tls_handshakes: ConnectLatencies::new(),
+            percentiles,
+            converged_rate: None,
+            malformed: 0,
+            clamped_values: 0,
+            quiet_failures: 0,
+            data_type_mismatches: 0,
+            datagram_sizes: Vec::new(),
+            generators: None,
+            self_profile: None,
+            udp_gso: None,
+            udp_gro: None,
+            udp_batches: None,
+            connection_requests: Vec::new(),
+            reconnects: 0,
+            timeouts: None,
+            unanswered: None,
+            duplicates: None,
+            spurious: 0,
+            intervals: Vec::new(),
+            response_gaps: None,
+            bytes_out: 0,
+            bytes_in: 0,
+            headers_out: 0,
+            headers_in: 0,
+            run_time: Duration::from_nanos(0),
+        }
+    }
+
+    pub fn record_samples(&mut self, wct_start: SystemTime, packets: &[Packet]) {
+        let failed = match self.samples {
+            Some(ref mut samples) => samples.write(wct_start, packets).is_err(),
+            None => false,
+        };
+        if failed {
+            println!("Failed to write latency samples, no more will be written");
+            self.samples = None;
+        }
+        let failed = match self.binary_samples {
+            Some(ref mut samples) => samples.write(wct_start, packets).is_err(),
+            None => false,
+        };
+        if failed {
+            println!("Failed to write binary latency samples, no more will be written");
+            self.binary_samples = None;
+        }
+    }
+
+    /// Writes each request's sample to `samples` from now on, as well.
+    pub fn write_binary_samples(&mut self, samples: BinarySampleWriter) {
+        self.binary_samples = Some(samples);
+    }
+
+    /// Logs the requests of each run from now on to `log`.
+    pub fn write_request_log(&mut self, log: RequestLogWriter) {
+        self.request_log = Some(log);
+    }
+
+    /// Tracks the gaps between responses on each connection from now on,
+    /// keeping the `top` longest.
+    pub fn track_response_gaps(&mut self, top: usize) {
+        self.response_gaps = Some(ResponseGaps::new(top));
+    }
+
+    /// Adds the gaps between the responses one thread of a run received, if
+    /// they are tracked, numbering its connections from `first_conn`.
+    pub fn record_response_gaps(
+        &mut self,
+        first_conn: usize,
+        wct_start: SystemTime,
+        packets: &[Packet],
+    ) {
+        if let Some(ref mut gaps) = self.response_gaps {
+            gaps.add(first_conn, wct_start, packets);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn response_gaps(&self) -> Option<&ResponseGaps> {
+        self.response_gaps.as_ref()
+    }
+
+    /// Logs the requests one thread of a run sent, if they are logged, over
+    /// connections counted from `first_conn`.
+    pub fn record_requests(
+        &mut self,
+        thread: usize,
+        first_conn: usize,
+        wct_start: SystemTime,
+        packets: &[Packet],
+    ) {
+        let failed = match self.request_log {
+            Some(ref mut log) => log.write(thread, first_conn, wct_start, packets).is_err(),
+            None => false,
+        };
+        if failed {
+            println!("Failed to write the request log, no more will be written");
+            self.request_log = None;
+        }
+    }
+
+    /// Checks that the samples of a run of `requests` would fit in the binary
+    /// dump, if one is written.
+    pub fn reserve_samples(&self, requests: usize) -> Result<(), String> {
+        match self.binary_samples {
+            Some(ref samples) => samples.reserve(requests),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the latency histogram of each schedule recorded from now on
+    /// to `histograms`.
+    pub fn write_histograms(&mut self, histograms: HistogramWriter) {
+        self.histograms = Some(histograms);
+    }
+
+    /// Writes the rows of each run's intervals from now on to `rows`.
+    pub fn write_intervals(&mut self, rows: IntervalWriter) {
+        self.interval_rows = Some(rows);
+    }
+
+    /// Hands over where interval rows are written, if they are, for a run to
+    /// write its rows to as it goes, and give back with `write_intervals`.
+    pub fn take_interval_rows(&mut self) -> Option<IntervalWriter> {
+        self.interval_rows.take()
+    }
+
+    /// Records the latency histogram of the schedule recorded last.
+    pub fn record_histogram(&mut self, histogram: &Histogram) {
+        let failed = match (&mut self.histograms, self.results.last()) {
+            (&mut Some(ref mut histograms), Some(result)) => {
+                histograms.write(result, histogram).is_err()
+            }
+            _ => false,
+        };
+        if failed {
+            println!("Failed to write latency histograms, no more will be written");
+            self.histograms = None;
+        }
+    }
+
+    pub fn metadata(&self) -> &RunMetadata {
+        &self.metadata
+    }
+
+    /// The servers requests are sent to.
+    pub fn targets(&self) -> &[String] {
+        &self.metadata.targets
+    }
+
+    /// The percentiles each result is summarized by.
+    pub fn percentiles(&self) -> &[f32] {
+        &self.percentiles
+    }
+
+    /// Adds a run's completions to the throughput series, if one is kept.
+    pub fn record_throughput(&mut self, wct_start: SystemTime, packets: &[Packet]) {
+        if let Some(ref mut throughput) = self.throughput {
+            throughput.add(wct_start, packets);
+        }
+    }
+
+    /// Adds how long a run's connections took to open, if that is measured.
+    pub fn record_connects(&mut self, times: &[Duration]) {
+        if let Some(ref mut connects) = self.connects {
+            connects.add(times);
+        }
+    }
+
+    pub fn record_tls_handshakes(&mut self, times: &[Duration]) {
+        self.tls_handshakes.add(times);
+    }
+
+    /// TLS handshake latencies at each reported percentile, None unless
+    /// there were any.
+    pub fn tls_handshake_summary(&self) -> Option<ConnectSummary> {
+        self.tls_handshakes.summary(&self.percentiles)
+    }
+
+    /// Connect latencies at each reported percentile, None unless connections
+    /// were measured.
+    pub fn connect_summary(&self) -> Option<ConnectSummary> {
+        self.connects
+            .as_ref()
+            .and_then(|c| c.summary(&self.percentiles))
+    }
+
+    pub fn is_text(&self) -> bool {
+        match self.format {
+            OutputFormat::Text => true,
+            OutputFormat::Json => false,
+        }
+    }
+
+    pub fn record(&mut self, mut result: ScheduleResult) {
+        if let (Some(connections), Some(_)) = (self.metadata.connections, &result.latencies) {
+            result.per_connection = Some(result.actual / connections);
+        }
+        if self.metadata.session_length.is_none() {
+            result.connect_rate = None;
+        }
+        let total: u64 = result.servers.iter().map(|s| s.actual).sum();
+        if total > 0 {
+            let weights = match self.metadata.weights {
+                Some(ref weights) => weights.clone(),
+                None => vec![1; result.servers.len()],
+            };
+            let total_weight: u32 = weights.iter().sum();
+            result.imbalance = result
+                .servers
+                .iter()
+                .zip(weights)
+                .map(|(s, w)| (s.actual as f64 / total as f64) / (w as f64 / total_weight as f64))
+                .fold(None, |max: Option<f64>, x| {
+                    Some(max.map_or(x, |m| m.max(x)))
+                });
+        }
+        if self.is_text() {
+            if result.warmup > 0 {
+                println!("Warmup: {}", result.warmup);
+            }
+            if result.timed_out > 0 {
+                println!("Timed out: {}", result.timed_out);
+            }
+            if result.late > 0 {
+                println!("Late responses: {}", result.late);
+            }
+            if result.retransmits > 0 {
+                println!("Retransmits: {}", result.retransmits);
+            }
+            if result.duplicates > 0 {
+                println!("Duplicate responses: {}", result.duplicates);
+            }
+            if let Some(ratio) = result.unanswered_ratio {
+                println!(
+                    "Unanswered: {} ({:.2}% of sent)",
+                    result.unanswered,
+                    ratio * 100.0
+                );
+            }
+            if result.failed > 0 {
+                println!("Failed in flight: {}", result.failed);
+            }
+            if result.rejected > 0 {
+                println!("Rejected: {}", result.rejected);
+            }
+            match result.latencies {
+                // Unread responses leave a dash in each percentile column.
+                None if result.sent.is_some() => {
+                    print_field("Sent", &result.sent);
+                    println!(
+                        "{}, {}, {}, {}, {}, {}, {}",
+                        result.distribution,
+                        result.target,
+                        result.actual,
+                        result.dropped,
+                        result.never_sent,
+                        vec!["-"; self.percentiles.len() + 4].join(", "),
+                        result.start
+                    );
+                }
+                None => println!(
+                    "{}, {}, 0, {}, {}, {}",
+                    result.distribution,
+                    result.target,
+                    result.dropped,
+                    result.never_sent,
+                    result.start
+                ),
+                Some(ref l) => {
+                    if result.window_dropped > 0 {
+                        println!("Window dropped: {}", result.window_dropped);
+                    }
+                    match result.miss_ratio {
+                        Some(ratio) if ratio > 0.0 => println!("Miss ratio: {:.4}", ratio),
+                        _ => {}
+                    }
+                    if let Some(ratio) = result.cas_success_ratio {
+                        println!("CAS success ratio: {:.4}", ratio);
+                    }
+                    print_field("Per-connection rate", &result.per_connection);
+                    print_field("Connect rate", &result.connect_rate);
+                    for server in &result.servers {
+                        print!(
+                            "Server {}: {} sent, {} actual",
+                            server.target, server.sent, server.actual
+                        );
+                        if let Some(ratio) = server.miss_ratio {
+                            print!(", miss ratio {:.4}", ratio);
+                        }
+                        if let Some(ref l) = server.latencies {
+                            for &(p, value) in &l.0 {
+                                print!(", {} {:.1}", percentile_column(p), value);
+                            }
+                        }
+                        println!();
+                    }
+                    for size in &result.sizes {
+                        print!(
+                            "Size {}: {} sent, {} completed",
+                            size.class, size.sent, size.completed
+                        );
+                        if let Some(ref l) = size.latencies {
+                            for &(p, value) in &l.0 {
+                                print!(", {} {:.1}", percentile_column(p), value);
+                            }
+                        }
+                        println!();
+                    }
+                    // Operations are only broken out when there are several.
+                    for op in result.ops.iter().filter(|_| result.ops.len() > 1) {
+                        println!(
+                            "Op {}: {} sent, {} completed, {:.3} Gbit/s out, \
+                             {:.3} Gbit/s in, {}",
+                            op.op,
+                            op.sent,
+                            op.completed,
+                            op.out_gbps,
+                            op.in_gbps,
+                            op.latencies.to_text()
+                        );
+                    }
+                    if let Some(imbalance) = result.imbalance {
+                        println!("Imbalance: {:.3}", imbalance);
+                    }
+                    if let Some(ref q) = result.queueing {
+                        println!("Queueing (us): {}", q.to_text());
+                    }
+                    if let Some(ref s) = result.service {
+                        println!("Service (us): {}", s.to_text());
+                    }
+                    if let Some(ref r) = result.response {
+                        println!("Response (us): {}", r.to_text());
+                        print_field("Max send lag (us)", &result.send_lag_max);
+                    }
+                    if result.latency_overflows + result.latency_underflows > 0 {
+                        println!(
+                            "Latencies outside the histogram: {} above its max, {} below its \
+                             min, counted at the bound",
+                            result.latency_overflows, result.latency_underflows
+                        );
+                    }
+                    let value = |v: Option<f32>| v.map_or("-".to_string(), |v| format!("{:.1}", v));
+                    println!(
+                        "{}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}",
+                        result.distribution,
+                        result.target,
+                        result.actual,
+                        result.dropped,
+                        result.never_sent,
+                        value(result.latency_min),
+                        value(result.latency_mean),
+                        l.0.iter()
+                            .map(|&(_, v)| format!("{:.1}", v))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        value(result.latency_max),
+                        result.samples,
+                        result.start
+                    );
+                }
+            }
+        }
+        let failed = match self.sweep {
+            Some(ref mut sweep) => sweep.write(&result).is_err(),
+            None => false,
+        };
+        if failed {
+            println!("Failed to write the sweep table, no more rows will be written");
+            self.sweep = None;
+        }
+        self.results.push(result);
+    }
+
+    #[cfg(test)]
+    pub fn connection_requests(&self) -> &[usize] {
+        &self.connection_requests
+    }
+
+    pub fn last_result(&self) -> Option<&ScheduleResult> {
+        self.results.last()
+    }
+
+    /// The given percentile of the most recently recorded result, if it
+    /// measured one.
+    pub fn last_percentile(&self, percentile: f32) -> Option<f32> {
+        self.last_result()
+            .and_then(|r| r.latencies.as_ref())
+            .and_then(|l| l.get(percentile))
+    }
+
+    /// Records the outcome of an SLO search, None if no rate held the SLO.
+    pub fn record_converged_rate(&mut self, rate: Option<u64>) {
+        if self.is_text() {
+            match rate {
+                Some(rate) => println!("Converged rate: {}", rate),
+                None => println!("Converged rate: none"),
+            }
+        }
+        self.converged_rate = Some(rate);
+    }
+
+    /// Records how many datagrams were malformed over the whole run.
+    pub fn record_malformed(&mut self, count: usize) {
+        if self.is_text() && count > 0 {
+            println!("Malformed datagrams: {}", count);
+        }
+        self.malformed = count;
+    }
+
+    /// Records how many value sizes were clamped over the whole run.
+    pub fn record_clamped_values(&mut self, count: usize) {
+        if self.is_text() && count > 0 {
+            println!("Clamped value sizes: {}", count);
+        }
+        self.clamped_values = count;
+    }
+
+    /// Records how many quiet writes failed over the whole run.
+    pub fn record_quiet_failures(&mut self, count: usize) {
+        if self.is_text() && count > 0 {
+            println!("Failed quiet writes: {}", count);
+        }
+        self.quiet_failures = count;
+    }
+
+    /// Records how many GET hits did not carry the data type SETs were sent
+    /// with over the whole run.
+    pub fn record_data_type_mismatches(&mut self, count: usize) {
+        if self.is_text() && count > 0 {
+            println!("Data type mismatches: {}", count);
+        }
+        self.data_type_mismatches = count;
+    }
+
+    /// Records what the generator threads did over the whole run, if any
+    /// ran.
+    pub fn record_generators(&mut self, stats: Option<GeneratorStats>) {
+        let stats = match stats {
+            Some(stats) => stats,
+            None => return,
+        };
+        let summary = GeneratorSummary {
+            requests: stats.requests,
+            mean_queue_depth: stats.mean_depth,
+            underruns: stats.underruns,
+            mean_lead_us: stats.mean_lead.as_nanos() as f64 / 1000.0,
+        };
+        if self.is_text() {
+            println!(
+                "Generators: {} requests, mean queue depth {:.1}, {} underruns, mean lead {:.1} us",
+                summary.requests, summary.mean_queue_depth, summary.underruns, summary.mean_lead_us
+            );
+        }
+        self.generators = Some(summary);
+    }
+
+    /// Records how long the client spent building requests, writing them and
+    /// reading or waiting for responses, if it timed them, as shares of the
+    /// wall time of the three.
+    pub fn record_profile(&mut self, phases: Option<Vec<PhaseTime>>) {
+        let phases = match phases {
+            Some(phases) => phases,
+            None => return,
+        };
+        let total = phases.iter().map(|p| p.total).sum::<Duration>().as_nanos();
+        let summary: Vec<PhaseSummary> = phases
+            .iter()
+            .map(|p| PhaseSummary {
+                phase: p.phase.name(),
+                calls: p.calls,
+                total_ms: p.total.as_nanos() as f64 / 1e6,
+                share: match total {
+                    0 => 0.0,
+                    t => p.total.as_nanos() as f64 / t as f64,
+                },
+            })
+            .collect();
+        if self.is_text() {
+            println!(
+                "Self profile, as shares of wall time: {}",
+                summary
+                    .iter()
+                    .map(|p| format!(
+                        "{} {:.1}% ({} calls, {:.1} ms)",
+                        p.phase,
+                        p.share * 100.0,
+                        p.calls,
+                        p.total_ms
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        self.self_profile = Some(summary);
+    }
+
+    /// Records whether UDP sends were segmented and receives coalesced over
+    /// the whole run, for runs that asked, with the longest a request waited
+    /// for its segmented send.
+    pub fn record_udp_offload(
+        &mut self,
+        gso: Option<Offload>,
+        gro: Option<Offload>,
+        gso_delay: Duration,
+    ) {
+        self.udp_gso = gso.map(|s| OffloadSummary::new(s, Some(gso_delay)));
+        self.udp_gro = gro.map(|s| OffloadSummary::new(s, None));
+        if !self.is_text() {
+            return;
+        }
+        if let Some(ref s) = self.udp_gso {
+            match s.active {
+                0 => println!("UDP GSO: inactive, unsupported by the kernel"),
+                n => println!(
+                    "UDP GSO: active on {} of {} connections, {} sends of {:.1} datagrams on \
+                     average; requests are stamped as they are batched, so they went out up \
+                     to {:.1} us after",
+                    n,
+                    s.connections,
+                    s.batches,
+                    s.mean_datagrams,
+                    s.max_delay_us.unwrap()
+                ),
+            }
+        }
+        if let Some(ref s) = self.udp_gro {
+            match s.active {
+                0 => println!("UDP GRO: inactive, unsupported by the kernel"),
+                n => println!(
+                    "UDP GRO: active on {} of {} connections, {} coalesced receives of {:.1} \
+                     datagrams on average",
+                    n, s.connections, s.batches, s.mean_datagrams
+                ),
+            }
+        }
+    }
+
+    /// Records how datagrams were batched into sends and receives over the
+    /// whole run, for runs that batched any.
+    pub fn record_udp_batches(&mut self, (sent, received): (Batches, Batches)) {
+        if sent.calls == 0 && received.calls == 0 {
+            return;
+        }
+        let mean = |b: Batches| match b.calls {
+            0 => 0.0,
+            n => b.datagrams as f64 / n as f64,
+        };
+        let summary = BatchSummary {
+            sends: sent.calls,
+            mean_sent: mean(sent),
+            receives: received.calls,
+            mean_received: mean(received),
+        };
+        if self.is_text() {
+            println!(
+                "UDP batches: {} sends of {:.1} datagrams on average, {} receives of {:.1}",
+                summary.sends, summary.mean_sent, summary.receives, summary.mean_received
+            );
+        }
+        self.udp_batches = Some(summary);
+    }
+
+    /// Records how many response datagrams were received in each size
+    /// bucket over the whole run, as its smallest and largest size and the
+    /// count, so that truncated or oversized responses stand out.
+    pub fn record_datagram_sizes(&mut self, sizes: Vec<(usize, usize, usize)>) {
+        if self.is_text() && !sizes.is_empty() {
+            let buckets: Vec<String> = sizes
+                .iter()
+                .map(|&(min, max, count)| format!("{}-{}: {}", min, max, count))
+                .collect();
+            println!("Response datagram sizes (bytes): {}", buckets.join(", "));
+        }
+        self.datagram_sizes = sizes
+            .into_iter()
+            .map(|(min, max, count)| DatagramSizes { min, max, count })
+            .collect();
+    }
+
+    /// Records the address family each connection of a sample connected
+    /// over. Every sample opens its connections the same way.
+    pub fn record_address_families(&mut self, families: Vec<String>) {
+        if let (OutputFormat::Text, None) = (self.format, &self.metadata.address_families) {
+            println!("Address families: {}", families.join(" "));
+        }
+        self.metadata.address_families = Some(families);
+    }
+
+    /// Records the local address each connection of a sample was bound to.
+    /// Every sample binds its connections the same way.
+    pub fn record_source_addresses(&mut self, addrs: Vec<String>) {
+        if let (OutputFormat::Text, None) = (self.format, &self.metadata.source_addresses) {
+            println!("Source addresses: {}", addrs.join(" "));
+        }
+        self.metadata.source_addresses = Some(addrs);
+    }
+
+    /// Adds the requests sent over each connection of a sample to the totals.
+    pub fn record_connection_requests(&mut self, counts: &[usize]) {
+        if self.connection_requests.len() < counts.len() {
+            self.connection_requests.resize(counts.len(), 0);
+        }
+        for (total, n) in self.connection_requests.iter_mut().zip(counts) {
+            *total += n;
+        }
+    }
+
+    /// Adds the connections a sample opened in place of failed ones.
+    pub fn record_reconnects(&mut self, count: usize) {
+        self.reconnects += count;
+    }
+
+    #[cfg(test)]
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    /// Adds a run's timed-out requests to the per-second series, each in the
+    /// second it went `timeout` unanswered, after its last transmission.
+    pub fn record_timeouts(
+        &mut self,
+        wct_start: SystemTime,
+        packets: &[Packet],
+        timeout: Duration,
+    ) {
+        self.timeouts
+            .get_or_insert_with(|| ThroughputSeries::new(Duration::from_secs(1), None))
+            .count(
+                wct_start,
+                packets.iter().filter(|p| p.timed_out).filter_map(|p| {
+                    p.actual_start
+                        .map(|t| t + timeout * (p.retransmits as u32 + 1))
+                }),
+            );
+    }
+
+    pub fn timeouts_per_second(&self) -> &[u64] {
+        self.timeouts.as_ref().map_or(&[], |t| &t.completed[..])
+    }
+
+    /// Adds a run's requests that were sent and never answered to the
+    /// per-second series, each in the second it was sent.
+    pub fn record_unanswered(&mut self, wct_start: SystemTime, packets: &[Packet]) {
+        self.unanswered
+            .get_or_insert_with(|| ThroughputSeries::new(Duration::from_secs(1), None))
+            .count(
+                wct_start,
+                packets
+                    .iter()
+                    .filter(|p| p.completion_time.is_none() && !p.failed && !p.late)
+                    .filter_map(|p| p.actual_start),
+            );
+    }
+
+    /// Empty if every request was answered.
+    pub fn unanswered_per_second(&self) -> &[u64] {
+        nonzero(&self.unanswered)
+    }
+
+    /// Adds a run's duplicate responses to the per-second series, in the
+    /// second their request was sent.
+    pub fn record_duplicates(&mut self, wct_start: SystemTime, packets: &[Packet]) {
+        self.duplicates
+            .get_or_insert_with(|| ThroughputSeries::new(Duration::from_secs(1), None))
+            .count(
+                wct_start,
+                packets.iter().flat_map(|p| {
+                    p.actual_start
+                        .into_iter()
+                        .flat_map(move |t| iter::repeat_n(t, p.duplicates))
+                }),
+            );
+    }
+
+    /// Empty if no response came twice.
+    pub fn duplicates_per_second(&self) -> &[u64] {
+        nonzero(&self.duplicates)
+    }
+
+    /// Adds the intervals of a run that started at `wct_start`, each as when
+    /// it ended since then, the requests sent and completed over it, and the
+    /// completions per second.
+    pub fn record_intervals(&mut self, wct_start: SystemTime, intervals: &[Interval]) {
+        let start = wct_start.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.intervals
+            .extend(intervals.iter().map(|interval| IntervalCounts {
+                start,
+                end_s: duration_to_ns(interval.end) as f64 / 1e9,
+                sent: interval.sent,
+                completed: interval.completed,
+                rps: interval.rps,
+                bytes_out: interval.bytes_out,
+                bytes_in: interval.bytes_in,
+                headers_out: interval.headers_out,
+                headers_in: interval.headers_in,
+                out_gbps: interval.out_gbps,
+                in_gbps: interval.in_gbps,
+            }));
+    }
+
+    /// Adds the responses a sample got that answered no request sent: from
+    /// elsewhere, or with a corrupted opaque.
+    pub fn record_spurious(&mut self, count: usize) {
+        self.spurious += count;
+    }
+
+    #[cfg(test)]
+    pub fn spurious(&self) -> usize {
+        self.spurious
+    }
+
+    /// Adds the bytes a run of `run_time` wrote and read, and the headers
+    /// estimated to have carried them.
+    pub fn record_bytes(&mut self, bytes: (u64, u64), headers: (u64, u64), run_time: Duration) {
+        self.bytes_out += bytes.0;
+        self.bytes_in += bytes.1;
+        self.headers_out += headers.0;
+        self.headers_in += headers.1;
+        self.run_time += run_time;
+    }
+
+    /// None before any run.
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        if self.run_time == Duration::from_nanos(0) {
+            return None;
+        }
+        let seconds = self.run_time.as_secs_f64();
+        Some(Bandwidth {
+            bytes_out: self.bytes_out,
+            bytes_in: self.bytes_in,
+            headers_out: self.headers_out,
+            headers_in: self.headers_in,
+            seconds,
+            out_mbps: self.bytes_out as f64 / seconds / 1e6,
+            in_mbps: self.bytes_in as f64 / seconds / 1e6,
+            out_gbps: gbps(self.bytes_out + self.headers_out, self.run_time),
+            in_gbps: gbps(self.bytes_in + self.headers_in, self.run_time),
+        })
+    }
+
+    pub fn finish(self) {
+        if let Some(ref throughput) = self.throughput {
+            if let Some(ref csv) = throughput.csv {
+                if let Err(e) = throughput.write_csv(csv) {
+                    println!("Failed to write the throughput series: {}", e);
+                }
+            }
+        }
+        let connect = self.connect_summary();
+        if let (OutputFormat::Text, Some(ref connect)) = (self.format, &connect) {
+            println!(
+                "Connect latency: {} connections, {}",
+                connect.connections,
+                connect.latencies.to_text()
+            );
+        }
+        let tls_handshake = self.tls_handshake_summary();
+        if let (OutputFormat::Text, Some(ref handshake)) = (self.format, &tls_handshake) {
+            println!(
+                "TLS handshake latency: {} handshakes, {}",
+                handshake.connections,
+                handshake.latencies.to_text()
+            );
+        }
+        if let (OutputFormat::Text, false) = (self.format, self.connection_requests.is_empty()) {
+            println!(
+                "Requests per connection: {}",
+                self.connection_requests
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        if let (OutputFormat::Text, true) = (self.format, self.reconnects > 0) {
+            println!("Reconnects: {}", self.reconnects);
+        }
+        if let (OutputFormat::Text, false) = (self.format, self.timeouts_per_second().is_empty()) {
+            println!(
+                "Timeouts per second: {}",
+                self.timeouts_per_second()
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        if let (OutputFormat::Text, false) = (self.format, self.unanswered_per_second().is_empty())
+        {
+            println!(
+                "Unanswered per second: {}",
+                self.unanswered_per_second()
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        if let (OutputFormat::Text, false) = (self.format, self.duplicates_per_second().is_empty())
+        {
+            println!(
+                "Duplicates per second: {}",
+                self.duplicates_per_second()
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        if let (OutputFormat::Text, true) = (self.format, self.spurious > 0) {
+            println!("Spurious responses: {}", self.spurious);
+        }
+        if let (OutputFormat::Text, Some(ref gaps)) = (self.format, &self.response_gaps) {
+            println!(
+                "Longest response gap per connection (ms): {}",
+                gaps.max_by_connection
+                    .iter()
+                    .map(|ms| format!("{:.3}", ms))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+            for gap in &gaps.longest {
+                println!(
+                    "Response gap: {:.3} ms on connection {} from {:.6}",
+                    gap.ms, gap.connection, gap.start
+                );
+            }
+        }
+        let bandwidth = self.bandwidth();
+        if let (OutputFormat::Text, Some(ref b)) = (self.format, &bandwidth) {
+            println!(
+                "Bandwidth: {:.3} MB/s out ({} bytes), {:.3} MB/s in ({} bytes)",
+                b.out_mbps, b.bytes_out, b.in_mbps, b.bytes_in
+            );
+            println!(
+                "On the wire: {:.3} Gbit/s out ({} header bytes), {:.3} Gbit/s in \
+                 ({} header bytes)",
+                b.out_gbps, b.headers_out, b.in_gbps, b.headers_in
+            );
+        }
+        if let OutputFormat::Json = self.format {
+            let report = JsonReport {
+                format_version: FORMAT_VERSION,
+                metadata: &self.metadata,
+                results: &self.results,
+                throughput: self.throughput.as_ref().map(|t| t.to_json()),
+                connect,
+                tls_handshake,
+                malformed: self.malformed,
+                clamped_values: self.clamped_values,
+                quiet_failures: self.quiet_failures,
+                data_type_mismatches: self.data_type_mismatches,
+                datagram_sizes: &self.datagram_sizes,
+                generators: self.generators.as_ref(),
+                self_profile: self.self_profile.as_ref().map(|p| &p[..]),
+                udp_gso: self.udp_gso.as_ref(),
+                udp_gro: self.udp_gro.as_ref(),
+                udp_batches: self.udp_batches.as_ref(),
+                connection_requests: &self.connection_requests,
+                reconnects: self.reconnects,
+                timeouts_per_second: self.timeouts_per_second(),
+                unanswered_per_second: self.unanswered_per_second(),
+                duplicates_per_second: self.duplicates_per_second(),
+                spurious_responses: self.spurious,
+                intervals: &self.intervals,
+                response_gaps: self.response_gaps.as_ref(),
+                bandwidth,
+                converged_rate: self.converged_rate,
+            };
+            println!("{}", serde_json::to_string(&report).unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_round_trip() {
+        let metadata = RunMetadata {
+            protocol: "memcached".to_string(),
+            workload: Some("usr".to_string()),
+            transport: "tcp".to_string(),
+            targets: vec!["10.0.0.1:11211".to_string()],
+            address_families: Some(vec!["ipv4".to_string()]),
+            source_ips: Some(vec!["10.0.0.2".to_string(), "10.0.0.3".to_string()]),
+            source_ports: Some("20000-20999".to_string()),
+            source_addresses: Some(vec!["10.0.0.2:20000".to_string()]),
+            cpu_list: Some("0,1,2,3,8".to_string()),
+            weights: None,
+            routing: None,
+            offered_rate: 100000,
+            arrival: Some("exponential".to_string()),
+            service: Some("exponential:10000".to_string()),
+            duration_ns: Some(5000000000),
+            count: None,
+            outstanding: None,
+            connections: None,
+            conns_per_thread: None,
+            session_length: None,
+            no_read: false,
+            latency_breakdown: true,
+            histogram_digits: Some(4),
+            histogram_min: None,
+            histogram_max: Some(10000.0),
+            trace: None,
+            replay_speed: Some(2.5),
+            hot_key: None,
+            hot_key_fraction: None,
+            key_distribution: Some("zipf:1000,0.99".to_string()),
+            op_mix: Some("get:70,set:20,delete:10".to_string()),
+            cas_writes: None,
+            request_timeout_ms: None,
+            max_retransmits: None,
+            watchdog_ms: None,
+            send_batch: Some(8),
+            udp_batch: Some(16),
+            generators: Some(2),
+            generator_lead_us: Some(100),
+            read_buffer: Some(8192),
+            io_uring: true,
+            epoll: true,
+            tcp_nodelay: Some(true),
+            send_buffer: Some(65536),
+            recv_buffer: None,
+            tos: Some(184),
+            tls_server_name: Some("localhost".to_string()),
+            tls_resumption: Some(true),
+            tls_client_cert: None,
+            size_classes: None,
+            think_time: Some("exponential mean=1000".to_string()),
+            seed: Some(42),
+            key_size: Some("20".to_string()),
+            value_size: Some("2".to_string()),
+        };
+        let json = serde_json::to_string(&metadata).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        for field in &[
+            "protocol",
+            "workload",
+            "transport",
+            "targets",
+            "offered_rate",
+            "arrival",
+            "service",
+            "duration_ns",
+            "seed",
+            "key_size",
+            "value_size",
+        ] {
+            assert!(!value[field].is_null(), "missing {}", field);
+        }
+        assert!(value["count"].is_null());
+        assert_eq!(value["think_time"], "exponential mean=1000");
+        assert_eq!(
+            serde_json::from_str::<RunMetadata>(&json).unwrap(),
+            metadata
+        );
+
+        let results = vec![ScheduleResult {
+            distribution: "zero".to_string(),
+            target: 100000,
+            actual: 99000,
+            dropped: 1,
+            timed_out: 0,
+            late: 0,
+            retransmits: 0,
+            duplicates: 0,
+            unanswered: 0,
+            unanswered_ratio: None,
+            failed: 0,
+            rejected: 0,
+            never_sent: 0,
+            warmup: 0,
+            window_dropped: 0,
+            miss_ratio: None,
+            cas_success_ratio: None,
+            per_connection: None,
+            connect_rate: None,
+            servers: Vec::new(),
+            sizes: Vec::new(),
+            ops: Vec::new(),
+            imbalance: None,
+            sent: None,
+            latencies: None,
+            samples: 0,
+            latency_min: None,
+            latency_mean: None,
+            latency_max: None,
+            latency_overflows: 0,
+            latency_underflows: 0,
+            send_lag: None,
+            send_lag_max: None,
+            queueing: None,
+            service: None,
+            response: None,
+            start: 0,
+        }];
+        let report = serde_json::to_value(&JsonReport {
+            format_version: FORMAT_VERSION,
+            metadata: &metadata,
+            results: &results,
+            throughput: None,
+            connect: None,
+            tls_handshake: None,
+            converged_rate: None,
+            malformed: 0,
+            clamped_values: 0,
+            quiet_failures: 0,
+            data_type_mismatches: 0,
+            datagram_sizes: &[],
+            generators: None,
+            self_profile: None,
+            udp_gso: None,
+            udp_gro: None,
+            udp_batches: None,
+            connection_requests: &[],
+            reconnects: 0,
+            timeouts_per_second: &[],
+            unanswered_per_second: &[],
+            duplicates_per_second: &[],
+            spurious_responses: 0,
+            intervals: &[],
+            response_gaps: None,
+            bandwidth: None,
+        })
+        .unwrap();
+        assert_eq!(report["format_version"], FORMAT_VERSION);
+        assert_eq!(report["metadata"]["seed"], 42);
+        assert_eq!(report["results"][0]["actual"], 99000);
+        assert!(report.get("converged_rate").is_none());
+        assert!(report.get("throughput").is_none());
+        assert!(report.get("connect").is_none());
+        assert!(report.get("tls_handshake").is_none());
+        assert!(report.get("connection_requests").is_none());
+        assert!(report.get("intervals").is_none());
+        assert!(report["results"][0].get("ops").is_none());
+    }
+
+    #[test]
+    fn requested_percentiles() {
+        let percentiles = parse_percentiles("99.9,50, 75").unwrap();
+        assert_eq!(percentiles, vec![99.9, 50.0, 75.0]);
+        assert_eq!(
+            text_header(&percentiles),
+            "Distribution, Target, Actual, Dropped, Never Sent, Min, Mean, 99.9th, Median, 75th, \
+             Max, Samples, Start"
+        );
+        assert_eq!(
+            text_header(&reported_percentiles(&[])),
+            "Distribution, Target, Actual, Dropped, Never Sent, Min, Mean, Median, 90th, 99th, \
+             99.9th, 99.99th, Max, Samples, Start"
+        );
+        // Extra percentiles join the standard ones in order, each once.
+        assert_eq!(
+            reported_percentiles(&parse_percentiles("25,99.95,99").unwrap()),
+            vec![25.0, 50.0, 90.0, 99.0, 99.9, 99.95, 99.99]
+        );
+
+        let latencies = Percentiles(percentiles.iter().map(|&p| (p, p * 2.0)).collect());
+        let json = serde_json::to_string(&latencies).unwrap();
+        assert_eq!(json, r#"{"p999":199.8,"p50":100.0,"p75":150.0}"#);
+        assert_eq!(latencies.get(75.0), Some(150.0));
+        assert_eq!(latencies.get(99.0), None);
+
+        for bad in &["0", "-1", "100.1", "nan", "fifty", "50,,90", "99.9,9.99"] {
+            assert!(parse_percentiles(bad).is_err(), "accepted {}", bad);
+        }
+        assert!(parse_percentiles("100").is_ok());
+    }
+
+    #[test]
+    fn throughput_per_bucket() {
+        use std::time::Duration;
+
+        // 10k completions per second for a second, then half that rate for
+        // another, in a run that starts 250ms after the first.
+        let completions = |from_ms: u64, gap_us: u64| -> Vec<Packet> {
+            (0..1_000_000 / gap_us)
+                .map(|i| Packet {
+                    completion_time: Some(
+                        Duration::from_millis(from_ms) + Duration::from_micros(i * gap_us),
+                    ),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let mut series = ThroughputSeries::new(Duration::from_millis(100), None);
+        series.add(UNIX_EPOCH, &completions(0, 100));
+        series.add(
+            UNIX_EPOCH + Duration::from_millis(250),
+            &completions(750, 200),
+        );
+
+        let rates = series.rates();
+        assert_eq!(rates.len(), 20);
+        assert!(rates[..10].iter().all(|&r| r == 10_000.0), "{:?}", rates);
+        assert!(rates[10..].iter().all(|&r| r == 5_000.0), "{:?}", rates);
+
+        let mut csv = Vec::new();
+        series.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 21);
+        assert_eq!(lines[0], "timestamp_ns,completed,rps");
+        assert_eq!(lines[1], "0,1000,10000");
+        assert_eq!(lines[20], "1900000000,500,5000");
+
+        let json = serde_json::to_value(series.to_json()).unwrap();
+        assert_eq!(json["bucket_ns"], 100_000_000);
+        assert_eq!(json["rps"].as_array().unwrap().len(), 20);
+    }
+
+    #[test]
+    fn sweep_rows_per_point() {
+        use std::fs;
+
+        let path = std::env::temp_dir().join(format!("sweep-{}.csv", std::process::id()));
+        let mut sweep = SweepWriter::create(path.to_str().unwrap(), &[50.0, 99.9], 0.9).unwrap();
+        let point = |target, actual, latencies: Option<Percentiles>| ScheduleResult {
+            distribution: "zero".to_string(),
+            target,
+            actual,
+            dropped: 0,
+            timed_out: 0,
+            late: 0,
+            retransmits: 0,
+            duplicates: 0,
+            unanswered: 0,
+            unanswered_ratio: None,
+            failed: 0,
+            rejected: 0,
+            never_sent: 0,
+            warmup: 0,
+            window_dropped: 0,
+            miss_ratio: if actual > 0 { Some(0.25) } else { None },
+            cas_success_ratio: None,
+            per_connection: None,
+            connect_rate: None,
+            servers: Vec::new(),
+            sizes: Vec::new(),
+            ops: Vec::new(),
+            imbalance: None,
+            sent: None,
+            latencies,
+            samples: 0,
+            latency_min: None,
+            latency_mean: None,
+            latency_max: None,
+            latency_overflows: 0,
+            latency_underflows: 0,
+            send_lag: None,
+            send_lag_max: None,
+            queueing: None,
+            service: None,
+            response: None,
+            start: 0,
+        };
+        let latencies = || Some(Percentiles(vec![(50.0, 10.0), (99.9, 42.34)]));
+        sweep.write(&point(1000, 990, latencies())).unwrap();
+        sweep.write(&point(2000, 0, None)).unwrap();
+        sweep.write(&point(3000, 2000, latencies())).unwrap();
+
+        // Rows are on disk before the writer is dropped.
+        let text = fs::read_to_string(&path).unwrap();
+        drop(sweep);
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "offered,achieved,dropped,never_sent,hit_rate,p50,p999,status",
+                "1000,990,0,0,0.7500,10.0,42.3,ok",
+                "2000,0,0,0,,,,failed",
+                "3000,2000,0,0,0.7500,10.0,42.3,saturated",
+            ]
+        );
+    }
+
+    #[test]
+    fn per_connection_rate_of_closed_loop() {
+        let result = |actual, latencies| ScheduleResult {
+            distribution: "zero".to_string(),
+            target: 1000,
+            actual,
+            dropped: 0,
+            timed_out: 0,
+            late: 0,
+            retransmits: 0,
+            duplicates: 0,
+            unanswered: 0,
+            unanswered_ratio: None,
+            failed: 0,
+            rejected: 0,
+            never_sent: 0,
+            warmup: 0,
+            window_dropped: 0,
+            miss_ratio: None,
+            cas_success_ratio: None,
+            per_connection: None,
+            connect_rate: None,
+            servers: Vec::new(),
+            sizes: Vec::new(),
+            ops: Vec::new(),
+            imbalance: None,
+            sent: None,
+            latencies,
+            samples: 0,
+            latency_min: None,
+            latency_mean: None,
+            latency_max: None,
+            latency_overflows: 0,
+            latency_underflows: 0,
+            send_lag: None,
+            send_lag_max: None,
+            queueing: None,
+            service: None,
+            response: None,
+            start: 0,
+        };
+        let report = |connections| {
+            Report::new(
+                OutputFormat::Json,
+                RunMetadata {
+                    connections,
+                    ..Default::default()
+                },
+                None,
+                None,
+                None,
+                None,
+                vec![50.0],
+            )
+        };
+
+        // Four connections, each one request in flight with a 3ms think time
+        // and 1ms latency.
+        let mut closed = report(Some(4));
+        closed.record(result(1000, Some(Percentiles(vec![(50.0, 1000.0)]))));
+        assert_eq!(closed.last_result().unwrap().per_connection, Some(250));
+        closed.record(result(0, None));
+        assert_eq!(closed.last_result().unwrap().per_connection, None);
+
+        let mut open = report(None);
+        open.record(result(1000, Some(Percentiles(vec![(50.0, 1000.0)]))));
+        assert_eq!(open.last_result().unwrap().per_connection, None);
+    }
+
+    #[test]
+    fn sample_rows_per_request() {
+        use std::fs;
+        use std::time::Duration;
+        use RequestInfo;
+
+        let path = std::env::temp_dir().join(format!("samples-{}.csv", std::process::id()));
+        let mut samples = SampleWriter::create(path.to_str().unwrap()).unwrap();
+        let packets: Vec<Packet> = (0..10)
+            .map(|i| Packet {
+                actual_start: if i != 3 {
+                    Some(Duration::from_micros(i))
+                } else {
+                    None
+                },
+                completion_time: if i % 2 == 0 {
+                    Some(Duration::from_micros(i + 5))
+                } else {
+                    None
+                },
+                miss: i == 4,
+                failed: i == 5,
+                info: RequestInfo::new("get", 100 + i),
+                ..Default::default()
+            })
+            .collect();
+        samples.write(UNIX_EPOCH, &packets).unwrap();
+        samples.write(UNIX_EPOCH, &packets[..1]).unwrap();
+        drop(samples);
+
+        // A request never sent has no row.
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "timestamp_ns,opcode,key,latency_ns,status");
+        assert_eq!(lines.len(), 1 + 9 + 1);
+        assert_eq!(lines[1], "0,get,100,5000,ok");
+        assert_eq!(lines[2], "1000,get,101,,timeout");
+        assert_eq!(lines[3], "2000,get,102,5000,ok");
+        assert_eq!(lines[4], "4000,get,104,5000,miss");
+        assert_eq!(lines[5], "5000,get,105,,failed");
+        assert!(lines[1..].iter().all(|l| l.split(',').count() == 5));
+    }
+
+    #[test]
+    fn binary_samples_convert_to_csv() {
+        use std::fs;
+        use std::time::Duration;
+        use RequestInfo;
+
+        let path = std::env::temp_dir().join(format!("samples-{}.bin", std::process::id()));
+        let mut samples = BinarySampleWriter::create(path.to_str().unwrap(), 1 << 10).unwrap();
+        let packets: Vec<Packet> = (0..10)
+            .map(|i| Packet {
+                actual_start: Some(Duration::from_micros(i)),
+                completion_time: if i % 2 == 0 {
+                    Some(Duration::from_micros(i + 5))
+                } else {
+                    None
+                },
+                miss: i == 4,
+                failed: i == 3,
+                info: RequestInfo::new(
+                    match i {
+                        2 => "delete",
+                        4 => "work",
+                        _ => "get",
+                    },
+                    i,
+                ),
+                ..Default::default()
+            })
+            .collect();
+        assert!(samples.reserve(packets.len()).is_ok());
+        samples.write(UNIX_EPOCH, &packets).unwrap();
+        samples.write(UNIX_EPOCH, &[Packet::default()]).unwrap();
+
+        // Every request sent takes a record, answered or not, and only
+        // those never sent have none.
+        let dump = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(dump.len(), SAMPLE_MAGIC.len() + 10 * SAMPLE_RECORD);
+        // The cap counts what was written.
+        assert!(samples.reserve(46).is_ok());
+        assert!(samples.reserve(47).is_err());
+
+        let mut csv = Vec::new();
+        assert_eq!(samples_to_csv(&dump[..], &mut csv).unwrap(), 10);
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "timestamp_ns,opcode,latency_ns,status");
+        assert_eq!(lines[1], "0,get,5000,ok");
+        assert_eq!(lines[2], "1000,get,,timeout");
+        assert_eq!(lines[3], "2000,delete,5000,ok");
+        assert_eq!(lines[4], "3000,get,,failed");
+        assert_eq!(lines[5], "4000,work,5000,miss");
+        assert_eq!(lines.len(), 11);
+
+        let mut unknown = dump.clone();
+        unknown[SAMPLE_MAGIC.len() + 17] = SAMPLE_STATUSES.len() as u8;
+        assert!(samples_to_csv(&unknown[..], io::sink()).is_err());
+
+        assert!(samples_to_csv(&b"timestamp_ns"[..], io::sink()).is_err());
+        assert!(samples_to_csv(&dump[..dump.len() - 1], io::sink()).is_err());
+    }
+
+    #[test]
+    fn request_log_per_thread() {
+        use std::fs;
+        use std::time::Duration;
+        use RequestInfo;
+
+        let path = std::env::temp_dir().join(format!("requests-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let us = Duration::from_micros;
+        let packets = vec![
+            Packet {
+                actual_start: Some(us(1)),
+                completion_time: Some(us(4)),
+                conn: 1,
+                info: RequestInfo::new("get", 7)
+                    .with_key_size(30)
+                    .with_value_size(Some(100)),
+                ..Default::default()
+            },
+            Packet {
+                actual_start: Some(us(2)),
+                ..Default::default()
+            },
+            Packet {
+                actual_start: Some(us(3)),
+                miss: true,
+                completion_time: Some(us(5)),
+                info: RequestInfo::new("get", 8).with_key_size(20),
+                ..Default::default()
+            },
+            Packet {
+                actual_start: Some(us(4)),
+                failed: true,
+                info: RequestInfo::new("set", 9).with_key_size(20),
+                ..Default::default()
+            },
+            // Never sent, so not logged.
+            Packet::default(),
+        ];
+        let mut log = RequestLogWriter::create(path, 1).unwrap();
+        log.write(1, 2, UNIX_EPOCH, &packets).unwrap();
+        let mut sampled = RequestLogWriter::create(&format!("{}.sampled", path), 2).unwrap();
+        sampled.write(0, 0, UNIX_EPOCH, &packets).unwrap();
+
+        let read = |path: String| {
+            let text = fs::read_to_string(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            text
+        };
+        // The first thread's file was made, though it logged nothing.
+        assert_eq!(read(format!("{}.0", path)).lines().count(), 1);
+        let text = read(format!("{}.1", path));
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "timestamp_ns,conn,opaque,opcode,key,key_length,value_length,status,latency_ns",
+                "1000,3,0,get,7,30,100,ok,3000",
+                "2000,2,1,,,,,timeout,",
+                "3000,2,2,get,8,20,,miss,2000",
+                "4000,2,3,set,9,20,,failed,",
+            ]
+        );
+        let text = read(format!("{}.sampled.0", path));
+        let opaques: Vec<&str> = text
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').nth(2).unwrap())
+            .collect();
+        assert_eq!(opaques, vec!["0", "2"]);
+    }
+
+    #[test]
+    fn response_stall_reported() {
+        let ms = Duration::from_millis;
+        // Two connections answered every millisecond, but for a 40ms stall
+        // on the second.
+        let packets: Vec<Packet> = (0..200u64)
+            .map(|i| {
+                let conn = (i % 2) as usize;
+                let mut done = ms(100 + i / 2);
+                if conn == 1 && i / 2 >= 50 {
+                    done += ms(40);
+                }
+                Packet {
+                    completion_time: Some(done),
+                    conn,
+                    ..Default::default()
+                }
+            })
+            // A request never answered leaves no gap.
+            .chain(Some(Packet {
+                conn: 0,
+                ..Default::default()
+            }))
+            .collect();
+        let mut report = Report::new(
+            OutputFormat::Json,
+            RunMetadata::default(),
+            None,
+            None,
+            None,
+            None,
+            vec![50.0],
+        );
+        report.record_response_gaps(0, UNIX_EPOCH, &packets);
+        assert!(report.response_gaps().is_none());
+        report.track_response_gaps(2);
+        // The thread's connections come after two of another thread's.
+        report.record_response_gaps(2, UNIX_EPOCH + Duration::from_secs(10), &packets);
+        let gaps = report.response_gaps().unwrap();
+        let max: Vec<u64> = gaps
+            .max_by_connection
+            .iter()
+            .map(|&ms| ms.round() as u64)
+            .collect();
+        assert_eq!(max, vec![0, 0, 1, 41]);
+        assert_eq!(gaps.longest.len(), 2);
+        let stall = &gaps.longest[0];
+        assert_eq!(stall.connection, 3);
+        assert!((stall.ms - 41.0).abs() < 1e-6, "{}", stall.ms);
+        assert!((stall.start - 10.149).abs() < 1e-6, "{}", stall.start);
+        assert!((gaps.longest[1].ms - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn interval_rows_per_interval() {
+        use std::fs;
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("intervals-{}.csv", std::process::id()));
+        let mut rows = IntervalWriter::create(path.to_str().unwrap()).unwrap();
+        let ms = Duration::from_millis;
+        let wct_start = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut fast = Histogram::new(DEFAULT_DIGITS);
+        fast.record(1_000_000);
+        let mut slow = Histogram::new(DEFAULT_DIGITS);
+        slow.record(1_000_000_000);
+        let empty = Histogram::new(DEFAULT_DIGITS);
+
+        rows.write(
+            wct_start,
+            ms(1000),
+            &Interval {
+                end: ms(1000),
+                sent: 2,
+                completed: 1,
+                rps: 1,
+                bytes_out: 64,
+                bytes_in: 32,
+                headers_out: 104,
+                headers_in: 52,
+                out_gbps: 0.000001344,
+                in_gbps: 0.000000672,
+            },
+            &IntervalRow {
+                offered: Some(100),
+                latencies: &fast,
+                misses: 0,
+                errors: 0,
+                timeouts: 0,
+            },
+        )
+        .unwrap();
+        // Each row is on disk as soon as it is written.
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        rows.write(
+            wct_start,
+            ms(1000),
+            &Interval {
+                end: ms(2000),
+                sent: 2,
+                completed: 1,
+                rps: 1,
+                bytes_out: 64,
+                bytes_in: 16,
+                ..Default::default()
+            },
+            &IntervalRow {
+                offered: Some(200),
+                latencies: &slow,
+                misses: 1,
+                errors: 1,
+                timeouts: 1,
+            },
+        )
+        .unwrap();
+        // After the last step.
+        rows.write(
+            wct_start,
+            ms(1000),
+            &Interval {
+                end: ms(3000),
+                ..Default::default()
+            },
+            &IntervalRow {
+                offered: None,
+                latencies: &empty,
+                misses: 0,
+                errors: 0,
+                timeouts: 0,
+            },
+        )
+        .unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        drop(rows);
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "time,step_offered,offered,achieved,p50,p99,p999,misses,errors,timeouts,\
+                 bytes_out,bytes_in,headers_out,headers_in,out_gbps,in_gbps",
+                "1001.000,100,2,1,1000.0,1000.0,1000.0,0,0,0,64,32,104,52,0.000001,0.000001",
+                "1002.000,200,2,1,1000000.0,1000000.0,1000000.0,1,1,1,64,16,0,0,0.000000,0.000000",
+                "1003.000,,0,0,,,,0,0,0,0,0,0,0,0.000000,0.000000",
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use std::f64::consts::PI;
+
+/// Modulates the offered rate over time, relative to the configured one.
+///
+/// Arrivals are still drawn at the base rate, and each gap is then rescaled in
+/// time: it ends once the area under the relative rate since its start equals
+/// its length. Gaps stretch where the rate dips and shrink where it peaks, so
+/// any arrival process follows the shape continuously rather than in steps.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LoadShape {
+    Constant,
+    /// 1 + amplitude * sin(2πt / period), with the period in ns.
+    Sine(f64, f64),
+    /// 1 + amplitude for the first half of each period, then 1 - amplitude.
+    Square(f64, f64),
+}
+
+/// Sine gaps are integrated in steps of this fraction of the period.
+const SINE_STEPS: f64 = 1000.0;
+
+impl LoadShape {
+    /// Parses `constant`, `sine:AMPLITUDE,PERIOD_MS` or
+    /// `square:AMPLITUDE,PERIOD_MS`, with the amplitude a fraction of the base
+    /// rate.
+    pub fn parse(spec: &str) -> Result<LoadShape, String> {
+        if spec == "constant" {
+            return Ok(LoadShape::Constant);
+        }
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap();
+        let params: Vec<&str> = parts.next().unwrap_or("").split(',').collect();
+        if params.len() != 2 {
+            return Err(format!("{} takes AMPLITUDE,PERIOD_MS", name));
+        }
+        let amplitude = match params[0].parse::<f64>() {
+            Ok(a) if a >= 0.0 => a,
+            _ => return Err(format!("bad AMPLITUDE '{}' for {}", params[0], name)),
+        };
+        let period = match params[1].parse::<f64>() {
+            Ok(p) if p > 0.0 => p * 1e6,
+            _ => return Err(format!("bad PERIOD_MS '{}' for {}", params[1], name)),
+        };
+        match name {
+            "sine" => Ok(LoadShape::Sine(amplitude, period)),
+            "square" => Ok(LoadShape::Square(amplitude, period)),
+            _ => Err(format!("unknown load shape '{}'", name)),
+        }
+    }
+
+    /// The rate `t` ns into the shape relative to the base, clamped at zero.
+    pub fn factor(&self, t: f64) -> f64 {
+        let f = match *self {
+            LoadShape::Constant => 1.0,
+            LoadShape::Sine(amplitude, period) => 1.0 + amplitude * (2.0 * PI * t / period).sin(),
+            LoadShape::Square(amplitude, period) => {
+                if t % period < period / 2.0 {
+                    1.0 + amplitude
+                } else {
+                    1.0 - amplitude
+                }
+            }
+        };
+        f64::max(f, 0.0)
+    }
+
+    /// Returns when a gap of `gap` ns at the base rate, starting `t` ns into
+    /// the shape, ends.
+    pub fn advance(&self, mut t: f64, gap: f64) -> f64 {
+        let mut remaining = gap;
+        loop {
+            // The relative rate over the next step, which is exact for a
+            // square wave since its steps end at the half-period edges.
+            let step = match *self {
+                LoadShape::Constant => return t + gap,
+                LoadShape::Sine(_, period) => period / SINE_STEPS,
+                LoadShape::Square(_, period) => period / 2.0 - t % (period / 2.0),
+            };
+            let f = self.factor(t + step / 2.0);
+            if f * step >= remaining {
+                return t + remaining / f;
+            }
+            remaining -= f * step;
+            t += step;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arrivals(shape: LoadShape, gap: f64, until: f64) -> Vec<f64> {
+        let mut t = 0.0;
+        let mut times = Vec::new();
+        while t < until {
+            t = shape.advance(t, gap);
+            times.push(t);
+        }
+        times
+    }
+
+    #[test]
+    fn mean_rate_over_a_period() {
+        let period = 100e6;
+        for &shape in &[LoadShape::Sine(0.8, period), LoadShape::Square(0.8, period)] {
+            // A 10us gap is 10,000 arrivals per period at the base rate.
+            let n = arrivals(shape, 10e3, 10.0 * period).len() as f64 / 10.0;
+            assert!(
+                (n - 10_000.0).abs() < 10.0,
+                "{} per period for {:?}",
+                n,
+                shape
+            );
+        }
+    }
+
+    #[test]
+    fn follows_the_wave() {
+        let period = 100e6;
+        let times = arrivals(LoadShape::Sine(0.5, period), 10e3, period);
+        let count = |from: f64, to: f64| times.iter().filter(|&&t| t >= from && t < to).count();
+        // Rates of 1.5x around the peak and 0.5x around the trough.
+        let peak = count(0.2 * period, 0.3 * period) as f64;
+        let trough = count(0.7 * period, 0.8 * period) as f64;
+        assert!(peak / trough > 2.5 && peak / trough < 3.5);
+    }
+
+    #[test]
+    fn negative_rates_clamp_to_zero() {
+        let period = 100e6;
+        let shape = LoadShape::Square(1.5, period);
+        assert_eq!(shape.factor(0.75 * period), 0.0);
+        let times = arrivals(shape, 10e3, 3.0 * period);
+        assert!(times
+            .iter()
+            .all(|&t| t % period <= period / 2.0 || t > 3.0 * period));
+        assert!(LoadShape::Sine(2.0, period).factor(0.75 * period) == 0.0);
+    }
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(LoadShape::parse("constant"), Ok(LoadShape::Constant));
+        assert_eq!(
+            LoadShape::parse("sine:0.5,1000"),
+            Ok(LoadShape::Sine(0.5, 1e9))
+        );
+        assert_eq!(
+            LoadShape::parse("square:1,250"),
+            Ok(LoadShape::Square(1.0, 250e6))
+        );
+        assert!(LoadShape::parse("sine:0.5").is_err());
+        assert!(LoadShape::parse("sine:-1,100").is_err());
+        assert!(LoadShape::parse("square:1,0").is_err());
+        assert!(LoadShape::parse("triangle:1,100").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+/// Searches for the highest offered rate whose latency at the SLO percentile
+/// stays under a target, one measured sample at a time.
+///
+/// The rate grows additively while the SLO holds. A violation backs it off
+/// multiplicatively and halves the additive step, so the rate oscillates ever
+/// closer to the boundary until the step reaches `min_step`.
+#[derive(Copy, Clone, Debug)]
+pub struct SloController {
+    /// Target latency, in the units results are reported in.
+    pub target: f32,
+    pub rate: usize,
+    pub step: usize,
+    pub min_step: usize,
+    /// Multiplier applied to the rate after a violation, in (0, 1).
+    pub backoff: f64,
+    pub max_rate: usize,
+    /// Highest rate seen to hold the SLO.
+    pub best: Option<usize>,
+}
+
+impl SloController {
+    pub fn new(
+        target: f32,
+        start_rate: usize,
+        step: usize,
+        backoff: f64,
+        max_rate: usize,
+    ) -> SloController {
+        assert!(backoff > 0.0 && backoff < 1.0, "backoff must be in (0, 1)");
+        let step = usize::max(step, 1);
+        SloController {
+            target,
+            rate: usize::min(usize::max(start_rate, 1), max_rate),
+            step,
+            min_step: usize::max(step / 64, 1),
+            backoff,
+            max_rate,
+            best: None,
+        }
+    }
+
+    /// Whether the search has narrowed down as far as it will.
+    pub fn converged(&self) -> bool {
+        self.step <= self.min_step
+    }
+
+    /// Takes the latency measured at the current rate, or None if too few
+    /// responses arrived to measure it, and moves to the next rate.
+    pub fn update(&mut self, latency: Option<f32>) -> usize {
+        match latency {
+            Some(latency) if latency <= self.target => {
+                self.best = Some(usize::max(self.best.unwrap_or(0), self.rate));
+                self.rate = usize::min(self.rate + self.step, self.max_rate);
+            }
+            _ => {
+                self.rate = usize::max((self.rate as f64 * self.backoff) as usize, 1);
+                self.step = usize::max(self.step / 2, self.min_step);
+            }
+        }
+        self.rate
+    }
+}
+
+/// Binary searches a range of rates for the highest one that holds the SLO.
+/// Each rate is measured until `repeats` samples in a row agree on whether it
+/// holds, so a single noisy sample cannot move the bracket. A rate whose
+/// samples still disagree after `2 * repeats` of them is taken to violate it.
+/// A range no wider than the tolerance, such as one of a single rate, is
+/// searched in one step: its midpoint is measured until there is a verdict.
+#[derive(Copy, Clone, Debug)]
+pub struct Bisection {
+    pub target: f32,
+    /// Highest rate shown to hold the SLO, or the bottom of the range.
+    pub lo: usize,
+    /// Lowest rate shown to violate the SLO, or the top of the range.
+    pub hi: usize,
+    /// The search ends once `hi - lo` is no wider than this.
+    pub tolerance: usize,
+    pub repeats: usize,
+    pub rate: usize,
+    pub best: Option<usize>,
+    last: Option<bool>,
+    streak: usize,
+    tries: usize,
+    /// Whether any rate has had a verdict, which ends even a search of one
+    /// step.
+    decided: bool,
+}
+
+impl Bisection {
+    pub fn new(
+        target: f32,
+        min_rate: usize,
+        max_rate: usize,
+        tolerance: usize,
+        repeats: usize,
+    ) -> Bisection {
+        assert!(min_rate <= max_rate, "the rate range is empty");
+        let mut bisection = Bisection {
+            target,
+            lo: min_rate,
+            hi: max_rate,
+            tolerance: usize::max(tolerance, 1),
+            repeats: usize::max(repeats, 1),
+            rate: 0,
+            best: None,
+            last: None,
+            streak: 0,
+            tries: 0,
+            decided: false,
+        };
+        bisection.rate = bisection.midpoint();
+        bisection
+    }
+
+    fn midpoint(&self) -> usize {
+        usize::max(self.lo + (self.hi - self.lo) / 2, 1)
+    }
+
+    pub fn converged(&self) -> bool {
+        self.decided && self.hi - self.lo <= self.tolerance
+    }
+
+    /// Takes the latency measured at the current rate, or None if too few
+    /// responses arrived to measure it, and returns the rate to measure next.
+    pub fn update(&mut self, latency: Option<f32>) -> usize {
+        let holds = match latency {
+            Some(latency) => latency <= self.target,
+            None => false,
+        };
+        self.streak = if self.last == Some(holds) {
+            self.streak + 1
+        } else {
+            1
+        };
+        self.last = Some(holds);
+        self.tries += 1;
+
+        let verdict = if self.streak >= self.repeats {
+            Some(holds)
+        } else if self.tries >= 2 * self.repeats {
+            Some(false)
+        } else {
+            None
+        };
+        if let Some(holds) = verdict {
+            if holds {
+                self.best = Some(self.rate);
+                self.lo = self.rate;
+            } else {
+                self.hi = self.rate;
+            }
+            self.last = None;
+            self.streak = 0;
+            self.tries = 0;
+            self.decided = true;
+            self.rate = self.midpoint();
+        }
+        self.rate
+    }
+}
+
+/// How an SLO-seeking run picks the rate of each sample.
+#[derive(Copy, Clone, Debug)]
+pub enum SloSearch {
+    Aimd(SloController),
+    Bisect(Bisection),
+}
+
+impl SloSearch {
+    pub fn rate(&self) -> usize {
+        match *self {
+            SloSearch::Aimd(ref c) => c.rate,
+            SloSearch::Bisect(ref b) => b.rate,
+        }
+    }
+
+    pub fn best(&self) -> Option<usize> {
+        match *self {
+            SloSearch::Aimd(ref c) => c.best,
+            SloSearch::Bisect(ref b) => b.best,
+        }
+    }
+
+    pub fn converged(&self) -> bool {
+        match *self {
+            SloSearch::Aimd(ref c) => c.converged(),
+            SloSearch::Bisect(ref b) => b.converged(),
+        }
+    }
+
+    pub fn update(&mut self, latency: Option<f32>) -> usize {
+        match *self {
+            SloSearch::Aimd(ref mut c) => c.update(latency),
+            SloSearch::Bisect(ref mut b) => b.update(latency),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// p99 of a server with 10us of service time and a capacity of 1M
+    /// requests per second, which holds a 100us SLO up to 900k.
+    fn simulated_p99(rate: usize) -> Option<f32> {
+        let load = rate as f64 / 1_000_000.0;
+        if load >= 1.0 {
+            return None;
+        }
+        Some((10.0 / (1.0 - load)) as f32)
+    }
+
+    #[test]
+    fn converges_below_slo() {
+        let mut c = SloController::new(100.0, 100_000, 100_000, 0.8, 2_000_000);
+        for _ in 0..200 {
+            let p99 = simulated_p99(c.rate);
+            c.update(p99);
+        }
+        assert!(c.converged());
+        let best = c.best.unwrap();
+        assert!(simulated_p99(best).unwrap() <= 100.0);
+        assert!(best >= 880_000, "converged on {}", best);
+    }
+
+    #[test]
+    fn rate_stays_in_bounds() {
+        let mut c = SloController::new(100.0, 0, 500_000, 0.5, 600_000);
+        assert_eq!(c.rate, 1);
+        assert_eq!(c.update(Some(1.0)), 500_001);
+        assert_eq!(c.update(Some(1.0)), 600_000);
+        assert_eq!(c.update(None), 300_000);
+        assert_eq!(c.step, 250_000);
+        assert_eq!(c.best, Some(500_001));
+    }
+
+    #[test]
+    fn bisection_outlasts_noise() {
+        let mut b = Bisection::new(100.0, 0, 2_000_000, 1_000, 2);
+        let mut samples = 0;
+        while !b.converged() {
+            samples += 1;
+            // Every fifth sample comes out on the wrong side of the SLO.
+            let p99 = match simulated_p99(b.rate) {
+                Some(p99) if samples % 5 == 0 => Some(if p99 <= 100.0 { 1000.0 } else { 1.0 }),
+                p99 => p99,
+            };
+            b.update(p99);
+            assert!(samples < 100, "no convergence");
+        }
+        let best = b.best.unwrap();
+        assert!(simulated_p99(best).unwrap() <= 100.0);
+        assert!(best >= 899_000, "converged on {}", best);
+        assert!(b.hi - b.lo <= 1_000);
+    }
+
+    #[test]
+    fn bisection_needs_agreement() {
+        let mut b = Bisection::new(100.0, 0, 1000, 1, 2);
+        assert_eq!(b.rate, 500);
+        assert_eq!(b.update(Some(1.0)), 500);
+        assert_eq!(b.update(Some(1.0)), 750);
+        assert_eq!(b.best, Some(500));
+
+        // Samples that keep disagreeing count as a violation.
+        assert_eq!(b.update(Some(1.0)), 750);
+        assert_eq!(b.update(None), 750);
+        assert_eq!(b.update(Some(1.0)), 750);
+        assert_eq!(b.update(None), 625);
+        assert_eq!((b.lo, b.hi), (500, 750));
+
+        let mut never = Bisection::new(100.0, 0, 1000, 1, 1);
+        while !never.converged() {
+            never.update(None);
+        }
+        assert_eq!(never.best, None);
+        assert_eq!(never.hi, 1);
+    }
+
+    #[test]
+    fn bisection_of_one_rate_takes_one_step() {
+        let mut one = Bisection::new(100.0, 500, 500, 1, 2);
+        assert_eq!(one.rate, 500);
+        assert!(!one.converged());
+        assert_eq!(one.update(Some(1.0)), 500);
+        assert!(!one.converged());
+        assert_eq!(one.update(Some(1.0)), 500);
+        assert!(one.converged());
+        assert_eq!(one.best, Some(500));
+
+        let mut violated = Bisection::new(100.0, 500, 500, 1, 1);
+        violated.update(None);
+        assert!(violated.converged());
+        assert_eq!(violated.best, None);
+    }
+}
@@ -0,0 +1,195 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use any_addr;
+
+/// Connections opened at the start of a sample, which take the first
+/// sources, and connections opened in place of others since.
+static OPENED: AtomicUsize = AtomicUsize::new(0);
+static REOPENED: AtomicUsize = AtomicUsize::new(0);
+
+/// Local addresses client connections are bound to in place of ones the OS
+/// picks, so that their flows spread over the server's receive queues.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sources {
+    ips: &'static [IpAddr],
+    /// First and last port, inclusive.
+    ports: Option<(u16, u16)>,
+}
+
+impl Sources {
+    pub fn new(ips: Vec<IpAddr>, ports: Option<(u16, u16)>) -> Sources {
+        Sources {
+            ips: Box::leak(ips.into_boxed_slice()),
+            ports,
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.ips.is_empty() || self.ports.is_some()
+    }
+
+    /// Checks that every target can be reached from a source IP of its
+    /// family.
+    pub fn check(&self, targets: &[SocketAddr]) -> Result<(), String> {
+        if self.ips.is_empty() {
+            return Ok(());
+        }
+        match targets
+            .iter()
+            .find(|t| !self.ips.iter().any(|ip| ip.is_ipv6() == t.is_ipv6()))
+        {
+            Some(target) => Err(format!(
+                "no source address is {}, as target {} is",
+                if target.is_ipv6() { "IPv6" } else { "IPv4" },
+                target
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The local address of the `n`th connection to `remote`, or None to let
+    /// the OS pick. Connections take the source IPs of `remote`'s family in
+    /// turn, and the next port of the range each time they have all been
+    /// taken, so the same connection always gets the same source.
+    pub fn addr(&self, n: usize, remote: SocketAddr) -> Option<SocketAddr> {
+        if !self.is_set() {
+            return None;
+        }
+        let ips: Vec<IpAddr> = self
+            .ips
+            .iter()
+            .cloned()
+            .filter(|ip| ip.is_ipv6() == remote.is_ipv6())
+            .collect();
+        let (ip, turn) = match ips.len() {
+            0 => (any_addr(remote, 0).ip(), n),
+            len => (ips[n % len], n / len),
+        };
+        let port = match self.ports {
+            Some((first, last)) => first + (turn % ((last - first) as usize + 1)) as u16,
+            None => 0,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Records that a sample opened `count` connections with the first
+    /// sources.
+    pub fn opened(&self, count: usize) {
+        OPENED.fetch_max(count, Ordering::SeqCst);
+    }
+
+    /// The local address of a connection opened in place of another to
+    /// `remote`. It takes the sources after those of the sample's own
+    /// connections, in turn, so that a session that ends leaves its port
+    /// alone for as long as the range allows.
+    pub fn reopened(&self, remote: SocketAddr) -> Option<SocketAddr> {
+        if !self.is_set() {
+            return None;
+        }
+        let n = OPENED.load(Ordering::SeqCst) + REOPENED.fetch_add(1, Ordering::SeqCst);
+        self.addr(n, remote)
+    }
+
+    /// Each source IP, and the port range if any, for the metadata.
+    pub fn to_strings(self) -> (Option<Vec<String>>, Option<String>) {
+        let ips = if self.ips.is_empty() {
+            None
+        } else {
+            Some(self.ips.iter().map(|ip| ip.to_string()).collect())
+        };
+        (
+            ips,
+            self.ports
+                .map(|(first, last)| format!("{}-{}", first, last)),
+        )
+    }
+}
+
+/// Parses a comma-separated list of IP addresses.
+pub fn parse_source_ips(spec: &str) -> Result<Vec<IpAddr>, String> {
+    spec.split(',')
+        .map(|ip| {
+            ip.trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not an IP address", ip.trim()))
+        })
+        .collect()
+}
+
+/// Parses a port range as `FIRST-LAST`, inclusive, or a single port.
+pub fn parse_port_range(spec: &str) -> Result<(u16, u16), String> {
+    let mut parts = spec.splitn(2, '-');
+    let first = parts.next().unwrap().trim();
+    let last = parts.next().unwrap_or(first).trim();
+    let bad = || format!("bad port range '{}', must be FIRST-LAST", spec);
+    let first: u16 = first.parse().map_err(|_| bad())?;
+    let last: u16 = last.parse().map_err(|_| bad())?;
+    if first == 0 || first > last {
+        return Err(bad());
+    }
+    Ok((first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_formats() {
+        assert_eq!(
+            parse_source_ips("10.0.0.1, ::1").unwrap(),
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert!(parse_source_ips("10.0.0.1,host").is_err());
+        assert_eq!(parse_port_range("20000-20999"), Ok((20000, 20999)));
+        assert_eq!(parse_port_range("5000"), Ok((5000, 5000)));
+        for bad in &["", "0-10", "10-5", "1-70000", "a-b"] {
+            assert!(parse_port_range(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn sources_round_robin() {
+        let sources = Sources::new(
+            parse_source_ips("10.0.0.1,10.0.0.2,::2").unwrap(),
+            Some((7000, 7002)),
+        );
+        let v4: SocketAddr = "10.0.1.1:11211".parse().unwrap();
+        let addrs: Vec<String> = (0..7)
+            .map(|n| sources.addr(n, v4).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "10.0.0.1:7000",
+                "10.0.0.2:7000",
+                "10.0.0.1:7001",
+                "10.0.0.2:7001",
+                "10.0.0.1:7002",
+                "10.0.0.2:7002",
+                "10.0.0.1:7000",
+            ]
+        );
+        // Only the IPs of the target's family are used.
+        let v6: SocketAddr = "[::1]:11211".parse().unwrap();
+        assert_eq!(sources.addr(1, v6).unwrap().to_string(), "[::2]:7001");
+        assert!(sources.check(&[v4, v6]).is_ok());
+
+        // Ports alone keep the wildcard address.
+        let ports = Sources::new(Vec::new(), Some((7000, 7001)));
+        assert_eq!(ports.addr(3, v4).unwrap().to_string(), "0.0.0.0:7001");
+        assert!(ports.check(&[v6]).is_ok());
+
+        let v4_only = Sources::new(parse_source_ips("10.0.0.1").unwrap(), None);
+        assert_eq!(v4_only.addr(5, v4).unwrap().to_string(), "10.0.0.1:0");
+        assert!(v4_only
+            .check(&[v4, v6])
+            .unwrap_err()
+            .contains("[::1]:11211"));
+        assert_eq!(Sources::default().addr(0, v4), None);
+    }
+}
@@ -0,0 +1,410 @@
+use rustls;
+use rustls::client::Resumption;
+use rustls::{AlertDescription, CertificateError, ClientConfig, ClientConnection, ServerName};
+use rustls_pemfile;
+use rustls_pemfile::Item;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use backend::Connection;
+
+/// How a client secures its connections: the CA bundle it trusts, the name
+/// the server's certificate must have, and the certificate it presents, if
+/// any. Connections share one session cache, so one that replaces another
+/// resumes its session rather than making a full handshake, unless that is
+/// disabled.
+pub struct TlsClient {
+    config: Arc<ClientConfig>,
+    server_name: ServerName,
+    name: String,
+    ca_bundle: String,
+}
+
+/// How long every TLS handshake of the process took.
+static HANDSHAKES: Mutex<Vec<Duration>> = Mutex::new(Vec::new());
+
+pub fn handshake_times() -> Vec<Duration> {
+    HANDSHAKES.lock().unwrap().clone()
+}
+
+/// The CA bundle trusted unless another is given: the system's.
+const SYSTEM_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+fn read_pem(path: &str) -> Result<Vec<Item>, String> {
+    let file = File::open(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| format!("could not parse {}: {}", path, e))
+}
+
+impl TlsClient {
+    /// `identity` is a client certificate chain and its key, for servers
+    /// that require one. All are PEM files, and the CA bundle is the
+    /// system's if none is given.
+    pub fn new(
+        name: &str,
+        ca_bundle: Option<&str>,
+        identity: Option<(&str, &str)>,
+        resume: bool,
+    ) -> Result<TlsClient, String> {
+        let ca_bundle = ca_bundle.unwrap_or(SYSTEM_CA_BUNDLE);
+        let mut roots = rustls::RootCertStore::empty();
+        for item in read_pem(ca_bundle)? {
+            if let Item::X509Certificate(cert) = item {
+                roots
+                    .add(&rustls::Certificate(cert))
+                    .map_err(|e| format!("bad CA certificate in {}: {}", ca_bundle, e))?;
+            }
+        }
+        if roots.is_empty() {
+            return Err(format!("no CA certificates in {}", ca_bundle));
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match identity {
+            None => builder.with_no_client_auth(),
+            Some((cert, key)) => {
+                let chain: Vec<rustls::Certificate> = read_pem(cert)?
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        Item::X509Certificate(c) => Some(rustls::Certificate(c)),
+                        _ => None,
+                    })
+                    .collect();
+                if chain.is_empty() {
+                    return Err(format!("no certificates in {}", cert));
+                }
+                let key = read_pem(key)?
+                    .into_iter()
+                    .filter_map(|item| match item {
+                        Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => {
+                            Some(rustls::PrivateKey(k))
+                        }
+                        _ => None,
+                    })
+                    .next()
+                    .ok_or_else(|| format!("no private key in {}", key))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .map_err(|e| format!("bad client certificate {}: {}", cert, e))?
+            }
+        };
+        if !resume {
+            config.resumption = Resumption::disabled();
+        }
+        Ok(TlsClient {
+            config: Arc::new(config),
+            server_name: ServerName::try_from(name)
+                .map_err(|_| format!("bad TLS server name '{}'", name))?,
+            name: name.to_string(),
+            ca_bundle: ca_bundle.to_string(),
+        })
+    }
+
+    /// The name the server's certificate must have.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs the client side of a handshake over `inner`, and returns the
+    /// secured connection.
+    pub fn connect(&'static self, inner: Connection) -> io::Result<Connection> {
+        let start = Instant::now();
+        let mut state = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| self.error("handshake", e))?;
+        while state.is_handshaking() {
+            if let Err(e) = state.complete_io(&mut &inner) {
+                let cause = e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>());
+                return Err(match cause {
+                    Some(cause) => self.error("handshake", cause.clone()),
+                    None => e,
+                });
+            }
+        }
+        HANDSHAKES.lock().unwrap().push(start.elapsed());
+        Ok(Connection::Tls(Box::new(TlsStream {
+            client: self,
+            inner,
+            state: Mutex::new(state),
+            sending: Mutex::new(()),
+            received: Mutex::new(vec![0; 16 * 1024]),
+        })))
+    }
+
+    /// Says what `e` means for whoever runs the client, and what to change.
+    fn error(&self, during: &str, e: rustls::Error) -> Error {
+        let reason = match e {
+            rustls::Error::InvalidCertificate(CertificateError::Expired) => {
+                "the server's certificate has expired".to_string()
+            }
+            rustls::Error::InvalidCertificate(CertificateError::NotValidYet) => {
+                "the server's certificate is not valid yet, check the clocks".to_string()
+            }
+            rustls::Error::InvalidCertificate(CertificateError::NotValidForName) => format!(
+                "the server's certificate is not valid for {}, which --tls-server-name must \
+                 match",
+                self.name
+            ),
+            rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer) => format!(
+                "the server's certificate is not issued by a CA in {}",
+                self.ca_bundle
+            ),
+            rustls::Error::AlertReceived(AlertDescription::CertificateRequired) => {
+                "the server requires a client certificate, see --tls-cert".to_string()
+            }
+            rustls::Error::AlertReceived(alert) => format!("the server sent a {:?} alert", alert),
+            e => e.to_string(),
+        };
+        Error::other(format!(
+            "TLS {} with {} failed: {}",
+            during, self.name, reason
+        ))
+    }
+}
+
+/// A connection secured by TLS. Its state is locked only while records are
+/// encrypted or decrypted, so that a thread blocked reading the connection
+/// does not hold up one writing it.
+pub struct TlsStream {
+    client: &'static TlsClient,
+    inner: Connection,
+    state: Mutex<ClientConnection>,
+    /// Held from encrypting a write until it is sent, so that records go out
+    /// in order.
+    sending: Mutex<()>,
+    /// Ciphertext read from `inner`, by one reader at a time.
+    received: Mutex<Vec<u8>>,
+}
+
+impl TlsStream {
+    pub fn inner(&self) -> &Connection {
+        &self.inner
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        let _sending = self.sending.lock().unwrap();
+        let mut ciphertext = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.writer().write_all(buf)?;
+            while state.wants_write() {
+                state.write_tls(&mut ciphertext)?;
+            }
+        }
+        (&self.inner).write_all(&ciphertext)
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = self.received.lock().unwrap();
+        loop {
+            match self.state.lock().unwrap().reader().read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            // No more than a record's worth, so the plaintext always fits.
+            let len = (&self.inner).read(&mut received[..])?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut ciphertext = &received[..len];
+            let reply = {
+                let mut state = self.state.lock().unwrap();
+                while !ciphertext.is_empty() {
+                    state.read_tls(&mut ciphertext)?;
+                    state
+                        .process_new_packets()
+                        .map_err(|e| self.client.error("connection", e))?;
+                }
+                state.wants_write()
+            };
+            if reply {
+                self.send(&[])?;
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::{
+        AllowAnyAuthenticatedClient, ServerSessionMemoryCache, StoresServerSessions,
+    };
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::net::{SocketAddrV4, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Counts the sessions a server resumes.
+    struct Sessions(Arc<ServerSessionMemoryCache>, Arc<AtomicUsize>);
+
+    impl Sessions {
+        fn count(&self, session: Option<Vec<u8>>) -> Option<Vec<u8>> {
+            if session.is_some() {
+                self.1.fetch_add(1, Ordering::SeqCst);
+            }
+            session
+        }
+    }
+
+    impl StoresServerSessions for Sessions {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.0.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.count(self.0.get(key))
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.count(self.0.take(key))
+        }
+
+        fn can_cache(&self) -> bool {
+            true
+        }
+    }
+
+    /// A server that echoes 16 bytes on every connection, presenting the
+    /// certificate `cert` and requiring a client certificate if
+    /// `client_auth`. It counts the sessions it resumes.
+    fn echo_server(cert: &str, client_auth: bool) -> (SocketAddrV4, Arc<AtomicUsize>) {
+        let chain = read_pem(&testdata(&format!("{}.pem", cert)))
+            .unwrap()
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(c) => Some(rustls::Certificate(c)),
+                _ => None,
+            })
+            .collect();
+        let key = match read_pem(&testdata(&format!("{}.key", cert)))
+            .unwrap()
+            .remove(0)
+        {
+            Item::PKCS8Key(k) | Item::ECKey(k) => rustls::PrivateKey(k),
+            _ => panic!("no key"),
+        };
+        let resumed = Arc::new(AtomicUsize::new(0));
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if client_auth {
+            let mut roots = rustls::RootCertStore::empty();
+            for item in read_pem(&testdata("ca.pem")).unwrap() {
+                if let Item::X509Certificate(c) = item {
+                    roots.add(&rustls::Certificate(c)).unwrap();
+                }
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        } else {
+            builder.with_no_client_auth()
+        };
+        let mut config = builder.with_single_cert(chain, key).unwrap();
+        config.session_storage =
+            Arc::new(Sessions(ServerSessionMemoryCache::new(16), resumed.clone()));
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            ::std::net::SocketAddr::V4(a) => a,
+            _ => unreachable!(),
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let config = config.clone();
+                thread::spawn(move || {
+                    let state = ServerConnection::new(config).unwrap();
+                    let mut stream = StreamOwned::new(state, stream.unwrap());
+                    let mut buf = [0; 16];
+                    if stream.read_exact(&mut buf).is_ok() {
+                        let _ = stream.write_all(&buf);
+                        let _ = stream.flush();
+                    }
+                });
+            }
+        });
+        (addr, resumed)
+    }
+
+    /// Connects to `addr` and echoes one request.
+    fn echo(client: &'static TlsClient, addr: SocketAddrV4) -> io::Result<()> {
+        let conn = client.connect(Connection::LinuxTcp(TcpStream::connect(addr)?))?;
+        (&conn).write_all(&[7; 16])?;
+        let mut buf = [0; 16];
+        (&conn).read_exact(&mut buf)?;
+        assert_eq!(buf, [7; 16]);
+        Ok(())
+    }
+
+    fn client(name: &str, identity: bool, resume: bool) -> &'static TlsClient {
+        let (cert, key) = (testdata("client.pem"), testdata("client.key"));
+        let identity = match identity {
+            true => Some((&cert[..], &key[..])),
+            false => None,
+        };
+        let client = TlsClient::new(name, Some(&testdata("ca.pem")), identity, resume).unwrap();
+        Box::leak(Box::new(client))
+    }
+
+    #[test]
+    fn reconnects_resume() {
+        let (addr, resumed) = echo_server("server", false);
+        let resuming = client("localhost", false, true);
+        for _ in 0..3 {
+            echo(resuming, addr).unwrap();
+        }
+        assert_eq!(resumed.load(Ordering::SeqCst), 2);
+        assert!(handshake_times().len() >= 3);
+
+        let (addr, resumed) = echo_server("server", false);
+        let fresh = client("127.0.0.1", false, false);
+        for _ in 0..3 {
+            echo(fresh, addr).unwrap();
+        }
+        assert_eq!(resumed.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn handshake_errors_say_what_to_fix() {
+        let message = |client, addr| echo(client, addr).unwrap_err().to_string();
+
+        let (addr, _) = echo_server("server", false);
+        let e = message(client("example.com", false, true), addr);
+        assert!(e.contains("not valid for example.com"), "{}", e);
+        assert!(e.contains("--tls-server-name"), "{}", e);
+
+        let (addr, _) = echo_server("expired", false);
+        let e = message(client("localhost", false, true), addr);
+        assert!(e.contains("expired"), "{}", e);
+
+        let (addr, _) = echo_server("server", true);
+        let e = message(client("localhost", false, true), addr);
+        assert!(e.contains("--tls-cert"), "{}", e);
+        echo(client("localhost", true, true), addr).unwrap();
+
+        let e = TlsClient::new("localhost", Some(&testdata("missing.pem")), None, true)
+            .err()
+            .unwrap();
+        assert!(e.contains("missing.pem"), "{}", e);
+    }
+}
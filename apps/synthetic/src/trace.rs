@@ -0,0 +1,161 @@
+use std::fs;
+use std::time::Duration;
+
+/// One request of a recorded trace.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    pub set: bool,
+    pub key: u64,
+    /// Size of a SET's value, or None for the workload's own.
+    pub value_size: Option<usize>,
+    /// When the request was recorded, from the start of the trace, if the
+    /// trace has times.
+    pub time: Option<Duration>,
+}
+
+/// Parses a trace, one request per line as `get KEY` or `set KEY [VALUE_SIZE]`
+/// with the fields separated by whitespace, each optionally led by the time
+/// it was recorded at in microseconds. Either every request has a time or
+/// none does, and times never go back. Blank lines and lines starting with
+/// `#` are skipped.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceRecord>, String> {
+    let mut records: Vec<TraceRecord> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = |what: &str| format!("line {}: {}: '{}'", n + 1, what, line);
+        let mut fields: Vec<&str> = line.split_whitespace().collect();
+        let time = match fields[0].parse::<f64>() {
+            Ok(us) if us >= 0.0 && us.is_finite() => {
+                fields.remove(0);
+                Some(Duration::from_nanos((us * 1000.0) as u64))
+            }
+            Ok(_) => return Err(bad("bad time")),
+            Err(_) => None,
+        };
+        match records.last() {
+            Some(last) if last.time.is_some() != time.is_some() => {
+                return Err(bad("only some requests have a time"))
+            }
+            Some(last) if last.time > time => return Err(bad("time goes back")),
+            _ => {}
+        }
+        let set = match fields.first().cloned().unwrap_or("") {
+            "get" => false,
+            "set" => true,
+            _ => return Err(bad("unknown operation")),
+        };
+        let key = match fields.get(1).map(|k| k.parse()) {
+            Some(Ok(key)) => key,
+            _ => return Err(bad("bad key")),
+        };
+        let value_size = match fields.get(2) {
+            None => None,
+            Some(_) if !set => return Err(bad("a get has no value")),
+            Some(size) => Some(size.parse().map_err(|_| bad("bad value size"))?),
+        };
+        if fields.len() > 3 {
+            return Err(bad("too many fields"));
+        }
+        records.push(TraceRecord {
+            set,
+            key,
+            value_size,
+            time,
+        });
+    }
+    if records.is_empty() {
+        return Err("the trace has no requests".to_string());
+    }
+    Ok(records)
+}
+
+/// Reads and parses the trace at `path` once, for the rest of the run.
+pub fn read_trace(path: &str) -> Result<&'static [TraceRecord], String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let records = parse_trace(&text).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Box::leak(records.into_boxed_slice()))
+}
+
+/// How long a timed trace takes to replay once before it starts over: from
+/// its first request to its last, and the mean gap between them again, so
+/// that the last request is not followed at once by the first. None for a
+/// trace without times, or one whose times are all the same.
+pub fn trace_period(trace: &[TraceRecord]) -> Option<Duration> {
+    let first = trace[0].time?;
+    let span = trace[trace.len() - 1].time? - first;
+    if span == Duration::from_nanos(0) {
+        return None;
+    }
+    Some(span + span / (trace.len() as u32 - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_format() {
+        let trace = parse_trace("# op key size\nget 5\n\n  set 7 100\nset 8\n").unwrap();
+        assert_eq!(
+            trace,
+            vec![
+                TraceRecord {
+                    set: false,
+                    key: 5,
+                    value_size: None,
+                    time: None,
+                },
+                TraceRecord {
+                    set: true,
+                    key: 7,
+                    value_size: Some(100),
+                    time: None,
+                },
+                TraceRecord {
+                    set: true,
+                    key: 8,
+                    value_size: None,
+                    time: None,
+                },
+            ]
+        );
+
+        for bad in &[
+            "put 5",
+            "get",
+            "get x",
+            "get 5 10",
+            "set 5 x",
+            "set 5 1 2",
+            "# empty",
+            "12",
+            "-5 get 1",
+            "5 get 1\nget 2",
+            "get 1\n5 get 2",
+            "5 get 1\n4 get 2",
+        ] {
+            assert!(parse_trace(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn timed_traces() {
+        let trace = parse_trace("0 get 1\n100.5 set 2 10\n300 get 3\n").unwrap();
+        let times: Vec<_> = trace.iter().map(|r| r.time.unwrap()).collect();
+        assert_eq!(
+            times,
+            vec![
+                Duration::from_nanos(0),
+                Duration::from_nanos(100_500),
+                Duration::from_micros(300),
+            ]
+        );
+        assert_eq!(trace[1].value_size, Some(10));
+        assert_eq!(trace_period(&trace), Some(Duration::from_micros(450)));
+        assert_eq!(trace_period(&parse_trace("get 1\nget 2").unwrap()), None);
+        assert_eq!(trace_period(&parse_trace("7 get 1").unwrap()), None);
+    }
+}
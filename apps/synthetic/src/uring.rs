@@ -0,0 +1,872 @@
+use libc;
+
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use backend::Connection;
+use MAX_DATAGRAM;
+
+// The parts of the kernel's io_uring interface the client uses, from
+// linux/io_uring.h.
+const OFF_SQ_RING: libc::off_t = 0;
+const OFF_CQ_RING: libc::off_t = 0x800_0000;
+const OFF_SQES: libc::off_t = 0x1000_0000;
+
+const OP_ASYNC_CANCEL: u8 = 14;
+const OP_SEND: u8 = 26;
+const OP_RECV: u8 = 27;
+const OP_PROVIDE_BUFFERS: u8 = 31;
+const OP_MSG_RING: u8 = 40;
+
+const SQE_IO_LINK: u8 = 1 << 2;
+const SQE_BUFFER_SELECT: u8 = 1 << 5;
+const RECV_MULTISHOT: u16 = 1 << 1;
+
+const CQE_F_BUFFER: u32 = 1 << 0;
+const CQE_F_MORE: u32 = 1 << 1;
+const CQE_BUFFER_SHIFT: u32 = 16;
+
+const ENTER_GETEVENTS: u32 = 1 << 0;
+const ENTER_EXT_ARG: u32 = 1 << 3;
+const FEAT_EXT_ARG: u32 = 1 << 8;
+
+/// Entries in each ring's submission queue, which is at least as many as are
+/// queued between submissions.
+const RING_ENTRIES: u32 = 32;
+
+/// Receive buffers each connection provides the kernel, and the size of each,
+/// which holds the largest datagram. Stream data is spread over as many as it
+/// fills.
+const BUFFERS: usize = 8;
+const BUFFER_LEN: usize = MAX_DATAGRAM;
+const BUFFER_GROUP: u16 = 0;
+
+/// What each completion on the receiving ring is for.
+const RECV: u64 = 0;
+const PROVIDE: u64 = 1;
+const CANCEL: u64 = 2;
+const WAKE: u64 = 3;
+/// A write's completion on the sending ring, whose other completions are
+/// numbered from 0 in each batch of datagrams.
+const WRITE: u64 = u64::MAX;
+
+#[repr(C)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+#[repr(C)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_group: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct GeteventsArg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: u32,
+    ts: u64,
+}
+
+/// An io_uring instance: a submission and a completion queue shared with the
+/// kernel, used by one thread at a time.
+struct Ring {
+    fd: RawFd,
+    maps: Vec<(*mut libc::c_void, usize)>,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    /// Entries queued since the last submission.
+    queued: u32,
+}
+
+// The ring's memory belongs to it alone, and it is only used under a lock.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let mut params: Params = unsafe { mem::zeroed() };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                RING_ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut ring = Ring {
+            fd: fd as RawFd,
+            maps: Vec::new(),
+            sq_head: ptr::null(),
+            sq_tail: ptr::null(),
+            sq_mask: 0,
+            sq_array: ptr::null_mut(),
+            sqes: ptr::null_mut(),
+            cq_head: ptr::null(),
+            cq_tail: ptr::null(),
+            cq_mask: 0,
+            cqes: ptr::null(),
+            queued: 0,
+        };
+        // Waits that time out need the extended arguments of 5.11.
+        if params.features & FEAT_EXT_ARG == 0 {
+            return Err(Error::other("this kernel's io_uring cannot time out waits"));
+        }
+        let (sq, cq) = (&params.sq_off, &params.cq_off);
+        let sq_ring = ring.map(
+            sq.array as usize + params.sq_entries as usize * mem::size_of::<u32>(),
+            OFF_SQ_RING,
+        )?;
+        let cq_ring = ring.map(
+            cq.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>(),
+            OFF_CQ_RING,
+        )?;
+        let sqes = ring.map(params.sq_entries as usize * mem::size_of::<Sqe>(), OFF_SQES)?;
+        unsafe {
+            ring.sq_head = sq_ring.add(sq.head as usize) as *const AtomicU32;
+            ring.sq_tail = sq_ring.add(sq.tail as usize) as *const AtomicU32;
+            ring.sq_mask = *(sq_ring.add(sq.ring_mask as usize) as *const u32);
+            ring.sq_array = sq_ring.add(sq.array as usize) as *mut u32;
+            ring.sqes = sqes as *mut Sqe;
+            ring.cq_head = cq_ring.add(cq.head as usize) as *const AtomicU32;
+            ring.cq_tail = cq_ring.add(cq.tail as usize) as *const AtomicU32;
+            ring.cq_mask = *(cq_ring.add(cq.ring_mask as usize) as *const u32);
+            ring.cqes = cq_ring.add(cq.cqes as usize) as *const Cqe;
+        }
+        Ok(ring)
+    }
+
+    fn map(&mut self, len: usize, offset: libc::off_t) -> io::Result<*mut u8> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                self.fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        self.maps.push((addr, len));
+        Ok(addr as *mut u8)
+    }
+
+    /// Queues `sqe`, submitting those already queued first if there is no
+    /// room for it.
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        let (head, tail) = unsafe { (&*self.sq_head, &*self.sq_tail) };
+        let tail_index = tail.load(Ordering::Relaxed);
+        if tail_index.wrapping_sub(head.load(Ordering::Acquire)) > self.sq_mask {
+            self.enter(0, None)?;
+        }
+        let index = tail_index & self.sq_mask;
+        unsafe {
+            *self.sqes.add(index as usize) = sqe;
+            *self.sq_array.add(index as usize) = index;
+        }
+        tail.store(tail_index.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Submits the queued entries and, if `wait`, waits for a completion for
+    /// at most `timeout`, failing with `WouldBlock` once it has passed.
+    fn enter(&mut self, wait: u32, timeout: Option<Duration>) -> io::Result<()> {
+        let ts = timeout.filter(|_| wait > 0).map(|t| libc::timespec {
+            tv_sec: t.as_secs() as libc::time_t,
+            tv_nsec: t.subsec_nanos() as libc::c_long,
+        });
+        let arg = GeteventsArg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            pad: 0,
+            ts: ts
+                .as_ref()
+                .map_or(0, |ts| ts as *const libc::timespec as u64),
+        };
+        let mut flags = if wait > 0 { ENTER_GETEVENTS } else { 0 };
+        let (arg, arg_len) = match ts {
+            Some(_) => {
+                flags |= ENTER_EXT_ARG;
+                (&arg as *const GeteventsArg, mem::size_of::<GeteventsArg>())
+            }
+            None => (ptr::null(), 0),
+        };
+        loop {
+            let submitted = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd,
+                    self.queued,
+                    wait,
+                    flags,
+                    arg,
+                    arg_len,
+                )
+            };
+            if submitted >= 0 {
+                self.queued -= submitted as u32;
+                return Ok(());
+            }
+            let e = Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // Timing out reads the way the socket's own timeout would.
+                Some(libc::ETIME) => return Err(Error::from_raw_os_error(libc::EAGAIN)),
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// The next completion, if there is one.
+    fn pop(&mut self) -> Option<Cqe> {
+        let (head, tail) = unsafe { (&*self.cq_head, &*self.cq_tail) };
+        let head_index = head.load(Ordering::Relaxed);
+        if head_index == tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let cqe = unsafe { *self.cqes.add((head_index & self.cq_mask) as usize) };
+        head.store(head_index.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+
+    /// Submits the queued entries and waits for the next completion.
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<Cqe> {
+        if self.queued > 0 {
+            self.enter(0, None)?;
+        }
+        loop {
+            if let Some(cqe) = self.pop() {
+                return Ok(cqe);
+            }
+            self.enter(1, timeout)?;
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        for &(addr, len) in &self.maps {
+            unsafe {
+                libc::munmap(addr, len);
+            }
+        }
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// The sending side of a connection. A write's SEND is submitted from a copy
+/// of its data, kept until the SEND completes, and is not waited for until
+/// the next one needs the ring. Only one is in flight at a time, so that
+/// writes to a stream go out in order.
+struct Sender {
+    ring: Ring,
+    /// The data of the write in flight, if one is.
+    in_flight: Option<Vec<u8>>,
+    /// The buffer of the last write to complete, kept for the next.
+    spare: Vec<u8>,
+    /// How the last write failed, if it did after it had returned, which the
+    /// next write reports.
+    failed: Option<Error>,
+}
+
+impl Sender {
+    /// Waits for the write in flight, if there is one, to complete. Every
+    /// other SEND is waited for as it is submitted, so it is the only
+    /// completion that can be outstanding.
+    fn settle(&mut self) -> io::Result<()> {
+        while let Some(mut data) = self.in_flight.take() {
+            let cqe = match self.ring.wait(None) {
+                Ok(cqe) => cqe,
+                Err(e) => {
+                    self.in_flight = Some(data);
+                    return Err(e);
+                }
+            };
+            if cqe.user_data != WRITE {
+                self.in_flight = Some(data);
+                continue;
+            }
+            match cqe.res {
+                e if e < 0 => self.failed = Some(Error::from_raw_os_error(-e)),
+                n if (n as usize) < data.len() => self.failed = Some(ErrorKind::WriteZero.into()),
+                _ => {}
+            }
+            data.clear();
+            self.spare = data;
+        }
+        Ok(())
+    }
+}
+
+/// The receiving side of a connection: a multishot RECV, which the kernel
+/// completes each time data arrives, in one of the buffers provided to it.
+struct Receiver {
+    ring: Ring,
+    buffers: Vec<u8>,
+    /// Whether the RECV will complete again.
+    armed: bool,
+    /// The buffer being read, by id, with how far it has been read and how
+    /// much it holds.
+    current: Option<(u16, usize, usize)>,
+}
+
+impl Receiver {
+    /// Hands buffer `id` back to the kernel, for the next RECV to fill.
+    fn provide(&mut self, id: u16, count: usize) -> io::Result<()> {
+        let addr = self.buffers[id as usize * BUFFER_LEN..].as_mut_ptr();
+        self.ring.push(Sqe {
+            opcode: OP_PROVIDE_BUFFERS,
+            fd: count as i32,
+            addr: addr as u64,
+            len: BUFFER_LEN as u32,
+            off: id as u64,
+            buf_group: BUFFER_GROUP,
+            user_data: PROVIDE,
+            ..Default::default()
+        })
+    }
+
+    fn arm(&mut self, fd: RawFd) -> io::Result<()> {
+        self.ring.push(Sqe {
+            opcode: OP_RECV,
+            flags: SQE_BUFFER_SELECT,
+            ioprio: RECV_MULTISHOT,
+            fd,
+            buf_group: BUFFER_GROUP,
+            user_data: RECV,
+            ..Default::default()
+        })?;
+        self.armed = true;
+        Ok(())
+    }
+
+    /// The buffer and length of the next data received, waiting for it for
+    /// at most `timeout` if `wait` and failing with `WouldBlock` otherwise,
+    /// or None once the stream has ended or the socket is shut down.
+    fn next(
+        &mut self,
+        fd: RawFd,
+        wait: bool,
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<(u16, usize)>> {
+        loop {
+            if !self.armed {
+                self.arm(fd)?;
+            }
+            let cqe = if wait {
+                self.ring.wait(timeout)?
+            } else {
+                if self.ring.queued > 0 {
+                    self.ring.enter(0, None)?;
+                }
+                match self.ring.pop() {
+                    Some(cqe) => cqe,
+                    None => return Err(Error::from_raw_os_error(libc::EAGAIN)),
+                }
+            };
+            if cqe.user_data == WAKE {
+                return Ok(None);
+            }
+            if cqe.user_data != RECV {
+                if cqe.res < 0 && cqe.user_data == PROVIDE {
+                    return Err(Error::from_raw_os_error(-cqe.res));
+                }
+                continue;
+            }
+            if cqe.flags & CQE_F_MORE == 0 {
+                self.armed = false;
+            }
+            let id = (cqe.flags >> CQE_BUFFER_SHIFT) as u16;
+            match cqe.res {
+                len if len > 0 => return Ok(Some((id, len as usize))),
+                // The stream has ended, or, on a UDP socket, an empty
+                // datagram came in a buffer of its own.
+                0 if cqe.flags & CQE_F_BUFFER != 0 => return Ok(Some((id, 0))),
+                0 => return Ok(None),
+                // Every buffer is in use, and one is on its way back.
+                e if -e == libc::ENOBUFS => continue,
+                e => return Err(Error::from_raw_os_error(-e)),
+            }
+        }
+    }
+}
+
+/// A Linux socket whose reads and writes go through io_uring. Writes are
+/// SENDs submitted on a ring of their own, and reads take their data from a
+/// multishot RECV on another, so that a thread blocked reading does not
+/// hold up one writing. A write returns once its SEND is submitted, and a
+/// failure it completes with is returned by the next. A read hands out the
+/// data of one completion at a time, so each protocol frames its responses
+/// as over any other connection.
+pub struct UringSocket {
+    fd: RawFd,
+    datagram: bool,
+    /// The receiving ring, which a shutdown wakes.
+    receiver_fd: RawFd,
+    shut_down: AtomicBool,
+    sender: Mutex<Sender>,
+    receiver: Mutex<Receiver>,
+    timeout: Mutex<Option<Duration>>,
+    inner: Connection,
+}
+
+/// Wraps a Linux socket so that its I/O goes through io_uring, or hands it
+/// back with the reason if the rings cannot be set up.
+pub fn wrap(inner: Connection) -> Result<Connection, (Connection, Error)> {
+    let (fd, datagram) = match inner {
+        Connection::LinuxUdp(ref s) => (s.as_raw_fd(), true),
+        Connection::LinuxTcp(ref s) => (s.as_raw_fd(), false),
+        Connection::LinuxUnix(ref s) => (s.as_raw_fd(), false),
+        _ => {
+            let e = Error::other("only Linux sockets can use io_uring");
+            return Err((inner, e));
+        }
+    };
+    let rings = Ring::new().and_then(|sender| Ok((sender, Ring::new()?)));
+    let (sender, ring) = match rings {
+        Ok(rings) => rings,
+        Err(e) => return Err((inner, e)),
+    };
+    let mut receiver = Receiver {
+        ring,
+        buffers: vec![0; BUFFERS * BUFFER_LEN],
+        armed: false,
+        current: None,
+    };
+    if let Err(e) = receiver.provide(0, BUFFERS).and_then(|_| receiver.arm(fd)) {
+        return Err((inner, e));
+    }
+    Ok(Connection::Uring(Box::new(UringSocket {
+        fd,
+        datagram,
+        receiver_fd: receiver.ring.fd,
+        shut_down: AtomicBool::new(false),
+        sender: Mutex::new(Sender {
+            ring: sender,
+            in_flight: None,
+            spare: Vec::new(),
+            failed: None,
+        }),
+        receiver: Mutex::new(receiver),
+        timeout: Mutex::new(None),
+        inner,
+    })))
+}
+
+/// Checks that this kernel has all the io_uring the client uses, which
+/// multishot receives need 6.0 for, by echoing a byte over a socket pair.
+pub fn check_support() -> io::Result<()> {
+    let (a, b) = UnixStream::pair()?;
+    let socket = wrap(Connection::LinuxUnix(a)).map_err(|(_, e)| e)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    (&b).write_all(b"x")?;
+    let mut buf = [0u8; 1];
+    match (&socket).read(&mut buf)? {
+        1 => Ok(()),
+        _ => Err(Error::other(
+            "this kernel's io_uring has no multishot receives",
+        )),
+    }
+}
+
+impl UringSocket {
+    pub fn inner(&self) -> &Connection {
+        &self.inner
+    }
+
+    /// Shuts the socket down, and wakes a read waiting on it, which a UDP
+    /// socket's shutdown alone does not.
+    pub fn shutdown(&self) {
+        self.inner.shutdown();
+        self.shut_down.store(true, Ordering::SeqCst);
+        let mut sender = self.sender.lock().unwrap();
+        let wake = Sqe {
+            opcode: OP_MSG_RING,
+            fd: self.receiver_fd,
+            off: WAKE,
+            user_data: WAKE,
+            ..Default::default()
+        };
+        if sender.settle().is_ok() && sender.ring.push(wake).is_ok() {
+            let _ = sender.ring.wait(None);
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.shut_down.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+        let timeout = *self.timeout.lock().unwrap();
+        let mut receiver = self.receiver.lock().unwrap();
+        self.read_from(&mut receiver, buf, true, timeout)
+    }
+
+    /// Copies out what it can of the buffer being read, or of the next one
+    /// received, and hands the buffer back once it is used up. A datagram is
+    /// used up by any read, whose buffer it is cut to fit.
+    fn read_from(
+        &self,
+        receiver: &mut Receiver,
+        buf: &mut [u8],
+        wait: bool,
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        let (id, offset, len) = match receiver.current.take() {
+            Some(current) => current,
+            None => match receiver.next(self.fd, wait, timeout)? {
+                Some((id, len)) => (id, 0, len),
+                None => return Ok(0),
+            },
+        };
+        let n = usize::min(buf.len(), len - offset);
+        let start = id as usize * BUFFER_LEN + offset;
+        buf[..n].copy_from_slice(&receiver.buffers[start..start + n]);
+        if self.datagram || offset + n == len {
+            receiver.provide(id, 1)?;
+        } else {
+            receiver.current = Some((id, offset + n, len));
+        }
+        Ok(n)
+    }
+
+    /// Reads one datagram into each of `bufs` for as long as they have been
+    /// received, waiting only for the first, like recvmmsg.
+    pub fn recv_datagrams(&self, bufs: &mut [Vec<u8>], lens: &mut [usize]) -> io::Result<usize> {
+        let timeout = *self.timeout.lock().unwrap();
+        let mut receiver = self.receiver.lock().unwrap();
+        lens[0] = self.read_from(&mut receiver, &mut bufs[0], true, timeout)?;
+        for i in 1..bufs.len() {
+            match self.read_from(&mut receiver, &mut bufs[i], false, None) {
+                Ok(len) => lens[i] = len,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(i),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(bufs.len())
+    }
+
+    /// Submits a SEND of all of `buf`, which a stream's waits to finish, after
+    /// the last write's has completed.
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut sender = self.sender.lock().unwrap();
+        sender.settle()?;
+        if let Some(e) = sender.failed.take() {
+            return Err(e);
+        }
+        let mut data = mem::take(&mut sender.spare);
+        data.extend_from_slice(buf);
+        let mut sqe = self.send(&data, WRITE);
+        if !self.datagram {
+            sqe.op_flags |= libc::MSG_WAITALL as u32;
+        }
+        sender.ring.push(sqe)?;
+        // Moving the copy leaves its data where the SEND points.
+        sender.in_flight = Some(data);
+        sender.ring.enter(0, None)?;
+        Ok(buf.len())
+    }
+
+    /// Sends each of `datagrams` with a SEND of its own, all linked so they
+    /// go in order and submitted together, like sendmmsg. Returns how many
+    /// were sent, which is fewer than all only if the next one could not be.
+    pub fn send_datagrams(&self, datagrams: &[&[u8]]) -> io::Result<usize> {
+        let mut sender = self.sender.lock().unwrap();
+        sender.settle()?;
+        let sender = &mut sender.ring;
+        let chain = usize::min(datagrams.len(), RING_ENTRIES as usize);
+        for (i, datagram) in datagrams[..chain].iter().enumerate() {
+            let mut sqe = self.send(datagram, i as u64);
+            if i + 1 < chain {
+                sqe.flags |= SQE_IO_LINK;
+            }
+            sender.push(sqe)?;
+        }
+        let mut results = vec![0; chain];
+        for _ in 0..chain {
+            let cqe = sender.wait(None)?;
+            results[cqe.user_data as usize] = cqe.res;
+        }
+        match results.iter().position(|&res| res < 0) {
+            Some(0) => Err(Error::from_raw_os_error(-results[0])),
+            Some(sent) => Ok(sent),
+            None => Ok(chain),
+        }
+    }
+
+    fn send(&self, buf: &[u8], user_data: u64) -> Sqe {
+        Sqe {
+            opcode: OP_SEND,
+            fd: self.fd,
+            addr: buf.as_ptr() as u64,
+            len: buf.len() as u32,
+            op_flags: libc::MSG_NOSIGNAL as u32,
+            user_data,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for UringSocket {
+    /// Waits for the write in flight and cancels the RECV and waits for its
+    /// last completion, so the kernel is done with their buffers before they
+    /// are freed. Buffers it may still be using are leaked instead.
+    fn drop(&mut self) {
+        let sender = self.sender.get_mut().unwrap();
+        if sender.settle().is_err() {
+            mem::forget(sender.in_flight.take());
+        }
+        let receiver = self.receiver.get_mut().unwrap();
+        if !receiver.armed {
+            return;
+        }
+        let cancel = Sqe {
+            opcode: OP_ASYNC_CANCEL,
+            addr: RECV,
+            user_data: CANCEL,
+            ..Default::default()
+        };
+        if receiver.ring.push(cancel).is_err() {
+            mem::forget(mem::take(&mut receiver.buffers));
+            return;
+        }
+        while receiver.armed {
+            match receiver.ring.wait(Some(Duration::from_secs(1))) {
+                Ok(cqe) if cqe.user_data == RECV && cqe.flags & CQE_F_MORE == 0 => {
+                    receiver.armed = false
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    mem::forget(mem::take(&mut receiver.buffers));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Whether the kernel running the tests has io_uring, which a sandbox
+    /// may not.
+    fn supported() -> bool {
+        match check_support() {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Skipping, io_uring is unavailable: {}", e);
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn streams_read_across_buffers() {
+        if !supported() {
+            return;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let socket = match wrap(Connection::LinuxTcp(client)) {
+            Ok(socket) => socket,
+            Err((_, e)) => panic!("{}", e),
+        };
+
+        // More than all the buffers hold at once, read in odd sizes.
+        let sent: Vec<u8> = (0..BUFFERS * BUFFER_LEN * 2)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let writer = {
+            let sent = sent.clone();
+            thread::spawn(move || server.write_all(&sent).map(|_| server))
+        };
+        let mut received = vec![0; sent.len()];
+        for chunk in received.chunks_mut(1000) {
+            (&socket).read_exact(chunk).unwrap();
+        }
+        assert!(received == sent);
+
+        // Writes go through the other ring, and a read times out as the
+        // socket's own would.
+        let mut server = writer.join().unwrap().unwrap();
+        (&socket).write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let e = (&socket).read(&mut buf).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+
+        drop(server);
+        assert_eq!((&socket).read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn writes_go_out_in_order_and_fail_late() {
+        if !supported() {
+            return;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let socket = match wrap(Connection::LinuxTcp(client)) {
+            Ok(socket) => socket,
+            Err((_, e)) => panic!("{}", e),
+        };
+
+        // Each write waits for the last one's SEND, so none overtakes another.
+        for i in 0..10_000u32 {
+            (&socket).write_all(&i.to_le_bytes()).unwrap();
+        }
+        let mut buf = [0u8; 4];
+        for i in 0..10_000u32 {
+            server.read_exact(&mut buf).unwrap();
+            assert_eq!(u32::from_le_bytes(buf), i);
+        }
+
+        // A SEND that fails after its write returned fails a later write.
+        drop(server);
+        assert!((0..100).any(|_| (&socket).write_all(b"ping").is_err()));
+    }
+
+    #[test]
+    fn datagrams_keep_their_bounds() {
+        if !supported() {
+            return;
+        }
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        server.connect(client.local_addr().unwrap()).unwrap();
+        let socket = match wrap(Connection::LinuxUdp(client)) {
+            Ok(socket) => socket,
+            Err((_, e)) => panic!("{}", e),
+        };
+
+        let datagrams: Vec<&[u8]> = vec![b"a", b"bb", b"ccc"];
+        assert_eq!(socket.send_datagrams(&datagrams).unwrap(), 3);
+        let mut buf = [0u8; 8];
+        for datagram in &datagrams {
+            let len = server.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], *datagram);
+            server.send(&buf[..len]).unwrap();
+        }
+
+        // A short read cuts the datagram, and the rest of it is dropped.
+        let mut one = [0u8; 1];
+        assert_eq!((&socket).read(&mut one).unwrap(), 1);
+        let mut bufs = vec![vec![0; 8]; 4];
+        let mut lens = [0; 4];
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut n = 0;
+        while n < 2 {
+            let received = socket
+                .recv_datagrams(&mut bufs[n..], &mut lens[n..])
+                .unwrap();
+            n += received;
+        }
+        assert_eq!(&bufs[0][..lens[0]], b"bb");
+        assert_eq!(&bufs[1][..lens[1]], b"ccc");
+
+        // A shutdown wakes a read waiting on the socket.
+        let socket = Arc::new(socket);
+        let reader = {
+            let socket = socket.clone();
+            thread::spawn(move || (&*socket).read(&mut [0u8; 8]).unwrap())
+        };
+        thread::sleep(Duration::from_millis(10));
+        socket.shutdown();
+        assert_eq!(reader.join().unwrap(), 0);
+    }
+}